@@ -91,7 +91,7 @@ fn run(opts: Options) -> ExitCode {
         Some(path) => {
             trace!("Opening --output_path file {}...", path.display());
             let file = fs::OpenOptions::new()
-                .create(true).write(true).append(false)
+                .create(true).write(true).truncate(true)
                 .open(path);
             match file {
                 Ok(file) => {
@@ -152,7 +152,7 @@ const YES: &'static str = "y";
 
 
 /// Render given `ImageMacro` and write it to the output.
-fn render<W: Write>(im: rofl::ImageMacro, mut output: W) -> io::Result<()> {
+fn render<W: Write>(im: rofl::ImageMacro, output: W) -> io::Result<()> {
     trace!("Rendering macro {:#?}", im);
 
     // TODO: allow to adjust the resource directories from the command line
@@ -160,9 +160,10 @@ fn render<W: Write>(im: rofl::ImageMacro, mut output: W) -> io::Result<()> {
         .template_directory("data/templates")
         .font_directory("data/fonts")
         .build().map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-    let captioned = engine.caption(im)
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
 
-    trace!("Writing {} bytes to the output...", captioned.len());
-    output.write_all(captioned.bytes())
+    // Write the image straight to the output, rather than keeping a copy of it around.
+    let format = engine.caption_to_writer(im, io::BufWriter::new(output))
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    debug!("Image macro written to the output as {:?}", format);
+    Ok(())
 }
@@ -0,0 +1,84 @@
+//! Module implementing the cancellation of captioning tasks.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+
+/// Token which allows to stop rendering an image macro while it's in progress.
+///
+/// The rendering checks the token between the stages of its pipeline
+/// and between the frames of animated templates, and fails with `CaptionError::Cancelled`
+/// as soon as the token has been cancelled or its deadline has passed.
+///
+/// Clones of the token share its state, so one of them can be given to the `Engine`
+/// while another is kept around to call `cancel` on.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+    deadline: Option<Instant>,
+}
+
+impl CancelToken {
+    /// Create a new token, which isn't cancelled and has no deadline.
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the moment after which the token counts as cancelled.
+    #[inline]
+    pub fn with_deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline); self
+    }
+
+    /// Set the deadline of the token to be the given amount of time from now.
+    #[inline]
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        self.with_deadline(Instant::now() + timeout)
+    }
+}
+
+impl CancelToken {
+    /// The deadline of the token, if any.
+    #[inline]
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Cancel the token (and all its clones).
+    #[inline]
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether the token has been cancelled, or its deadline has passed.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+            || self.deadline.map(|d| Instant::now() >= d).unwrap_or(false)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use super::CancelToken;
+
+    #[test]
+    fn cancel_clones() {
+        let token = CancelToken::new();
+        let clone = token.clone();
+        assert!(!clone.is_cancelled());
+        token.cancel();
+        assert!(clone.is_cancelled());
+    }
+
+    #[test]
+    fn deadline() {
+        let future = CancelToken::new().with_timeout(Duration::from_secs(3600));
+        assert!(!future.is_cancelled());
+        let past = CancelToken::new().with_deadline(Instant::now());
+        assert!(past.is_cancelled());
+    }
+}
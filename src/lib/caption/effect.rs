@@ -0,0 +1,150 @@
+//! Module defining the interface for custom rendering effects.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use image::DynamicImage;
+
+use model::ImageMacro;
+
+
+/// Custom rendering pass that can be applied to the frames of an image macro.
+///
+/// Effects are registered with the `Engine` under a name (see `EngineBuilder::effect`),
+/// and then referenced by that name from `ImageMacro::effects`.
+///
+/// Any `Fn(DynamicImage, &FrameInfo) -> Result<DynamicImage, EffectError>` closure
+/// is also an `Effect` that runs at the default stage.
+pub trait Effect: Send + Sync {
+    /// The point of the captioning pipeline where the effect should be applied.
+    fn stage(&self) -> EffectStage {
+        EffectStage::default()
+    }
+
+    /// Apply the effect to a single frame of the image macro.
+    /// Returns the modified frame.
+    fn apply(&self, frame: DynamicImage, info: &FrameInfo) -> Result<DynamicImage, EffectError>;
+}
+
+impl<F> Effect for F
+    where F: Fn(DynamicImage, &FrameInfo) -> Result<DynamicImage, EffectError> + Send + Sync
+{
+    fn apply(&self, frame: DynamicImage, info: &FrameInfo) -> Result<DynamicImage, EffectError> {
+        self(frame, info)
+    }
+}
+
+/// Error that an `Effect` may return when it fails to apply.
+pub type EffectError = Box<Error + Send + Sync>;
+
+
+/// Point of the captioning pipeline at which an `Effect` is applied.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum EffectStage {
+    /// Before the template is resized
+    /// (but after the `ImageMacro` transformations have been applied).
+    PreResize,
+    /// After the template has been resized (and filtered), but before it's captioned.
+    PostResize,
+    /// After the captions have been rendered (and post-filters applied).
+    PostCaption,
+}
+
+impl Default for EffectStage {
+    fn default() -> Self {
+        EffectStage::PostResize
+    }
+}
+
+
+/// Information about the frame that an `Effect` is being applied to.
+#[derive(Clone, Copy, Debug)]
+pub struct FrameInfo<'m> {
+    /// Index of the frame (0-based).
+    pub index: usize,
+    /// Total number of frames in the image macro.
+    pub count: usize,
+    /// How long the frame is displayed for, if it's a part of an animation.
+    pub delay: Option<Duration>,
+    /// The stage of the captioning pipeline that we're currently at.
+    pub stage: EffectStage,
+    /// The image macro being rendered.
+    pub image_macro: &'m ImageMacro,
+}
+
+impl<'m> FrameInfo<'m> {
+    /// Whether the frame is a part of an animation.
+    #[inline]
+    pub fn is_animated(&self) -> bool {
+        self.count > 1
+    }
+}
+
+
+/// Collection of named effects registered with an `Engine`.
+#[derive(Clone, Default)]
+pub(crate) struct Effects {
+    effects: HashMap<String, Arc<Effect>>,
+}
+
+impl Effects {
+    /// Register an effect under given name.
+    /// Returns `false` if an effect of that name already exists.
+    pub fn insert(&mut self, name: &str, effect: Arc<Effect>) -> bool {
+        let name = normalize_name(name);
+        if self.effects.contains_key(&name) {
+            return false;
+        }
+        self.effects.insert(name, effect);
+        true
+    }
+
+    /// Retrieve the effect of given name, if it has been registered.
+    #[inline]
+    pub fn get(&self, name: &str) -> Option<Arc<Effect>> {
+        self.effects.get(&normalize_name(name)).cloned()
+    }
+}
+
+impl fmt::Debug for Effects {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let mut names: Vec<_> = self.effects.keys().collect();
+        names.sort();
+        fmt.debug_set().entries(names).finish()
+    }
+}
+
+#[inline]
+fn normalize_name(name: &str) -> String {
+    name.trim().to_lowercase()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use image::DynamicImage;
+    use super::{Effect, EffectError, EffectStage, Effects, FrameInfo};
+
+    fn identity(img: DynamicImage, _: &FrameInfo) -> Result<DynamicImage, EffectError> {
+        Ok(img)
+    }
+
+    #[test]
+    fn closures_use_default_stage() {
+        assert_eq!(EffectStage::PostResize, identity.stage());
+    }
+
+    #[test]
+    fn names_are_case_insensitive() {
+        let mut effects = Effects::default();
+        assert!(effects.insert("Glitch", Arc::new(identity)));
+        assert!(effects.get("glitch").is_some());
+        assert!(effects.get("  GLITCH ").is_some());
+        assert!(!effects.insert("glitch", Arc::new(identity)));
+        assert!(effects.get("vhs").is_none());
+    }
+}
@@ -4,17 +4,24 @@ use std::error;
 use std::fmt;
 use std::mem;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use either::*;
 
 use ext::rust::OptionMutExt;
+use model::Resampling;
 use resources::{CachingLoader, Font, FontLoader, Loader, Template, TemplateLoader};
-use super::config::{self, Config};
+use util::animated_gif::Dithering;
+use util::glyphs::GlyphCache;
+use super::super::effect::{Effect, Effects};
+use super::super::watermark::{Watermark, WatermarkContent};
+use super::config::{self, Config, Upscaling};
 use super::Engine;
 
 
 const DEFAULT_TEMPLATE_CAPACITY: usize = 128;
 const DEFAULT_FONT_CAPACITY: usize = 16;
+const DEFAULT_GLYPH_CAPACITY: usize = 2048;
 
 
 /// Builder for `Engine`.
@@ -27,9 +34,23 @@ pub struct Builder<Tl = TemplateLoader, Fl = FontLoader>
 
     template_loader_builder: Option<LoaderBuilder<Tl>>,
     font_loader_builder: Option<LoaderBuilder<Fl>>,
+    glyph_cache_size: Option<usize>,
 
     jpeg_quality: Option<u8>,
     gif_quality: Option<u8>,
+    gif_optimize: Option<bool>,
+    gif_dithering: Option<Dithering>,
+    gif_reserve_caption_colors: Option<bool>,
+    webp_quality: Option<u8>,
+    webp_lossless: Option<bool>,
+    resampling: Option<Resampling>,
+    upscaling: Option<Upscaling>,
+    preserve_metadata: Option<bool>,
+    parallelism: Option<usize>,
+    text_size_range: Option<(f32, f32)>,
+
+    effects: Effects,
+    watermark: Option<Watermark>,
 }
 
 
@@ -133,8 +154,21 @@ impl<Tl, Fl> Default for Builder<Tl, Fl>
             errors: vec![],
             template_loader_builder: None,
             font_loader_builder: None,
+            glyph_cache_size: None,
             jpeg_quality: None,
             gif_quality: None,
+            gif_optimize: None,
+            gif_dithering: None,
+            gif_reserve_caption_colors: None,
+            webp_quality: None,
+            webp_lossless: None,
+            resampling: None,
+            upscaling: None,
+            preserve_metadata: None,
+            parallelism: None,
+            text_size_range: None,
+            effects: Effects::default(),
+            watermark: None,
         }
     }
 }
@@ -215,6 +249,14 @@ impl<Tl, Fl> Builder<Tl, Fl>
         if ok { self } else { self.err(Error::loader_config(Resource::Font)) }
     }
 
+    /// Change the size of the cache of rasterized glyphs (in glyphs).
+    ///
+    /// Use zero to disable the glyph cache.
+    #[inline]
+    pub fn glyph_cache_size(mut self, size: usize) -> Self {
+        self.glyph_cache_size = Some(size); self
+    }
+
     /// Set a custom "raw" loader for templates.
     ///
     /// Templates loaded this way will not be cached (unless the loader itself
@@ -257,6 +299,106 @@ impl<Tl, Fl> Builder<Tl, Fl>
     pub fn gif_quality(mut self, quality: u8) -> Self {
         self.gif_quality = Some(quality); self
     }
+
+    /// Set whether GIF animations generated by the `Engine` should be optimized for size.
+    ///
+    /// Optimized animations are usually much smaller, but take a little longer to encode.
+    /// By default, they are not optimized.
+    #[inline]
+    pub fn gif_optimize(mut self, optimize: bool) -> Self {
+        self.gif_optimize = Some(optimize); self
+    }
+
+    /// Set the dithering method used when reducing the colors of GIFs.
+    #[inline]
+    pub fn gif_dithering(mut self, dithering: Dithering) -> Self {
+        self.gif_dithering = Some(dithering); self
+    }
+
+    /// Set whether caption colors should be reserved exactly in GIF palettes.
+    #[inline]
+    pub fn gif_reserve_caption_colors(mut self, reserve: bool) -> Self {
+        self.gif_reserve_caption_colors = Some(reserve); self
+    }
+
+    /// Set the quality percentage of lossy WebP images & animations generated by the `Engine`.
+    #[inline]
+    pub fn webp_quality(mut self, quality: u8) -> Self {
+        self.webp_quality = Some(quality); self
+    }
+
+    /// Set whether WebP images & animations generated by the `Engine` should be lossless.
+    ///
+    /// Lossless WebP has perfect quality but is considerably larger.
+    #[inline]
+    pub fn webp_lossless(mut self, lossless: bool) -> Self {
+        self.webp_lossless = Some(lossless); self
+    }
+
+    /// Set the default filter used to resize templates.
+    ///
+    /// Individual `ImageMacro`s can still override it.
+    #[inline]
+    pub fn resampling(mut self, resampling: Resampling) -> Self {
+        self.resampling = Some(resampling); self
+    }
+
+    /// Set the policy for enlarging templates beyond their original size.
+    #[inline]
+    pub fn upscaling(mut self, upscaling: Upscaling) -> Self {
+        self.upscaling = Some(upscaling); self
+    }
+
+    /// Set whether the EXIF metadata of templates should be kept in image macros.
+    ///
+    /// Templates are always rotated upright & converted to sRGB when loaded,
+    /// so the preserved metadata is adjusted accordingly.
+    #[inline]
+    pub fn preserve_metadata(mut self, preserve: bool) -> Self {
+        self.preserve_metadata = Some(preserve); self
+    }
+
+    /// Set the maximum number of frames of animated templates that are processed at once.
+    ///
+    /// Use 1 to process the frames sequentially.
+    /// By default, as many frames are processed as there are CPU cores.
+    #[inline]
+    pub fn parallelism(mut self, parallelism: usize) -> Self {
+        self.parallelism = Some(parallelism); self
+    }
+
+    /// Set the range of text sizes that captions of `Size::Fit`
+    /// and `Size::Shrink` can be rendered at.
+    ///
+    /// By default, such captions are never larger than `DEFAULT_TEXT_SIZE`.
+    #[inline]
+    pub fn text_size_range(mut self, min: f32, max: f32) -> Self {
+        self.text_size_range = Some((min, max)); self
+    }
+}
+impl<Tl, Fl> Builder<Tl, Fl>
+    where Tl: Loader<Item=Template>, Fl: Loader<Item=Font>
+{
+    /// Register a custom effect under given name.
+    ///
+    /// `ImageMacro`s can then refer to the effect by this (case-insensitive) name
+    /// in their `effects` field.
+    #[inline]
+    pub fn effect<N, E>(mut self, name: N, effect: E) -> Self
+        where N: AsRef<str>, E: Effect + 'static
+    {
+        let name = name.as_ref();
+        let ok = !name.trim().is_empty() && self.effects.insert(name, Arc::new(effect));
+        if ok { self } else { self.err(Error::effect(name)) }
+    }
+
+    /// Set a watermark to be put on every image macro rendered by the `Engine`.
+    ///
+    /// The watermark is applied to every frame, after the captions have been rendered.
+    #[inline]
+    pub fn watermark(mut self, watermark: Watermark) -> Self {
+        self.watermark = Some(watermark); self
+    }
 }
 
 // Validation & building.
@@ -274,7 +416,10 @@ impl<Tl, Fl> Builder<Tl, Fl>
         let font_loader = self.font_loader_builder
             .ok_or_else(|| Error::no_loader_for(Resource::Font))?
             .build(|d| FontLoader::new(d))?;
-        Ok(Engine::from(super::Inner::new(config, template_loader, font_loader)))
+        let glyph_cache = GlyphCache::new(
+            self.glyph_cache_size.unwrap_or(DEFAULT_GLYPH_CAPACITY));
+        Ok(Engine::from(super::Inner::new(
+            config, template_loader, font_loader, glyph_cache, self.effects, self.watermark)))
     }
 }
 
@@ -331,22 +476,57 @@ impl<Tl, Fl> Builder<Tl, Fl>
     fn build_config(&self) -> Result<Config, config::Error> {
         let mut config = Config::default();
         if let Some(quality) = self.jpeg_quality {
-            Self::validate_quality(quality, config::Error::GifQuality)?;
             config.jpeg_quality = quality;
         }
         if let Some(quality) = self.gif_quality {
-            Self::validate_quality(quality, config::Error::JpegQuality)?;
             config.gif_quality = quality;
         }
-        Ok(config)
+        if let Some(optimize) = self.gif_optimize {
+            config.gif_optimize = optimize;
+        }
+        if let Some(dithering) = self.gif_dithering {
+            config.gif_dithering = dithering;
+        }
+        if let Some(reserve) = self.gif_reserve_caption_colors {
+            config.gif_reserve_caption_colors = reserve;
+        }
+        if let Some(quality) = self.webp_quality {
+            config.webp_quality = quality;
+        }
+        if let Some(lossless) = self.webp_lossless {
+            config.webp_lossless = lossless;
+        }
+        if let Some(resampling) = self.resampling {
+            config.resampling = resampling;
+        }
+        if let Some(upscaling) = self.upscaling {
+            config.upscaling = upscaling;
+        }
+        if let Some(preserve) = self.preserve_metadata {
+            config.preserve_metadata = preserve;
+        }
+        if let Some(parallelism) = self.parallelism {
+            config.parallelism = parallelism;
+        }
+        if let Some((min, max)) = self.text_size_range {
+            config.min_text_size = min;
+            config.max_text_size = max;
+        }
+        if let Some(ref watermark) = self.watermark {
+            Self::validate_watermark(watermark)?;
+        }
+        config.validate().map(|()| config)
     }
 
     #[doc(hidden)]
-    fn validate_quality<F>(quality: u8, err_ctor: F) -> Result<(), config::Error>
-        where F: FnOnce(u8) -> config::Error
-    {
-        if !(0 < quality && quality <= 100) {
-            return Err(err_ctor(quality));
+    fn validate_watermark(watermark: &Watermark) -> Result<(), config::Error> {
+        if !(watermark.opacity >= 0.0 && watermark.opacity <= 1.0) {
+            return Err(config::Error::WatermarkOpacity(watermark.opacity));
+        }
+        if let WatermarkContent::Text{size, ..} = watermark.content {
+            if !(size.is_finite() && size > 0.0) {
+                return Err(config::Error::WatermarkTextSize(size));
+            }
         }
         Ok(())
     }
@@ -404,6 +584,8 @@ pub enum Error {
     LoaderConfig(Resource),
     /// Error in the `Engine` configuration parameters.
     EngineConfig(config::Error),
+    /// Custom effect has an empty name, or one that's already taken.
+    Effect(String),
 }
 
 impl Error {
@@ -423,6 +605,10 @@ impl Error {
     pub(super) fn engine_config(inner: config::Error) -> Self {
         Error::EngineConfig(inner)
     }
+    #[inline]
+    pub(super) fn effect<N: ToString>(name: N) -> Self {
+        Error::Effect(name.to_string())
+    }
 }
 impl From<config::Error> for Error {
     fn from(inner: config::Error) -> Self {
@@ -449,6 +635,8 @@ impl fmt::Display for Error {
             Error::LoaderConfig(r) => write!(fmt,
                 "invalid combination of configuration parameters for setting up {} loader", r),
             Error::EngineConfig(ref e) => write!(fmt, "engine configuration error: {}", e),
+            Error::Effect(ref name) => write!(fmt,
+                "invalid or duplicate name of custom effect: `{}`", name),
         }
     }
 }
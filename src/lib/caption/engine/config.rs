@@ -3,6 +3,15 @@
 use std::error;
 use std::fmt;
 
+use rayon;
+
+use model::{Resampling, DEFAULT_RESAMPLING, DEFAULT_TEXT_SIZE};
+use util::animated_gif::Dithering;
+
+
+/// Default minimum size of the text of captions that are fitted to the image.
+const MIN_TEXT_SIZE: f32 = 8.0;
+
 
 /// Structure holding configuration for the `Engine`.
 ///
@@ -13,6 +22,48 @@ pub struct Config {
     pub jpeg_quality: u8,
     /// Quality of the generated GIF animations (in %).
     pub gif_quality: u8,
+    /// Whether GIF animations should be optimized for size
+    /// (see `animated_gif::EncodeOptions::optimize`).
+    ///
+    /// This is off by default, so that the output stays the same as in previous versions.
+    pub gif_optimize: bool,
+    /// Dithering method used when reducing the colors of GIFs.
+    pub gif_dithering: Dithering,
+    /// Whether the colors of captions should be reserved in GIF palettes,
+    /// so that they're rendered exactly rather than approximated.
+    pub gif_reserve_caption_colors: bool,
+    /// Quality of the generated WebP images & animations (in %).
+    ///
+    /// This only matters if `webp_lossless` is false.
+    pub webp_quality: u8,
+    /// Whether WebP images & animations should be compressed losslessly.
+    pub webp_lossless: bool,
+    /// Filter used to resize templates,
+    /// unless an `ImageMacro` asks for a different one.
+    pub resampling: Resampling,
+    /// Policy for resizing templates beyond their original size.
+    pub upscaling: Upscaling,
+    /// Whether the metadata of still image templates (like EXIF)
+    /// should be copied to the PNG & JPEG image macros made from them.
+    ///
+    /// By default, all metadata is stripped from the output.
+    pub preserve_metadata: bool,
+    /// Maximum number of frames of an animated template that are processed at once
+    /// (resized, captioned, quantized, etc.).
+    ///
+    /// Frames are processed on a thread pool shared by all `Engine`s,
+    /// so the actual parallelism is also limited by the number of CPU cores.
+    /// A value of 1 means the frames are processed sequentially,
+    /// whereas by default, all the available cores are used.
+    pub parallelism: usize,
+    /// Smallest size that the text of `Size::Fit` and `Size::Shrink` captions
+    /// can be rendered at.
+    ///
+    /// Texts that don't fit even at this size will overflow their image.
+    pub min_text_size: f32,
+    /// Largest size that the text of `Size::Fit` and `Size::Shrink` captions
+    /// can be rendered at.
+    pub max_text_size: f32,
 }
 
 impl Default for Config {
@@ -21,6 +72,79 @@ impl Default for Config {
         Config {
             jpeg_quality: 85,
             gif_quality: 60,
+            gif_optimize: false,
+            gif_dithering: Dithering::None,
+            gif_reserve_caption_colors: true,
+            webp_quality: 80,
+            webp_lossless: false,
+            resampling: DEFAULT_RESAMPLING,
+            upscaling: Upscaling::Allow,
+            preserve_metadata: false,
+            parallelism: rayon::current_num_threads(),
+            min_text_size: MIN_TEXT_SIZE,
+            max_text_size: DEFAULT_TEXT_SIZE,
+        }
+    }
+}
+
+impl Config {
+    /// Check that all the options have valid values.
+    ///
+    /// `Builder` does this when the `Engine` is created, but the configuration
+    /// can be changed afterwards with `Engine::config_mut`, so the captioning tasks
+    /// check it again before they use it.
+    pub fn validate(&self) -> Result<(), Error> {
+        let is_quality = |q| 0 < q && q <= 100;
+        if !is_quality(self.jpeg_quality) {
+            return Err(Error::JpegQuality(self.jpeg_quality));
+        }
+        if !is_quality(self.gif_quality) {
+            return Err(Error::GifQuality(self.gif_quality));
+        }
+        if !is_quality(self.webp_quality) {
+            return Err(Error::WebpQuality(self.webp_quality));
+        }
+        if let Upscaling::Limit(factor) = self.upscaling {
+            if !(factor.is_finite() && factor >= 1.0) {
+                return Err(Error::UpscalingLimit(factor));
+            }
+        }
+        if self.parallelism == 0 {
+            return Err(Error::Parallelism(self.parallelism));
+        }
+        let (min, max) = (self.min_text_size, self.max_text_size);
+        if !(min.is_finite() && max.is_finite() && 0.0 < min && min <= max) {
+            return Err(Error::TextSizeRange(min, max));
+        }
+        Ok(())
+    }
+}
+
+
+/// Policy for upscaling the templates beyond their native resolution.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Upscaling {
+    /// Templates can be enlarged to any size requested by the `ImageMacro`.
+    Allow,
+    /// Templates are never enlarged.
+    ///
+    /// Image macros that ask for a larger size will be rendered
+    /// at the template's original size instead.
+    Forbid,
+    /// Templates can be enlarged up to given factor of their original size.
+    ///
+    /// The factor must be a finite number that's at least 1.0.
+    Limit(f32),
+}
+
+impl Upscaling {
+    /// The maximum scale factor that the template can be resized by.
+    #[inline]
+    pub fn max_factor(&self) -> Option<f32> {
+        match *self {
+            Upscaling::Allow => None,
+            Upscaling::Forbid => Some(1.0),
+            Upscaling::Limit(f) => Some(f),
         }
     }
 }
@@ -33,6 +157,18 @@ pub enum Error {
     GifQuality(u8),
     /// Invalid value for the JPEG image quality percentage.
     JpegQuality(u8),
+    /// Invalid value for the WebP quality percentage.
+    WebpQuality(u8),
+    /// Invalid value for the upscaling limit factor.
+    UpscalingLimit(f32),
+    /// Invalid value for the maximum number of frames processed at once.
+    Parallelism(usize),
+    /// Invalid range of text sizes for captions that are fitted to the image.
+    TextSizeRange(f32, f32),
+    /// Invalid value for the watermark opacity.
+    WatermarkOpacity(f32),
+    /// Invalid size of the watermark text.
+    WatermarkTextSize(f32),
 }
 
 impl error::Error for Error {
@@ -45,6 +181,13 @@ impl fmt::Display for Error {
         match *self {
             Error::GifQuality(q) => write!(fmt, "invalid GIF quality value: {}%", q),
             Error::JpegQuality(q) => write!(fmt, "invalid JPEG quality value: {}%", q),
+            Error::WebpQuality(q) => write!(fmt, "invalid WebP quality value: {}%", q),
+            Error::UpscalingLimit(f) => write!(fmt, "invalid upscaling limit: {}x", f),
+            Error::Parallelism(p) => write!(fmt, "invalid parallelism: {}", p),
+            Error::TextSizeRange(min, max) =>
+                write!(fmt, "invalid text size range: {} to {}", min, max),
+            Error::WatermarkOpacity(o) => write!(fmt, "invalid watermark opacity: {}", o),
+            Error::WatermarkTextSize(s) => write!(fmt, "invalid watermark text size: {}", s),
         }
     }
 }
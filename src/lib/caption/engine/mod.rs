@@ -4,20 +4,29 @@ mod builder;
 mod config;
 
 pub use self::builder::Error as BuildError;
-pub use self::config::{Config, Error as ConfigError};
+pub use self::config::{Config, Error as ConfigError, Upscaling};
 
 
+use std::io::Write;
 use std::path::Path;
 use std::sync::Arc;
 
 use antidote::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use image::ImageFormat;
 
 use model::ImageMacro;
-use resources::{CachingLoader, Font, FontLoader, Loader, Template, TemplateLoader};
+use resources::{CachingLoader, Font, FontLoader, Loader,
+                Template, TemplateLoader, TemplateSource};
 use util::cache::ThreadSafeCache;
+use util::glyphs::GlyphCache;
+use super::cancel::CancelToken;
+use super::effect::Effects;
 use super::error::CaptionError;
-use super::output::CaptionOutput;
+use super::layout::MacroLayout;
+use super::output::{CaptionOutput, RenderedFrame};
+use super::progress::ProgressObserver;
 use super::task::CaptionTask;
+use super::watermark::Watermark;
 pub use self::builder::Builder;
 
 
@@ -25,6 +34,7 @@ pub use self::builder::Builder;
 ///
 /// The engine is thread-safe (`Sync`) since normally you'd want the captioning
 /// to be performed in a background thread.
+/// (With the `pool` feature enabled, `CaptionPool` can manage those threads for you).
 ///
 /// *Note*: `Engine` implements `Clone`
 /// by merely cloning a shared reference to the underlying object.
@@ -43,6 +53,9 @@ pub(super) struct Inner<Tl, Fl>
     pub(super) config: RwLock<Config>,
     pub template_loader: CachingLoader<Tl>,
     pub font_loader: CachingLoader<Fl>,
+    pub glyph_cache: GlyphCache,
+    pub(super) effects: Effects,
+    pub(super) watermark: Option<Watermark>,
 }
 
 impl<Tl, Fl> Inner<Tl, Fl>
@@ -51,9 +64,12 @@ impl<Tl, Fl> Inner<Tl, Fl>
     #[inline]
     pub fn new(config: Config,
                template_loader: CachingLoader<Tl>,
-               font_loader: CachingLoader<Fl>) -> Self {
+               font_loader: CachingLoader<Fl>,
+               glyph_cache: GlyphCache,
+               effects: Effects,
+               watermark: Option<Watermark>) -> Self {
         let config = RwLock::new(config);
-        Inner{config, template_loader, font_loader}
+        Inner{config, template_loader, font_loader, glyph_cache, effects, watermark}
     }
 }
 
@@ -112,17 +128,106 @@ impl<Tl, Fl> Engine<Tl, Fl>
 
 // Image macro captioning.
 impl<Tl, Fl> Engine<Tl, Fl>
-    where Tl: Loader<Item=Template>, Fl: Loader<Item=Font>
+    where Tl: Loader<Item=Template> + Send + Sync, Fl: Loader<Item=Font> + Send + Sync,
+          Tl::Err: Send, Fl::Err: Send
 {
     /// Render a given image macro by captioning the template with the specified text(s).
     ///
     /// Note that captioning is a CPU-intensive process and can be relatively lengthy,
     /// especially if the template is an animated GIF.
     /// It is recommended to execute it in a separate thread.
+    ///
+    /// The rendering cannot be stopped once it has started;
+    /// see `caption_cancellable` for a variant which can be.
     #[inline]
     pub fn caption(&self, image_macro: ImageMacro) -> Result<CaptionOutput, CaptionError<Tl, Fl>> {
         CaptionTask::new(image_macro, self.inner.clone()).perform()
     }
+
+    /// Render a given image macro like `caption` does,
+    /// but stop early if given token gets cancelled (or its deadline passes) in the meantime.
+    ///
+    /// The rendering is then abandoned with `CaptionError::Cancelled`.
+    #[inline]
+    pub fn caption_cancellable(&self, image_macro: ImageMacro,
+                               cancel: CancelToken) -> Result<CaptionOutput, CaptionError<Tl, Fl>> {
+        CaptionTask::new(image_macro, self.inner.clone()).with_cancel(cancel).perform()
+    }
+
+    /// Render a given image macro like `caption_cancellable` does,
+    /// while reporting the progress of the rendering to given observer.
+    ///
+    /// (Pass `CancelToken::new()` if the rendering doesn't need to be cancelled).
+    pub fn caption_with_progress<P>(&self, image_macro: ImageMacro, cancel: CancelToken,
+                                    observer: P) -> Result<CaptionOutput, CaptionError<Tl, Fl>>
+        where P: ProgressObserver + 'static
+    {
+        CaptionTask::new(image_macro, self.inner.clone())
+            .with_cancel(cancel)
+            .with_progress(Arc::new(observer))
+            .perform()
+    }
+
+    /// Render a given image macro and write the encoded result to given output.
+    ///
+    /// Returns the image format that the image macro has been encoded as.
+    ///
+    /// This only saves the memory that `caption` needs for the final `CaptionOutput`.
+    /// All the frames are rendered (and for GIFs, also quantized) in memory
+    /// before anything is written, so the peak memory usage is still
+    /// about the size of all the decoded frames of the template.
+    /// GIFs are then written out frame by frame,
+    /// while the other formats are encoded into a buffer first.
+    ///
+    /// If an error occurs, some of the output may have been written already.
+    ///
+    /// Like `caption`, the rendering cannot be cancelled.
+    #[inline]
+    pub fn caption_to_writer<W: Write>(&self, image_macro: ImageMacro,
+                                       output: W) -> Result<ImageFormat, CaptionError<Tl, Fl>> {
+        CaptionTask::new(image_macro, self.inner.clone()).perform_to(output)
+    }
+
+    /// Render a given image macro, but return its frames as raw pixels
+    /// instead of encoding them.
+    ///
+    /// The frames are the same as those that `caption` would encode,
+    /// so there is only one unless the result is an animation.
+    ///
+    /// Like `caption`, the rendering cannot be cancelled.
+    #[inline]
+    pub fn render_frames(&self, image_macro: ImageMacro)
+                         -> Result<Vec<RenderedFrame>, CaptionError<Tl, Fl>> {
+        CaptionTask::new(image_macro, self.inner.clone()).render_frames()
+    }
+
+    /// Render a given image macro using the provided image as its template,
+    /// rather than loading the template by name.
+    ///
+    /// The image can be given either as a `Template` or `DynamicImage`,
+    /// or as the contents of an image file (whose format is detected automatically).
+    /// The `template` of the image macro is then only used to refer to the image in logs.
+    ///
+    /// Templates larger than the maximum size of image macros are rejected
+    /// (with `CaptionError::Image` if given as bytes, or `CaptionError::TooLarge` otherwise).
+    ///
+    /// Like `caption`, the rendering cannot be cancelled.
+    pub fn caption_image<T>(&self, template: T,
+                            image_macro: ImageMacro) -> Result<CaptionOutput, CaptionError<Tl, Fl>>
+        where T: Into<TemplateSource>
+    {
+        let template = template.into().load().map_err(CaptionError::Image)?;
+        CaptionTask::with_template(image_macro, template, self.inner.clone()).perform()
+    }
+
+    /// Compute the layout of a given image macro without rendering it:
+    /// its dimensions, and the font size, line breaks & bounding boxes of its captions.
+    ///
+    /// The layout is exactly the same as in the result of `caption`,
+    /// unless a custom effect changes the size of the image after the captions are drawn.
+    pub fn layout(&self, image_macro: &ImageMacro) -> Result<MacroLayout, CaptionError<Tl, Fl>> {
+        CaptionTask::new(image_macro.clone(), self.inner.clone()).layout()
+    }
 }
 
 // Managing resources.
@@ -164,6 +269,13 @@ impl<Tl, Fl> Engine<Tl, Fl>
             Some(self.inner.font_loader.cache())
         }
     }
+
+    /// Return a reference to the cache of rasterized glyphs.
+    /// This can be used to examine cache statistics (hits & misses).
+    #[inline]
+    pub fn glyph_cache(&self) -> &GlyphCache {
+        &self.inner.glyph_cache
+    }
 }
 
 // Configuration.
@@ -188,7 +300,16 @@ impl<Tl, Fl> Engine<Tl, Fl>
 
 #[cfg(test)]
 mod tests {
-    use super::Engine;
+    use image::{self, DynamicImage, GenericImage, ImageFormat, RgbaImage};
+
+    use caption::{CancelToken, CaptionError, Progress, RenderStage};
+    use caption::tests::{data_dir, engine, unexpected};
+    use model::{Caption, ImageMacro, ImageMacroBuilder, OutputFormat, Transform, VAlign,
+                MAX_HEIGHT, MAX_WIDTH};
+    use resources::TemplateError;
+    use util::{animated_gif, animated_png};
+    use util::animated_gif::Dithering;
+    use super::{BuildError, Builder, ConfigError, Engine};
 
     #[test]
     fn thread_safe() {
@@ -198,4 +319,250 @@ mod tests {
         assert_sync::<Engine>();
         assert_send::<Engine>();
     }
+
+    #[test]
+    fn caption_image_limits_size() {
+        let fonts = data_dir().join("fonts");
+        let engine = Engine::new(fonts.clone(), fonts);  // templates aren't needed
+        let photo = DynamicImage::ImageRgba8(RgbaImage::new(3000, 1500));
+        let im = ImageMacroBuilder::new()
+            .template("photo")
+            .caption(Caption::text_at(VAlign::Bottom, "MY OWN PHOTO"))
+            .build().unwrap();
+
+        let mut bytes = vec![];
+        photo.save(&mut bytes, ImageFormat::PNG).unwrap();
+        match engine.caption_image(bytes, im.clone()) {
+            Err(CaptionError::Image(TemplateError::TooLarge(_))) => {}
+            r => unexpected(r),
+        }
+        match engine.caption_image(photo, im.clone()) {
+            Err(CaptionError::TooLarge{width, height}) => assert_eq!((3000, 1500), (width, height)),
+            r => unexpected(r),
+        }
+
+        // Images within the limits can still be shrunk further.
+        let photo = DynamicImage::ImageRgba8(RgbaImage::new(MAX_WIDTH, MAX_HEIGHT / 2));
+        let output = engine.caption_image(photo, ImageMacro{width: Some(100), ..im}).unwrap();
+        let img = image::load_from_memory(output.bytes()).unwrap();
+        assert_eq!((100, 50), img.dimensions());
+    }
+
+    #[test]
+    fn transform_cannot_exceed_size() {
+        let fonts = data_dir().join("fonts");
+        let engine = Engine::new(fonts.clone(), fonts);
+        let photo = DynamicImage::ImageRgba8(RgbaImage::new(1000, 500));
+        let rotated = |degrees| ImageMacroBuilder::new()
+            .template("photo")
+            .transform(Transform::rotate(degrees, None))
+            .build().unwrap();
+
+        // Rotating by 45deg would need a 1061x1061 image.
+        match engine.caption_image(photo.clone(), rotated(45.0)) {
+            Err(CaptionError::TooLarge{width, height}) => {
+                assert!(width > MAX_WIDTH && height > MAX_HEIGHT);
+            }
+            r => unexpected(r),
+        }
+        // Right angle rotations don't enlarge the image, though.
+        assert!(engine.caption_image(photo, rotated(90.0)).is_ok());
+    }
+
+    #[test]
+    fn png_output_is_still_unless_apng() {
+        let engine = engine();
+        let im = |format| ImageMacroBuilder::new()
+            .template("badger")
+            .format(format)
+            .caption(Caption::text_at(VAlign::Bottom, "STILL? ANIMATED?"))
+            .build().unwrap();
+
+        let png = engine.caption(im(OutputFormat::Png)).unwrap();
+        assert_eq!(Some(false), animated_png::is_animated(png.bytes()));
+        let apng = engine.caption(im(OutputFormat::Apng)).unwrap();
+        assert_eq!(Some(true), animated_png::is_animated(apng.bytes()));
+    }
+
+    #[test]
+    fn transparent_templates_stay_transparent() {
+        let data = data_dir();
+        let engine = Engine::new(data.join("fixtures"), data.join("fonts"));
+        let im = |template, format| ImageMacroBuilder::new()
+            .template(template)
+            .width(256)
+            .format(format)
+            .caption(Caption::text_at(VAlign::Bottom, "SEE-THROUGH"))
+            .build().unwrap();
+
+        // Left half of the fixtures is transparent, and the caption
+        // at the bottom doesn't reach their top corners.
+        let png = engine.caption(im("transparent", OutputFormat::Png)).unwrap();
+        let img = image::load_from_memory(png.bytes()).unwrap().to_rgba();
+        assert_eq!(256, img.width());
+        assert_eq!(0, img.get_pixel(0, 0).data[3]);
+        assert_eq!(0xff, img.get_pixel(255, 0).data[3]);
+
+        let gif = engine.caption(im("transparent-animated", OutputFormat::Gif)).unwrap();
+        let anim = animated_gif::decode(&mut gif.bytes()).unwrap();
+        assert_eq!(2, anim.frames_count());
+        for frame in anim.iter_frames() {
+            let img = frame.image.to_rgba();
+            assert_eq!(0, img.get_pixel(0, 0).data[3]);
+            assert_eq!(0xff, img.get_pixel(img.width() - 1, 0).data[3]);
+        }
+    }
+
+    #[test]
+    fn animation_frame_range_is_checked() {
+        let engine = engine();
+        let im = |start, end| ImageMacroBuilder::new()
+            .template("badger")
+            .frame_range(start, end)
+            .caption(Caption::text_at(VAlign::Bottom, "HOW MANY FRAMES?"))
+            .build().unwrap();
+
+        let count = match engine.caption(im(0, 1000)) {
+            Err(CaptionError::FrameOutOfRange{count, ..}) => count,
+            r => unexpected(r),
+        };
+        assert!(count > 1);
+        assert!(engine.caption(im(1, count)).is_ok());
+        for &(start, end) in &[(count, count + 1), (1, count + 1)] {
+            match engine.caption(im(start, end)) {
+                Err(CaptionError::FrameOutOfRange{..}) => {}
+                r => panic!("unexpected result for {}..{}: {:?}", start, end, r.map(|o| o.len())),
+            }
+        }
+    }
+
+    #[test]
+    fn caption_to_writer_matches_caption() {
+        let engine = engine();
+        for &template in &["zoidberg", "badger"] {
+            let im = ImageMacroBuilder::new()
+                .template(template)
+                .caption(Caption::text_at(VAlign::Top, "WRITE IT"))
+                .caption(Caption::text_at(VAlign::Bottom, "STREAM IT"))
+                .build().unwrap();
+
+            let output = engine.caption(im.clone()).unwrap();
+            let mut bytes = vec![];
+            let format = engine.caption_to_writer(im, &mut bytes).unwrap();
+            assert_eq!(output.format(), format, "template: {}", template);
+            assert!(output.bytes() == &bytes[..], "template: {}", template);
+        }
+    }
+
+    #[test]
+    fn parallelism_doesnt_change_output() {
+        let engine = engine();
+        let im = ImageMacroBuilder::new()
+            .template("badger")
+            .caption(Caption::text_at(VAlign::Top, "ONE AT A TIME"))
+            .caption(Caption::text_at(VAlign::Bottom, "ALL AT ONCE"))
+            .build().unwrap();
+
+        for &dithering in &[Dithering::None, Dithering::FloydSteinberg] {
+            engine.config_mut().gif_dithering = dithering;
+            engine.config_mut().parallelism = 1;
+            let sequential = engine.caption(im.clone()).unwrap();
+            for &parallelism in &[2, 4, 16] {
+                engine.config_mut().parallelism = parallelism;
+                let parallel = engine.caption(im.clone()).unwrap();
+                assert!(sequential.bytes() == parallel.bytes(),
+                    "{:?}, parallelism: {}", dithering, parallelism);
+            }
+        }
+    }
+
+    #[test]
+    fn modified_config_is_validated() {
+        let engine = engine();
+        let im = ImageMacroBuilder::new()
+            .template("zoidberg")
+            .caption(Caption::text_at(VAlign::Bottom, "WHY NOT ZOIDBERG?"))
+            .build().unwrap();
+
+        engine.config_mut().parallelism = 0;
+        match engine.caption(im.clone()) {
+            Err(CaptionError::Config(ConfigError::Parallelism(0))) => {}
+            r => unexpected(r),
+        }
+        engine.config_mut().parallelism = 1;
+        engine.config_mut().min_text_size = -1.0;
+        match engine.layout(&im) {
+            Err(CaptionError::Config(ConfigError::TextSizeRange(..))) => {}
+            r => unexpected(r),
+        }
+        engine.config_mut().min_text_size = 8.0;
+        assert!(engine.caption(im).is_ok());
+    }
+
+    #[test]
+    fn built_config_is_validated() {
+        let data = data_dir();
+        let builder = || Builder::new()
+            .template_directory(data.join("templates"))
+            .font_directory(data.join("fonts"));
+
+        let result: Result<Engine, BuildError> = builder().jpeg_quality(0).build();
+        match result {
+            Err(BuildError::EngineConfig(ConfigError::JpegQuality(0))) => {}
+            r => unexpected(r),
+        }
+        let result: Result<Engine, BuildError> = builder().gif_quality(101).build();
+        match result {
+            Err(BuildError::EngineConfig(ConfigError::GifQuality(101))) => {}
+            r => unexpected(r),
+        }
+        let result: Result<Engine, BuildError> = builder().parallelism(0).build();
+        match result {
+            Err(BuildError::EngineConfig(ConfigError::Parallelism(0))) => {}
+            r => unexpected(r),
+        }
+        let result: Result<Engine, BuildError> = builder().parallelism(2).build();
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn caption_cancelled() {
+        let engine = engine();
+        let im = ImageMacroBuilder::new()
+            .template("badger")
+            .caption(Caption::text_at(VAlign::Bottom, "NEVER MIND"))
+            .build().unwrap();
+
+        let token = CancelToken::new();
+        token.cancel();
+        match engine.caption_cancellable(im.clone(), token) {
+            Err(CaptionError::Cancelled) => {}
+            r => unexpected(r),
+        }
+        assert!(engine.caption_cancellable(im, CancelToken::new()).is_ok());
+    }
+
+    #[test]
+    fn encoding_cancelled() {
+        let engine = engine();
+        for &format in &[OutputFormat::Gif, OutputFormat::Apng] {
+            let im = ImageMacroBuilder::new()
+                .template("badger")
+                .caption(Caption::text_at(VAlign::Bottom, "TOO LATE"))
+                .format(format)
+                .build().unwrap();
+
+            // Cancel the rendering once all the frames have been drawn.
+            let token = CancelToken::new();
+            let result = engine.caption_with_progress(im, token.clone(), move |p: Progress| {
+                if p.stage == RenderStage::Encode {
+                    token.cancel();
+                }
+            });
+            match result {
+                Err(CaptionError::Cancelled) => {}
+                r => panic!("unexpected result for {:?}: {:?}", format, r.map(|o| o.len())),
+            }
+        }
+    }
 }
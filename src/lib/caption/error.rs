@@ -4,7 +4,11 @@ use std::error::Error;
 use std::fmt;
 use std::io;
 
-use resources::{Loader, Font, FontLoader, Template, TemplateLoader};
+use model::{MAX_HEIGHT, MAX_WIDTH};
+use resources::{Loader, Font, FontLoader, Template, TemplateError, TemplateLoader};
+use util::animation::MAX_FRAME_COUNT;
+use super::effect::EffectError;
+use super::engine::ConfigError;
 
 
 /// Error that may occur during the captioning.
@@ -18,6 +22,8 @@ pub enum CaptionError<Tl = TemplateLoader, Fl = FontLoader>
         /// Error that occurred while loading the template.
         error: Tl::Err,
     },
+    /// Error while decoding an image that was given directly as the template.
+    Image(TemplateError),
     /// Error while loading the font.
     Font {
         /// Name of the font that failed to load.
@@ -25,8 +31,40 @@ pub enum CaptionError<Tl = TemplateLoader, Fl = FontLoader>
         /// Error that occurred while loading the font.
         error: Fl::Err,
     },
+    /// Image macro refers to an effect that hasn't been registered with the `Engine`.
+    UnknownEffect(String),
+    /// Error while applying a custom effect.
+    Effect {
+        /// Name of the effect that failed.
+        name: String,
+        /// Error returned by the effect.
+        error: EffectError,
+    },
+    /// Image macro asked for a frame that the template doesn't have.
+    FrameOutOfRange {
+        /// Index of the requested frame.
+        index: usize,
+        /// Number of frames in the template.
+        count: usize,
+    },
+    /// Template image given directly, or the result of transforming a template,
+    /// is larger than allowed.
+    TooLarge {
+        /// Width of the (transformed) image.
+        width: u32,
+        /// Height of the (transformed) image.
+        height: u32,
+    },
+    /// Animation options would produce more frames than allowed.
+    TooManyFrames(usize),
+    /// The `Engine`'s configuration has been changed to an invalid one
+    /// (through `Engine::config_mut`).
+    Config(ConfigError),
     /// Error while encoding the final image macro.
     Encode(io::Error),
+    /// Rendering was stopped because its `CancelToken` was cancelled
+    /// (or its deadline has passed).
+    Cancelled,
 }
 
 impl<Tl, Fl> CaptionError<Tl, Fl>
@@ -43,6 +81,12 @@ impl<Tl, Fl> CaptionError<Tl, Fl>
     pub fn font<N: ToString>(name: N, error: Fl::Err) -> Self {
         CaptionError::Font{ name: name.to_string(), error: error }
     }
+
+    /// Create `CaptionError` for when a custom effect failed to apply.
+    #[inline]
+    pub fn effect<N: ToString>(name: N, error: EffectError) -> Self {
+        CaptionError::Effect{ name: name.to_string(), error: error }
+    }
 }
 
 impl<Tl, Fl> Error for CaptionError<Tl, Fl>
@@ -52,8 +96,16 @@ impl<Tl, Fl> Error for CaptionError<Tl, Fl>
     fn cause(&self) -> Option<&Error> {
         match *self {
             CaptionError::Template{ ref error, .. } => Some(error),
+            CaptionError::Image(ref e) => Some(e),
             CaptionError::Font{ ref error, .. } => Some(error),
+            CaptionError::UnknownEffect(..) => None,
+            CaptionError::Effect{ ref error, .. } => Some(&**error),
+            CaptionError::FrameOutOfRange{..} => None,
+            CaptionError::TooLarge{..} => None,
+            CaptionError::TooManyFrames(..) => None,
+            CaptionError::Config(ref e) => Some(e),
             CaptionError::Encode(ref e) => Some(e),
+            CaptionError::Cancelled => None,
         }
     }
 }
@@ -68,12 +120,34 @@ impl<Tl, Fl> fmt::Debug for CaptionError<Tl, Fl>
                     .field("name", name)
                     .field("error", &error.description())
                     .finish(),
+            CaptionError::Image(ref e) => write!(fmt, "CaptionError::Image({:?})", e),
             CaptionError::Font{ ref name, ref error } =>
                 fmt.debug_struct("CaptionError::Font")
                     .field("name", name)
                     .field("error", &error.description())
                     .finish(),
-            CaptionError::Encode(ref e) => write!(fmt, "CaptionError::Encode({:?})", e)
+            CaptionError::UnknownEffect(ref name) =>
+                write!(fmt, "CaptionError::UnknownEffect({:?})", name),
+            CaptionError::Effect{ ref name, ref error } =>
+                fmt.debug_struct("CaptionError::Effect")
+                    .field("name", name)
+                    .field("error", &error.description())
+                    .finish(),
+            CaptionError::FrameOutOfRange{ index, count } =>
+                fmt.debug_struct("CaptionError::FrameOutOfRange")
+                    .field("index", &index)
+                    .field("count", &count)
+                    .finish(),
+            CaptionError::TooLarge{ width, height } =>
+                fmt.debug_struct("CaptionError::TooLarge")
+                    .field("width", &width)
+                    .field("height", &height)
+                    .finish(),
+            CaptionError::TooManyFrames(count) =>
+                write!(fmt, "CaptionError::TooManyFrames({})", count),
+            CaptionError::Config(ref e) => write!(fmt, "CaptionError::Config({:?})", e),
+            CaptionError::Encode(ref e) => write!(fmt, "CaptionError::Encode({:?})", e),
+            CaptionError::Cancelled => write!(fmt, "CaptionError::Cancelled"),
         }
     }
 }
@@ -85,9 +159,23 @@ impl<Tl, Fl> fmt::Display for CaptionError<Tl, Fl>
         match *self {
             CaptionError::Template{ ref name, ref error } =>
                 write!(fmt, "cannot load template `{}`: {}", name, error.description()),
+            CaptionError::Image(ref e) =>
+                write!(fmt, "cannot decode template image: {}", e.description()),
             CaptionError::Font{ ref name, ref error } =>
                 write!(fmt, "cannot load font `{}`: {}", name, error.description()),
+            CaptionError::UnknownEffect(ref name) => write!(fmt, "unknown effect `{}`", name),
+            CaptionError::Effect{ ref name, ref error } =>
+                write!(fmt, "failed to apply effect `{}`: {}", name, error),
+            CaptionError::FrameOutOfRange{ index, count } =>
+                write!(fmt, "frame #{} requested, but the template has only {}", index, count),
+            CaptionError::TooLarge{ width, height } =>
+                write!(fmt, "template image too large: {}x{} > {}x{}",
+                    width, height, MAX_WIDTH, MAX_HEIGHT),
+            CaptionError::TooManyFrames(count) =>
+                write!(fmt, "animation has too many frames: {} > {}", count, MAX_FRAME_COUNT),
+            CaptionError::Config(ref e) => write!(fmt, "invalid engine configuration: {}", e),
             CaptionError::Encode(ref e) => write!(fmt, "failed to encode the  final image: {}", e),
+            CaptionError::Cancelled => write!(fmt, "rendering cancelled"),
         }
     }
 }
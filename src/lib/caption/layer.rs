@@ -0,0 +1,204 @@
+//! Module implementing the caption layer.
+//!
+//! Captions are the same on every frame of an animated template,
+//! so rather than drawing them over and over, they are rendered once
+//! onto a transparent layer which is then composited onto each frame.
+
+use image::{DynamicImage, GenericImage, Pixel, RgbaImage};
+
+
+/// Transparent image with the rendered text of image macro's captions.
+pub(super) struct CaptionLayer {
+    image: RgbaImage,
+    /// Smallest rectangle containing all the non-transparent pixels of the layer,
+    /// as (left, top, right, bottom) coordinates (with the latter two exclusive).
+    bounds: Option<(u32, u32, u32, u32)>,
+}
+
+impl CaptionLayer {
+    /// Create an empty (fully transparent) layer of given size
+    /// that the captions can be drawn onto.
+    #[inline]
+    pub fn blank(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::new(width, height))
+    }
+
+    /// Create the layer from an image that the captions have been drawn onto.
+    pub fn new(img: DynamicImage) -> Self {
+        let image = match img {
+            DynamicImage::ImageRgba8(image) => image,
+            img => img.to_rgba(),
+        };
+
+        let mut bounds: Option<(u32, u32, u32, u32)> = None;
+        for (x, y, pixel) in image.enumerate_pixels() {
+            if pixel.data[3] == 0 {
+                continue;
+            }
+            bounds = Some(match bounds {
+                None => (x, y, x + 1, y + 1),
+                Some((left, top, right, bottom)) =>
+                    (left.min(x), top.min(y), right.max(x + 1), bottom.max(y + 1)),
+            });
+        }
+
+        CaptionLayer{image, bounds}
+    }
+
+    /// Size of the layer.
+    #[inline]
+    pub fn dimensions(&self) -> (u32, u32) {
+        self.image.dimensions()
+    }
+
+    /// Draw the layer over given image (with alpha blending).
+    /// Returns a new image.
+    ///
+    /// The image must be of the same size as the layer.
+    pub fn composite(&self, img: DynamicImage) -> DynamicImage {
+        assert_eq!(self.dimensions(), img.dimensions(),
+            "caption layer must be of the same size as the image it's drawn onto");
+
+        let mut result = match img {
+            DynamicImage::ImageRgba8(image) => image,
+            img => img.to_rgba(),
+        };
+        if let Some((left, top, right, bottom)) = self.bounds {
+            for y in top..bottom {
+                for x in left..right {
+                    let pixel = self.image.get_pixel(x, y);
+                    if pixel.data[3] > 0 {
+                        result.get_pixel_mut(x, y).blend(pixel);
+                    }
+                }
+            }
+        }
+        DynamicImage::ImageRgba8(result)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use image::{DynamicImage, GenericImage, Rgba, RgbaImage};
+    use rusttype::{point, Rect};
+
+    use caption::tests::{data_dir, font};
+    use model::{Color, HAlign, VAlign};
+    use resources::Font;
+    use util::animated_gif;
+    use util::text::{self, Style};
+    use super::CaptionLayer;
+
+    const WIDTH: u32 = 400;
+    const HEIGHT: u32 = 300;
+    const TEXT: &'static str = "ONE DOES NOT SIMPLY RENDER CAPTIONS ON EVERY FRAME";
+
+    fn frame(i: u8) -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_pixel(
+            WIDTH, HEIGHT, Rgba{data: [i.wrapping_mul(8), 0x80, 0xff - i, 0xff]}))
+    }
+
+    /// Draw the test captions (with an outline) directly onto given image.
+    fn draw_captions(img: DynamicImage, font: &Font) -> DynamicImage {
+        let mut img = img;
+        let (width, height) = img.dimensions();
+        let rect = Rect{
+            min: point(8.0, 8.0),
+            max: point(width as f32 - 8.0, height as f32 - 8.0),
+        };
+        let outline = Rect{
+            min: point(10.0, 10.0),
+            max: point(width as f32 - 6.0, height as f32 - 6.0),
+        };
+        let alignment = (HAlign::Center, VAlign::Bottom);
+        img = text::render_text(img, TEXT, alignment, outline,
+                                Style::new(font, 48.0, Color(0, 0, 0)));
+        text::render_text(img, TEXT, alignment, rect,
+                          Style::new(font, 48.0, Color(0xff, 0xff, 0xff)))
+    }
+
+    /// Check that the images are the same, save for small rounding errors
+    /// from blending the pixels in a different order.
+    fn assert_similar(expected: &DynamicImage, actual: &DynamicImage) {
+        assert_eq!(expected.dimensions(), actual.dimensions());
+        for ((x, y, p), (_, _, q)) in expected.pixels().zip(actual.pixels()) {
+            for (&a, &b) in p.data.iter().zip(q.data.iter()) {
+                assert!((a as i32 - b as i32).abs() <= 3,
+                    "pixels at ({}, {}) differ too much: {:?} vs {:?}", x, y, p, q);
+            }
+        }
+    }
+
+    #[test]
+    fn empty_layer_changes_nothing() {
+        let layer = CaptionLayer::new(CaptionLayer::blank(WIDTH, HEIGHT));
+        let img = frame(3);
+        assert_eq!(img.raw_pixels(), layer.composite(img.clone()).raw_pixels());
+    }
+
+    #[test]
+    fn composite_matches_direct_rendering() {
+        let font = font();
+        let layer = CaptionLayer::new(draw_captions(CaptionLayer::blank(WIDTH, HEIGHT), &font));
+        assert_similar(&draw_captions(frame(7), &font), &layer.composite(frame(7)));
+    }
+
+    #[test]
+    fn layer_matches_drawing_every_frame() {
+        const FRAME_COUNT: u8 = 8;
+        let font = font();
+
+        // The same layer is reused for all the frames of an animation,
+        // so it mustn't be affected by compositing it onto any of them.
+        let layer = CaptionLayer::new(draw_captions(CaptionLayer::blank(WIDTH, HEIGHT), &font));
+        for i in 0..FRAME_COUNT {
+            let mut img = frame(i * 4);
+            if i % 2 == 1 {
+                // Some frames have transparent areas, like those of GIF templates do.
+                if let DynamicImage::ImageRgba8(ref mut rgba) = img {
+                    for y in HEIGHT / 2..HEIGHT {
+                        for x in 0..WIDTH / 2 {
+                            rgba.put_pixel(x, y, Rgba{data: [0, 0, 0, 0]});
+                        }
+                    }
+                }
+            }
+            assert_similar(&draw_captions(img.clone(), &font), &layer.composite(img));
+        }
+    }
+
+    /// Compare the time it takes to caption the frames of a real GIF template
+    /// by drawing the text on every one of them, and by compositing the caption layer.
+    /// Run with `cargo test layer_timing -- --ignored --nocapture` to see the results.
+    #[test]
+    #[ignore]
+    fn layer_timing() {
+        let path = data_dir().join("templates/badger.gif");
+        let anim = animated_gif::decode_from_file(path).unwrap();
+        let frames: Vec<_> = anim.iter_frames().map(|f| f.image.clone()).collect();
+        let (width, height) = frames[0].dimensions();
+        let font = font();
+
+        let start = Instant::now();
+        for img in frames.iter().cloned() {
+            draw_captions(img, &font);
+        }
+        let before = start.elapsed();
+
+        let start = Instant::now();
+        let layer = CaptionLayer::new(draw_captions(CaptionLayer::blank(width, height), &font));
+        for img in frames.iter().cloned() {
+            layer.composite(img);
+        }
+        let after = start.elapsed();
+
+        let ms = |d: Duration| d.as_secs() as f64 * 1000.0 + d.subsec_nanos() as f64 / 1e6;
+        println!("Captioning {} frames of {}x{}: {:.1} ms drawing every frame, \
+                  {:.1} ms with the layer ({:.1}x faster)",
+            frames.len(), width, height, ms(before), ms(after), ms(before) / ms(after));
+        assert!(after < before);
+    }
+}
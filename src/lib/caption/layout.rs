@@ -0,0 +1,138 @@
+//! Module defining the layout of image macros,
+//! which can be computed without actually rendering them.
+
+use rusttype::Rect;
+
+use util::text::TextLayout;
+
+
+/// Layout of an image macro and its captions.
+///
+/// See `Engine::layout`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MacroLayout {
+    /// Width of the image macro (in pixels).
+    pub width: u32,
+    /// Height of the image macro (in pixels).
+    pub height: u32,
+    /// Layouts of the image macro's captions,
+    /// in the same order as in `ImageMacro::captions`.
+    pub captions: Vec<CaptionLayout>,
+}
+
+
+/// Layout of a single caption of an image macro.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CaptionLayout {
+    /// Size of the font that the caption's text is rendered with.
+    pub size: f32,
+    /// Lines that the caption's text is broken into.
+    ///
+    /// This is empty if the caption has no text.
+    pub lines: Vec<LineLayout>,
+}
+
+impl CaptionLayout {
+    #[doc(hidden)]
+    pub(super) fn new(layout: TextLayout, bounds: Vec<Rect<f32>>) -> Self {
+        let lines = layout.lines.into_iter().zip(bounds)
+            .map(|((text, _), rect)| LineLayout{text, bounds: BoundingBox{
+                left: rect.min.x, top: rect.min.y, right: rect.max.x, bottom: rect.max.y,
+            }})
+            .collect();
+        CaptionLayout{size: layout.size, lines}
+    }
+
+    /// Bounding box of the whole caption text, if it's not empty.
+    pub fn bounds(&self) -> Option<BoundingBox> {
+        let mut lines = self.lines.iter().map(|l| l.bounds);
+        let first = try_opt!(lines.next());
+        Some(lines.fold(first, |acc, b| BoundingBox{
+            left: acc.left.min(b.left),
+            top: acc.top.min(b.top),
+            right: acc.right.max(b.right),
+            bottom: acc.bottom.max(b.bottom),
+        }))
+    }
+}
+
+
+/// Layout of a single line of caption text.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LineLayout {
+    /// Text of the line.
+    pub text: String,
+    /// Bounding box of the line.
+    ///
+    /// Its height is that of the font's ascent & descent at the caption's size,
+    /// regardless of the actual characters in the line.
+    /// It doesn't include the text's outline.
+    pub bounds: BoundingBox,
+}
+
+
+/// Rectangle in the pixel coordinates of an image macro.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BoundingBox {
+    /// X coordinate of the left edge.
+    pub left: f32,
+    /// Y coordinate of the top edge.
+    pub top: f32,
+    /// X coordinate of the right edge.
+    pub right: f32,
+    /// Y coordinate of the bottom edge.
+    pub bottom: f32,
+}
+
+impl BoundingBox {
+    /// Width of the box.
+    #[inline]
+    pub fn width(&self) -> f32 {
+        self.right - self.left
+    }
+
+    /// Height of the box.
+    #[inline]
+    pub fn height(&self) -> f32 {
+        self.bottom - self.top
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use image::{self, GenericImage};
+
+    use caption::tests::engine;
+    use model::{Caption, ImageMacroBuilder, Size, VAlign};
+
+    #[test]
+    fn layout_matches_render() {
+        let engine = engine();
+        let im = ImageMacroBuilder::new()
+            .template("badluckbrian")
+            .width(320)
+            .caption(Caption::text_at(VAlign::Top, "GETS A LAYOUT API"))
+            .caption(Caption{
+                size: Size::Fit,
+                ..Caption::text_at(VAlign::Bottom,
+                                   "ASKS FOR THE LAYOUT FIRST, STILL RENDERS IT WRONG")
+            })
+            .build().unwrap();
+
+        let layout = engine.layout(&im).unwrap();
+        let output = engine.caption(im).unwrap();
+        let img = image::load_from_memory(output.bytes()).unwrap();
+        assert_eq!((img.width(), img.height()), (layout.width, layout.height));
+
+        assert_eq!(2, layout.captions.len());
+        for caption in &layout.captions {
+            assert!(!caption.lines.is_empty());
+            let bounds = caption.bounds().unwrap();
+            assert!(bounds.left >= 0.0 && bounds.right <= layout.width as f32);
+            assert!(bounds.top >= 0.0 && bounds.bottom <= layout.height as f32);
+        }
+        assert_eq!(64.0, layout.captions[0].size);
+        assert!(layout.captions[1].lines.len() > 1);
+    }
+}
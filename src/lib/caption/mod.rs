@@ -1,15 +1,34 @@
 //! Module implementing image captioning.
 
+mod cancel;
+mod effect;
 mod engine;
 mod error;
+mod layer;
+mod layout;
 mod output;
+#[cfg(feature = "pool")]
+mod pool;
+mod progress;
 mod task;
+mod watermark;
 
+#[cfg(test)]
+pub(crate) mod tests;
 
+
+pub use self::cancel::CancelToken;
 pub use self::engine::{Builder as EngineBuilder,
                        BuildError as EngineBuildError,
                        Config as EngineConfig,
                        ConfigError as EngineConfigError,
-                       Engine};
+                       Engine,
+                       Upscaling};
+pub use self::effect::{Effect, EffectError, EffectStage, FrameInfo};
 pub use self::error::CaptionError;
-pub use self::output::CaptionOutput;
+pub use self::layout::{BoundingBox, CaptionLayout, LineLayout, MacroLayout};
+pub use self::output::{CaptionOutput, Disposal, RenderedFrame};
+#[cfg(feature = "pool")]
+pub use self::pool::{CaptionFuture, CaptionPool, PoolError, PoolStats};
+pub use self::progress::{Progress, ProgressObserver, RenderStage};
+pub use self::watermark::{Watermark, WatermarkContent};
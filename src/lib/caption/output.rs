@@ -1,8 +1,10 @@
 //! Defines the output of a captioning operation.
 
+use std::fmt;
 use std::ops::Deref;
+use std::time::Duration;
 
-use image::ImageFormat;
+use image::{DynamicImage, GenericImage, ImageFormat, RgbaImage};
 use mime::{self, Mime};
 
 
@@ -54,6 +56,7 @@ impl CaptionOutput {
             ImageFormat::GIF => Some(mime::IMAGE_GIF),
             ImageFormat::JPEG => Some(mime::IMAGE_JPEG),
             ImageFormat::PNG => Some(mime::IMAGE_PNG),
+            ImageFormat::WEBP => Some("image/webp".parse().unwrap()),
             _ => None,
         }
     }
@@ -77,3 +80,112 @@ impl Into<Box<[u8]>> for CaptionOutput {
         self.into_boxed_bytes()
     }
 }
+
+
+/// A single frame of a rendered image macro, before it's been encoded.
+///
+/// See `Engine::render_frames`.
+#[derive(Clone)]
+pub struct RenderedFrame {
+    /// Pixels of the frame.
+    ///
+    /// Every frame covers the whole image macro
+    /// and doesn't depend on any of the previous frames.
+    pub image: RgbaImage,
+    /// How long the frame should be displayed for,
+    /// or None if the image macro is a still image.
+    pub delay: Option<Duration>,
+    /// What should happen to the frame before the next one is displayed.
+    pub disposal: Disposal,
+}
+
+impl RenderedFrame {
+    #[doc(hidden)]
+    pub(super) fn new(image: DynamicImage, delay: Option<Duration>) -> Self {
+        let image = match image {
+            DynamicImage::ImageRgba8(image) => image,
+            image => image.to_rgba(),
+        };
+        // Frames of animated templates are "flattened" when they're loaded,
+        // so they never need to be drawn over the previous ones.
+        RenderedFrame{image, delay, disposal: Disposal::Clear}
+    }
+}
+
+impl fmt::Debug for RenderedFrame {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let (w, h) = self.image.dimensions();
+        fmt.debug_struct("RenderedFrame")
+            .field("image", &format_args!("{}x{}", w, h))
+            .field("delay", &self.delay)
+            .field("disposal", &self.disposal)
+            .finish()
+    }
+}
+
+
+/// How a frame of an animation is disposed of before the next one is displayed.
+///
+/// Since every `RenderedFrame` is a full-canvas image, the frames never need
+/// to be drawn over the previous ones, and so the canvas is always cleared.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Disposal {
+    /// The canvas is cleared (to transparency) before the next frame is drawn.
+    Clear,
+}
+
+
+#[cfg(test)]
+mod tests {
+    use image::{self, GenericImage};
+
+    use caption::tests::engine;
+    use model::{Caption, ImageMacroBuilder, OutputFormat, VAlign};
+    use super::Disposal;
+
+    #[test]
+    fn still_template_renders_single_frame() {
+        let engine = engine();
+        let im = ImageMacroBuilder::new()
+            .template("zoidberg")
+            .caption(Caption::text_at(VAlign::Top, "WHY NOT"))
+            .build().unwrap();
+        let frames = engine.render_frames(im).unwrap();
+        assert_eq!(1, frames.len());
+        assert!(frames[0].delay.is_none());
+    }
+
+    #[test]
+    fn animated_template_renders_all_frames() {
+        let engine = engine();
+        let im = ImageMacroBuilder::new()
+            .template("badger")
+            .caption(Caption::text_at(VAlign::Bottom, "MUSHROOM MUSHROOM"))
+            .build().unwrap();
+        let frames = engine.render_frames(im).unwrap();
+        assert!(frames.len() > 1);
+
+        let size = frames[0].image.dimensions();
+        for frame in &frames {
+            assert_eq!(size, frame.image.dimensions());
+            assert!(frame.delay.is_some());
+            assert_eq!(Disposal::Clear, frame.disposal);
+        }
+    }
+
+    #[test]
+    fn caption_encodes_rendered_frames() {
+        let engine = engine();
+        let im = ImageMacroBuilder::new()
+            .template("zoidberg")
+            .format(OutputFormat::Png)
+            .caption(Caption::text_at(VAlign::Top, "WHY NOT"))
+            .build().unwrap();
+        let frames = engine.render_frames(im.clone()).unwrap();
+        let output = engine.caption(im).unwrap();
+
+        // PNG is lossless, so the encoded image is exactly the rendered frame.
+        let img = image::load_from_memory(output.bytes()).unwrap().to_rgba();
+        assert!(*frames[0].image == *img);
+    }
+}
@@ -0,0 +1,476 @@
+//! Module implementing the pool of worker threads for rendering image macros.
+//!
+//! This is only available with the `pool` feature.
+
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use futures::{Async, Future, Poll};
+use futures_cpupool::{self, CpuFuture, CpuPool};
+
+use model::ImageMacro;
+use resources::{Font, FontLoader, Loader, Template, TemplateLoader};
+use super::cancel::CancelToken;
+use super::engine::Engine;
+use super::error::CaptionError;
+use super::output::CaptionOutput;
+use super::progress::ProgressObserver;
+
+
+/// Pool of worker threads which render image macros in the background.
+///
+/// Rather than blocking the current thread with a call to `Engine::caption`,
+/// image macros can be submitted to the pool, which returns a future of each result.
+/// (Call `wait` on the future to block until the rendering is done).
+pub struct CaptionPool<Tl = TemplateLoader, Fl = FontLoader>
+    where Tl: Loader<Item=Template>, Fl: Loader<Item=Font>
+{
+    engine: Engine<Tl, Fl>,
+    pool: CpuPool,
+    max_queue_length: Option<usize>,
+    counters: Arc<Counters>,
+}
+
+impl<Tl, Fl> CaptionPool<Tl, Fl>
+    where Tl: Loader<Item=Template>, Fl: Loader<Item=Font>
+{
+    /// Create a pool with given number of worker threads
+    /// which renders image macros using given `Engine`.
+    ///
+    /// If the thread count is 0, there will be one thread per each CPU core.
+    pub fn new(engine: Engine<Tl, Fl>, thread_count: usize) -> Self {
+        let mut builder = futures_cpupool::Builder::new();
+        builder.name_prefix("rofl-caption-");
+        if thread_count > 0 {
+            builder.pool_size(thread_count);
+        }
+        Self::with_builder(engine, &mut builder)
+    }
+
+    /// Create a pool whose worker threads are configured by given `futures_cpupool::Builder`
+    /// and which renders image macros using given `Engine`.
+    pub fn with_builder(engine: Engine<Tl, Fl>, builder: &mut futures_cpupool::Builder) -> Self {
+        CaptionPool{
+            engine,
+            pool: builder.create(),
+            max_queue_length: None,
+            counters: Arc::new(Counters::default()),
+        }
+    }
+
+    /// Limit the number of image macros that can be waiting for a free worker thread.
+    ///
+    /// When the queue is full, the futures of any further image macros
+    /// fail immediately with `PoolError::QueueFull`.
+    /// By default, the queue is unbounded.
+    #[inline]
+    pub fn with_max_queue_length(mut self, length: usize) -> Self {
+        self.max_queue_length = Some(length); self
+    }
+}
+
+impl<Tl, Fl> CaptionPool<Tl, Fl>
+    where Tl: Loader<Item=Template>, Fl: Loader<Item=Font>
+{
+    /// The `Engine` that the pool renders image macros with.
+    #[inline]
+    pub fn engine(&self) -> &Engine<Tl, Fl> {
+        &self.engine
+    }
+
+    /// Maximum number of image macros that can be waiting for a free worker thread, if any.
+    #[inline]
+    pub fn max_queue_length(&self) -> Option<usize> {
+        self.max_queue_length
+    }
+
+    /// Current statistics of the image macros rendered by the pool.
+    pub fn stats(&self) -> PoolStats {
+        let load = |counter: &AtomicUsize| counter.load(Ordering::SeqCst);
+        PoolStats{
+            queued: load(&self.counters.queued),
+            running: load(&self.counters.running),
+            completed: load(&self.counters.completed),
+            failed: load(&self.counters.failed),
+            rejected: load(&self.counters.rejected),
+        }
+    }
+}
+
+impl<Tl, Fl> CaptionPool<Tl, Fl>
+    where Tl: Loader<Item=Template> + Send + Sync + 'static,
+          Fl: Loader<Item=Font> + Send + Sync + 'static,
+          Tl::Err: Send, Fl::Err: Send
+{
+    /// Render given image macro on one of the pool's worker threads.
+    ///
+    /// Returns a future of the result.
+    /// If the future is dropped before the rendering has finished,
+    /// the rendering is cancelled.
+    #[inline]
+    pub fn caption(&self, image_macro: ImageMacro) -> CaptionFuture<Tl, Fl> {
+        self.caption_cancellable(image_macro, CancelToken::new())
+    }
+
+    /// Render given image macro on one of the pool's worker threads,
+    /// stopping early if given token gets cancelled (or its deadline passes).
+    ///
+    /// Dropping the returned future before the rendering has finished also cancels the token.
+    pub fn caption_cancellable(&self, image_macro: ImageMacro,
+                               cancel: CancelToken) -> CaptionFuture<Tl, Fl> {
+        self.submit(image_macro, cancel, |engine, im, cancel| {
+            engine.caption_cancellable(im, cancel)
+        })
+    }
+
+    /// Render given image macro on one of the pool's worker threads
+    /// like `caption_cancellable` does, while reporting the progress to given observer.
+    pub fn caption_with_progress<P>(&self, image_macro: ImageMacro, cancel: CancelToken,
+                                    observer: P) -> CaptionFuture<Tl, Fl>
+        where P: ProgressObserver + 'static
+    {
+        self.submit(image_macro, cancel, move |engine, im, cancel| {
+            engine.caption_with_progress(im, cancel, observer)
+        })
+    }
+
+    /// Submit given image macro to be rendered on a worker thread using given function.
+    fn submit<F>(&self, image_macro: ImageMacro, cancel: CancelToken,
+                 render: F) -> CaptionFuture<Tl, Fl>
+        where F: FnOnce(&Engine<Tl, Fl>, ImageMacro, CancelToken)
+                        -> Result<CaptionOutput, CaptionError<Tl, Fl>>,
+              F: Send + 'static
+    {
+        let queued = match Queued::enter(self.counters.clone(), self.max_queue_length) {
+            Ok(q) => q,
+            Err(max) => {
+                debug!("Caption queue full ({} image macro(s)), rejecting {:?}",
+                    max, image_macro);
+                self.counters.rejected.fetch_add(1, Ordering::SeqCst);
+                return CaptionFuture{inner: FutureInner::Rejected(max), cancel};
+            }
+        };
+        let engine = self.engine.clone();
+        let future = self.pool.spawn_fn({
+            let cancel = cancel.clone();
+            move || {
+                let running = queued.start();
+                let result = render(&engine, image_macro, cancel);
+                running.finish(result.is_ok());
+                result
+            }
+        });
+        CaptionFuture{inner: FutureInner::Pending(future), cancel}
+    }
+}
+
+impl<Tl, Fl> fmt::Debug for CaptionPool<Tl, Fl>
+    where Tl: Loader<Item=Template> + fmt::Debug, Fl: Loader<Item=Font> + fmt::Debug
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("CaptionPool")
+            .field("engine", &self.engine)
+            .field("max_queue_length", &self.max_queue_length)
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+
+/// Statistics of the image macros rendered by a `CaptionPool`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct PoolStats {
+    /// Number of image macros waiting for a free worker thread.
+    pub queued: usize,
+    /// Number of image macros being rendered right now.
+    pub running: usize,
+    /// Number of image macros rendered successfully so far.
+    pub completed: usize,
+    /// Number of image macros whose rendering has failed so far.
+    pub failed: usize,
+    /// Number of image macros rejected so far because the queue was full.
+    pub rejected: usize,
+}
+
+/// Counters backing the `PoolStats`, shared with the worker threads.
+#[derive(Debug, Default)]
+struct Counters {
+    queued: AtomicUsize,
+    running: AtomicUsize,
+    completed: AtomicUsize,
+    failed: AtomicUsize,
+    rejected: AtomicUsize,
+}
+
+/// Marker of an image macro waiting in the pool's queue.
+///
+/// It leaves the queue when dropped, which also happens
+/// if its rendering is canceled before it starts.
+struct Queued(Arc<Counters>);
+
+impl Queued {
+    /// Enter the queue, unless it already holds `max_length` image macros.
+    ///
+    /// The slot is reserved before the length is checked,
+    /// so that concurrent submissions cannot overfill the queue.
+    /// If the queue turns out to be full, the slot is released
+    /// and the maximum length is returned as the error.
+    fn enter(counters: Arc<Counters>, max_length: Option<usize>) -> Result<Self, usize> {
+        let previous = counters.queued.fetch_add(1, Ordering::SeqCst);
+        let queued = Queued(counters);
+        match max_length {
+            Some(max) if previous >= max => Err(max),  // dropping `queued` rolls it back
+            _ => Ok(queued),
+        }
+    }
+
+    fn start(self) -> Running {
+        self.0.running.fetch_add(1, Ordering::SeqCst);
+        Running(self.0.clone())
+    }
+}
+
+impl Drop for Queued {
+    fn drop(&mut self) {
+        self.0.queued.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Marker of an image macro that's being rendered.
+///
+/// It stops counting as running when dropped (even if the rendering has panicked).
+struct Running(Arc<Counters>);
+
+impl Running {
+    fn finish(self, success: bool) {
+        let counter = if success { &self.0.completed } else { &self.0.failed };
+        counter.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.0.running.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+
+/// Future of an image macro rendered by a `CaptionPool`.
+#[must_use = "futures do nothing unless polled"]
+pub struct CaptionFuture<Tl = TemplateLoader, Fl = FontLoader>
+    where Tl: Loader<Item=Template>, Fl: Loader<Item=Font>
+{
+    inner: FutureInner<Tl, Fl>,
+    cancel: CancelToken,
+}
+
+enum FutureInner<Tl, Fl>
+    where Tl: Loader<Item=Template>, Fl: Loader<Item=Font>
+{
+    /// Image macro has been submitted to the pool.
+    Pending(CpuFuture<CaptionOutput, CaptionError<Tl, Fl>>),
+    /// Image macro was rejected because the queue (of given length) was full.
+    Rejected(usize),
+    /// Rendering has finished and its result has been returned.
+    Done,
+}
+
+impl<Tl, Fl> CaptionFuture<Tl, Fl>
+    where Tl: Loader<Item=Template>, Fl: Loader<Item=Font>
+{
+    /// Whether the image macro has been rejected because the pool's queue was full.
+    ///
+    /// Such a future fails with `PoolError::QueueFull` as soon as it's polled.
+    #[inline]
+    pub fn is_rejected(&self) -> bool {
+        match self.inner {
+            FutureInner::Rejected(..) => true,
+            _ => false,
+        }
+    }
+}
+
+impl<Tl, Fl> Future for CaptionFuture<Tl, Fl>
+    where Tl: Loader<Item=Template>, Fl: Loader<Item=Font>
+{
+    type Item = CaptionOutput;
+    type Error = PoolError<Tl, Fl>;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let result = match self.inner {
+            FutureInner::Pending(ref mut future) => match future.poll() {
+                Ok(Async::Ready(output)) => Ok(Async::Ready(output)),
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(e) => Err(PoolError::Caption(e)),
+            },
+            FutureInner::Rejected(length) => return Err(PoolError::QueueFull(length)),
+            FutureInner::Done => panic!("CaptionFuture polled after completion"),
+        };
+        // The token may be shared with other renderings,
+        // so it mustn't be cancelled once this one is over.
+        self.inner = FutureInner::Done;
+        result
+    }
+}
+
+impl<Tl, Fl> Drop for CaptionFuture<Tl, Fl>
+    where Tl: Loader<Item=Template>, Fl: Loader<Item=Font>
+{
+    fn drop(&mut self) {
+        // Only stop the rendering if it's still in progress.
+        if let FutureInner::Pending(..) = self.inner {
+            self.cancel.cancel();
+        }
+    }
+}
+
+impl<Tl, Fl> fmt::Debug for CaptionFuture<Tl, Fl>
+    where Tl: Loader<Item=Template>, Fl: Loader<Item=Font>
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self.inner {
+            FutureInner::Pending(..) => write!(fmt, "CaptionFuture::Pending"),
+            FutureInner::Rejected(length) => write!(fmt, "CaptionFuture::Rejected({})", length),
+            FutureInner::Done => write!(fmt, "CaptionFuture::Done"),
+        }
+    }
+}
+
+
+/// Error that may occur when rendering an image macro in a `CaptionPool`.
+pub enum PoolError<Tl = TemplateLoader, Fl = FontLoader>
+    where Tl: Loader<Item=Template>, Fl: Loader<Item=Font>
+{
+    /// Error while captioning the image macro.
+    Caption(CaptionError<Tl, Fl>),
+    /// Image macro was rejected because the queue (of given length) was full.
+    QueueFull(usize),
+}
+
+impl<Tl, Fl> Error for PoolError<Tl, Fl>
+    where Tl: Loader<Item=Template>, Fl: Loader<Item=Font>
+{
+    fn description(&self) -> &str { "caption pool error" }
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            PoolError::Caption(ref e) => Some(e),
+            PoolError::QueueFull(..) => None,
+        }
+    }
+}
+
+impl<Tl, Fl> fmt::Debug for PoolError<Tl, Fl>
+    where Tl: Loader<Item=Template>, Fl: Loader<Item=Font>
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PoolError::Caption(ref e) => write!(fmt, "PoolError::Caption({:?})", e),
+            PoolError::QueueFull(length) => write!(fmt, "PoolError::QueueFull({})", length),
+        }
+    }
+}
+
+impl<Tl, Fl> fmt::Display for PoolError<Tl, Fl>
+    where Tl: Loader<Item=Template>, Fl: Loader<Item=Font>
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PoolError::Caption(ref e) => write!(fmt, "{}", e),
+            PoolError::QueueFull(length) =>
+                write!(fmt, "too many image macros waiting to be rendered (limit: {})", length),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Barrier};
+    use std::sync::atomic::Ordering;
+    use std::thread;
+
+    use futures::Future;
+
+    use caption::CancelToken;
+    use caption::tests::{engine, unexpected};
+    use model::{Caption, ImageMacro, ImageMacroBuilder, VAlign};
+    use super::{CaptionPool, Counters, PoolError, Queued};
+
+    fn image_macro() -> ImageMacro {
+        ImageMacroBuilder::new()
+            .template("zoidberg")
+            .caption(Caption::text_at(VAlign::Bottom, "WHY NOT A POOL?"))
+            .build().unwrap()
+    }
+
+    #[test]
+    fn render_in_background() {
+        let pool = CaptionPool::new(engine(), 2);
+        let futures: Vec<_> = (0..3).map(|_| pool.caption(image_macro())).collect();
+        for future in futures {
+            assert!(!future.wait().unwrap().is_empty());
+        }
+
+        let stats = pool.stats();
+        assert_eq!(3, stats.completed);
+        assert_eq!((0, 0, 0), (stats.queued, stats.running, stats.failed));
+    }
+
+    #[test]
+    fn full_queue_rejects() {
+        let pool = CaptionPool::new(engine(), 1).with_max_queue_length(0);
+        let future = pool.caption(image_macro());
+        assert!(future.is_rejected());
+        match future.wait() {
+            Err(PoolError::QueueFull(0)) => {}
+            r => unexpected(r),
+        }
+        assert_eq!(1, pool.stats().rejected);
+    }
+
+    #[test]
+    fn finished_future_keeps_token() {
+        let pool = CaptionPool::new(engine(), 1);
+        let cancel = CancelToken::new();
+        assert!(pool.caption_cancellable(image_macro(), cancel.clone()).wait().is_ok());
+        assert!(!cancel.is_cancelled());
+
+        // Same for a future that's never even started.
+        let pool = pool.with_max_queue_length(0);
+        assert!(pool.caption_cancellable(image_macro(), cancel.clone()).wait().is_err());
+        assert!(!cancel.is_cancelled());
+    }
+
+    #[test]
+    fn dropped_future_cancels_token() {
+        let pool = CaptionPool::new(engine(), 1);
+        let cancel = CancelToken::new();
+        drop(pool.caption_cancellable(image_macro(), cancel.clone()));
+        assert!(cancel.is_cancelled());
+    }
+
+    #[test]
+    fn queue_never_exceeds_max_length() {
+        const MAX: usize = 4;
+        const THREADS: usize = 16;
+
+        let counters = Arc::new(Counters::default());
+        let barrier = Arc::new(Barrier::new(THREADS));
+        let threads: Vec<_> = (0..THREADS).map(|_| {
+            let (counters, barrier) = (counters.clone(), barrier.clone());
+            thread::spawn(move || {
+                barrier.wait();
+                let queued = Queued::enter(counters.clone(), Some(MAX));
+                // Hold on to the slot until everyone has tried to get one.
+                barrier.wait();
+                queued.is_ok()
+            })
+        }).collect();
+        let admitted = threads.into_iter().filter(|t| t.join().unwrap()).count();
+
+        assert_eq!(MAX, admitted);
+        assert_eq!(0, counters.queued.load(Ordering::SeqCst));
+    }
+}
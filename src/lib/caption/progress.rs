@@ -0,0 +1,110 @@
+//! Module implementing the reporting of rendering progress.
+
+
+/// Stage of rendering an image macro.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RenderStage {
+    /// Loading the template.
+    Load,
+    /// Transforming, resizing & filtering a frame of the template.
+    Resize,
+    /// Drawing the captions (and the rest of the image macro) on a frame.
+    Draw,
+    /// Encoding the final image macro.
+    ///
+    /// This is reported once when the encoding starts,
+    /// and then for every frame of an animation as it's encoded.
+    Encode,
+}
+
+impl RenderStage {
+    /// Lowercase name of the stage.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            RenderStage::Load => "load",
+            RenderStage::Resize => "resize",
+            RenderStage::Draw => "draw",
+            RenderStage::Encode => "encode",
+        }
+    }
+}
+
+
+/// Progress of rendering an image macro, as reported to a `ProgressObserver`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Progress {
+    /// Stage that the rendering has just entered.
+    pub stage: RenderStage,
+    /// Index of the frame that has entered the stage,
+    /// for the stages which happen separately for every frame
+    /// (`Resize` & `Draw`, as well as `Encode` of animations).
+    ///
+    /// Frames of animations may be rendered in parallel,
+    /// so their indices aren't necessarily reported in order.
+    pub frame: Option<usize>,
+    /// Total number of frames being rendered,
+    /// or 0 if it's not known yet (during the `Load` stage).
+    pub frame_count: usize,
+}
+
+
+/// Observer of the progress of rendering an image macro.
+///
+/// This is implemented for closures taking a `Progress`.
+///
+/// The observer is called synchronously from the rendering thread(s),
+/// so it should return quickly.
+pub trait ProgressObserver: Send + Sync {
+    /// Called whenever the rendering enters a new stage.
+    fn on_progress(&self, progress: Progress);
+}
+
+impl<F> ProgressObserver for F where F: Fn(Progress) + Send + Sync {
+    fn on_progress(&self, progress: Progress) {
+        self(progress)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use antidote::Mutex;
+
+    use caption::CancelToken;
+    use caption::tests::engine;
+    use model::{Caption, ImageMacroBuilder, VAlign};
+    use super::{Progress, RenderStage};
+
+    #[test]
+    fn animation_progress() {
+        let engine = engine();
+        let im = ImageMacroBuilder::new()
+            .template("badger")
+            .caption(Caption::text_at(VAlign::Bottom, "PROGRESS? PROGRESS!"))
+            .build().unwrap();
+
+        let reports = Arc::new(Mutex::new(vec![]));
+        engine.caption_with_progress(im, CancelToken::new(), {
+            let reports = reports.clone();
+            move |p: Progress| reports.lock().push(p)
+        }).unwrap();
+
+        let reports = reports.lock();
+        assert_eq!(RenderStage::Load, reports.first().unwrap().stage);
+        let last = reports.last().unwrap();
+        assert_eq!(RenderStage::Encode, last.stage);
+        let count = last.frame_count;
+        assert!(count > 1);
+        assert_eq!(None, reports.iter().find(|p| p.stage == RenderStage::Encode).unwrap().frame);
+        for &stage in &[RenderStage::Resize, RenderStage::Draw, RenderStage::Encode] {
+            let mut frames: Vec<_> = reports.iter()
+                .filter(|p| p.stage == stage)
+                .filter_map(|p| p.frame)
+                .collect();
+            frames.sort();
+            assert_eq!((0..count).collect::<Vec<_>>(), frames);
+        }
+    }
+}
@@ -1,20 +1,32 @@
 //! Module implementing the actual captioning task.
 //! Most if not all captioning logic lives here.
 
-use std::io;
+use std::io::{self, Write};
 use std::ops::Deref;
 use std::sync::Arc;
+use std::time::Duration;
 
-use image::{self, DynamicImage, FilterType, GenericImage, ImageFormat};
+use antidote::Mutex;
+use image::{self, DynamicImage, GenericImage, ImageFormat};
 use rusttype::{point, Rect, vector};
 
-use model::{Caption, ImageMacro, Size, DEFAULT_TEXT_SIZE};
+use model::{Caption, Filter, ImageMacro, Size, DEFAULT_TEXT_SIZE, MAX_HEIGHT, MAX_WIDTH};
 use resources::{Loader, Font, FontLoader, Template, TemplateLoader};
-use util::animated_gif;
-use util::text::{self, Style};
+use util::{animated_gif, animation, filters, parallel};
+use util::animated_png::{self, PngAnimation, PngFrame};
+use util::text::{self, Style, TextLayout};
+use util::transform;
+#[cfg(feature = "webp")]
+use util::webp;
+use super::cancel::CancelToken;
+use super::effect::{Effect, EffectStage, FrameInfo};
 use super::error::CaptionError;
+use super::layer::CaptionLayer;
+use super::layout::{CaptionLayout, MacroLayout};
 use super::engine;
-use super::output::CaptionOutput;
+use super::output::{CaptionOutput, RenderedFrame};
+use super::progress::{Progress, ProgressObserver, RenderStage};
+use super::watermark::{self, Watermark, WatermarkContent};
 
 
 /// Represents a single captioning task and contains all the relevant logic.
@@ -29,6 +41,12 @@ pub(super) struct CaptionTask<Tl = TemplateLoader, Fl = FontLoader>
 {
     image_macro: ImageMacro,
     engine: Arc<engine::Inner<Tl, Fl>>,
+    /// Template given directly, to be used instead of loading one by name.
+    given_template: Option<Arc<Template>>,
+    /// Token checked throughout the rendering to see if it should be stopped.
+    cancel: CancelToken,
+    /// Observer to report the rendering progress to, if any.
+    progress: Option<Arc<ProgressObserver>>,
 }
 
 impl<Tl, Fl> Deref for CaptionTask<Tl, Fl>
@@ -45,38 +63,372 @@ impl<Tl, Fl> CaptionTask<Tl, Fl>
 {
     #[inline]
     pub fn new(image_macro: ImageMacro, engine: Arc<engine::Inner<Tl, Fl>>) -> Self {
-        CaptionTask{image_macro, engine}
+        CaptionTask{image_macro, engine, given_template: None,
+                    cancel: CancelToken::new(), progress: None}
+    }
+
+    /// Create a task which uses given template instead of the one named by the `ImageMacro`.
+    ///
+    /// Unlike the engine's own templates, these can be arbitrarily large,
+    /// so the task fails with `CaptionError::TooLarge` if any of the template's images
+    /// exceeds the maximum size of image macros.
+    pub fn with_template(image_macro: ImageMacro, template: Template,
+                         engine: Arc<engine::Inner<Tl, Fl>>) -> Self {
+        CaptionTask{image_macro, engine, given_template: Some(Arc::new(template)),
+                    cancel: CancelToken::new(), progress: None}
+    }
+
+    /// Make the task stop rendering once given token is cancelled.
+    #[inline]
+    pub fn with_cancel(mut self, token: CancelToken) -> Self {
+        self.cancel = token; self
+    }
+
+    /// Make the task report its progress to given observer.
+    #[inline]
+    pub fn with_progress(mut self, observer: Arc<ProgressObserver>) -> Self {
+        self.progress = Some(observer); self
+    }
+
+    /// Report that the rendering has entered given stage (for given frame out of total count).
+    fn report(&self, stage: RenderStage, frame: Option<usize>, frame_count: usize) {
+        if let Some(ref observer) = self.progress {
+            observer.on_progress(Progress{stage, frame, frame_count});
+        }
+    }
+
+    /// Check whether the task has been cancelled, and fail if so.
+    fn check_cancelled(&self) -> Result<(), CaptionError<Tl, Fl>> {
+        if self.cancel.is_cancelled() {
+            debug!("Rendering of {:?} cancelled", self.image_macro);
+            return Err(CaptionError::Cancelled);
+        }
+        Ok(())
+    }
+
+    /// Report that given frame of an animation is being encoded,
+    /// and check whether the task has been cancelled in the meantime.
+    ///
+    /// The encoders can only fail with I/O errors, so the cancellation is signaled
+    /// as an `Interrupted` one, which `encode_error` turns back into `CaptionError::Cancelled`.
+    fn encode_frame(&self, frame: usize, frame_count: usize) -> io::Result<()> {
+        self.report(RenderStage::Encode, Some(frame), frame_count);
+        if self.cancel.is_cancelled() {
+            return Err(io::Error::new(io::ErrorKind::Interrupted, "encoding cancelled"));
+        }
+        Ok(())
+    }
+
+    /// Convert an error of encoding the result into `CaptionError`.
+    fn encode_error(&self, e: io::Error) -> CaptionError<Tl, Fl> {
+        if e.kind() == io::ErrorKind::Interrupted && self.cancel.is_cancelled() {
+            debug!("Encoding of {:?} cancelled", self.image_macro);
+            return CaptionError::Cancelled;
+        }
+        CaptionError::Encode(e)
     }
 }
 
+// Frames of animated templates are processed in parallel,
+// which requires the loaders (and their errors) to be thread-safe.
 impl<Tl, Fl> CaptionTask<Tl, Fl>
-    where Tl: Loader<Item=Template>, Fl: Loader<Item=Font>
+    where Tl: Loader<Item=Template> + Send + Sync, Fl: Loader<Item=Font> + Send + Sync,
+          Tl::Err: Send, Fl::Err: Send
 {
     /// Perform the captioning task.
     pub fn perform(self) -> Result<CaptionOutput, CaptionError<Tl, Fl>> {
+        let mut bytes = vec![];
+        let format = self.perform_to(&mut bytes)?;
+        Ok(CaptionOutput::new(format, bytes))
+    }
+
+    /// Perform the captioning task, writing the encoded image macro to given output.
+    ///
+    /// Returns the format that the image macro has been encoded as.
+    /// All the frames are rendered in memory (as `render_frames` would return them)
+    /// before the encoding starts.
+    pub fn perform_to<W: Write>(self, output: W) -> Result<ImageFormat, CaptionError<Tl, Fl>> {
+        let (template, format, frames) = self.render()?;
+        self.check_cancelled()?;
+        self.report(RenderStage::Encode, None, frames.len());
+        self.encode_result(frames, &*template, format, output)?;
+        Ok(format)
+    }
+
+    /// Perform the captioning task, but return the rendered frames without encoding them.
+    pub fn render_frames(self) -> Result<Vec<RenderedFrame>, CaptionError<Tl, Fl>> {
+        self.render().map(|(_, _, frames)| frames)
+    }
+
+    /// Render the frames of the image macro.
+    ///
+    /// Returns the (possibly rearranged) template, the format that the result
+    /// should be encoded as, and the rendered frames.
+    fn render(&self)
+              -> Result<(Arc<Template>, ImageFormat, Vec<RenderedFrame>), CaptionError<Tl, Fl>> {
         debug!("Rendering {:?}", self.image_macro);
+        self.check_cancelled()?;
+        self.engine.config.read().validate().map_err(CaptionError::Config)?;
+
+        // Resolve the custom effects upfront, so we don't fail halfway through rendering.
+        let effects = self.resolve_effects()?;
+
+        self.report(RenderStage::Load, None, 0);
+        let template = self.load_template()?;
+        self.check_cancelled()?;
 
-        let template = self.engine.template_loader.load(&self.template)
-            .map_err(|e| CaptionError::template(self.template.clone(), e))?;
+        // Render the text on all images of the templates
+        // (which usually means just one, unless it's an animated GIF),
+        // or only on the single frame we need if the output is a still image.
+        let format = self.output_format(&*template);
+        let count = template.image_count();
+        let selected_frame = self.select_frame(&*template, format)?;
+        if let Some(index) = selected_frame {
+            debug!("Rendering frame #{} of {} as a still image", index, count);
+        }
+
+        // Captions are the same on every frame, so they're only rendered once
+        // (or again, if an effect has changed the frame size).
+        let caption_layer = Mutex::new(None);
+
+        let frames: Vec<_> = template.iter_images().zip(template.iter_delays()).enumerate()
+            .filter(|&(index, _)| selected_frame.map(|i| i == index).unwrap_or(true))
+            .enumerate()
+            .collect();
+        let frame_count = frames.len();
+        let parallelism = self.engine.config.read().parallelism;
+        let rendered = parallel::map(frames, parallelism, |(position, (index, (img, delay)))| {
+            self.check_cancelled()?;
+            let frame = FrameInfo{
+                index, count, delay,
+                stage: EffectStage::default(),
+                image_macro: &self.image_macro,
+            };
+            self.render_frame(img.clone(), frame, (position, frame_count),
+                              &effects, &caption_layer)
+                .map(|img| RenderedFrame::new(img, delay))
+        });
+        let mut frames = rendered.into_iter().collect::<Result<Vec<_>, _>>()?;
+        if frames.len() == 1 {
+            frames[0].delay = None;  // still image
+        }
+
+        Ok((template, format, frames))
+    }
+
+    /// Compute the layout of the image macro's captions, without rendering it.
+    pub fn layout(self) -> Result<MacroLayout, CaptionError<Tl, Fl>> {
+        debug!("Computing layout of {:?}", self.image_macro);
+        self.engine.config.read().validate().map_err(CaptionError::Config)?;
+        let effects = self.resolve_effects()?;
+        let template = self.load_template()?;
+
+        // The captions are the same on every frame, but the frame we pick
+        // still matters to the effects which may change its size.
+        let format = self.output_format(&*template);
+        let count = template.image_count();
+        let index = self.select_frame(&*template, format)?.unwrap_or(0);
+        let img = template.iter_images().nth(index).unwrap().clone();
+        let delay = template.iter_delays().nth(index).unwrap();
+        let frame = FrameInfo{
+            index, count, delay,
+            stage: EffectStage::default(),
+            image_macro: &self.image_macro,
+        };
+        let (width, height) = self.prepare_frame(img, frame, &effects)?.dimensions();
+
+        let mut captions = Vec::with_capacity(self.captions.len());
+        for cap in &self.captions {
+            let (font, rect, layout) = self.layout_caption(cap, (width, height))?;
+            let bounds = layout.line_bounds(&font, (cap.halign, cap.valign), rect);
+            captions.push(CaptionLayout::new(layout, bounds));
+        }
+        Ok(MacroLayout{width, height, captions})
+    }
+
+    /// Load the template of the image macro,
+    /// rearranging its frames as per the animation options if it's animated.
+    fn load_template(&self) -> Result<Arc<Template>, CaptionError<Tl, Fl>> {
+        let mut template = match self.given_template {
+            Some(ref template) => {
+                for img in template.iter_images() {
+                    let (width, height) = img.dimensions();
+                    if width > MAX_WIDTH || height > MAX_HEIGHT {
+                        return Err(CaptionError::TooLarge{width, height});
+                    }
+                }
+                template.clone()
+            }
+            None => self.engine.template_loader.load(&self.template)
+                .map_err(|e| CaptionError::template(self.template.clone(), e))?,
+        };
         if template.is_animated() {
             debug!("Image macro uses an animated template `{}` with {} frames",
                 self.template, template.image_count());
+            if self.animation.rearranges_frames() {
+                template = Arc::new(self.rearrange_frames(&template)?);
+            }
         }
+        Ok(template)
+    }
 
-        // Render the text on all images of the templates
-        // (which usually means just one, unless it's an animated GIF).
-        let mut images = Vec::with_capacity(template.image_count());
-        for mut img in template.iter_images().cloned() {
-            img = self.resize_template(img);
-            if self.has_text() {
-                img = self.draw_texts(img)?;
+    /// Determine the only frame of the template
+    /// that should be rendered when the result is encoded in given format,
+    /// or None if all of them should.
+    fn select_frame(&self, template: &Template,
+                    format: ImageFormat) -> Result<Option<usize>, CaptionError<Tl, Fl>> {
+        let count = template.image_count();
+        match self.output.frame {
+            Some(index) if index >= count =>
+                Err(CaptionError::FrameOutOfRange{index, count}),
+            Some(index) => Ok(Some(index)),
+            None if count > 1 && !self.is_animated_output(template, format) => Ok(Some(0)),
+            None => Ok(None),
+        }
+    }
+
+    /// Whether the result encoded in given format should be animated.
+    fn is_animated_output(&self, template: &Template, format: ImageFormat) -> bool {
+        match format {
+            ImageFormat::GIF | ImageFormat::WEBP => true,
+            // PNG is a still image unless APNG has been asked for explicitly,
+            // or the template is an APNG itself and no format has been asked for at all.
+            ImageFormat::PNG => match self.output.format {
+                Some(f) => f.is_animated(),
+                None => match *template {
+                    Template::AnimatedPng(..) => true,
+                    _ => false,
+                },
+            },
+            _ => false,
+        }
+    }
+
+    /// Render a single frame of the image macro from given template image.
+    ///
+    /// The position is the frame's index & the total count of frames being rendered,
+    /// as reported to the progress observer.
+    fn render_frame(&self, img: DynamicImage, frame: FrameInfo, position: (usize, usize),
+                    effects: &[(&str, Arc<Effect>)],
+                    caption_layer: &Mutex<Option<Arc<CaptionLayer>>>)
+                    -> Result<DynamicImage, CaptionError<Tl, Fl>> {
+        let at = |stage| FrameInfo{stage, ..frame};
+        let (position, frame_count) = position;
+
+        self.report(RenderStage::Resize, Some(position), frame_count);
+        let mut img = self.prepare_frame(img, frame, effects)?;
+        self.check_cancelled()?;
+
+        self.report(RenderStage::Draw, Some(position), frame_count);
+        if self.has_text() {
+            let size = img.dimensions();
+            let layer = {
+                let mut cached = caption_layer.lock();
+                let is_stale = cached.as_ref().map(|l| l.dimensions() != size).unwrap_or(true);
+                if is_stale {
+                    *cached = Some(Arc::new(self.render_caption_layer(size)?));
+                }
+                cached.as_ref().unwrap().clone()
+            };
+            img = layer.composite(img);
+        }
+        self.check_cancelled()?;
+        img = Self::apply_filters(img, &self.post_filters);
+        img = Self::apply_effects(img, effects, &at(EffectStage::PostCaption))?;
+        if let Some(ref wm) = self.engine.watermark {
+            img = self.draw_watermark(img, wm)?;
+        }
+        Ok(img)
+    }
+
+    /// Prepare a template image for captioning by transforming, resizing & filtering it
+    /// (along with applying any effects that come before the captions).
+    fn prepare_frame(&self, img: DynamicImage, frame: FrameInfo,
+                     effects: &[(&str, Arc<Effect>)])
+                     -> Result<DynamicImage, CaptionError<Tl, Fl>> {
+        let at = |stage| FrameInfo{stage, ..frame};
+
+        let mut img = self.transform_template(img)?;
+        img = Self::apply_effects(img, effects, &at(EffectStage::PreResize))?;
+        self.check_cancelled()?;
+        img = self.resize_template(img);
+        self.check_cancelled()?;
+        img = Self::apply_filters(img, &self.filters);
+        Self::apply_effects(img, effects, &at(EffectStage::PostResize))
+    }
+
+    /// Change the playback of an animated template as per the ImageMacro's animation options.
+    fn rearrange_frames(&self, template: &Template) -> Result<Template, CaptionError<Tl, Fl>> {
+        let count = template.image_count();
+        if let Some(index) = self.animation.start_frame {
+            if index >= count {
+                return Err(CaptionError::FrameOutOfRange{index, count});
+            }
+        }
+        if let Some(end) = self.animation.end_frame {
+            if end > count {  // end is exclusive, so it may be equal to the count
+                return Err(CaptionError::FrameOutOfRange{index: end - 1, count});
             }
-            images.push(img);
         }
 
-        let bytes = self.encode_result(images, &*template)?;
-        let output = CaptionOutput::new(template.preferred_format(), bytes);
-        Ok(output)
+        let delays = template.iter_delays()
+            .map(|d| d.unwrap_or_else(|| Duration::from_millis(0)));
+        let frames = animation::arrange_frames(delays, &self.animation);
+        if frames.len() > animation::MAX_FRAME_COUNT {
+            return Err(CaptionError::TooManyFrames(frames.len()));
+        }
+        debug!("Playing {} frame(s) of the {}-frame template with {:?}",
+            frames.len(), count, self.animation);
+        Ok(template.rearrange(&frames))
+    }
+
+    /// How many times the resulting animation should be repeated after it's played once.
+    #[inline]
+    fn loop_count(&self) -> Option<u16> {
+        self.output.loop_count.or(self.animation.loop_count)
+    }
+
+    /// Look up the ImageMacro's custom effects in the engine.
+    fn resolve_effects(&self) -> Result<Vec<(&str, Arc<Effect>)>, CaptionError<Tl, Fl>> {
+        self.effects.iter().map(|name| {
+            self.engine.effects.get(name)
+                .map(|e| (name.as_str(), e))
+                .ok_or_else(|| CaptionError::UnknownEffect(name.clone()))
+        }).collect()
+    }
+
+    /// Apply those of given effects that run at the frame's current stage.
+    fn apply_effects(img: DynamicImage,
+                     effects: &[(&str, Arc<Effect>)],
+                     frame: &FrameInfo) -> Result<DynamicImage, CaptionError<Tl, Fl>> {
+        let mut img = img;
+        for &(name, ref effect) in effects.iter().filter(|&&(_, ref e)| e.stage() == frame.stage) {
+            trace!("Applying effect `{}` to frame #{} at {:?} stage",
+                name, frame.index, frame.stage);
+            img = effect.apply(img, frame).map_err(|e| CaptionError::effect(name, e))?;
+        }
+        Ok(img)
+    }
+
+    /// Apply the ImageMacro's transformations to a template image.
+    ///
+    /// Fails if any transformation would enlarge the image beyond the maximum size
+    /// (which arbitrary rotations can do), before the enlarged image is allocated.
+    fn transform_template(&self,
+                          template: DynamicImage) -> Result<DynamicImage, CaptionError<Tl, Fl>> {
+        let mut img = template;
+        for t in &self.transforms {
+            let (width, height) = img.dimensions();
+            let (new_width, new_height) = transform::output_size((width, height), t);
+            let enlarged = new_width as u64 * new_height as u64 > width as u64 * height as u64;
+            if enlarged && (new_width > MAX_WIDTH || new_height > MAX_HEIGHT) {
+                debug!("{:?} would turn the {}x{} template into {}x{} image, exceeding {}x{}",
+                    t, width, height, new_width, new_height, MAX_WIDTH, MAX_HEIGHT);
+                return Err(CaptionError::TooLarge{width: new_width, height: new_height});
+            }
+            img = transform::apply(img, t);
+        }
+        Ok(img)
     }
 
     /// Resize a template image to fit the desired dimensions.
@@ -86,14 +438,20 @@ impl<Tl, Fl> CaptionTask<Tl, Fl>
         let (orig_width, orig_height) = template.dimensions();
         trace!("Original size of the template image `{}`: {}x{}",
             self.template, orig_width, orig_height);
-        let target_width = self.width.unwrap_or(orig_width);
-        let target_height = self.height.unwrap_or(orig_height);
+
+        let config = *self.engine.config.read();
+        let (target_width, target_height) = target_size(
+            (orig_width, orig_height),
+            (self.width.unwrap_or(orig_width), self.height.unwrap_or(orig_height)),
+            config.upscaling.max_factor());
 
         let img;
         if target_width != orig_width || target_height != orig_height {
-            debug!("Resizing template image `{}` from {}x{} to {}x{}",
-                self.template, orig_width, orig_height, target_width, target_height);
-            img = template.resize(target_width, target_height, FilterType::Lanczos3);
+            let resampling = self.resampling.unwrap_or(config.resampling);
+            debug!("Resizing template image `{}` from {}x{} to {}x{} using {:?} filter",
+                self.template, orig_width, orig_height, target_width, target_height, resampling);
+            img = transform::resize(&template, target_width, target_height,
+                                    resampling.to_filter_type());
         } else {
             debug!("Using original template image size of {}x{}", orig_width, orig_height);
             img = template;
@@ -104,21 +462,27 @@ impl<Tl, Fl> CaptionTask<Tl, Fl>
         img
     }
 
-    /// Draw the text from ImageMacro on given image.
-    /// Returns a new image.
-    fn draw_texts(&self, img: DynamicImage) -> Result<DynamicImage, CaptionError<Tl, Fl>> {
-        // Rendering text requires alpha blending.
+    /// Apply a sequence of the ImageMacro's filters to given image.
+    fn apply_filters(img: DynamicImage, pipeline: &[Filter]) -> DynamicImage {
         let mut img = img;
-        if img.as_rgba8().is_none() {
-            trace!("Converting image to RGBA...");
-            img = DynamicImage::ImageRgba8(img.to_rgba());
+        for f in pipeline {
+            img = filters::apply(img, f);
         }
+        img
+    }
 
+    /// Render the text from ImageMacro onto a transparent layer of given size,
+    /// so that it can be composited onto the frames of the image macro.
+    fn render_caption_layer(&self, size: (u32, u32)) -> Result<CaptionLayer, CaptionError<Tl, Fl>> {
+        let (width, height) = size;
+        trace!("Rendering caption layer of size {}x{}", width, height);
+
+        let mut img = CaptionLayer::blank(width, height);
         for cap in &self.captions {
             img = self.draw_single_caption(img, cap)?;
         }
 
-        Ok(img)
+        Ok(CaptionLayer::new(img))
     }
 
     /// Draws a single caption text.
@@ -135,6 +499,40 @@ impl<Tl, Fl> CaptionTask<Tl, Fl>
             v = format!("{:?}", caption.valign).to_lowercase(),
             h = format!("{:?}", caption.halign).to_lowercase());
 
+        let (font, rect, layout) = self.layout_caption(caption, img.dimensions())?;
+        let alignment = (caption.halign, caption.valign);
+
+        // Draw four copies of the text, shifted in four diagonal directions,
+        // to create the basis for an outline.
+        if let Some(outline_color) = caption.outline {
+            let outline_width = 2.0;
+            debug!("Drawing text outline (width = {})", outline_width);
+            for &v in [vector(-outline_width, -outline_width),
+                       vector(outline_width, -outline_width),
+                       vector(outline_width, outline_width),
+                       vector(-outline_width, outline_width)].iter() {
+                let style = Style::new(&font, layout.size, outline_color)
+                    .with_glyph_cache(&self.engine.glyph_cache, &caption.font);
+                let rect = Rect{min: rect.min + v, max: rect.max + v};
+                img = text::render_layout(img, &layout, alignment, rect, style);
+            }
+        }
+
+        // Now render the white text in the original position.
+        debug!("Rendering actual caption text...");
+        let style = Style::new(&font, layout.size, caption.color)
+            .with_glyph_cache(&self.engine.glyph_cache, &caption.font);
+        img = text::render_layout(img, &layout, alignment, rect, style);
+
+        Ok(img)
+    }
+
+    /// Lay out the text of a single caption on an image of given size.
+    ///
+    /// Returns the caption's font, the rectangle that its text is aligned within,
+    /// and the text's layout.
+    fn layout_caption(&self, caption: &Caption, size: (u32, u32))
+                      -> Result<(Arc<Font>, Rect<f32>, TextLayout), CaptionError<Tl, Fl>> {
         trace!("Loading font `{}`...", caption.font);
         let font = self.engine.font_loader.load(&caption.font)
             .map_err(|e| CaptionError::font(caption.font.clone(), e))?;
@@ -143,7 +541,7 @@ impl<Tl, Fl> CaptionTask<Tl, Fl>
             caption.font, caption.text);
         text::check(&*font, &caption.text);
 
-        let (width, height) = img.dimensions();
+        let (width, height) = size;
         let width = width as f32;
         let height = height as f32;
 
@@ -164,75 +562,174 @@ impl<Tl, Fl> CaptionTask<Tl, Fl>
             max: point(width, height) - margin_vector,
         };
 
-        let alignment = (caption.halign, caption.valign);
+        let size_range = {
+            let config = self.engine.config.read();
+            (config.min_text_size, config.max_text_size)
+        };
+        let layout = match caption.size {
+            Size::Fixed(s) => Some(text::layout_text(&caption.text, &*font, s, rect.width())),
+            Size::Shrink => text::fit_line(rect.width(), &caption.text, &*font, size_range),
+            Size::Fit => text::fit_text(rect, &caption.text, &*font, size_range),
+        }.unwrap_or_else(|| {
+            text::layout_text(&caption.text, &*font, DEFAULT_TEXT_SIZE, rect.width())
+        });
+        trace!("Caption laid out in {} line(s) at size {}", layout.lines.len(), layout.size);
+        Ok((font, rect, layout))
+    }
 
-        let text_size = match caption.size {
-            Size::Fixed(s) => Some(s),
-            Size::Shrink => text::fit_line(rect.width(), &caption.text, &*font),
-            Size::Fit => text::fit_text(rect, &caption.text, &*font),
-        }.unwrap_or(DEFAULT_TEXT_SIZE);
+    /// Stamp the engine's watermark on given image.
+    /// Returns a new image.
+    fn draw_watermark(&self, img: DynamicImage,
+                      wm: &Watermark) -> Result<DynamicImage, CaptionError<Tl, Fl>> {
+        let (width, height) = img.dimensions();
+        if !wm.applies_to(width, height) {
+            trace!("Image too small ({}x{}) for the watermark to apply", width, height);
+            return Ok(img);
+        }
 
-        // Draw four copies of the text, shifted in four diagonal directions,
-        // to create the basis for an outline.
-        if let Some(outline_color) = caption.outline {
-            let outline_width = 2.0;
-            debug!("Drawing text outline (width = {})", outline_width);
-            for &v in [vector(-outline_width, -outline_width),
-                       vector(outline_width, -outline_width),
-                       vector(outline_width, outline_width),
-                       vector(-outline_width, outline_width)].iter() {
-                let style = Style::new(&font, text_size, outline_color);
-                let rect = Rect{min: rect.min + v, max: rect.max + v};
-                img = text::render_text(img, &caption.text, alignment, rect, style);
+        let stamped = match wm.content {
+            WatermarkContent::Image{ref template} => {
+                trace!("Loading watermark image `{}`...", template);
+                let wm_template = self.engine.template_loader.load(template)
+                    .map_err(|e| CaptionError::template(template.clone(), e))?;
+                let mut overlay = wm_template.iter_images().next().unwrap().clone();
+
+                // Shrink the watermark image if it wouldn't fit otherwise.
+                let max_width = width.saturating_sub(2 * wm.margin).max(1);
+                let max_height = height.saturating_sub(2 * wm.margin).max(1);
+                if overlay.width() > max_width || overlay.height() > max_height {
+                    let resampling = self.engine.config.read().resampling;
+                    overlay = overlay.resize(max_width, max_height, resampling.to_filter_type());
+                }
+
+                let (x, y) = watermark::overlay_position(
+                    (width, height), overlay.dimensions(), wm.valign, wm.halign, wm.margin);
+                debug!("Drawing {}x{} watermark image `{}` at {}x{}",
+                    overlay.width(), overlay.height(), template, x, y);
+                watermark::draw_overlay(img.clone(), &overlay, x, y)
             }
-        }
+            WatermarkContent::Text{ref text, ref font, color, size} => {
+                trace!("Loading watermark font `{}`...", font);
+                let font_obj = self.engine.font_loader.load(font)
+                    .map_err(|e| CaptionError::font(font.clone(), e))?;
+
+                let margin = wm.margin as f32;
+                let rect: Rect<f32> = Rect{
+                    min: point(margin, margin),
+                    max: point(width as f32 - margin, height as f32 - margin),
+                };
+                debug!("Drawing watermark text: {:?}", text);
+                let style = Style::new(&font_obj, size, color)
+                    .with_glyph_cache(&self.engine.glyph_cache, font);
+                text::render_text(img.clone(), text, (wm.halign, wm.valign), rect, style)
+            }
+        };
 
-        // Now render the white text in the original position.
-        debug!("Rendering actual caption text...");
-        let style = Style::new(&font, text_size, caption.color);
-        img = text::render_text(img, &caption.text, alignment, rect, style);
+        Ok(watermark::mix(&img, stamped, wm.opacity))
+    }
 
-        Ok(img)
+    /// Determine the image format that the result should be encoded in.
+    fn output_format(&self, template: &Template) -> ImageFormat {
+        match self.output.format {
+            Some(format) => format.into(),
+            None => template.preferred_format(),
+        }
     }
 
-    /// Encode final result as bytes of the appropriate image format.
-    fn encode_result(&self, images: Vec<DynamicImage>,
-                     template: &Template) -> Result<Vec<u8>, CaptionError<Tl, Fl>> {
-        let format = template.preferred_format();
+    /// Encode final result in given image format, writing it to given output.
+    fn encode_result<W: Write>(&self, frames: Vec<RenderedFrame>, template: &Template,
+                               format: ImageFormat,
+                               mut output: W) -> Result<(), CaptionError<Tl, Fl>> {
         debug!("Encoding final image as {:?}...", format);
+        let config = *self.engine.config.read();
+
+        let delays: Vec<_> = frames.iter()
+            .map(|f| f.delay.unwrap_or_else(|| Duration::from_millis(0)))
+            .collect();
+        let images: Vec<_> = frames.into_iter()
+            .map(|f| DynamicImage::ImageRgba8(f.image))
+            .collect();
+
+        // Animations are encoded frame by frame, so they can also be cancelled in between.
+        let frame_count = images.len();
+        let on_frame = |i| self.encode_frame(i, frame_count);
 
-        let mut result = vec![];
         match format {
+            ImageFormat::PNG if images.len() > 1 => {
+                trace!("Writing animated PNG of {} frame(s)", images.len());
+                let frames = images.into_iter().zip(delays)
+                    .map(|(image, delay)| PngFrame{image, delay})
+                    .collect();
+                let mut png_anim = PngAnimation::new(frames);
+                png_anim.num_plays = self.loop_count()
+                    .map(|n| n as u32 + 1).unwrap_or(0);  // 0 means forever
+                animated_png::encode_with_hook(&png_anim, &mut output, &on_frame)
+                    .map_err(|e| self.encode_error(e))?;
+            }
             ImageFormat::PNG => {
                 trace!("Writing PNG image");
                 assert_eq!(1, images.len());
                 let img = &images[0];
 
+                // Still images are small enough to be buffered,
+                // which we need to do anyway in order to embed the metadata.
                 let (width, height) = img.dimensions();
                 let pixels = &*img.raw_pixels();
+                let mut result = vec![];
                 image::png::PNGEncoder::new(&mut result)
                     .encode(pixels, width, height, img.color())
                     .map_err(CaptionError::Encode)?;
+                result = Self::embed_metadata(result, template, format, &config);
+                output.write_all(&result).map_err(CaptionError::Encode)?;
             }
             ImageFormat::JPEG => {
-                let quality = self.engine.config.read().jpeg_quality;
+                let quality = self.output.quality.unwrap_or(config.jpeg_quality);
                 trace!("Writing JPEG with quality {}%", quality);
                 assert_eq!(1, images.len());
-                let img = &images[0];
+                let img = &flatten(&images[0]);
 
                 let (width, height) = img.dimensions();
                 let pixels = &*img.raw_pixels();
+                let mut result = vec![];
                 image::jpeg::JPEGEncoder::new_with_quality(&mut result, quality)
                     .encode(pixels, width, height, img.color())
                     .map_err(CaptionError::Encode)?;
+                result = Self::embed_metadata(result, template, format, &config);
+                output.write_all(&result).map_err(CaptionError::Encode)?;
             }
             ImageFormat::GIF => {
-                let quality = self.engine.config.read().gif_quality;
-                if let &Template::Animation(ref gif_anim) = template {
+                let quality = self.output.quality.unwrap_or(config.gif_quality);
+                let options = animated_gif::EncodeOptions{
+                    quality,
+                    loop_count: self.loop_count(),
+                    optimize: config.gif_optimize,
+                    dithering: config.gif_dithering,
+                    reserved_colors: if config.gif_reserve_caption_colors {
+                        animated_gif::ReservedColors::new(&self.caption_colors())
+                    } else {
+                        animated_gif::ReservedColors::default()
+                    },
+                    parallelism: config.parallelism,
+                };
+                let gif_anim = match *template {
+                    Template::Animation(ref gif_anim)
+                        if gif_anim.frames_count() == images.len() => Some(gif_anim),
+                    _ => None,
+                };
+                if let Some(gif_anim) = gif_anim {
                     trace!("Writing animated GIF of {} frame(s) with quality {}%",
                         gif_anim.frames_count(), quality);
-                    animated_gif::encode_modified(gif_anim, images, quality, &mut result)
-                        .map_err(CaptionError::Encode)?;
+                    animated_gif::encode_modified_with_hook(
+                        gif_anim, images, &options, &mut output, &on_frame)
+                        .map_err(|e| self.encode_error(e))?;
+                } else if images.len() > 1 {
+                    // Animation from some other format (e.g. APNG), so there is
+                    // no original GIF metadata to reuse; we only need the frame delays.
+                    trace!("Writing animated GIF of {} converted frame(s) with quality {}%",
+                        images.len(), quality);
+                    let frames = images.into_iter().zip(delays).collect();
+                    animated_gif::encode_images_with_hook(frames, &options, &mut output, &on_frame)
+                        .map_err(|e| self.encode_error(e))?;
                 } else {
                     trace!("Writing regular (still) GIF with quality {}%", quality);
                     assert_eq!(1, images.len());
@@ -241,14 +738,15 @@ impl<Tl, Fl> CaptionTask<Tl, Fl>
                     // Encode the image as a single GIF frame.
                     let (width, height) = img.dimensions();
                     let mut frame = image::gif::Frame::default();
-                    let (buffer, palette, transparent) = animated_gif::quantize_image(img, quality);
+                    let (buffer, palette, transparent) = animated_gif::quantize_image_with(
+                        img, quality, options.dithering, &options.reserved_colors);
                     frame.width = width as u16;
                     frame.height = height as u16;
                     frame.buffer = buffer.into();
                     frame.palette = Some(palette);
                     frame.transparent = transparent;
 
-                    image::gif::Encoder::new(&mut result).encode(frame).map_err(|e| {
+                    image::gif::Encoder::new(&mut output).encode(frame).map_err(|e| {
                         let io_error = match e {
                             image::ImageError::IoError(e) => e,
                             e => io::Error::new(io::ErrorKind::Other, e),
@@ -257,19 +755,135 @@ impl<Tl, Fl> CaptionTask<Tl, Fl>
                     })?;
                 }
             }
+            ImageFormat::WEBP => {
+                // libwebp produces the whole image in memory anyway.
+                let result = self.encode_webp(images, delays, template, &config)?;
+                output.write_all(&result).map_err(CaptionError::Encode)?;
+            }
             f => {
                 panic!("Unexpected image format in CaptionTask::encode_result: {:?}", f);
             }
         }
 
-        Ok(result)
+        output.flush().map_err(CaptionError::Encode)
+    }
+
+    /// Colors of the captions' text & outlines, which should be rendered exactly.
+    fn caption_colors(&self) -> Vec<[u8; 3]> {
+        let mut colors = vec![];
+        for cap in &self.captions {
+            colors.push([cap.color.0, cap.color.1, cap.color.2]);
+            if let Some(ref outline) = cap.outline {
+                colors.push([outline.0, outline.1, outline.2]);
+            }
+        }
+        colors
+    }
+
+    /// Copy the template's metadata to the encoded image, if the engine is configured to.
+    fn embed_metadata(bytes: Vec<u8>, template: &Template,
+                      format: ImageFormat, config: &engine::Config) -> Vec<u8> {
+        if !config.preserve_metadata {
+            return bytes;
+        }
+        match template.metadata() {
+            Some(metadata) => {
+                trace!("Embedding template metadata in {:?} image", format);
+                metadata.embed(bytes, format)
+            }
+            None => bytes,
+        }
+    }
+
+    /// Encode final result as WebP image or animation.
+    #[cfg(feature = "webp")]
+    fn encode_webp(&self, images: Vec<DynamicImage>, delays: Vec<Duration>, template: &Template,
+                   config: &engine::Config) -> Result<Vec<u8>, CaptionError<Tl, Fl>> {
+        let compression = if config.webp_lossless {
+            webp::Compression::Lossless
+        } else {
+            webp::Compression::Lossy(self.output.quality.unwrap_or(config.webp_quality))
+        };
+
+        if images.len() > 1 && images.len() == template.image_count() {
+            trace!("Writing animated WebP of {} frame(s) with {:?} compression",
+                images.len(), compression);
+            let frames: Vec<_> = images.into_iter().zip(delays).collect();
+            let frame_count = frames.len();
+            let on_frame = |i| self.encode_frame(i, frame_count);
+            webp::encode_animation_with_hook(&frames, compression, self.loop_count(), &on_frame)
+                .map_err(|e| self.encode_error(e))
+        } else {
+            trace!("Writing still WebP image with {:?} compression", compression);
+            assert_eq!(1, images.len());
+            webp::encode(&images[0], compression).map_err(CaptionError::Encode)
+        }
     }
+
+    /// Encode final result as WebP image or animation.
+    ///
+    /// Since WebP support hasn't been compiled in, this always fails.
+    #[cfg(not(feature = "webp"))]
+    fn encode_webp(&self, _: Vec<DynamicImage>, _: Vec<Duration>, _: &Template,
+                   _: &engine::Config) -> Result<Vec<u8>, CaptionError<Tl, Fl>> {
+        Err(CaptionError::Encode(io::Error::new(io::ErrorKind::Other,
+            "WebP output requires the `webp` feature of the rofl crate")))
+    }
+}
+
+
+/// Flatten the image onto a white background, removing its transparency.
+///
+/// This is needed for output formats that don't support transparency (i.e. JPEG),
+/// as otherwise the transparent areas would turn black.
+fn flatten(img: &DynamicImage) -> DynamicImage {
+    let mut img = match *img {
+        DynamicImage::ImageRgba8(ref rgba) => rgba.clone(),
+        // Other image types are already opaque (or not worth the effort).
+        ref img => return img.clone(),
+    };
+    for pixel in img.pixels_mut() {
+        let alpha = pixel.data[3] as f32 / 255.0;
+        for c in pixel.data[..3].iter_mut() {
+            *c = (*c as f32 * alpha + 255.0 * (1.0 - alpha)).round() as u8;
+        }
+        pixel.data[3] = 0xff;
+    }
+    DynamicImage::ImageRgba8(img)
+}
+
+/// Compute the bounds that the template should be resized to.
+///
+/// If the requested size would mean enlarging the template by more
+/// than `max_factor`, the bounds are clamped to the maximum allowed size.
+fn target_size(orig: (u32, u32), requested: (u32, u32),
+               max_factor: Option<f32>) -> (u32, u32) {
+    let (orig_width, orig_height) = orig;
+    let (width, height) = requested;
+    let max_factor = match max_factor {
+        Some(f) => f,
+        None => return requested,
+    };
+
+    // This mirrors how DynamicImage::resize() preserves the aspect ratio,
+    // i.e. the image is scaled to fit within requested bounds.
+    let factor = (width as f32 / orig_width as f32)
+        .min(height as f32 / orig_height as f32);
+    if factor <= max_factor {
+        return requested;
+    }
+
+    let clamped = ((orig_width as f32 * max_factor).round() as u32,
+                   (orig_height as f32 * max_factor).round() as u32);
+    debug!("Upscaling {}x{} to {}x{} would exceed the limit of {}x, clamping to {}x{}",
+        orig_width, orig_height, width, height, max_factor, clamped.0, clamped.1);
+    clamped
 }
 
 
 #[cfg(test)]
 mod tests {
-    use super::CaptionTask;
+    use super::{CaptionTask, target_size};
 
     #[test]
     fn thread_safe() {
@@ -279,4 +893,25 @@ mod tests {
         assert_sync::<CaptionTask>();
         assert_send::<CaptionTask>();
     }
+
+    #[test]
+    fn target_size_without_limit() {
+        assert_eq!((800, 600), target_size((400, 300), (800, 600), None));
+        assert_eq!((200, 150), target_size((400, 300), (200, 150), None));
+    }
+
+    #[test]
+    fn target_size_forbidding_upscale() {
+        assert_eq!((400, 300), target_size((400, 300), (800, 600), Some(1.0)));
+        // Downscaling is always fine.
+        assert_eq!((200, 150), target_size((400, 300), (200, 150), Some(1.0)));
+    }
+
+    #[test]
+    fn target_size_limiting_upscale() {
+        assert_eq!((600, 450), target_size((400, 300), (1000, 750), Some(1.5)));
+        assert_eq!((500, 375), target_size((400, 300), (500, 375), Some(1.5)));
+        // Only the dimension that limits the aspect-preserving resize matters.
+        assert_eq!((1000, 300), target_size((400, 300), (1000, 300), Some(1.5)));
+    }
 }
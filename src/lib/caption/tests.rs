@@ -0,0 +1,31 @@
+//! Fixtures shared by the tests of the captioning code.
+
+use std::fmt::Debug;
+use std::path::{Path, PathBuf};
+
+use resources::{Font, FontLoader, Loader};
+use super::Engine;
+
+
+/// Path to the repository's data directory with templates, fonts & test fixtures.
+pub fn data_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("../../data")
+}
+
+/// Engine which uses the templates & fonts from the data directory.
+pub fn engine() -> Engine {
+    let data = data_dir();
+    Engine::new(data.join("templates"), data.join("fonts"))
+}
+
+/// The Impact font from the data directory.
+pub fn font() -> Font {
+    FontLoader::new(data_dir().join("fonts")).load("Impact").unwrap()
+}
+
+/// Fail the test because of an unexpected result of an operation.
+///
+/// Only errors are shown in full, since successful outputs are mostly large images.
+pub fn unexpected<T, E: Debug>(result: Result<T, E>) -> ! {
+    panic!("unexpected result: {:?}", result.map(|_| ()))
+}
@@ -0,0 +1,242 @@
+//! Module defining the watermark that the `Engine` can stamp on its output.
+
+use image::{DynamicImage, GenericImage, Pixel};
+
+use model::{Color, HAlign, VAlign, DEFAULT_COLOR, DEFAULT_FONT};
+
+
+/// Default size of the watermark text.
+const DEFAULT_TEXT_SIZE: f32 = 16.0;
+/// Default distance between the watermark and edges of the image (in pixels).
+const DEFAULT_MARGIN: u32 = 8;
+/// Default opacity of the watermark.
+const DEFAULT_OPACITY: f32 = 0.5;
+
+
+/// Watermark (like a logo or website URL) put on every image macro rendered by an `Engine`.
+///
+/// See `EngineBuilder::watermark`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Watermark {
+    /// What the watermark consists of.
+    pub content: WatermarkContent,
+    /// Vertical alignment of the watermark within the image.
+    pub valign: VAlign,
+    /// Horizontal alignment of the watermark within the image.
+    pub halign: HAlign,
+    /// Distance between the watermark and the image edges it's aligned to (in pixels).
+    pub margin: u32,
+    /// Opacity of the watermark, from 0.0 (invisible) to 1.0 (fully opaque).
+    pub opacity: f32,
+    /// Minimum width & height of the image macro for the watermark to be applied.
+    ///
+    /// Smaller images are left without the watermark.
+    pub min_size: (u32, u32),
+}
+
+/// Content of the `Watermark`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum WatermarkContent {
+    /// Image loaded with the `Engine`'s template loader.
+    ///
+    /// If the template is animated, only its first frame is used.
+    Image {
+        /// Name of the template to use as the watermark.
+        template: String,
+    },
+    /// Text rendered with given font.
+    Text {
+        /// The watermark text.
+        text: String,
+        /// Name of the font to render the text with.
+        font: String,
+        /// Color of the text.
+        color: Color,
+        /// Size of the text.
+        size: f32,
+    },
+}
+
+impl Watermark {
+    /// Create a watermark using given template image.
+    pub fn image<S: Into<String>>(template: S) -> Self {
+        Self::with_content(WatermarkContent::Image{template: template.into()})
+    }
+
+    /// Create a watermark with given text, rendered using the default font & color.
+    pub fn text<S: Into<String>>(text: S) -> Self {
+        Self::with_content(WatermarkContent::Text{
+            text: text.into(),
+            font: DEFAULT_FONT.into(),
+            color: DEFAULT_COLOR,
+            size: DEFAULT_TEXT_SIZE,
+        })
+    }
+
+    #[inline]
+    fn with_content(content: WatermarkContent) -> Self {
+        Watermark{
+            content,
+            valign: VAlign::Bottom,
+            halign: HAlign::Right,
+            margin: DEFAULT_MARGIN,
+            opacity: DEFAULT_OPACITY,
+            min_size: (0, 0),
+        }
+    }
+}
+
+impl Watermark {
+    /// Place the watermark at given position within the image.
+    ///
+    /// By default, it's the bottom right corner.
+    #[inline]
+    pub fn at(mut self, valign: VAlign, halign: HAlign) -> Self {
+        self.valign = valign;
+        self.halign = halign;
+        self
+    }
+
+    /// Change the watermark's distance from the image edges.
+    #[inline]
+    pub fn margin(mut self, margin: u32) -> Self {
+        self.margin = margin; self
+    }
+
+    /// Change the opacity of the watermark.
+    #[inline]
+    pub fn opacity(mut self, opacity: f32) -> Self {
+        self.opacity = opacity; self
+    }
+
+    /// Set the minimum image size for the watermark to be applied.
+    #[inline]
+    pub fn min_size(mut self, width: u32, height: u32) -> Self {
+        self.min_size = (width, height); self
+    }
+
+    /// Change the font of a text watermark.
+    /// Has no effect if the watermark is an image.
+    #[inline]
+    pub fn font<S: Into<String>>(mut self, font: S) -> Self {
+        if let WatermarkContent::Text{font: ref mut f, ..} = self.content {
+            *f = font.into();
+        }
+        self
+    }
+
+    /// Change the color of a text watermark.
+    /// Has no effect if the watermark is an image.
+    #[inline]
+    pub fn color(mut self, color: Color) -> Self {
+        if let WatermarkContent::Text{color: ref mut c, ..} = self.content {
+            *c = color;
+        }
+        self
+    }
+
+    /// Change the text size of a text watermark.
+    /// Has no effect if the watermark is an image.
+    #[inline]
+    pub fn size(mut self, size: f32) -> Self {
+        if let WatermarkContent::Text{size: ref mut s, ..} = self.content {
+            *s = size;
+        }
+        self
+    }
+}
+
+impl Watermark {
+    /// Whether the watermark should be applied to an image of given size.
+    #[inline]
+    pub fn applies_to(&self, width: u32, height: u32) -> bool {
+        let (min_width, min_height) = self.min_size;
+        width >= min_width && height >= min_height
+    }
+}
+
+
+/// Position of the top-left corner of an overlay of given size
+/// when it's aligned within the image (and kept `margin` pixels away from its edges).
+pub(super) fn overlay_position(img_size: (u32, u32), overlay_size: (u32, u32),
+                               valign: VAlign, halign: HAlign, margin: u32) -> (u32, u32) {
+    let (width, height) = img_size;
+    let (overlay_width, overlay_height) = overlay_size;
+    let x = match halign {
+        HAlign::Left => margin,
+        HAlign::Center => width.saturating_sub(overlay_width) / 2,
+        HAlign::Right => width.saturating_sub(overlay_width + margin),
+    };
+    let y = match valign {
+        VAlign::Top => margin,
+        VAlign::Middle => height.saturating_sub(overlay_height) / 2,
+        VAlign::Bottom => height.saturating_sub(overlay_height + margin),
+    };
+    (x, y)
+}
+
+/// Mix the `stamped` image (i.e. one with the watermark fully drawn on it)
+/// with the original, so that the watermark has given opacity.
+pub(super) fn mix(original: &DynamicImage, stamped: DynamicImage, opacity: f32) -> DynamicImage {
+    if opacity >= 1.0 {
+        return stamped;
+    }
+    let original = original.to_rgba();
+    let mut result = stamped.to_rgba();
+    for (pixel, orig) in result.pixels_mut().zip(original.pixels()) {
+        *pixel = pixel.map2(orig, |s, o| {
+            (o as f32 + (s as f32 - o as f32) * opacity).round() as u8
+        });
+    }
+    DynamicImage::ImageRgba8(result)
+}
+
+/// Draw the overlay image onto another one (with alpha blending) at given position.
+pub(super) fn draw_overlay(img: DynamicImage, overlay: &DynamicImage,
+                           x: u32, y: u32) -> DynamicImage {
+    let mut img = match img {
+        img @ DynamicImage::ImageRgba8(_) => img,
+        img => DynamicImage::ImageRgba8(img.to_rgba()),
+    };
+    for (ox, oy, pixel) in overlay.pixels() {
+        let (px, py) = (x + ox, y + oy);
+        if img.in_bounds(px, py) {
+            img.blend_pixel(px, py, pixel);
+        }
+    }
+    img
+}
+
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, GenericImage, Rgba, RgbaImage};
+    use model::{HAlign, VAlign};
+    use super::{mix, overlay_position, Watermark};
+
+    #[test]
+    fn position_in_corners() {
+        let img = (200, 100);
+        let overlay = (50, 20);
+        assert_eq!((8, 8), overlay_position(img, overlay, VAlign::Top, HAlign::Left, 8));
+        assert_eq!((142, 72), overlay_position(img, overlay, VAlign::Bottom, HAlign::Right, 8));
+        assert_eq!((75, 40), overlay_position(img, overlay, VAlign::Middle, HAlign::Center, 8));
+    }
+
+    #[test]
+    fn min_size() {
+        let wm = Watermark::text("example.com").min_size(100, 100);
+        assert!(wm.applies_to(100, 200));
+        assert!(!wm.applies_to(99, 200));
+    }
+
+    #[test]
+    fn mix_with_opacity() {
+        let black = DynamicImage::ImageRgba8(
+            RgbaImage::from_pixel(2, 2, Rgba{data: [0, 0, 0, 0xff]}));
+        let white = DynamicImage::ImageRgba8(
+            RgbaImage::from_pixel(2, 2, Rgba{data: [0xff, 0xff, 0xff, 0xff]}));
+        let result = mix(&black, white, 0.5);
+        assert_eq!([0x80, 0x80, 0x80, 0xff], result.get_pixel(1, 1).data);
+    }
+}
@@ -84,6 +84,7 @@
 #[macro_use] extern crate newtype_derive;
              extern crate num;
              extern crate rand;
+             extern crate rayon;
              extern crate regex;
              extern crate rusttype;
              extern crate serde;
@@ -92,6 +93,9 @@
 #[macro_use] extern crate try_opt;
              extern crate unicode_normalization;
              extern crate unreachable;
+#[cfg(feature = "webp")] extern crate webp;
+#[cfg(feature = "pool")] extern crate futures;
+#[cfg(feature = "pool")] extern crate futures_cpupool;
 
 
 #[cfg(test)] #[macro_use] extern crate serde_json;
@@ -110,4 +114,5 @@ mod util;
 pub use caption::*;
 pub use model::*;
 pub use resources::*;
-pub use util::{animated_gif, cache};
+pub use util::{animated_gif, animated_png, animated_webp, cache, glyphs, metadata};
+#[cfg(feature = "webp")] pub use util::webp;
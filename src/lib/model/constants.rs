@@ -1,6 +1,6 @@
 //! Module defining constants relevant to the data model.
 
-use super::types::{Color, HAlign};
+use super::types::{Color, HAlign, Resampling};
 
 
 /// Name of the default font.
@@ -18,10 +18,29 @@ pub const DEFAULT_HALIGN: HAlign = HAlign::Center;
 /// Default size of caption text.
 pub const DEFAULT_TEXT_SIZE: f32 = 64.0;
 
+/// Default filter used when resizing templates.
+pub const DEFAULT_RESAMPLING: Resampling = Resampling::Lanczos3;
+
 
 /// Maximum number of captions an ImageMacro can have.
 pub const MAX_CAPTION_COUNT: usize = 16;
 
+/// Maximum number of template transformations an ImageMacro can have.
+pub const MAX_TRANSFORM_COUNT: usize = 16;
+
+/// Maximum number of image filters an ImageMacro can have
+/// (separately for those applied before and after captioning).
+pub const MAX_FILTER_COUNT: usize = 8;
+
+/// Maximum number of custom effects an ImageMacro can have.
+pub const MAX_EFFECT_COUNT: usize = 8;
+
+/// Maximum sigma (in pixels) of the blur & sharpen filters.
+///
+/// The cost of these filters grows with the sigma,
+/// and values above this one just smear the image beyond recognition anyway.
+pub const MAX_FILTER_SIGMA: f32 = 50.0;
+
 /// Maximum width of the result image.
 pub const MAX_WIDTH: u32 = 1024;
 /// Maximum height of the result image.
@@ -29,3 +48,8 @@ pub const MAX_HEIGHT: u32 = 1024;
 
 /// Maximum length (in Unicode codepoints) of a single caption text.
 pub const MAX_CAPTION_LENGTH: usize = 256;
+
+/// Minimum playback speed multiplier of an animated template.
+pub const MIN_ANIMATION_SPEED: f32 = 0.1;
+/// Maximum playback speed multiplier of an animated template.
+pub const MAX_ANIMATION_SPEED: f32 = 10.0;
@@ -0,0 +1,94 @@
+//! Deserializer for the animation options.
+
+use serde::de::{self, Deserialize, Unexpected};
+
+use super::super::{AnimationOptions, MIN_ANIMATION_SPEED, MAX_ANIMATION_SPEED};
+
+
+impl<'de> Deserialize<'de> for AnimationOptions {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: de::Deserializer<'de>
+    {
+        #[derive(Default, Deserialize)]
+        #[serde(default, deny_unknown_fields)]
+        struct Options {
+            speed: Option<f32>,
+            reverse: bool,
+            boomerang: bool,
+            start_frame: Option<usize>,
+            end_frame: Option<usize>,
+            normalize_delays: bool,
+            loop_count: Option<u16>,
+        }
+
+        let opts = Options::deserialize(deserializer)?;
+        if let Some(speed) = opts.speed {
+            if !(MIN_ANIMATION_SPEED <= speed && speed <= MAX_ANIMATION_SPEED) {
+                return Err(de::Error::invalid_value(
+                    Unexpected::Float(speed as f64),
+                    &format!("speed multiplier between {} and {}",
+                        MIN_ANIMATION_SPEED, MAX_ANIMATION_SPEED).as_str()));
+            }
+        }
+        if let (Some(start), Some(end)) = (opts.start_frame, opts.end_frame) {
+            if start >= end {
+                return Err(de::Error::invalid_value(
+                    Unexpected::Unsigned(end as u64), &"end frame greater than start frame"));
+            }
+        }
+
+        let result = AnimationOptions{
+            speed: opts.speed,
+            reverse: opts.reverse,
+            boomerang: opts.boomerang,
+            start_frame: opts.start_frame,
+            end_frame: opts.end_frame,
+            normalize_delays: opts.normalize_delays,
+            loop_count: opts.loop_count,
+        };
+        trace!("{:?}", result);
+        Ok(result)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json::from_value as from_json;
+    use spectral::prelude::*;
+    use ::model::AnimationOptions;
+
+    #[test]
+    fn options() {
+        assert_that!(from_json::<AnimationOptions>(json!({})))
+            .is_ok().is_equal_to(AnimationOptions::default());
+        assert_that!(from_json::<AnimationOptions>(json!({"speed": 2.0, "boomerang": true})))
+            .is_ok().is_equal_to(AnimationOptions{
+                speed: Some(2.0),
+                boomerang: true,
+                ..AnimationOptions::default()
+            });
+        assert_that!(from_json::<AnimationOptions>(json!({"start_frame": 2, "end_frame": 5})))
+            .is_ok().is_equal_to(AnimationOptions{
+                start_frame: Some(2),
+                end_frame: Some(5),
+                ..AnimationOptions::default()
+            });
+        assert_that!(from_json::<AnimationOptions>(json!({"reverse": true, "loop_count": 2})))
+            .is_ok().is_equal_to(AnimationOptions{
+                reverse: true,
+                loop_count: Some(2),
+                ..AnimationOptions::default()
+            });
+    }
+
+    #[test]
+    fn invalid_options() {
+        assert_that!(from_json::<AnimationOptions>(json!({"speed": 0}))).is_err();
+        assert_that!(from_json::<AnimationOptions>(json!({"speed": -1.5}))).is_err();
+        assert_that!(from_json::<AnimationOptions>(json!({"speed": 100}))).is_err();
+        assert_that!(from_json::<AnimationOptions>(json!({"start_frame": 3, "end_frame": 3})))
+            .is_err();
+        assert_that!(from_json::<AnimationOptions>(json!({"loop": true}))).is_err();
+    }
+}
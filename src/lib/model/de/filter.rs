@@ -0,0 +1,214 @@
+//! Deserializer for the Filter type.
+
+use std::fmt;
+
+use serde::de::{self, Deserialize, Visitor, Unexpected};
+
+use super::super::Filter;
+use model::constants::MAX_FILTER_SIGMA;
+
+
+const FILTERS: &'static [&'static str] = &[
+    "grayscale", "sepia", "invert", "blur", "sharpen", "saturate", "contrast",
+    "pixelate", "jpeg_crunch", "noise", "deep_fry",
+];
+
+const EXPECTING_MSG: &'static str = "filter name or single-key map with its parameter";
+
+// Parameters of the filters when they're given just by name.
+const DEFAULT_BLUR: f32 = 2.0;
+const DEFAULT_SHARPEN: f32 = 1.0;
+const DEFAULT_SATURATE: f32 = 1.5;
+const DEFAULT_CONTRAST: f32 = 30.0;
+const DEFAULT_PIXELATE: u32 = 8;
+const DEFAULT_JPEG_CRUNCH: u8 = 10;
+const DEFAULT_NOISE: f32 = 0.1;
+
+
+impl<'de> Deserialize<'de> for Filter {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: de::Deserializer<'de>
+    {
+        deserializer.deserialize_any(FilterVisitor)
+    }
+}
+
+struct FilterVisitor;
+impl<'de> Visitor<'de> for FilterVisitor {
+    type Value = Filter;
+
+    fn expecting(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}", EXPECTING_MSG)
+    }
+
+    /// Deserialize the filter from its name, using the default parameter (if any).
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        let filter = match normalize_name(v).as_str() {
+            "grayscale" => Filter::Grayscale,
+            "sepia" => Filter::Sepia,
+            "invert" => Filter::Invert,
+            "blur" => Filter::Blur(DEFAULT_BLUR),
+            "sharpen" => Filter::Sharpen(DEFAULT_SHARPEN),
+            "saturate" => Filter::Saturate(DEFAULT_SATURATE),
+            "contrast" => Filter::Contrast(DEFAULT_CONTRAST),
+            "pixelate" => Filter::Pixelate(DEFAULT_PIXELATE),
+            "jpeg_crunch" => Filter::JpegCrunch(DEFAULT_JPEG_CRUNCH),
+            "noise" => Filter::Noise(DEFAULT_NOISE),
+            "deep_fry" => Filter::DeepFry,
+            _ => return Err(E::unknown_variant(v, FILTERS)),
+        };
+        Ok(filter)
+    }
+
+    /// Deserialize the filter from a single-key map of its name & parameter.
+    fn visit_map<V>(self, mut map: V) -> Result<Self::Value, V::Error>
+        where V: de::MapAccess<'de>
+    {
+        if let Some(size) = map.size_hint() {
+            if size != 1 {
+                return Err(de::Error::invalid_length(size, &"1"));
+            }
+        }
+
+        let key = map.next_key::<String>()?
+            .ok_or_else(|| de::Error::invalid_length(0, &"1"))?;
+        let Number(param) = map.next_value()?;
+        let filter = match normalize_name(&key).as_str() {
+            "blur" => Filter::Blur(param as f32),
+            "sharpen" => Filter::Sharpen(param as f32),
+            "saturate" => Filter::Saturate(param as f32),
+            "contrast" => Filter::Contrast(param as f32),
+            "pixelate" => Filter::Pixelate(integer(param, u32::max_value())),
+            "jpeg_crunch" => Filter::JpegCrunch(integer(param, 100) as u8),
+            "noise" => Filter::Noise(param as f32),
+            "grayscale" | "sepia" | "invert" | "deep_fry" => {
+                return Err(de::Error::custom(
+                    format_args!("filter `{}` doesn't take any parameters", key)));
+            }
+            _ => return Err(de::Error::unknown_variant(&key, FILTERS)),
+        };
+        if !filter.is_valid() {
+            return Err(de::Error::invalid_value(
+                Unexpected::Float(param), &expected_param(&filter).as_str()));
+        }
+
+        if map.next_key::<String>()?.is_some() {
+            return Err(de::Error::custom("filter must be a single-key map"));
+        }
+        Ok(filter)
+    }
+}
+
+/// Normalize the filter name, accounting for some common alternative spellings.
+fn normalize_name(name: &str) -> String {
+    let name = name.trim().to_lowercase().replace("-", "_");
+    match name.as_str() {
+        "greyscale" => "grayscale".into(),
+        "deepfry" | "deepfried" | "deep_fried" => "deep_fry".into(),
+        "jpeg" | "jpeg_artifacts" | "crunch" => "jpeg_crunch".into(),
+        _ => name,
+    }
+}
+
+/// Convert the filter parameter to an integer no larger than `max`.
+///
+/// Values out of that range become 0, which `Filter::is_valid` then rejects.
+fn integer(param: f64, max: u32) -> u32 {
+    if param >= 0.0 && param <= max as f64 { param as u32 } else { 0 }
+}
+
+/// Description of the valid values of the filter's parameter, for error messages.
+fn expected_param(filter: &Filter) -> String {
+    match *filter {
+        Filter::Blur(..) => format!("positive blur sigma of at most {}", MAX_FILTER_SIGMA),
+        Filter::Sharpen(..) =>
+            format!("positive sharpening sigma of at most {}", MAX_FILTER_SIGMA),
+        Filter::Saturate(..) => "non-negative saturation factor".into(),
+        Filter::Contrast(..) => "contrast percentage".into(),
+        Filter::Pixelate(..) => "positive pixel block size".into(),
+        Filter::JpegCrunch(..) => "JPEG quality between 1 and 100".into(),
+        Filter::Noise(..) => "noise intensity between 0.0 and 1.0".into(),
+        _ => "no parameter".into(),
+    }
+}
+
+
+/// Numeric parameter of a filter.
+/// Can be provided as either an actual number, or a string (e.g. in query strings).
+struct Number(f64);
+
+impl<'de> Deserialize<'de> for Number {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: de::Deserializer<'de>
+    {
+        deserializer.deserialize_any(NumberVisitor)
+    }
+}
+
+struct NumberVisitor;
+impl<'de> Visitor<'de> for NumberVisitor {
+    type Value = Number;
+
+    fn expecting(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "numeric filter parameter")
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<Self::Value, E> {
+        Ok(Number(v))
+    }
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+        Ok(Number(v as f64))
+    }
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+        Ok(Number(v as f64))
+    }
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        v.trim().parse::<f64>().map(Number)
+            .map_err(|_| E::invalid_value(Unexpected::Str(v), &self))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json::from_value as from_json;
+    use spectral::prelude::*;
+    use ::model::Filter;
+
+    #[test]
+    fn filters_by_name() {
+        assert_that!(from_json::<Filter>(json!("sepia")))
+            .is_ok().is_equal_to(Filter::Sepia);
+        assert_that!(from_json::<Filter>(json!("greyscale")))
+            .is_ok().is_equal_to(Filter::Grayscale);
+        assert_that!(from_json::<Filter>(json!("deep-fried")))
+            .is_ok().is_equal_to(Filter::DeepFry);
+        assert_that!(from_json::<Filter>(json!("pixelate")))
+            .is_ok().matches(|f| match *f { Filter::Pixelate(_) => true, _ => false });
+        assert_that!(from_json::<Filter>(json!("vaporwave"))).is_err();
+    }
+
+    #[test]
+    fn filters_with_parameters() {
+        assert_that!(from_json::<Filter>(json!({"blur": 3.5})))
+            .is_ok().is_equal_to(Filter::Blur(3.5));
+        assert_that!(from_json::<Filter>(json!({"jpeg_crunch": 5})))
+            .is_ok().is_equal_to(Filter::JpegCrunch(5));
+        assert_that!(from_json::<Filter>(json!({"noise": "0.25"})))
+            .is_ok().is_equal_to(Filter::Noise(0.25));
+    }
+
+    #[test]
+    fn invalid_parameters() {
+        assert_that!(from_json::<Filter>(json!({"blur": -1}))).is_err();
+        assert_that!(from_json::<Filter>(json!({"blur": 1e9}))).is_err();
+        assert_that!(from_json::<Filter>(json!({"sharpen": "inf"}))).is_err();
+        assert_that!(from_json::<Filter>(json!({"jpeg_crunch": 0}))).is_err();
+        assert_that!(from_json::<Filter>(json!({"noise": 2.0}))).is_err();
+        assert_that!(from_json::<Filter>(json!({"pixelate": 0}))).is_err();
+        assert_that!(from_json::<Filter>(json!({"pixelate": 0.5}))).is_err();
+        assert_that!(from_json::<Filter>(json!({"saturate": -0.5}))).is_err();
+        assert_that!(from_json::<Filter>(json!({"contrast": 1e300}))).is_err();
+        assert_that!(from_json::<Filter>(json!({"sepia": 1}))).is_err();
+    }
+}
@@ -9,14 +9,17 @@ use serde::de::{self, Deserialize, IntoDeserializer, Visitor, Unexpected};
 use unicode_normalization::UnicodeNormalization;
 use unreachable::unreachable;
 
-use super::super::{Caption, Color, ImageMacro, Size, VAlign,
+use super::super::{AnimationOptions, Caption, Color, Filter, ImageMacro, OutputOptions,
+                   Resampling, Size, Transform, VAlign,
                    DEFAULT_COLOR, DEFAULT_OUTLINE_COLOR, DEFAULT_FONT, DEFAULT_HALIGN,
-                   MAX_CAPTION_COUNT, MAX_WIDTH, MAX_HEIGHT, MAX_CAPTION_LENGTH};
+                   MAX_CAPTION_COUNT, MAX_WIDTH, MAX_HEIGHT, MAX_CAPTION_LENGTH,
+                   MAX_EFFECT_COUNT, MAX_FILTER_COUNT, MAX_TRANSFORM_COUNT};
 
 
 /// Publicly mentioned fields of ImageMacro.
 const FIELDS: &'static [&'static str] = &[
-    "template", "width", "height", "captions",
+    "template", "transforms", "width", "height", "resampling", "filters", "captions",
+    "post_filters", "effects", "animation", "output",
 ];
 /// Semi-official fields that allow to set properties of all captions at once.
 const WHOLESALE_CAPTION_FIELDS: &'static [&'static str] = &[
@@ -59,8 +62,15 @@ impl<'de> Visitor<'de> for ImageMacroVisitor {
         }
 
         let mut template = None;
+        let mut transforms = None;
         let mut width = None;
         let mut height = None;
+        let mut resampling = None;
+        let mut filters = None;
+        let mut post_filters = None;
+        let mut effects = None;
+        let mut animation = None;
+        let mut output = None;
 
         let mut simple_fields = HashSet::new();
         let mut simple_captions: HashMap<VAlign, Caption> = HashMap::new();
@@ -83,6 +93,18 @@ impl<'de> Visitor<'de> for ImageMacroVisitor {
                     }
                     template = Some(value);
                 }
+                "transforms" => {
+                    if transforms.is_some() {
+                        return Err(de::Error::duplicate_field("transforms"));
+                    }
+                    let value: Vec<Transform> = map.next_value()?;
+                    trace!("ImageMacro::transforms = {:?}", value);
+                    if value.len() > MAX_TRANSFORM_COUNT {
+                        return Err(de::Error::custom(format_args!(
+                            "there can be at most {} transformations", MAX_TRANSFORM_COUNT)));
+                    }
+                    transforms = Some(value);
+                }
                 "width" => {
                     if width.is_some() {
                         return Err(de::Error::duplicate_field("width"));
@@ -107,6 +129,70 @@ impl<'de> Visitor<'de> for ImageMacroVisitor {
                     }
                     height = Some(value);
                 }
+                "resampling" => {
+                    if resampling.is_some() {
+                        return Err(de::Error::duplicate_field("resampling"));
+                    }
+                    let value: Resampling = map.next_value()?;
+                    trace!("ImageMacro::resampling = {:?}", value);
+                    resampling = Some(value);
+                }
+                "filters" => {
+                    if filters.is_some() {
+                        return Err(de::Error::duplicate_field("filters"));
+                    }
+                    let value: Vec<Filter> = map.next_value()?;
+                    trace!("ImageMacro::filters = {:?}", value);
+                    if value.len() > MAX_FILTER_COUNT {
+                        return Err(de::Error::custom(format_args!(
+                            "there can be at most {} filters", MAX_FILTER_COUNT)));
+                    }
+                    filters = Some(value);
+                }
+                "post_filters" => {
+                    if post_filters.is_some() {
+                        return Err(de::Error::duplicate_field("post_filters"));
+                    }
+                    let value: Vec<Filter> = map.next_value()?;
+                    trace!("ImageMacro::post_filters = {:?}", value);
+                    if value.len() > MAX_FILTER_COUNT {
+                        return Err(de::Error::custom(format_args!(
+                            "there can be at most {} post-captioning filters", MAX_FILTER_COUNT)));
+                    }
+                    post_filters = Some(value);
+                }
+                "effects" => {
+                    if effects.is_some() {
+                        return Err(de::Error::duplicate_field("effects"));
+                    }
+                    let value: Vec<String> = map.next_value()?;
+                    trace!("ImageMacro::effects = {:?}", value);
+                    if value.len() > MAX_EFFECT_COUNT {
+                        return Err(de::Error::custom(format_args!(
+                            "there can be at most {} effects", MAX_EFFECT_COUNT)));
+                    }
+                    if let Some(name) = value.iter().find(|e| e.trim().is_empty()) {
+                        return Err(de::Error::invalid_value(
+                            Unexpected::Str(name), &"non-empty effect name"));
+                    }
+                    effects = Some(value);
+                }
+                "animation" => {
+                    if animation.is_some() {
+                        return Err(de::Error::duplicate_field("animation"));
+                    }
+                    let value: AnimationOptions = map.next_value()?;
+                    trace!("ImageMacro::animation = {:?}", value);
+                    animation = Some(value);
+                }
+                "output" => {
+                    if output.is_some() {
+                        return Err(de::Error::duplicate_field("output"));
+                    }
+                    let value: OutputOptions = map.next_value()?;
+                    trace!("ImageMacro::output = {:?}", value);
+                    output = Some(value);
+                }
 
                 // Simplified way of defining top/middle/bottom captions.
                 "top_text"    | "middle_text"    | "bottom_text"    |
@@ -252,7 +338,8 @@ impl<'de> Visitor<'de> for ImageMacroVisitor {
                             1 => vec![VAlign::Bottom],
                             2 => vec![VAlign::Top, VAlign::Bottom],
                             3 => vec![VAlign::Top, VAlign::Middle, VAlign::Bottom],
-                            len => return Err(de::Error::invalid_length(len, &"0, 1, 2, or 3 strings")),
+                            len => return Err(
+                                de::Error::invalid_length(len, &"0, 1, 2, or 3 strings")),
                         };
                         full_captions = Some(valigns.into_iter().zip(captions)
                             .map(|(v, c)| Caption { valign: v, ..c })
@@ -260,8 +347,8 @@ impl<'de> Visitor<'de> for ImageMacroVisitor {
                     } else {
                         let captions: Vec<_> = captions.collect();
                         if captions.len() > MAX_CAPTION_COUNT {
-                            return Err(de::Error::custom(
-                                format_args!("there can be at most {} captions", MAX_CAPTION_COUNT)));
+                            return Err(de::Error::custom(format_args!(
+                                "there can be at most {} captions", MAX_CAPTION_COUNT)));
                         }
                         full_captions = Some(captions);
                     }
@@ -300,7 +387,16 @@ impl<'de> Visitor<'de> for ImageMacroVisitor {
         }
 
         let template = template.ok_or_else(|| de::Error::missing_field("template"))?;
-        Ok(ImageMacro{template, width, height, captions})
+        let transforms = transforms.unwrap_or_else(Vec::new);
+        let filters = filters.unwrap_or_else(Vec::new);
+        let post_filters = post_filters.unwrap_or_else(Vec::new);
+        let effects = effects.unwrap_or_else(Vec::new);
+        let animation = animation.unwrap_or_default();
+        let output = output.unwrap_or_default();
+        Ok(ImageMacro{
+            template, transforms, width, height, resampling,
+            filters, captions, post_filters, effects, animation, output,
+        })
     }
 }
 
@@ -1,9 +1,13 @@
 //! Deserializers for data model types.
 
+mod animation;
 mod caption;
 mod color;
+mod filter;
 mod image_macro;
+mod output;
 mod size;
+mod transform;
 
 #[cfg(test)]
 mod tests;
@@ -0,0 +1,105 @@
+//! Deserializers for the output options.
+
+use std::fmt;
+
+use serde::de::{self, Deserialize, Visitor, Unexpected};
+
+use super::super::{OutputFormat, OutputOptions};
+
+
+const FORMATS: &'static [&'static str] = &["png", "apng", "jpeg", "gif", "webp"];
+
+
+impl<'de> Deserialize<'de> for OutputFormat {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: de::Deserializer<'de>
+    {
+        deserializer.deserialize_str(OutputFormatVisitor)
+    }
+}
+
+struct OutputFormatVisitor;
+impl<'de> Visitor<'de> for OutputFormatVisitor {
+    type Value = OutputFormat;
+
+    fn expecting(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "image format name")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        let format = match v.trim().to_lowercase().as_str() {
+            "png" => OutputFormat::Png,
+            "apng" => OutputFormat::Apng,
+            "jpeg" | "jpg" => OutputFormat::Jpeg,
+            "gif" => OutputFormat::Gif,
+            "webp" => OutputFormat::Webp,
+            _ => return Err(E::unknown_variant(v, FORMATS)),
+        };
+        Ok(format)
+    }
+}
+
+
+impl<'de> Deserialize<'de> for OutputOptions {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: de::Deserializer<'de>
+    {
+        #[derive(Default, Deserialize)]
+        #[serde(default, deny_unknown_fields)]
+        struct Options {
+            format: Option<OutputFormat>,
+            quality: Option<u8>,
+            loop_count: Option<u16>,
+            frame: Option<usize>,
+        }
+
+        let opts = Options::deserialize(deserializer)?;
+        if let Some(quality) = opts.quality {
+            if !(0 < quality && quality <= 100) {
+                return Err(de::Error::invalid_value(
+                    Unexpected::Unsigned(quality as u64), &"quality percentage between 1 and 100"));
+            }
+        }
+        trace!("OutputOptions{{format: {:?}, quality: {:?}, loop_count: {:?}, frame: {:?}}}",
+            opts.format, opts.quality, opts.loop_count, opts.frame);
+        Ok(OutputOptions{
+            format: opts.format,
+            quality: opts.quality,
+            loop_count: opts.loop_count,
+            frame: opts.frame,
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json::from_value as from_json;
+    use spectral::prelude::*;
+    use ::model::{OutputFormat, OutputOptions};
+
+    #[test]
+    fn format() {
+        assert_that!(from_json::<OutputFormat>(json!("png")))
+            .is_ok().is_equal_to(OutputFormat::Png);
+        assert_that!(from_json::<OutputFormat>(json!("apng")))
+            .is_ok().is_equal_to(OutputFormat::Apng);
+        assert_that!(from_json::<OutputFormat>(json!("JPG")))
+            .is_ok().is_equal_to(OutputFormat::Jpeg);
+        assert_that!(from_json::<OutputFormat>(json!("bmp"))).is_err();
+    }
+
+    #[test]
+    fn options() {
+        assert_that!(from_json::<OutputOptions>(json!({})))
+            .is_ok().is_equal_to(OutputOptions::default());
+        assert_that!(from_json::<OutputOptions>(json!({"format": "gif", "loop_count": 3})))
+            .is_ok().is_equal_to(OutputOptions{
+                format: Some(OutputFormat::Gif),
+                loop_count: Some(3),
+                ..OutputOptions::default()
+            });
+        assert_that!(from_json::<OutputOptions>(json!({"quality": 0}))).is_err();
+        assert_that!(from_json::<OutputOptions>(json!({"speed": 11}))).is_err();
+    }
+}
@@ -3,7 +3,7 @@
 use serde_json::{self, from_value as from_json, Value};
 use spectral::prelude::*;
 
-use model::{Caption, Color, HAlign, ImageMacro, VAlign};
+use model::{Caption, Color, Filter, HAlign, ImageMacro, Resampling, Transform, VAlign};
 
 
 #[test]
@@ -32,6 +32,97 @@ fn scaled_template() {
     assert_that!(parse(input)).is_ok().is_equal_to(expected);
 }
 
+#[test]
+fn scaled_template_with_resampling() {
+    let input = json!({
+        "template": "pixelart",
+        "width": 640,
+        "resampling": "nearest",
+    });
+    let expected = ImageMacro{
+        template: "pixelart".into(),
+        width: Some(640),
+        resampling: Some(Resampling::Nearest),
+        ..Default::default()
+    };
+    assert_that!(parse(input)).is_ok().is_equal_to(expected);
+}
+
+#[test]
+fn invalid_resampling() {
+    let input = json!({
+        "template": "pixelart",
+        "resampling": "bestest",
+    });
+    assert_that!(parse(input)).is_err();
+}
+
+#[test]
+fn transformed_template() {
+    let input = json!({
+        "template": "zoidberg",
+        "transforms": [
+            {"crop": [0, 0, 200, 100]},
+            "flip_horizontal",
+            {"rotate": 180},
+        ],
+    });
+    let expected = ImageMacro{
+        template: "zoidberg".into(),
+        transforms: vec![
+            Transform::Crop{left: 0, top: 0, width: 200, height: 100},
+            Transform::FlipHorizontal,
+            Transform::Rotate180,
+        ],
+        ..Default::default()
+    };
+    assert_that!(parse(input)).is_ok().is_equal_to(expected);
+}
+
+#[test]
+fn filtered_template() {
+    let input = json!({
+        "template": "zoidberg",
+        "filters": ["grayscale", {"blur": 1.5}],
+        "bottom_text": "Why not?",
+        "post_filters": ["deep_fry"],
+    });
+    let result = parse(input);
+    assert_that!(result).is_ok();
+    let im = result.unwrap();
+    assert_that!(im.filters).is_equal_to(vec![Filter::Grayscale, Filter::Blur(1.5)]);
+    assert_that!(im.post_filters).is_equal_to(vec![Filter::DeepFry]);
+}
+
+#[test]
+fn too_many_filters() {
+    let input = json!({
+        "template": "zoidberg",
+        "filters": vec!["sepia"; 64],
+    });
+    assert_that!(parse(input)).is_err();
+}
+
+#[test]
+fn template_with_effects() {
+    let input = json!({
+        "template": "zoidberg",
+        "effects": ["glitch", "vhs"],
+    });
+    let expected = ImageMacro{
+        template: "zoidberg".into(),
+        effects: vec!["glitch".into(), "vhs".into()],
+        ..Default::default()
+    };
+    assert_that!(parse(input)).is_ok().is_equal_to(expected);
+}
+
+#[test]
+fn empty_effect_name() {
+    let input = json!({"template": "zoidberg", "effects": ["glitch", "  "]});
+    assert_that!(parse(input)).is_err();
+}
+
 #[test]
 fn one_simple_caption() {
     let input = json!({
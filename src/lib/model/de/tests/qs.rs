@@ -3,7 +3,7 @@
 use serde_qs::{self, from_str as from_qs};
 use spectral::prelude::*;
 
-use model::{Caption, Color, ImageMacro, VAlign};
+use model::{AnimationOptions, Caption, Color, ImageMacro, OutputFormat, OutputOptions, VAlign};
 
 
 #[test]
@@ -51,6 +51,40 @@ fn caption_text_with_ampersand() {
     assert_that!(parse(input)).is_ok();
 }
 
+#[test]
+fn output_options() {
+    let input = "template=zoidberg&output[format]=jpg&output[quality]=40";
+    let expected = ImageMacro{
+        template: "zoidberg".into(),
+        output: OutputOptions{
+            format: Some(OutputFormat::Jpeg),
+            quality: Some(40),
+            ..OutputOptions::default()
+        },
+        ..Default::default()
+    };
+    assert_that!(parse(input)).is_ok().is_equal_to(expected);
+}
+
+#[test]
+fn animation_options() {
+    let input = "template=smart&\
+        animation[speed]=1.5&animation[reverse]=true&animation[end_frame]=10&\
+        animation[loop_count]=3";
+    let expected = ImageMacro{
+        template: "smart".into(),
+        animation: AnimationOptions{
+            speed: Some(1.5),
+            reverse: true,
+            end_frame: Some(10),
+            loop_count: Some(3),
+            ..AnimationOptions::default()
+        },
+        ..Default::default()
+    };
+    assert_that!(parse(input)).is_ok().is_equal_to(expected);
+}
+
 
 // Common test data
 
@@ -0,0 +1,207 @@
+//! Deserializer for the Transform type.
+
+use std::fmt;
+
+use serde::de::{self, Deserialize, Visitor, Unexpected};
+
+use super::super::{Color, Transform};
+
+
+/// Names of transformations that don't take any parameters.
+const SIMPLE_TRANSFORMS: &'static [&'static str] = &[
+    "flip_horizontal", "flip_vertical", "rotate90", "rotate180", "rotate270",
+];
+/// Names of transformations that must be given as single-key maps.
+const PARAMETRIZED_TRANSFORMS: &'static [&'static str] = &["crop", "rotate"];
+
+const EXPECTING_MSG: &'static str = "transformation name or single-key map with its parameters";
+
+
+impl<'de> Deserialize<'de> for Transform {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: de::Deserializer<'de>
+    {
+        deserializer.deserialize_any(TransformVisitor)
+    }
+}
+
+struct TransformVisitor;
+impl<'de> Visitor<'de> for TransformVisitor {
+    type Value = Transform;
+
+    fn expecting(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}", EXPECTING_MSG)
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        let transform = match v.trim().to_lowercase().as_str() {
+            "flip_horizontal" | "flip_h" | "fliph" | "mirror" => Transform::FlipHorizontal,
+            "flip_vertical" | "flip_v" | "flipv" => Transform::FlipVertical,
+            "rotate90" | "rotate_90" => Transform::Rotate90,
+            "rotate180" | "rotate_180" => Transform::Rotate180,
+            "rotate270" | "rotate_270" => Transform::Rotate270,
+            _ => return Err(E::unknown_variant(v, SIMPLE_TRANSFORMS)),
+        };
+        Ok(transform)
+    }
+
+    fn visit_map<V>(self, mut map: V) -> Result<Self::Value, V::Error>
+        where V: de::MapAccess<'de>
+    {
+        if let Some(size) = map.size_hint() {
+            if size != 1 {
+                return Err(de::Error::invalid_length(size, &"1"));
+            }
+        }
+
+        let key = map.next_key::<String>()?
+            .ok_or_else(|| de::Error::invalid_length(0, &"1"))?;
+        let transform = match key.trim().to_lowercase().as_str() {
+            "crop" => {
+                let rect: CropRect = map.next_value()?;
+                if rect.width == 0 || rect.height == 0 {
+                    return Err(de::Error::custom(format_args!(
+                        "crop rectangle cannot be empty (got {}x{})", rect.width, rect.height)));
+                }
+                trace!("Transform::Crop = {:?}", rect);
+                Transform::Crop{
+                    left: rect.left, top: rect.top,
+                    width: rect.width, height: rect.height,
+                }
+            }
+            "rotate" => {
+                let Rotation(degrees, background) = map.next_value()?;
+                if !degrees.is_finite() {
+                    return Err(de::Error::invalid_value(
+                        Unexpected::Float(degrees as f64), &"finite angle in degrees"));
+                }
+                trace!("Transform::Rotate = {}deg", degrees);
+                Transform::rotate(degrees, background)
+            }
+            key => return Err(de::Error::unknown_variant(key, PARAMETRIZED_TRANSFORMS)),
+        };
+
+        if map.next_key::<String>()?.is_some() {
+            return Err(de::Error::custom("transformation must be a single-key map"));
+        }
+        Ok(transform)
+    }
+}
+
+
+/// Parameters of the crop transformation.
+/// Can be provided as either a map or a sequence of four numbers.
+#[derive(Debug, Deserialize)]
+struct CropRect {
+    left: u32,
+    top: u32,
+    width: u32,
+    height: u32,
+}
+
+
+/// Parameters of the arbitrary rotation.
+/// Can be provided as just a number of degrees, or a map with optional background color.
+struct Rotation(f32, Option<Color>);
+
+impl<'de> Deserialize<'de> for Rotation {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: de::Deserializer<'de>
+    {
+        deserializer.deserialize_any(RotationVisitor)
+    }
+}
+
+struct RotationVisitor;
+impl<'de> Visitor<'de> for RotationVisitor {
+    type Value = Rotation;
+
+    fn expecting(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "angle in degrees or map with `degrees` and `background`")
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<Self::Value, E> {
+        Ok(Rotation(v as f32, None))
+    }
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+        self.visit_f64(v as f64)
+    }
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+        self.visit_f64(v as f64)
+    }
+
+    // Query strings give us everything as strings.
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        let degrees = v.trim().parse::<f32>()
+            .map_err(|_| E::invalid_value(Unexpected::Str(v), &self))?;
+        Ok(Rotation(degrees, None))
+    }
+
+    fn visit_map<V>(self, map: V) -> Result<Self::Value, V::Error>
+        where V: de::MapAccess<'de>
+    {
+        #[derive(Deserialize)]
+        struct RotationSpec {
+            degrees: f32,
+            #[serde(default)]
+            background: Option<Color>,
+        }
+
+        let inner_de = de::value::MapAccessDeserializer::new(map);
+        let spec = RotationSpec::deserialize(inner_de)?;
+        Ok(Rotation(spec.degrees, spec.background))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json::from_value as from_json;
+    use spectral::prelude::*;
+    use ::model::{Color, Transform};
+
+    #[test]
+    fn simple_transforms() {
+        assert_that!(from_json::<Transform>(json!("mirror")))
+            .is_ok().is_equal_to(Transform::FlipHorizontal);
+        assert_that!(from_json::<Transform>(json!("flip_vertical")))
+            .is_ok().is_equal_to(Transform::FlipVertical);
+        assert_that!(from_json::<Transform>(json!("rotate270")))
+            .is_ok().is_equal_to(Transform::Rotate270);
+        assert_that!(from_json::<Transform>(json!("teleport"))).is_err();
+    }
+
+    #[test]
+    fn crop() {
+        let expected = Transform::Crop{left: 10, top: 20, width: 100, height: 50};
+        assert_that!(from_json::<Transform>(json!({"crop": [10, 20, 100, 50]})))
+            .is_ok().is_equal_to(expected);
+        assert_that!(from_json::<Transform>(json!({
+            "crop": {"left": 10, "top": 20, "width": 100, "height": 50}
+        }))).is_ok().is_equal_to(expected);
+        // Empty rectangle is not allowed.
+        assert_that!(from_json::<Transform>(json!({"crop": [10, 20, 0, 50]}))).is_err();
+    }
+
+    #[test]
+    fn rotate() {
+        assert_that!(from_json::<Transform>(json!({"rotate": 90})))
+            .is_ok().is_equal_to(Transform::Rotate90);
+        assert_that!(from_json::<Transform>(json!({"rotate": 30.5})))
+            .is_ok().is_equal_to(Transform::Rotate{degrees: 30.5, background: None});
+        assert_that!(from_json::<Transform>(json!({
+            "rotate": {"degrees": 45, "background": "white"}
+        }))).is_ok()
+            .is_equal_to(Transform::Rotate{degrees: 45.0, background: Some(Color::white())});
+        // Angle has to be finite (query strings can spell out any float).
+        assert_that!(from_json::<Transform>(json!({"rotate": "NaN"}))).is_err();
+        assert_that!(from_json::<Transform>(json!({"rotate": "inf"}))).is_err();
+    }
+
+    #[test]
+    fn must_be_single_key_map() {
+        assert_that!(from_json::<Transform>(json!({}))).is_err();
+        assert_that!(from_json::<Transform>(json!({"rotate": 45, "crop": [0, 0, 1, 1]})))
+            .is_err();
+    }
+}
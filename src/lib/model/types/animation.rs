@@ -0,0 +1,97 @@
+//! Module defining the animation options of an image macro.
+
+use std::fmt;
+
+use float_ord::FloatOrd;
+
+
+/// Options changing the playback of an animated template.
+///
+/// They are applied to the template's frames before those are captioned,
+/// and have no effect if the template is a still image.
+#[derive(Clone, Copy, Default)]
+pub struct AnimationOptions {
+    /// Playback speed multiplier, e.g. 2.0 for an animation that plays twice as fast.
+    ///
+    /// If omitted, the animation plays at its original speed.
+    pub speed: Option<f32>,
+    /// Whether the animation should be played backwards.
+    pub reverse: bool,
+    /// Whether the animation should be played forwards and then backwards ("ping-pong").
+    ///
+    /// This is applied after `reverse`, so the two can be combined
+    /// for an animation that starts by playing backwards.
+    pub boomerang: bool,
+    /// Index of the first frame of the template to include.
+    pub start_frame: Option<usize>,
+    /// Index of the frame *after* the last one to include.
+    pub end_frame: Option<usize>,
+    /// Whether frame delays should be normalized the way web browsers treat them.
+    ///
+    /// Browsers play frames shorter than 20 milliseconds (including those with
+    /// no delay at all) for 100 milliseconds instead. With this option,
+    /// such delays of the template are changed to 100ms upfront,
+    /// and sped up frames are never made shorter than 20ms,
+    /// so that the animation plays at the same speed everywhere.
+    pub normalize_delays: bool,
+    /// How many times the animation should be repeated after it's played once.
+    ///
+    /// This is an alias of `OutputOptions::loop_count`, which takes precedence if both are set.
+    pub loop_count: Option<u16>,
+}
+
+impl AnimationOptions {
+    /// Whether all the options are at their defaults,
+    /// i.e. the animation is played exactly like the template.
+    #[inline]
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Whether the options change which frames are played, or for how long.
+    #[inline]
+    pub fn rearranges_frames(&self) -> bool {
+        AnimationOptions{loop_count: None, ..*self} != Self::default()
+    }
+}
+
+impl PartialEq for AnimationOptions {
+    fn eq(&self, other: &Self) -> bool {
+        self.speed.map(FloatOrd) == other.speed.map(FloatOrd) &&
+        self.reverse == other.reverse &&
+        self.boomerang == other.boomerang &&
+        self.start_frame == other.start_frame &&
+        self.end_frame == other.end_frame &&
+        self.normalize_delays == other.normalize_delays &&
+        self.loop_count == other.loop_count
+    }
+}
+impl Eq for AnimationOptions {}
+
+impl fmt::Debug for AnimationOptions {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let mut ds = fmt.debug_struct("AnimationOptions");
+        if let Some(speed) = self.speed {
+            ds.field("speed", &format_args!("{}x", speed));
+        }
+        if self.reverse {
+            ds.field("reverse", &self.reverse);
+        }
+        if self.boomerang {
+            ds.field("boomerang", &self.boomerang);
+        }
+        if let Some(ref start) = self.start_frame {
+            ds.field("start_frame", start);
+        }
+        if let Some(ref end) = self.end_frame {
+            ds.field("end_frame", end);
+        }
+        if self.normalize_delays {
+            ds.field("normalize_delays", &self.normalize_delays);
+        }
+        if let Some(ref count) = self.loop_count {
+            ds.field("loop_count", count);
+        }
+        ds.finish()
+    }
+}
@@ -0,0 +1,121 @@
+//! Module defining the image filter enum.
+
+use std::fmt;
+
+use float_ord::FloatOrd;
+
+use model::constants::MAX_FILTER_SIGMA;
+
+
+/// Image filter that can be applied to the image macro.
+///
+/// Filters can be applied either to the template (before captioning),
+/// or to the final image (after captioning).
+#[derive(Clone, Copy)]
+pub enum Filter {
+    /// Convert the image to shades of gray.
+    Grayscale,
+    /// Give the image an old-timey brownish tint.
+    Sepia,
+    /// Invert the image colors.
+    Invert,
+    /// Gaussian blur with given sigma (in pixels).
+    Blur(f32),
+    /// Sharpen the image (using unsharp masking) with given sigma (in pixels).
+    Sharpen(f32),
+    /// Multiply the color saturation by given factor.
+    ///
+    /// Values above 1.0 make the colors more vivid,
+    /// whereas those below 1.0 wash them out.
+    Saturate(f32),
+    /// Change the contrast by given percentage.
+    ///
+    /// Positive values increase the contrast, negative ones decrease it.
+    Contrast(f32),
+    /// Pixelate the image into square blocks of given size (in pixels).
+    Pixelate(u32),
+    /// Introduce JPEG compression artifacts by encoding the image
+    /// with given (presumably very low) JPEG quality percentage.
+    JpegCrunch(u8),
+    /// Add monochrome noise of given intensity (from 0.0 to 1.0).
+    Noise(f32),
+    /// The "deep-fried" meme preset.
+    ///
+    /// This is a combination of several other filters;
+    /// see `Filter::deep_fry` for details.
+    DeepFry,
+}
+
+impl Filter {
+    /// The sequence of filters that the `DeepFry` preset is made of.
+    pub fn deep_fry() -> &'static [Filter] {
+        const DEEP_FRY: &'static [Filter] = &[
+            Filter::Saturate(3.0),
+            Filter::Contrast(60.0),
+            Filter::Sharpen(2.0),
+            Filter::Noise(0.12),
+            Filter::JpegCrunch(8),
+        ];
+        DEEP_FRY
+    }
+
+    /// Whether the parameter of this filter makes sense,
+    /// e.g. the blur sigma is positive (up to `MAX_FILTER_SIGMA`)
+    /// and the JPEG quality is a percentage.
+    pub fn is_valid(&self) -> bool {
+        match *self {
+            Filter::Blur(s) | Filter::Sharpen(s) => s > 0.0 && s <= MAX_FILTER_SIGMA,
+            Filter::Saturate(f) => f.is_finite() && f >= 0.0,
+            Filter::Contrast(c) => c.is_finite(),
+            Filter::Pixelate(b) => b > 0,
+            Filter::JpegCrunch(q) => q > 0 && q <= 100,
+            Filter::Noise(n) => n >= 0.0 && n <= 1.0,
+            _ => true,
+        }
+    }
+
+    /// Whether this filter is a preset composed of other filters.
+    #[inline]
+    pub fn is_preset(&self) -> bool {
+        match *self { Filter::DeepFry => true, _ => false }
+    }
+}
+
+impl PartialEq for Filter {
+    fn eq(&self, other: &Self) -> bool {
+        use self::Filter::*;
+        match (*self, *other) {
+            (Grayscale, Grayscale) => true,
+            (Sepia, Sepia) => true,
+            (Invert, Invert) => true,
+            (Blur(a), Blur(b)) => FloatOrd(a).eq(&FloatOrd(b)),
+            (Sharpen(a), Sharpen(b)) => FloatOrd(a).eq(&FloatOrd(b)),
+            (Saturate(a), Saturate(b)) => FloatOrd(a).eq(&FloatOrd(b)),
+            (Contrast(a), Contrast(b)) => FloatOrd(a).eq(&FloatOrd(b)),
+            (Pixelate(a), Pixelate(b)) => a == b,
+            (JpegCrunch(a), JpegCrunch(b)) => a == b,
+            (Noise(a), Noise(b)) => FloatOrd(a).eq(&FloatOrd(b)),
+            (DeepFry, DeepFry) => true,
+            _ => false,
+        }
+    }
+}
+impl Eq for Filter {}
+
+impl fmt::Debug for Filter {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Filter::Grayscale => write!(fmt, "Grayscale"),
+            Filter::Sepia => write!(fmt, "Sepia"),
+            Filter::Invert => write!(fmt, "Invert"),
+            Filter::Blur(s) => write!(fmt, "Blur({})", s),
+            Filter::Sharpen(s) => write!(fmt, "Sharpen({})", s),
+            Filter::Saturate(f) => write!(fmt, "Saturate({})", f),
+            Filter::Contrast(c) => write!(fmt, "Contrast({})", c),
+            Filter::Pixelate(b) => write!(fmt, "Pixelate({})", b),
+            Filter::JpegCrunch(q) => write!(fmt, "JpegCrunch({}%)", q),
+            Filter::Noise(n) => write!(fmt, "Noise({})", n),
+            Filter::DeepFry => write!(fmt, "DeepFry"),
+        }
+    }
+}
@@ -3,9 +3,17 @@
 use std::error;
 use std::fmt;
 
-use model::constants::{MAX_CAPTION_COUNT, MAX_CAPTION_LENGTH, MAX_HEIGHT, MAX_WIDTH};
+use model::constants::{MAX_CAPTION_COUNT, MAX_CAPTION_LENGTH, MAX_HEIGHT, MAX_WIDTH,
+                       MAX_EFFECT_COUNT, MAX_FILTER_COUNT,
+                       MAX_TRANSFORM_COUNT,
+                       MIN_ANIMATION_SPEED, MAX_ANIMATION_SPEED};
 use super::align::{HAlign, VAlign};
+use super::animation::AnimationOptions;
 use super::caption::Caption;
+use super::filter::Filter;
+use super::output::{OutputFormat, OutputOptions};
+use super::resampling::Resampling;
+use super::transform::Transform;
 
 
 /// Describes an image macro. Used as an input structure.
@@ -17,12 +25,31 @@ use super::caption::Caption;
 pub struct ImageMacro {
     /// Name of the template used by this image macro.
     pub template: String,
+    /// Transformations to apply to the template before it's resized & captioned.
+    pub transforms: Vec<Transform>,
     /// Width of the rendered macro (if it is to be different from the template).
     pub width: Option<u32>,
     /// Height of the rendered macro (if it is to be different from the template).
     pub height: Option<u32>,
+    /// Filter to use when resizing the template.
+    ///
+    /// If omitted, the `Engine`'s configured default will be used.
+    pub resampling: Option<Resampling>,
+    /// Filters to apply to the (resized) template before it's captioned.
+    pub filters: Vec<Filter>,
     /// Text captions to render over the template.
     pub captions: Vec<Caption>,
+    /// Filters to apply to the final image, after it's been captioned.
+    pub post_filters: Vec<Filter>,
+    /// Names of custom effects (registered with the `Engine`) to apply to the image macro.
+    ///
+    /// Each effect runs at the stage of the captioning pipeline that it has chosen,
+    /// in the order they are listed here.
+    pub effects: Vec<String>,
+    /// Options changing the playback of an animated template (speed, direction, etc.).
+    pub animation: AnimationOptions,
+    /// Options for encoding the rendered image macro (format, quality, etc.).
+    pub output: OutputOptions,
 }
 
 impl ImageMacro {
@@ -38,8 +65,15 @@ impl PartialEq<ImageMacro> for ImageMacro {
     /// This is implemented not to take the order of Captions into account.
     fn eq(&self, other: &Self) -> bool {
         self.template == other.template &&
+        self.transforms == other.transforms &&
         self.width == other.width &&
         self.height == other.height &&
+        self.resampling == other.resampling &&
+        self.filters == other.filters &&
+        self.post_filters == other.post_filters &&
+        self.effects == other.effects &&
+        self.animation == other.animation &&
+        self.output == other.output &&
         // O(n^2), I know.
         self.captions.iter().all(|c1| other.captions.iter().any(|c2| c1 == c2))
         // TODO: consider implementing captions as HashSet for this reason
@@ -50,6 +84,9 @@ impl fmt::Debug for ImageMacro {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         let mut ds = fmt.debug_struct("ImageMacro");
         ds.field("template", &self.template);
+        if self.transforms.len() > 0 {
+            ds.field("transforms", &self.transforms);
+        }
 
         macro_rules! fmt_opt_field {
             ($name:ident) => (
@@ -60,10 +97,26 @@ impl fmt::Debug for ImageMacro {
         }
         fmt_opt_field!(width);
         fmt_opt_field!(height);
+        fmt_opt_field!(resampling);
 
+        if self.filters.len() > 0 {
+            ds.field("filters", &self.filters);
+        }
         if self.captions.len() > 0 {
             ds.field("captions", &self.captions);
         }
+        if self.post_filters.len() > 0 {
+            ds.field("post_filters", &self.post_filters);
+        }
+        if self.effects.len() > 0 {
+            ds.field("effects", &self.effects);
+        }
+        if !self.animation.is_default() {
+            ds.field("animation", &self.animation);
+        }
+        if !self.output.is_default() {
+            ds.field("output", &self.output);
+        }
 
         ds.finish()
     }
@@ -75,9 +128,16 @@ impl fmt::Debug for ImageMacro {
 #[must_use = "unused builder which must be used"]
 pub struct Builder {
     template: Option<String>,
+    transforms: Vec<Transform>,
     width: Option<u32>,
     height: Option<u32>,
+    resampling: Option<Resampling>,
+    filters: Vec<Filter>,
     captions: Vec<Caption>,
+    post_filters: Vec<Filter>,
+    effects: Vec<String>,
+    animation: AnimationOptions,
+    output: OutputOptions,
 }
 
 impl Builder {
@@ -95,6 +155,15 @@ impl Builder {
         self.template = Some(template.into()); self
     }
 
+    /// Add a transformation to be applied to the template.
+    ///
+    /// Transformations are applied in the order they've been added,
+    /// before the template is resized to the `ImageMacro` dimensions.
+    #[inline]
+    pub fn transform(mut self, transform: Transform) -> Self {
+        self.transforms.push(transform); self
+    }
+
     /// Change the width of the resulting image macro.
     ///
     /// Note that any resizing done during rendering of the result `ImageMacro`
@@ -130,6 +199,138 @@ impl Builder {
     pub fn clear_height(mut self) -> Self {
         self.height = None; self
     }
+
+    /// Change the filter used to resize the template, should it be necessary.
+    ///
+    /// By default, the filter configured for the `Engine` will be used.
+    #[inline]
+    pub fn resampling(mut self, resampling: Resampling) -> Self {
+        self.resampling = Some(resampling); self
+    }
+
+    /// Reset the resampling filter back to the `Engine`'s default.
+    #[inline]
+    pub fn clear_resampling(mut self) -> Self {
+        self.resampling = None; self
+    }
+}
+
+// Filtering interface.
+impl Builder {
+    /// Add a filter to be applied to the template before it's captioned.
+    ///
+    /// Filters are applied in the order they've been added,
+    /// after the template has been transformed and resized.
+    #[inline]
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.filters.push(filter); self
+    }
+
+    /// Add a filter to be applied to the final image, after it's been captioned.
+    ///
+    /// Unlike those added with `filter`, these will also affect the caption text.
+    #[inline]
+    pub fn post_filter(mut self, filter: Filter) -> Self {
+        self.post_filters.push(filter); self
+    }
+
+    /// Apply the "deep-fried" preset to the whole image macro, including the captions.
+    #[inline]
+    pub fn deep_fry(self) -> Self {
+        self.post_filter(Filter::DeepFry)
+    }
+}
+
+// Custom effects.
+impl Builder {
+    /// Add a custom effect (registered with the `Engine` under given name)
+    /// to be applied to the resulting `ImageMacro`.
+    #[inline]
+    pub fn effect<S: Into<String>>(mut self, name: S) -> Self {
+        self.effects.push(name.into()); self
+    }
+}
+
+// Animation options.
+impl Builder {
+    /// Set all the animation options of the resulting `ImageMacro` at once.
+    #[inline]
+    pub fn animation(mut self, animation: AnimationOptions) -> Self {
+        self.animation = animation; self
+    }
+
+    /// Change the playback speed of an animated template by given factor.
+    #[inline]
+    pub fn speed(mut self, speed: f32) -> Self {
+        self.animation.speed = Some(speed); self
+    }
+
+    /// Play the animated template backwards.
+    #[inline]
+    pub fn reverse(mut self) -> Self {
+        self.animation.reverse = true; self
+    }
+
+    /// Play the animated template forwards and then backwards.
+    #[inline]
+    pub fn boomerang(mut self) -> Self {
+        self.animation.boomerang = true; self
+    }
+
+    /// Only use given range of frames (`start` inclusive, `end` exclusive)
+    /// of an animated template.
+    #[inline]
+    pub fn frame_range(mut self, start: usize, end: usize) -> Self {
+        self.animation.start_frame = Some(start);
+        self.animation.end_frame = Some(end);
+        self
+    }
+
+    /// Normalize the frame delays of an animated template
+    /// so that it plays at the same speed in all web browsers.
+    #[inline]
+    pub fn normalize_delays(mut self) -> Self {
+        self.animation.normalize_delays = true; self
+    }
+}
+
+// Output options.
+impl Builder {
+    /// Set all the output options of the resulting `ImageMacro` at once.
+    #[inline]
+    pub fn output(mut self, output: OutputOptions) -> Self {
+        self.output = output; self
+    }
+
+    /// Change the image format of the rendered `ImageMacro`.
+    ///
+    /// By default, the format is chosen based on the template.
+    #[inline]
+    pub fn format(mut self, format: OutputFormat) -> Self {
+        self.output.format = Some(format); self
+    }
+
+    /// Change the quality percentage of the rendered JPEG or GIF.
+    ///
+    /// By default, the quality configured for the `Engine` will be used.
+    #[inline]
+    pub fn quality(mut self, quality: u8) -> Self {
+        self.output.quality = Some(quality); self
+    }
+
+    /// Set how many times the resulting GIF animation is repeated after it's played once.
+    ///
+    /// By default, animations loop forever.
+    #[inline]
+    pub fn loop_count(mut self, count: u16) -> Self {
+        self.output.loop_count = Some(count); self
+    }
+
+    /// Render just a single frame (of given index) of an animated template.
+    #[inline]
+    pub fn frame(mut self, index: usize) -> Self {
+        self.output.frame = Some(index); self
+    }
 }
 
 // Captioning interface.
@@ -166,9 +367,16 @@ impl Builder {
         self.validate()?;
         Ok(ImageMacro{
             template: self.template.unwrap(),
+            transforms: self.transforms,
             width: self.width,
             height: self.height,
+            resampling: self.resampling,
+            filters: self.filters,
             captions: self.captions,
+            post_filters: self.post_filters,
+            effects: self.effects,
+            animation: self.animation,
+            output: self.output,
         })
     }
 
@@ -178,6 +386,46 @@ impl Builder {
             return Err(Error::NoTemplate);
         }
 
+        if self.transforms.len() > MAX_TRANSFORM_COUNT {
+            return Err(Error::TooManyTransforms(self.transforms.len()));
+        }
+        if let Some(t) = self.transforms.iter().find(|t| !t.is_valid()) {
+            return Err(Error::InvalidTransform(*t));
+        }
+
+        for filters in &[&self.filters, &self.post_filters] {
+            if filters.len() > MAX_FILTER_COUNT {
+                return Err(Error::TooManyFilters(filters.len()));
+            }
+            if let Some(f) = filters.iter().find(|f| !f.is_valid()) {
+                return Err(Error::InvalidFilter(*f));
+            }
+        }
+
+        if self.effects.len() > MAX_EFFECT_COUNT {
+            return Err(Error::TooManyEffects(self.effects.len()));
+        }
+        if self.effects.iter().any(|e| e.trim().is_empty()) {
+            return Err(Error::EmptyEffectName);
+        }
+
+        if let Some(speed) = self.animation.speed {
+            if !(MIN_ANIMATION_SPEED <= speed && speed <= MAX_ANIMATION_SPEED) {
+                return Err(Error::InvalidSpeed(speed));
+            }
+        }
+        if let (Some(start), Some(end)) = (self.animation.start_frame, self.animation.end_frame) {
+            if start >= end {
+                return Err(Error::InvalidFrameRange(start, end));
+            }
+        }
+
+        if let Some(quality) = self.output.quality {
+            if !(0 < quality && quality <= 100) {
+                return Err(Error::InvalidQuality(quality));
+            }
+        }
+
         let width = self.width.unwrap_or(0);
         let height = self.height.unwrap_or(0);
         if !(width <= MAX_WIDTH && height <= MAX_HEIGHT) {
@@ -203,6 +451,24 @@ impl Builder {
 pub enum Error {
     /// No template given.
     NoTemplate,
+    /// Too many template transformations.
+    TooManyTransforms(usize),
+    /// Template transformation with invalid parameters.
+    InvalidTransform(Transform),
+    /// Too many image filters.
+    TooManyFilters(usize),
+    /// Image filter with invalid parameters.
+    InvalidFilter(Filter),
+    /// Too many custom effects.
+    TooManyEffects(usize),
+    /// Custom effect with an empty name.
+    EmptyEffectName,
+    /// Animation speed multiplier outside of the allowed range.
+    InvalidSpeed(f32),
+    /// Empty range of animation frames.
+    InvalidFrameRange(usize, usize),
+    /// Output quality outside of the 1-100 range.
+    InvalidQuality(u8),
     /// Requested image size is too large.
     TooLarge(Option<u32>, Option<u32>),
     /// Too many captions.
@@ -220,6 +486,19 @@ impl fmt::Display for Error {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             Error::NoTemplate => write!(fmt, "no template chosen"),
+            Error::TooManyTransforms(c) =>
+                write!(fmt, "too many transformations: {} > {}", c, MAX_TRANSFORM_COUNT),
+            Error::InvalidTransform(ref t) => write!(fmt, "invalid transformation: {:?}", t),
+            Error::TooManyFilters(c) =>
+                write!(fmt, "too many filters: {} > {}", c, MAX_FILTER_COUNT),
+            Error::InvalidFilter(ref f) => write!(fmt, "invalid filter: {:?}", f),
+            Error::TooManyEffects(c) =>
+                write!(fmt, "too many effects: {} > {}", c, MAX_EFFECT_COUNT),
+            Error::EmptyEffectName => write!(fmt, "effect name cannot be empty"),
+            Error::InvalidSpeed(s) => write!(fmt, "invalid animation speed: {}x", s),
+            Error::InvalidFrameRange(s, e) =>
+                write!(fmt, "invalid range of animation frames: {}..{}", s, e),
+            Error::InvalidQuality(q) => write!(fmt, "invalid output quality: {}%", q),
             Error::TooLarge(w, h) => write!(fmt, "target image too large: {}x{} > {}x{}",
                 w.map(|w| format!("{}", w)).as_ref().map(|s| s.as_str()).unwrap_or("(default)"),
                 h.map(|h| format!("{}", h)).as_ref().map(|s| s.as_str()).unwrap_or("(default)"),
@@ -231,3 +510,40 @@ impl fmt::Display for Error {
         }
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use std::f32;
+    use model::{Filter, ImageMacroBuilder, Transform};
+    use super::Error;
+
+    #[test]
+    fn invalid_transforms() {
+        let build = |t| ImageMacroBuilder::new().template("zoidberg").transform(t).build();
+        assert!(build(Transform::Crop{left: 0, top: 0, width: 10, height: 10}).is_ok());
+        for &t in &[Transform::Crop{left: 0, top: 0, width: 10, height: 0},
+                    Transform::rotate(f32::NAN, None),
+                    Transform::rotate(f32::NEG_INFINITY, None)] {
+            match build(t) {
+                Err(Error::InvalidTransform(_)) => {}
+                r => panic!("unexpected result for {:?}: {:?}", t, r),
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_filters() {
+        let build = |f| ImageMacroBuilder::new().template("zoidberg").filter(f).build();
+        assert!(build(Filter::Blur(2.0)).is_ok());
+        for &f in &[Filter::Blur(0.0), Filter::Sharpen(1e6), Filter::Blur(f32::NAN),
+                    Filter::JpegCrunch(0), Filter::JpegCrunch(101), Filter::Pixelate(0),
+                    Filter::Saturate(-1.0), Filter::Contrast(f32::INFINITY),
+                    Filter::Noise(1.5)] {
+            match build(f) {
+                Err(Error::InvalidFilter(_)) => {}
+                r => panic!("unexpected result for {:?}: {:?}", f, r),
+            }
+        }
+    }
+}
@@ -1,17 +1,27 @@
 //! Module defining the model types.
 
 mod align;
+mod animation;
 mod caption;
 mod color;
+mod filter;
 mod image_macro;
+mod output;
+mod resampling;
 mod size;
+mod transform;
 
 pub use self::align::{HAlign, VAlign};
+pub use self::animation::AnimationOptions;
 pub use self::caption::{Caption,
                         CaptionBuilder,
                         Error as CaptionBuildError};
 pub use self::color::Color;
+pub use self::filter::Filter;
 pub use self::image_macro::{ImageMacro,
                             Builder as ImageMacroBuilder,
                             Error as ImageMacroBuildError};
+pub use self::output::{OutputFormat, OutputOptions};
+pub use self::resampling::Resampling;
 pub use self::size::Size;
+pub use self::transform::Transform;
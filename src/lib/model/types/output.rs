@@ -0,0 +1,85 @@
+//! Module defining the output options of an image macro.
+
+use image::ImageFormat;
+
+
+/// Image format that an image macro can be rendered as.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum OutputFormat {
+    /// PNG image.
+    ///
+    /// Animated templates are rendered as a still image of a single frame;
+    /// use `Apng` for an animated PNG.
+    Png,
+    /// Animated PNG (APNG).
+    ///
+    /// Still templates result in a regular PNG image.
+    Apng,
+    /// JPEG image.
+    Jpeg,
+    /// GIF image or animation.
+    Gif,
+    /// WebP image or animation.
+    ///
+    /// Encoding WebP requires the `webp` feature of this crate.
+    Webp,
+}
+
+impl OutputFormat {
+    /// Whether the format supports animation.
+    #[inline]
+    pub fn is_animated(&self) -> bool {
+        match *self {
+            OutputFormat::Apng | OutputFormat::Gif | OutputFormat::Webp => true,
+            _ => false,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn to_image_format(&self) -> ImageFormat {
+        match *self {
+            OutputFormat::Png | OutputFormat::Apng => ImageFormat::PNG,
+            OutputFormat::Jpeg => ImageFormat::JPEG,
+            OutputFormat::Gif => ImageFormat::GIF,
+            OutputFormat::Webp => ImageFormat::WEBP,
+        }
+    }
+}
+
+impl From<OutputFormat> for ImageFormat {
+    #[inline]
+    fn from(format: OutputFormat) -> ImageFormat {
+        format.to_image_format()
+    }
+}
+
+
+/// Options for encoding the rendered image macro.
+///
+/// All options are optional; if omitted, the format is chosen based on the template
+/// and the quality settings are taken from the `Engine` configuration.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OutputOptions {
+    /// Image format of the output.
+    ///
+    /// Rendering an animated template as PNG or JPEG yields a still image
+    /// of its first frame (or the one given by `frame`).
+    /// If omitted, animated PNG templates result in animated PNGs.
+    pub format: Option<OutputFormat>,
+    /// Quality percentage (1-100) of the output, if it's a JPEG, GIF, or lossy WebP.
+    pub quality: Option<u8>,
+    /// How many times a GIF, APNG, or WebP animation should be repeated after it's played once.
+    ///
+    /// If omitted, the animation loops forever.
+    pub loop_count: Option<u16>,
+    /// Index of a single frame of an animated template to render as a still image.
+    pub frame: Option<usize>,
+}
+
+impl OutputOptions {
+    /// Whether all the options are at their defaults.
+    #[inline]
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
@@ -0,0 +1,46 @@
+//! Module defining the resampling filter enum.
+
+use image::FilterType;
+
+
+/// Filter used for resampling the template when it's being resized.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Resampling {
+    /// Nearest neighbor.
+    ///
+    /// This is the fastest filter, and the only one that keeps
+    /// pixel art templates crisp.
+    Nearest,
+    /// Linear (triangle) filter.
+    Linear,
+    /// Cubic (Catmull-Rom) filter.
+    Cubic,
+    /// Gaussian filter.
+    Gaussian,
+    /// Lanczos filter with a window of 3.
+    ///
+    /// This gives the best quality, but is also the slowest one,
+    /// especially for big animated GIFs.
+    Lanczos3,
+}
+
+impl Resampling {
+    #[inline]
+    pub(crate) fn to_filter_type(&self) -> FilterType {
+        match *self {
+            Resampling::Nearest => FilterType::Nearest,
+            Resampling::Linear => FilterType::Triangle,
+            Resampling::Cubic => FilterType::CatmullRom,
+            Resampling::Gaussian => FilterType::Gaussian,
+            Resampling::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+impl From<Resampling> for FilterType {
+    #[inline]
+    fn from(resampling: Resampling) -> FilterType {
+        resampling.to_filter_type()
+    }
+}
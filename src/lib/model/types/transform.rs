@@ -0,0 +1,157 @@
+//! Module defining the template transformation enum.
+
+use std::fmt;
+
+use float_ord::FloatOrd;
+
+use super::color::Color;
+
+
+/// Geometric transformation applied to the template before it's captioned.
+///
+/// Transformations are applied in order, before the template is resized,
+/// and identically for every frame of an animated template.
+#[derive(Clone, Copy)]
+pub enum Transform {
+    /// Cut out a rectangular region of the template.
+    ///
+    /// If the rectangle extends beyond the template, it is clipped to its bounds.
+    Crop {
+        /// X coordinate of the region's top-left corner.
+        left: u32,
+        /// Y coordinate of the region's top-left corner.
+        top: u32,
+        /// Width of the region.
+        width: u32,
+        /// Height of the region.
+        height: u32,
+    },
+    /// Mirror the template horizontally (i.e. left to right).
+    FlipHorizontal,
+    /// Mirror the template vertically (i.e. upside down).
+    FlipVertical,
+    /// Rotate the template by 90 degrees clockwise.
+    Rotate90,
+    /// Rotate the template by 180 degrees.
+    Rotate180,
+    /// Rotate the template by 270 degrees clockwise (90 counterclockwise).
+    Rotate270,
+    /// Rotate the template by an arbitrary angle, clockwise.
+    ///
+    /// The image is enlarged to fit the rotated template,
+    /// and the area outside of it is filled with the `background` color.
+    Rotate {
+        /// Rotation angle in degrees.
+        degrees: f32,
+        /// Color to fill the uncovered area with.
+        /// If omitted, it will be transparent.
+        background: Option<Color>,
+    },
+}
+
+impl Transform {
+    /// Create a `Transform` which rotates the template by given angle (in degrees).
+    ///
+    /// Multiples of 90 degrees result in the exact (lossless) rotation variants.
+    pub fn rotate(degrees: f32, background: Option<Color>) -> Self {
+        let normalized = degrees % 360.0;
+        let normalized = if normalized < 0.0 { normalized + 360.0 } else { normalized };
+        match normalized {
+            a if a == 0.0 => Transform::Rotate{degrees: 0.0, background},
+            a if a == 90.0 => Transform::Rotate90,
+            a if a == 180.0 => Transform::Rotate180,
+            a if a == 270.0 => Transform::Rotate270,
+            a => Transform::Rotate{degrees: a, background},
+        }
+    }
+
+    /// Whether the parameters of this transformation make sense,
+    /// i.e. the crop rectangle isn't empty and the rotation angle is finite.
+    pub fn is_valid(&self) -> bool {
+        match *self {
+            Transform::Crop{width, height, ..} => width > 0 && height > 0,
+            Transform::Rotate{degrees, ..} => degrees.is_finite(),
+            _ => true,
+        }
+    }
+
+    /// Whether this transformation doesn't actually change anything.
+    #[inline]
+    pub fn is_noop(&self) -> bool {
+        match *self {
+            Transform::Rotate{degrees, ..} => degrees % 360.0 == 0.0,
+            _ => false,
+        }
+    }
+}
+
+impl PartialEq for Transform {
+    fn eq(&self, other: &Self) -> bool {
+        use self::Transform::*;
+        match (*self, *other) {
+            (Crop{left: l1, top: t1, width: w1, height: h1},
+             Crop{left: l2, top: t2, width: w2, height: h2}) =>
+                (l1, t1, w1, h1) == (l2, t2, w2, h2),
+            (FlipHorizontal, FlipHorizontal) => true,
+            (FlipVertical, FlipVertical) => true,
+            (Rotate90, Rotate90) => true,
+            (Rotate180, Rotate180) => true,
+            (Rotate270, Rotate270) => true,
+            (Rotate{degrees: d1, background: b1}, Rotate{degrees: d2, background: b2}) =>
+                FloatOrd(d1).eq(&FloatOrd(d2)) && b1 == b2,
+            _ => false,
+        }
+    }
+}
+impl Eq for Transform {}
+
+impl fmt::Debug for Transform {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Transform::Crop{left, top, width, height} =>
+                write!(fmt, "Crop({}x{}+{}+{})", width, height, left, top),
+            Transform::FlipHorizontal => write!(fmt, "FlipHorizontal"),
+            Transform::FlipVertical => write!(fmt, "FlipVertical"),
+            Transform::Rotate90 => write!(fmt, "Rotate90"),
+            Transform::Rotate180 => write!(fmt, "Rotate180"),
+            Transform::Rotate270 => write!(fmt, "Rotate270"),
+            Transform::Rotate{degrees, background} => match background {
+                Some(bg) => write!(fmt, "Rotate({}deg, {})", degrees, bg),
+                None => write!(fmt, "Rotate({}deg)", degrees),
+            },
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use model::Color;
+    use super::Transform;
+
+    #[test]
+    fn rotate_right_angles() {
+        assert_eq!(Transform::Rotate90, Transform::rotate(90.0, None));
+        assert_eq!(Transform::Rotate180, Transform::rotate(180.0, Some(Color::white())));
+        assert_eq!(Transform::Rotate270, Transform::rotate(-90.0, None));
+        assert_eq!(Transform::Rotate90, Transform::rotate(450.0, None));
+        assert!(Transform::rotate(360.0, None).is_noop());
+    }
+
+    #[test]
+    fn validity() {
+        assert!(Transform::Crop{left: 0, top: 0, width: 10, height: 10}.is_valid());
+        assert!(!Transform::Crop{left: 0, top: 0, width: 0, height: 10}.is_valid());
+        assert!(Transform::rotate(30.0, None).is_valid());
+        assert!(!Transform::rotate(::std::f32::NAN, None).is_valid());
+        assert!(!Transform::rotate(::std::f32::INFINITY, None).is_valid());
+    }
+
+    #[test]
+    fn rotate_arbitrary_angle() {
+        assert_eq!(Transform::Rotate{degrees: 45.0, background: None},
+                   Transform::rotate(45.0, None));
+        assert_eq!(Transform::Rotate{degrees: 315.0, background: None},
+                   Transform::rotate(-45.0, None));
+    }
+}
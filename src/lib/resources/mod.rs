@@ -9,7 +9,7 @@ pub use self::filesystem::{BytesLoader, FileLoader};
 pub use self::fonts::{Font, FontLoader, FontError,
                       FILE_EXTENSION as FONT_FILE_EXTENSION};
 pub use self::templates::{DEFAULT_IMAGE_FORMAT, IMAGE_FORMAT_EXTENSIONS,
-                          Template, TemplateLoader, TemplateError};
+                          Template, TemplateLoader, TemplateError, TemplateSource};
 
 
 use std::error::Error;
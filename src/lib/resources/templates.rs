@@ -2,13 +2,21 @@
 
 use std::collections::HashMap;
 use std::fmt;
-use std::io;
+use std::fs::File;
+use std::io::{self, Read};
 use std::iter;
 use std::path::Path;
+use std::time::Duration;
 
-use image::{self, DynamicImage, GenericImage, ImageFormat};
+use image::{self, DynamicImage, GenericImage, ImageDecoder, ImageFormat, ImageResult};
 
 use util::animated_gif::{self, GifAnimation, is_gif, is_gif_animated};
+use util::animated_png::{self, PngAnimation, is_png, is_png_animated};
+use model::{MAX_HEIGHT, MAX_WIDTH};
+use util::animated_webp::WebpAnimation;
+use util::metadata::Metadata;
+#[cfg(feature = "webp")]
+use util::webp::{self, is_webp};
 use super::Loader;
 use super::filesystem::PathLoader;
 
@@ -19,11 +27,21 @@ pub const DEFAULT_IMAGE_FORMAT: ImageFormat = ImageFormat::PNG;
 lazy_static! {
     /// Map of template file extensions to supported image formats.
     #[doc(hidden)]
-    pub static ref IMAGE_FORMAT_EXTENSIONS: HashMap<&'static str, ImageFormat> = hashmap!{
-        "gif" => ImageFormat::GIF,
-        "jpeg" => ImageFormat::JPEG,
-        "jpg" => ImageFormat::JPEG,
-        "png" => ImageFormat::PNG,
+    pub static ref IMAGE_FORMAT_EXTENSIONS: HashMap<&'static str, ImageFormat> = {
+        let mut extensions = hashmap!{
+            "bmp" => ImageFormat::BMP,
+            "gif" => ImageFormat::GIF,
+            "jpeg" => ImageFormat::JPEG,
+            "jpg" => ImageFormat::JPEG,
+            "png" => ImageFormat::PNG,
+            "tif" => ImageFormat::TIFF,
+            "tiff" => ImageFormat::TIFF,
+        };
+        // WebP templates are decoded with libwebp, just like they are encoded.
+        if cfg!(feature = "webp") {
+            extensions.insert("webp", ImageFormat::WEBP);
+        }
+        extensions
     };
 }
 
@@ -31,25 +49,36 @@ lazy_static! {
 /// Represents an image macro template.
 ///
 /// Currently, templates can either be regular (still) images,
-/// or animations loaded from a GIF file.
+/// or animations loaded from a GIF, APNG, or (with the `webp` feature) WebP file.
 #[derive(Clone)]
 pub enum Template {
-    /// Single still image, loaded from some image format.
-    Image(DynamicImage, ImageFormat),
+    /// Single still image, loaded from some image format,
+    /// along with the metadata of the image file.
+    Image(DynamicImage, ImageFormat, Metadata),
     /// An animation, loaded from a GIF.
     Animation(GifAnimation),
+    /// An animation, loaded from an animated PNG.
+    AnimatedPng(PngAnimation),
+    /// An animation, loaded from an animated WebP.
+    ///
+    /// Templates are only loaded from WebP files with the `webp` feature,
+    /// but the variant is always present so that matching on `Template` works the same.
+    AnimatedWebp(WebpAnimation),
 }
 
 impl Template {
     /// Create the template for an image loaded from a file.
     /// Image format is figured out from the file extension.
     pub fn for_image<P: AsRef<Path>>(img: DynamicImage, path: P) -> Self {
-        let extension = path.as_ref().extension().and_then(|e| e.to_str())
-            .map(|s| s.trim().to_lowercase());
-        let img_format = extension
-            .and_then(|ext| IMAGE_FORMAT_EXTENSIONS.get(ext.as_str()).map(|f| *f))
-            .unwrap_or(DEFAULT_IMAGE_FORMAT);
-        Template::Image(img, img_format)
+        Template::Image(img, image_format_for(path), Metadata::default())
+    }
+
+    /// Create the template for an image loaded from a file,
+    /// applying the metadata (EXIF orientation & ICC profile) read from it.
+    pub fn for_image_with_metadata<P: AsRef<Path>>(img: DynamicImage, path: P,
+                                                   mut metadata: Metadata) -> Self {
+        let img = metadata.normalize(img);
+        Template::Image(img, image_format_for(path), metadata)
     }
 
     /// Create the template for an animation loaded from a GIF file.
@@ -57,13 +86,112 @@ impl Template {
     pub fn for_gif_animation(gif_anim: GifAnimation) -> Self {
         Template::Animation(gif_anim)
     }
+
+    /// Create the template for an animation loaded from an APNG file.
+    #[inline]
+    pub fn for_png_animation(png_anim: PngAnimation) -> Self {
+        Template::AnimatedPng(png_anim)
+    }
+
+    /// Create the template for an animation loaded from a WebP file.
+    #[inline]
+    pub fn for_webp_animation(webp_anim: WebpAnimation) -> Self {
+        Template::AnimatedWebp(webp_anim)
+    }
+}
+
+impl Template {
+    /// Create the template from the contents of an image file.
+    ///
+    /// Image format is detected from the data itself,
+    /// and animated GIFs, APNGs, and (with the `webp` feature) WebPs
+    /// are loaded as animations.
+    ///
+    /// Unlike the template files, the data can come from anywhere,
+    /// so images larger than the maximum size of image macros are rejected
+    /// (based on their header, before they're decoded).
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, TemplateError> {
+        check_size(bytes)?;
+
+        #[cfg(feature = "webp")]
+        {
+            if webp::is_riff_webp(bytes) {
+                return decode_webp(bytes);
+            }
+        }
+
+        let format = image::guess_format(bytes)?;
+        match format {
+            ImageFormat::GIF if animated_gif::is_animated(&mut &bytes[..]).unwrap_or(false) => {
+                let gif_anim = animated_gif::decode(&mut &bytes[..])?;
+                Ok(Template::for_gif_animation(gif_anim))
+            }
+            ImageFormat::PNG if animated_png::is_animated(bytes).unwrap_or(false) => {
+                let png_anim = animated_png::decode(&mut &bytes[..])?;
+                Ok(Template::for_png_animation(png_anim))
+            }
+            _ => {
+                trace!("Opening {:?} image of {} bytes", format, bytes.len());
+                let img = image::load_from_memory_with_format(bytes, format)?;
+                let mut metadata = Metadata::read(bytes);
+                let img = metadata.normalize(img);
+                Ok(Template::Image(img, format, metadata))
+            }
+        }
+    }
+}
+
+/// Check the size of an image, as declared in the header of its file,
+/// against the maximum size of image macros.
+///
+/// The number of frames of animations is limited by their decoders.
+fn check_size(bytes: &[u8]) -> Result<(), TemplateError> {
+    let (width, height) = image_dimensions(bytes)?;
+    if width > MAX_WIDTH || height > MAX_HEIGHT {
+        return Err(TemplateError::TooLarge(format!("{}x{} > {}x{}",
+            width, height, MAX_WIDTH, MAX_HEIGHT)));
+    }
+    Ok(())
+}
+
+/// Read the dimensions of an image (or animation canvas) from the header of its file.
+fn image_dimensions(bytes: &[u8]) -> ImageResult<(u32, u32)> {
+    #[cfg(feature = "webp")]
+    {
+        if webp::is_riff_webp(bytes) {
+            return webp::dimensions(bytes).ok_or_else(||
+                image::ImageError::FormatError("missing WebP image header".into()));
+        }
+    }
+
+    let input = io::Cursor::new(bytes);
+    match image::guess_format(bytes)? {
+        ImageFormat::BMP => image::bmp::BMPDecoder::new(input).dimensions(),
+        ImageFormat::GIF => image::gif::Decoder::new(input).dimensions(),
+        ImageFormat::JPEG => image::jpeg::JPEGDecoder::new(input).dimensions(),
+        ImageFormat::PNG => image::png::PNGDecoder::new(input).dimensions(),
+        ImageFormat::TIFF => image::tiff::TIFFDecoder::new(input)?.dimensions(),
+        ImageFormat::WEBP => image::webp::WebpDecoder::new(input).dimensions(),
+        // Only the formats of template files are supported.
+        format => Err(image::ImageError::UnsupportedError(
+            format!("unsupported template image format: {:?}", format))),
+    }
+}
+
+/// Figure out the image format from the file extension.
+fn image_format_for<P: AsRef<Path>>(path: P) -> ImageFormat {
+    let extension = path.as_ref().extension().and_then(|e| e.to_str())
+        .map(|s| s.trim().to_lowercase());
+    extension
+        .and_then(|ext| IMAGE_FORMAT_EXTENSIONS.get(ext.as_str()).map(|f| *f))
+        .unwrap_or(DEFAULT_IMAGE_FORMAT)
 }
 
 impl Template {
     /// Whether this is an animated template.
     #[inline]
     pub fn is_animated(&self) -> bool {
-        match *self { Template::Animation(..) => true, _ => false, }
+        match *self { Template::Image(..) => false, _ => true, }
     }
 
     /// Number of images that comprise the template
@@ -72,6 +200,17 @@ impl Template {
         match *self {
             Template::Image(..) => 1,
             Template::Animation(ref gif_anim) => gif_anim.frames_count(),
+            Template::AnimatedPng(ref png_anim) => png_anim.frames_count(),
+            Template::AnimatedWebp(ref webp_anim) => webp_anim.frames_count(),
+        }
+    }
+
+    /// Metadata of the template's image file, if it's a still image.
+    #[inline]
+    pub fn metadata(&self) -> Option<&Metadata> {
+        match *self {
+            Template::Image(_, _, ref metadata) => Some(metadata),
+            _ => None,
         }
     }
 
@@ -81,19 +220,69 @@ impl Template {
             Template::Image(ref img, ..) => Box::new(iter::once(img)),
             Template::Animation(ref gif_anim) => Box::new(
                 gif_anim.iter_frames().map(|f| &f.image)),
+            Template::AnimatedPng(ref png_anim) => Box::new(
+                png_anim.iter_frames().map(|f| &f.image)),
+            Template::AnimatedWebp(ref webp_anim) => Box::new(
+                webp_anim.iter_frames().map(|f| &f.image)),
+        }
+    }
+
+    /// Iterate over the durations that images of this template are displayed for.
+    ///
+    /// These are all `None` unless the template is animated.
+    pub fn iter_delays<'t>(&'t self) -> Box<Iterator<Item=Option<Duration>> + 't> {
+        match *self {
+            Template::Image(..) => Box::new(iter::once(None)),
+            Template::Animation(ref gif_anim) => Box::new(
+                gif_anim.iter_frames().map(|f| {
+                    let delay_ms = f.metadata.delay as u64 * 10;  // GIF delay unit is 10ms.
+                    Some(Duration::from_millis(delay_ms))
+                })),
+            Template::AnimatedPng(ref png_anim) => Box::new(
+                png_anim.iter_frames().map(|f| Some(f.delay))),
+            Template::AnimatedWebp(ref webp_anim) => Box::new(
+                webp_anim.iter_frames().map(|f| Some(f.delay))),
+        }
+    }
+
+    /// Create a template out of (possibly repeated) frames of this one,
+    /// given by their indices and the durations they should now be displayed for.
+    ///
+    /// Still image templates are simply copied.
+    pub fn rearrange(&self, frames: &[(usize, Duration)]) -> Template {
+        let frames = frames.iter().cloned();
+        match *self {
+            Template::Image(..) => self.clone(),
+            Template::Animation(ref gif_anim) =>
+                Template::Animation(gif_anim.rearrange(frames)),
+            Template::AnimatedPng(ref png_anim) =>
+                Template::AnimatedPng(png_anim.rearrange(frames)),
+            Template::AnimatedWebp(ref webp_anim) =>
+                Template::AnimatedWebp(webp_anim.rearrange(frames)),
         }
     }
 
     /// The preferred format for image macros generated using this template.
-    /// This is usually the same that the template was loaded from.
+    /// This is usually the same that the template was loaded from,
+    /// unless it's a format we cannot (or shouldn't) encode, like BMP or TIFF.
     pub fn preferred_format(&self) -> ImageFormat {
         match *self {
-            Template::Image(_, fmt) => match fmt {
+            Template::Image(_, fmt, _) => match fmt {
                 // These are the formats that image crate encodes natively.
                 ImageFormat::PNG | ImageFormat::JPEG => return fmt,
+                #[cfg(feature = "webp")]
+                ImageFormat::WEBP => return fmt,
+                // BMP & TIFF are lossless but uncompressed, so PNG is a better fit.
                 _ => {}
             },
             Template::Animation(..) => return ImageFormat::GIF,
+            Template::AnimatedPng(..) => return ImageFormat::PNG,
+            // Without the feature, WebP can't be encoded, so fall back to GIF.
+            Template::AnimatedWebp(..) => return if cfg!(feature = "webp") {
+                ImageFormat::WEBP
+            } else {
+                ImageFormat::GIF
+            },
         }
         DEFAULT_IMAGE_FORMAT
     }
@@ -102,17 +291,67 @@ impl Template {
 impl fmt::Debug for Template {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            Template::Image(ref img, f) => {
+            Template::Image(ref img, f, _) => {
                 let (width, height) = img.dimensions();
                 write!(fmt, "Template::Image({}x{}, {:?})", width, height, f)
             }
             Template::Animation(ref gif_anim) => {
                 write!(fmt, "Template::Animation({} frame(s))", gif_anim.frames_count())
             }
+            Template::AnimatedPng(ref png_anim) => {
+                write!(fmt, "Template::AnimatedPng({} frame(s))", png_anim.frames_count())
+            }
+            Template::AnimatedWebp(ref webp_anim) => {
+                write!(fmt, "Template::AnimatedWebp({} frame(s))", webp_anim.frames_count())
+            }
+        }
+    }
+}
+
+
+/// Template image given directly, rather than loaded by a `Loader`.
+///
+/// See `Engine::caption_image`.
+#[derive(Clone, Debug)]
+pub enum TemplateSource {
+    /// Template that has already been loaded.
+    Template(Template),
+    /// Contents of an image file in one of the supported formats.
+    Bytes(Vec<u8>),
+}
+
+impl TemplateSource {
+    /// Load the template from this source.
+    pub fn load(self) -> Result<Template, TemplateError> {
+        match self {
+            TemplateSource::Template(template) => Ok(template),
+            TemplateSource::Bytes(bytes) => Template::from_bytes(&bytes),
         }
     }
 }
 
+impl From<Template> for TemplateSource {
+    fn from(template: Template) -> Self {
+        TemplateSource::Template(template)
+    }
+}
+impl From<DynamicImage> for TemplateSource {
+    fn from(img: DynamicImage) -> Self {
+        TemplateSource::Template(
+            Template::Image(img, DEFAULT_IMAGE_FORMAT, Metadata::default()))
+    }
+}
+impl From<Vec<u8>> for TemplateSource {
+    fn from(bytes: Vec<u8>) -> Self {
+        TemplateSource::Bytes(bytes)
+    }
+}
+impl<'b> From<&'b [u8]> for TemplateSource {
+    fn from(bytes: &'b [u8]) -> Self {
+        TemplateSource::Bytes(bytes.to_vec())
+    }
+}
+
 
 /// Error that may occur during template load.
 #[derive(Debug, Error)]
@@ -126,6 +365,23 @@ pub enum TemplateError {
     /// Error when opening a template's animated GIF didn't succeed.
     #[error(msg = "error while opening animated GIF template")]
     DecodeAnimatedGif(animated_gif::DecodeError),
+    /// Error when opening a template's animated PNG didn't succeed.
+    #[error(msg = "error while opening animated PNG template")]
+    DecodeAnimatedPng(animated_png::DecodeError),
+    /// Template image given directly is larger than the maximum size of image macros.
+    #[error(no_from, non_std, msg = "template image too large")]
+    TooLarge(String),
+}
+
+#[cfg(feature = "webp")]
+impl From<webp::DecodeError> for TemplateError {
+    fn from(e: webp::DecodeError) -> Self {
+        match e {
+            webp::DecodeError::Io(e) => TemplateError::File(e),
+            webp::DecodeError::Format(msg) =>
+                TemplateError::OpenImage(image::ImageError::FormatError(msg)),
+        }
+    }
 }
 
 
@@ -155,6 +411,13 @@ impl Loader for TemplateLoader {
     fn load<'n>(&self, name: &'n str) -> Result<Template, Self::Err> {
         let path = self.inner.load(name)?;
 
+        #[cfg(feature = "webp")]
+        {
+            if is_webp(&path) {
+                return load_webp(&path);
+            }
+        }
+
         // Use the `gif` crate to load animated GIFs.
         // Use the regular `image` crate to load any other (still) image.
         if is_gif(&path) && is_gif_animated(&path).unwrap_or(false) {
@@ -164,10 +427,93 @@ impl Loader for TemplateLoader {
                     path.display(), e); e
             })?;
             Ok(Template::for_gif_animation(gif_anim))
+        } else if is_png(&path) && is_png_animated(&path).unwrap_or(false) {
+            trace!("Image {} is an animated PNG", path.display());
+            let png_anim = animated_png::decode_from_file(&path).map_err(|e| {
+                error!("Failed to open animated PNG template {}: {}",
+                    path.display(), e); e
+            })?;
+            Ok(Template::for_png_animation(png_anim))
         } else {
             trace!("Opening image {}", path.display());
-            let img = image::open(&path)?;
-            Ok(Template::for_image(img, &path))
+            let mut bytes = vec![];
+            File::open(&path)?.read_to_end(&mut bytes)?;
+            let img = image::load_from_memory(&bytes)?;
+            let metadata = Metadata::read(&bytes);
+            Ok(Template::for_image_with_metadata(img, &path, metadata))
+        }
+    }
+}
+
+/// Load a template from WebP file, which can be either a still image or an animation.
+#[cfg(feature = "webp")]
+fn load_webp(path: &Path) -> Result<Template, TemplateError> {
+    trace!("Opening WebP file {}", path.display());
+    let bytes = webp::read_file(path)?;
+    decode_webp(&bytes).map_err(|e| {
+        error!("Failed to open WebP template {}: {}", path.display(), e); e
+    })
+}
+
+/// Decode a WebP template, which can be either a still image or an animation.
+#[cfg(feature = "webp")]
+fn decode_webp(bytes: &[u8]) -> Result<Template, TemplateError> {
+    if webp::is_animated(bytes) {
+        trace!("Image is an animated WebP");
+        let webp_anim = webp::decode_animation(bytes)?;
+        Ok(Template::for_webp_animation(webp_anim))
+    } else {
+        let img = webp::decode(bytes)?;
+        Ok(Template::Image(img, ImageFormat::WEBP, Metadata::default()))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::Read;
+    use std::path::Path;
+
+    use image::{DynamicImage, ImageFormat, RgbImage};
+    use model::MAX_WIDTH;
+    use super::{Template, TemplateError};
+
+    fn read_template(file_name: &str) -> Vec<u8> {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../../data/templates").join(file_name);
+        let mut bytes = vec![];
+        File::open(path).unwrap().read_to_end(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn still_image_from_bytes() {
+        let template = Template::from_bytes(&read_template("zoidberg.jpg")).unwrap();
+        assert!(!template.is_animated());
+        assert_eq!(ImageFormat::JPEG, template.preferred_format());
+    }
+
+    #[test]
+    fn animated_gif_from_bytes() {
+        let template = Template::from_bytes(&read_template("badger.gif")).unwrap();
+        assert!(template.is_animated());
+        assert!(template.image_count() > 1);
+    }
+
+    #[test]
+    fn oversized_image_from_bytes() {
+        let img = DynamicImage::ImageRgb8(RgbImage::new(MAX_WIDTH + 1, 16));
+        let mut bytes = vec![];
+        img.save(&mut bytes, ImageFormat::PNG).unwrap();
+        match Template::from_bytes(&bytes) {
+            Err(TemplateError::TooLarge(_)) => {}
+            r => panic!("unexpected result: {:?}", r),
         }
     }
+
+    #[test]
+    fn garbage_bytes() {
+        assert!(Template::from_bytes(b"definitely not an image").is_err());
+    }
 }
@@ -7,14 +7,18 @@ use std::error::Error;
 use std::fmt;
 use std::fs::File;
 use std::io::{self, Read, Write};
+use std::ops::Deref;
 use std::path::Path;
 use std::slice;
+use std::time::Duration;
 
 use color_quant::NeuQuant;
 use gif::{self, SetParameter};
 use gif_dispose::Screen;
 use image::{DynamicImage, GenericImage, RgbaImage};
 
+use super::{animation, parallel};
+
 
 // Data structures
 
@@ -47,6 +51,27 @@ impl GifAnimation {
     pub fn iter_frames<'a>(&'a self) -> Box<Iterator<Item=&'a GifFrame> + 'a> {
         Box::new(self.frames.iter())
     }
+
+    /// Create a new animation out of (possibly repeated) frames of this one,
+    /// given by their indices and the durations they should now be displayed for.
+    pub fn rearrange<I>(&self, frames: I) -> GifAnimation
+        where I: IntoIterator<Item=(usize, Duration)>
+    {
+        // Frames have been "flattened" when decoding, so they cover the whole canvas
+        // and can be shown in any order.
+        let frames = frames.into_iter().map(|(index, delay)| {
+            let mut frame = self.frames[index].clone();
+            frame.metadata.delay = gif_delay(delay);
+            frame
+        }).collect();
+        GifAnimation{
+            width: self.width,
+            height: self.height,
+            palette: self.palette.clone(),
+            bg_color: self.bg_color,
+            frames,
+        }
+    }
 }
 
 impl fmt::Debug for GifAnimation {
@@ -132,11 +157,17 @@ pub fn is_gif_animated<P: AsRef<Path>>(path: P) -> Option<bool> {
             path.display(), e); e
     }).ok());
 
+    is_animated(&mut file)
+}
+
+/// Check if the GIF image read from given input is animated.
+/// Returns None if it cannot be determined (e.g. the input isn't a valid GIF).
+pub fn is_animated<R: Read>(input: &mut R) -> Option<bool> {
     // The `image` crate technically has an ImageDecoder::is_animated() method,
     // but it doesn't seem to actually work.
     // So instead we just check if the GIF has at least two frames spaced in time.
 
-    let mut decoder = gif::Decoder::new(&mut file);
+    let mut decoder = gif::Decoder::new(input);
     decoder.set(gif::ColorOutput::Indexed);
     decoder.set(MEMORY_LIMIT);
     let mut reader = try_opt!(decoder.read_info().ok());
@@ -147,17 +178,15 @@ pub fn is_gif_animated<P: AsRef<Path>>(path: P) -> Option<bool> {
         frame_count += 1;
         delay_ms += frame.delay * 10;  // GIF delay unit is 10ms.
         if frame_count > 1 && delay_ms > 0 {
-            trace!("File {} is a >={}ms animated GIF with {}+ frames",
-                path.display(), delay_ms, frame_count);
+            trace!("Image is a >={}ms animated GIF with {}+ frames", delay_ms, frame_count);
             return Some(true);
         }
     }
 
     if frame_count > 0 {
-        trace!("File {} is a still but compound GIF image with {} parts",
-            path.display(), frame_count);
+        trace!("Image is a still but compound GIF image with {} parts", frame_count);
     } else {
-        trace!("File {} is a still GIF image", path.display());
+        trace!("Image is a still GIF image");
     }
     Some(false)
 }
@@ -177,6 +206,9 @@ pub enum DecodeError {
     Gif(gif::DecodingError),
     /// Error arising from the `gif-dispose` crate "rendering" process.
     GifDispose(String),
+    /// The animation is larger than the decoding limits allow
+    /// (see `animation::check_decoding_limits`).
+    TooLarge(String),
 }
 
 impl From<io::Error> for DecodeError {
@@ -212,6 +244,7 @@ impl fmt::Display for DecodeError {
             DecodeError::Io(ref e) => write!(fmt, "I/O error while decoding GIF: {}", e),
             DecodeError::Gif(ref e) => write!(fmt, "cannot decode GIF file: {}", e),
             DecodeError::GifDispose(ref e) => write!(fmt, "GIF rendering error: {}", e),
+            DecodeError::TooLarge(ref e) => write!(fmt, "GIF exceeds decoding limits: {}", e),
         }
     }
 }
@@ -238,11 +271,18 @@ pub fn decode<R: Read>(input: &mut R) -> Result<GifAnimation, DecodeError> {
         .map(|p| p.to_vec()).unwrap_or_else(Vec::new);
     let bg_color = reader.bg_color();
 
+    // Check the declared size before the virtual screen is allocated for it.
+    animation::check_decoding_limits(width as u32, height as u32, 0)
+        .map_err(DecodeError::TooLarge)?;
+
     // Read the frames and "draw" them on a virtual screen to ensure
     // that the frame disposal mechanics are applied correctly.
     let mut screen = Screen::new(&reader);
     let mut frames = vec![];
     while let Some(frame) = reader.read_next_frame()? {
+        // Every frame is kept as a copy of the whole screen, so their number is limited, too.
+        animation::check_decoding_limits(width as u32, height as u32, frames.len() + 1)
+            .map_err(DecodeError::TooLarge)?;
         screen.blit(&frame)?;
 
         // Get the current pixels of the GIF logical screen as raw bytes
@@ -276,57 +316,653 @@ const RGBA_SIZE_BYTES: usize = 4;
 /// Encode an animated GIF and write it to given writer.
 ///
 /// The `quality` parameter is a percentage from 1 to 100.
+/// The animation will loop forever.
+#[inline]
 pub fn encode<W: Write>(anim: &GifAnimation, quality: u8, output: W) -> io::Result<()> {
-    let output = BgColorFixer::new(anim.bg_color.map(|i| i as u8), output);
-    let mut encoder =
-        gif::Encoder::new(output, anim.width, anim.height, &*anim.palette)?;
-    encoder.set(gif::Repeat::Infinite)?;
+    encode_with_loop_count(anim, quality, None, output)
+}
 
-    for (i, frame) in anim.iter_frames().enumerate() {
-        trace!("Writing frame #{}", i + 1);
-        let mut gif_frame = frame.metadata.clone();
+/// Encode an animated GIF that's repeated given number of times
+/// (after being played once), and write it to given writer.
+///
+/// If `loop_count` is `None`, the animation will loop forever.
+#[inline]
+pub fn encode_with_loop_count<W: Write>(anim: &GifAnimation,
+                                        quality: u8,
+                                        loop_count: Option<u16>,
+                                        output: W) -> io::Result<()> {
+    let options = EncodeOptions{quality, loop_count, ..EncodeOptions::default()};
+    encode_with_options(anim, &options, output)
+}
 
-        let (buffer, palette, transparent) = quantize_image(&frame.image, quality);
-        gif_frame.buffer = buffer.into();
-        gif_frame.palette = Some(palette);
-        gif_frame.transparent = transparent;
+/// Encode an animated GIF using given options, and write it to given writer.
+#[inline]
+pub fn encode_with_options<W: Write>(anim: &GifAnimation,
+                                     options: &EncodeOptions,
+                                     output: W) -> io::Result<()> {
+    encode_with_hook(anim, options, output, &|_| Ok(()))
+}
 
-        encoder.write_frame(&gif_frame)?;
+/// Encode an animated GIF using given options like `encode_with_options`,
+/// calling given function with the index of every frame before it's encoded.
+///
+/// Frames may be encoded in parallel, so their indices aren't necessarily passed in order.
+/// When the animation is optimized, frames which are merged with the previous ones are skipped.
+/// If the function returns an error, the encoding stops and fails with that error.
+pub fn encode_with_hook<W: Write>(anim: &GifAnimation,
+                                  options: &EncodeOptions,
+                                  output: W,
+                                  on_frame: &(Fn(usize) -> io::Result<()> + Sync))
+                                  -> io::Result<()> {
+    let (palette, bg_color, frames) = if options.optimize {
+        let (palette, frames) = optimize_frames(anim, options, on_frame)?;
+        // Original background color refers to a palette we no longer use.
+        (palette, None, frames)
+    } else {
+        let has_transparency = anim.iter_frames().any(|f| {
+            f.image.to_rgba().chunks(RGBA_SIZE_BYTES).any(is_transparent)
+        });
+        let frames: Vec<_> = anim.iter_frames().enumerate().collect();
+        let frames = parallel::map(frames, options.parallelism, |(i, frame)| {
+            on_frame(i)?;
+            let mut gif_frame = frame.metadata.clone();
+            if has_transparency {
+                // Frames are fully rendered, so the previous one must not show through.
+                gif_frame.dispose = gif::DisposalMethod::Background;
+            }
+            let (buffer, palette, transparent) = quantize_image_with(
+                &frame.image, options.quality, options.dithering, &options.reserved_colors);
+            gif_frame.buffer = buffer.into();
+            gif_frame.palette = Some(palette);
+            gif_frame.transparent = transparent;
+            Ok(gif_frame)
+        });
+        let frames = frames.into_iter().collect::<io::Result<Vec<_>>>()?;
+        (anim.palette.clone(), anim.bg_color.map(|i| i as u8), frames)
+    };
+
+    let output = BgColorFixer::new(bg_color, output);
+    let mut encoder = gif::Encoder::new(output, anim.width, anim.height, &*palette)?;
+    match options.loop_count {
+        None => encoder.set(gif::Repeat::Infinite)?,
+        // Without the looping extension, the animation is only played once.
+        Some(0) => {}
+        Some(n) => encoder.set(gif::Repeat::Finite(n))?,
+    }
+    for (i, gif_frame) in frames.iter().enumerate() {
+        trace!("Writing frame #{}", i + 1);
+        encoder.write_frame(gif_frame)?;
     }
     Ok(())
 }
 
+
+/// Options for encoding GIF animations.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EncodeOptions {
+    /// Quality percentage of the color quantization (1-100).
+    pub quality: u8,
+    /// How many times the animation should be repeated after it's played once.
+    ///
+    /// If `None`, the animation will loop forever.
+    pub loop_count: Option<u16>,
+    /// Whether to reduce the size of the animation by using a single palette
+    /// for all frames (where possible), encoding only the changed parts of frames,
+    /// and merging identical consecutive frames.
+    pub optimize: bool,
+    /// Dithering applied when reducing the colors of frames.
+    pub dithering: Dithering,
+    /// Colors which should be present in the palettes exactly (e.g. those of captions).
+    pub reserved_colors: ReservedColors,
+    /// Maximum number of frames that are quantized at once.
+    ///
+    /// The encoded animation is the same regardless of this setting.
+    pub parallelism: usize,
+}
+
+impl Default for EncodeOptions {
+    fn default() -> Self {
+        EncodeOptions{
+            quality: DEFAULT_QUALITY,
+            loop_count: None,
+            optimize: false,
+            dithering: Dithering::None,
+            reserved_colors: ReservedColors::default(),
+            parallelism: 1,
+        }
+    }
+}
+
+/// Maximum number of colors that can be reserved in GIF palettes.
+pub const MAX_RESERVED_COLORS: usize = 16;
+
+/// Set of (up to `MAX_RESERVED_COLORS`) RGB colors reserved in GIF palettes.
+///
+/// It's stored inline, so that `EncodeOptions` can stay `Copy`.
+/// Dereferences to a slice of the colors.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReservedColors {
+    colors: [[u8; 3]; MAX_RESERVED_COLORS],
+    len: usize,
+}
+
+impl ReservedColors {
+    /// Create the set from given colors.
+    ///
+    /// Duplicates are skipped, and colors beyond `MAX_RESERVED_COLORS` are ignored.
+    pub fn new(colors: &[[u8; 3]]) -> Self {
+        let mut result = Self::default();
+        for &color in colors {
+            if result.len == MAX_RESERVED_COLORS {
+                warn!("Ignoring more than {} reserved GIF colors", MAX_RESERVED_COLORS);
+                break;
+            }
+            if !result.contains(&color) {
+                result.colors[result.len] = color;
+                result.len += 1;
+            }
+        }
+        result
+    }
+}
+
+impl Deref for ReservedColors {
+    type Target = [[u8; 3]];
+    fn deref(&self) -> &Self::Target {
+        &self.colors[..self.len]
+    }
+}
+
+
+/// Dithering method used when reducing the colors of GIF frames.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Dithering {
+    /// No dithering; every pixel is mapped to the closest palette color.
+    ///
+    /// This produces the smallest files, but gradients exhibit banding.
+    None,
+    /// Floyd-Steinberg error diffusion.
+    ///
+    /// Gives the best looking gradients, but the noise it introduces
+    /// varies between frames, which makes animations larger.
+    FloydSteinberg,
+    /// Ordered dithering with a Bayer matrix.
+    ///
+    /// The pattern is stable across frames, so it's friendlier to animations.
+    Ordered,
+}
+
+impl Default for Dithering {
+    fn default() -> Self {
+        Dithering::None
+    }
+}
+
+const DEFAULT_QUALITY: u8 = 60;
+
 /// Encode animated GIF with its frames modified (replaced with given images).
 ///
 /// Original animation will be used to provide metadata for GIF frames
 /// (frame delays, transitions, etc.).
+///
+/// The images may have different size than the original frames
+/// (e.g. if they have been resized or cropped), but they all have to be
+/// of the same size. This will become the new size of the animation canvas.
+#[inline]
 pub fn encode_modified<W: Write>(orig_anim: &GifAnimation,
                                  images: Vec<DynamicImage>,
                                  quality: u8,
                                  output: W) -> io::Result<()> {
-    assert_eq!(orig_anim.frames_count(), images.len());
+    encode_modified_with_loop_count(orig_anim, images, quality, None, output)
+}
+
+/// Encode animated GIF with its frames modified (replaced with given images),
+/// repeating it given number of times after it's played once.
+///
+/// See `encode_modified` and `encode_with_loop_count` for details.
+#[inline]
+pub fn encode_modified_with_loop_count<W: Write>(orig_anim: &GifAnimation,
+                                                 images: Vec<DynamicImage>,
+                                                 quality: u8,
+                                                 loop_count: Option<u16>,
+                                                 output: W) -> io::Result<()> {
+    let options = EncodeOptions{quality, loop_count, ..EncodeOptions::default()};
+    encode_modified_with_options(orig_anim, images, &options, output)
+}
+
+/// Encode animated GIF with its frames modified (replaced with given images),
+/// using given encoding options.
+///
+/// See `encode_modified` for details.
+#[inline]
+pub fn encode_modified_with_options<W: Write>(orig_anim: &GifAnimation,
+                                              images: Vec<DynamicImage>,
+                                              options: &EncodeOptions,
+                                              output: W) -> io::Result<()> {
+    encode_modified_with_hook(orig_anim, images, options, output, &|_| Ok(()))
+}
+
+/// Encode animated GIF with its frames modified (replaced with given images),
+/// calling given function with the index of every frame before it's encoded.
+///
+/// See `encode_modified` and `encode_with_hook` for details.
+pub fn encode_modified_with_hook<W: Write>(orig_anim: &GifAnimation,
+                                           images: Vec<DynamicImage>,
+                                           options: &EncodeOptions,
+                                           output: W,
+                                           on_frame: &(Fn(usize) -> io::Result<()> + Sync))
+                                           -> io::Result<()> {
+    if orig_anim.frames_count() != images.len() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+            "expected {} frame(s) of modified GIF animation, got {}",
+            orig_anim.frames_count(), images.len())));
+    }
+    let (width, height) = images.first().map(|img| img.dimensions())
+        .unwrap_or((orig_anim.width as u32, orig_anim.height as u32));
+    check_frame_sizes(images.iter(), (width, height))?;
 
     // Create a new GifAnimation which is a shallow copy of the frame metadata,
     // where frame images are replaced with given DynamicImages.
     let mut new_frames = vec![];
     for (orig_frame, image) in orig_anim.iter_frames().zip(images.into_iter()) {
-        let new_frame = GifFrame{
+        let mut new_frame = GifFrame{
             image: image,
             metadata: orig_frame.metadata.clone(),
         };
+        // Frames have been "flattened" when decoding, so they cover the whole canvas.
+        new_frame.metadata.left = 0;
+        new_frame.metadata.top = 0;
+        new_frame.metadata.width = width as u16;
+        new_frame.metadata.height = height as u16;
         new_frames.push(new_frame);
     }
     let new_anim = GifAnimation{
         frames: new_frames,
+        width: width as u16,
+        height: height as u16,
         // Copy the rest of animation data
         // (can't use struct unpacking as it requires ownership of the source).
-        width: orig_anim.width,
-        height: orig_anim.height,
         palette: orig_anim.palette.clone(),
         bg_color: orig_anim.bg_color,
     };
 
-    encode(&new_anim, quality, output)
+    encode_with_hook(&new_anim, options, output, on_frame)
+}
+
+/// Encode an animated GIF made of given images (with their display durations),
+/// using given encoding options.
+///
+/// All images must be of the same size.
+#[inline]
+pub fn encode_images<W: Write>(images: Vec<(DynamicImage, Duration)>,
+                               options: &EncodeOptions,
+                               output: W) -> io::Result<()> {
+    encode_images_with_hook(images, options, output, &|_| Ok(()))
+}
+
+/// Encode an animated GIF made of given images (with their display durations),
+/// calling given function with the index of every frame before it's encoded.
+///
+/// See `encode_images` and `encode_with_hook` for details.
+pub fn encode_images_with_hook<W: Write>(images: Vec<(DynamicImage, Duration)>,
+                                         options: &EncodeOptions,
+                                         output: W,
+                                         on_frame: &(Fn(usize) -> io::Result<()> + Sync))
+                                         -> io::Result<()> {
+    let (width, height) = images.first().map(|&(ref img, _)| img.dimensions())
+        .unwrap_or((0, 0));
+    check_frame_sizes(images.iter().map(|&(ref img, _)| img), (width, height))?;
+
+    let mut frames = vec![];
+    for (image, delay) in images {
+        let mut metadata = gif::Frame::default();
+        metadata.delay = gif_delay(delay);
+        metadata.width = width as u16;
+        metadata.height = height as u16;
+        frames.push(GifFrame{image, metadata});
+    }
+    let anim = GifAnimation{
+        frames,
+        width: width as u16,
+        height: height as u16,
+        palette: vec![],
+        bg_color: None,
+    };
+
+    encode_with_hook(&anim, options, output, on_frame)
+}
+
+/// Check that all the frame images have given size, and that it fits in a GIF.
+fn check_frame_sizes<'i, I>(images: I, size: (u32, u32)) -> io::Result<()>
+    where I: Iterator<Item=&'i DynamicImage>
+{
+    let (width, height) = size;
+    if width > u16::max_value() as u32 || height > u16::max_value() as u32 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            format!("{}x{} image is too large for a GIF", width, height)));
+    }
+    for (i, image) in images.enumerate() {
+        if image.dimensions() != size {
+            let (w, h) = image.dimensions();
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+                "all frames of GIF animation must have the same size \
+                 (frame #{} is {}x{} rather than {}x{})", i, w, h, width, height)));
+        }
+    }
+    Ok(())
+}
+
+/// Convert frame duration to GIF delay, which is expressed in 10ms units.
+fn gif_delay(delay: Duration) -> u16 {
+    let delay_ms = delay.as_secs() * 1000 + delay.subsec_nanos() as u64 / 1_000_000;
+    (delay_ms / 10).min(u16::max_value() as u64) as u16
+}
+
+/// Quantize the frames of an animation for the optimized encoding.
+///
+/// Returns the global palette & the GIF frames to write.
+fn optimize_frames(anim: &GifAnimation, options: &EncodeOptions,
+                   on_frame: &(Fn(usize) -> io::Result<()> + Sync))
+                   -> io::Result<(Vec<u8>, Vec<gif::Frame<'static>>)> {
+    let (width, height) = (anim.width as usize, anim.height as usize);
+
+    // Merge identical consecutive frames, adding up their delays.
+    let mut frames: Vec<(usize, Vec<u8>, &GifFrame, u16)> = vec![];
+    for (i, frame) in anim.iter_frames().enumerate() {
+        let pixels = frame.image.to_rgba().into_raw();
+        if let Some(last) = frames.last_mut() {
+            if last.1 == pixels {
+                last.3 = last.3.saturating_add(frame.metadata.delay);
+                continue;
+            }
+        }
+        frames.push((i, pixels, frame, frame.metadata.delay));
+    }
+    trace!("Merged {} GIF frame(s) into {}", anim.frames_count(), frames.len());
+
+    let global = Palette::new(&sample_pixels(frames.iter().map(|f| &f.1[..])),
+                              options.quality, &options.reserved_colors);
+    let global_palette = global.to_rgb();
+
+    // Encoding just the changed parts of frames relies on them being drawn
+    // over the previous ones, which makes it impossible for pixels to become transparent.
+    let has_transparency = frames.iter()
+        .any(|f| f.1.chunks(RGBA_SIZE_BYTES).any(is_transparent));
+
+    // Quantizing the frames is independent of each other, so it can be done in parallel.
+    let frames = parallel::map(frames, options.parallelism, |(i, pixels, orig_frame, delay)| {
+        on_frame(i)?;
+        let mut indices = global.quantize(&pixels, width, options.dithering);
+        let mut local = None;
+        if mean_error(&global_palette, &pixels, &indices) > MAX_GLOBAL_PALETTE_ERROR {
+            trace!("Using local palette for GIF frame #{}", i + 1);
+            let palette = Palette::new(&sample_pixels(Some(&pixels[..])),
+                                       options.quality, &options.reserved_colors);
+            indices = palette.quantize(&pixels, width, options.dithering);
+            local = Some(palette.to_rgb());
+        }
+        Ok((orig_frame, delay, indices, local))
+    });
+    let frames = frames.into_iter().collect::<io::Result<Vec<_>>>()?;
+
+    let mut canvas: Option<Vec<Option<[u8; 3]>>> = None;
+    let mut result: Vec<gif::Frame<'static>> = vec![];
+    for (orig_frame, delay, mut indices, local) in frames {
+
+        let mut gif_frame = orig_frame.metadata.clone();
+        gif_frame.delay = delay;
+        gif_frame.transparent = Some(TRANSPARENT_INDEX);
+        gif_frame.left = 0;
+        gif_frame.top = 0;
+        gif_frame.width = width as u16;
+        gif_frame.height = height as u16;
+
+        if !has_transparency {
+            let colors: Vec<_> = {
+                let palette = local.as_ref().unwrap_or(&global_palette);
+                indices.iter().map(|&i| color_at(palette, i)).collect()
+            };
+            if let Some(ref previous) = canvas {
+                match changed_rect(previous, &colors, width) {
+                    Some((x, y, w, h)) => {
+                        let mut buffer = Vec::with_capacity(w * h);
+                        for cy in y..y + h {
+                            for cx in x..x + w {
+                                let i = cy * width + cx;
+                                buffer.push(if previous[i] == colors[i] { TRANSPARENT_INDEX }
+                                            else { indices[i] });
+                            }
+                        }
+                        indices = buffer;
+                        gif_frame.left = x as u16;
+                        gif_frame.top = y as u16;
+                        gif_frame.width = w as u16;
+                        gif_frame.height = h as u16;
+                    }
+                    None => {
+                        // Frame looks just like the previous one after quantization.
+                        if let Some(last) = result.last_mut() {
+                            last.delay = last.delay.saturating_add(delay);
+                        }
+                        continue;
+                    }
+                }
+            }
+            gif_frame.dispose = gif::DisposalMethod::Keep;
+            canvas = Some(colors);
+        } else {
+            // Frames cover the whole canvas, so the previous one needs to be cleared
+            // for the transparent pixels to actually be transparent.
+            gif_frame.dispose = gif::DisposalMethod::Background;
+        }
+
+        gif_frame.buffer = indices.into();
+        gif_frame.palette = local;
+        result.push(gif_frame);
+    }
+    Ok((global_palette, result))
+}
+
+/// Palette index reserved for transparent pixels.
+const TRANSPARENT_INDEX: u8 = 255;
+
+/// Whether the RGBA pixel should be encoded as transparent.
+///
+/// GIF transparency is binary, so semi-transparent pixels
+/// (e.g. antialiased edges) are rounded to whichever is closer.
+#[inline]
+fn is_transparent(pix: &[u8]) -> bool {
+    pix[3] < ALPHA_THRESHOLD
+}
+const ALPHA_THRESHOLD: u8 = 128;
+/// Maximum number of pixels used to compute the global palette.
+const MAX_SAMPLE_PIXELS: usize = 1 << 20;
+/// Maximum mean squared error of a frame's colors in the global palette
+/// before it gets a palette of its own.
+const MAX_GLOBAL_PALETTE_ERROR: f64 = 100.0;
+
+/// Collect (a sample of) opaque pixels from given RGBA buffers, for building a palette.
+fn sample_pixels<'p, I: IntoIterator<Item=&'p [u8]>>(buffers: I) -> Vec<u8> {
+    let buffers: Vec<_> = buffers.into_iter().collect();
+    let total = buffers.iter().map(|b| b.len() / RGBA_SIZE_BYTES).sum::<usize>();
+    let step = max(1, total / MAX_SAMPLE_PIXELS);
+
+    let mut sample = vec![];
+    for buffer in buffers {
+        let pixels = buffer.chunks(RGBA_SIZE_BYTES).enumerate()
+            .filter(|&(i, _)| i % step == 0).map(|(_, pix)| pix);
+        for pix in pixels {
+            if !is_transparent(pix) {
+                sample.extend_from_slice(&[pix[0], pix[1], pix[2], 0xff]);
+            }
+        }
+    }
+    if sample.is_empty() {
+        sample.extend_from_slice(&[0, 0, 0, 0xff]);
+    }
+    sample
+}
+
+/// GIF palette consisting of the reserved colors, followed by those
+/// computed by the color quantizer, and finally the transparent color.
+struct Palette {
+    reserved: Vec<[u8; 3]>,
+    quantizer: NeuQuant,
+    /// All the colors of the palette, as contiguous RGB bytes.
+    colors: Vec<u8>,
+}
+
+impl Palette {
+    /// Build the palette for given (sample of) pixels.
+    fn new(sample: &[u8], quality: u8, reserved: &[[u8; 3]]) -> Self {
+        let mut unique: Vec<[u8; 3]> = vec![];
+        for &color in reserved {
+            if !unique.contains(&color) {
+                unique.push(color);
+            }
+        }
+        let mut reserved = unique;
+        reserved.truncate(MAX_RESERVED_COLORS);
+
+        let size = TRANSPARENT_INDEX as usize - reserved.len();
+        let quantizer = NeuQuant::new(color_faction(quality), size, sample);
+
+        let mut colors: Vec<u8> = reserved.iter().flat_map(|c| c.iter().cloned()).collect();
+        colors.extend(quantizer.color_map_rgb());
+        colors.resize(256 * RGB_SIZE_BYTES, 0);  // the (black) transparent color
+        Palette{reserved, quantizer, colors}
+    }
+
+    /// The palette as contiguous RGB bytes.
+    #[inline]
+    fn to_rgb(&self) -> Vec<u8> {
+        self.colors.clone()
+    }
+
+    /// Palette index for given opaque color.
+    fn index_of(&self, rgb: [u8; 3]) -> u8 {
+        match self.reserved.iter().position(|&c| c == rgb) {
+            Some(i) => i as u8,
+            None => (self.reserved.len() +
+                self.quantizer.index_of(&[rgb[0], rgb[1], rgb[2], 0xff])) as u8,
+        }
+    }
+
+    /// Map RGBA pixels of an image with given width to palette indices.
+    fn quantize(&self, pixels: &[u8], width: usize, dithering: Dithering) -> Vec<u8> {
+        match dithering {
+            Dithering::None => pixels.chunks(RGBA_SIZE_BYTES).map(|pix| {
+                if is_transparent(pix) { TRANSPARENT_INDEX }
+                else { self.index_of([pix[0], pix[1], pix[2]]) }
+            }).collect(),
+            Dithering::FloydSteinberg => self.quantize_floyd_steinberg(pixels, width),
+            Dithering::Ordered => self.quantize_ordered(pixels, width),
+        }
+    }
+
+    fn quantize_floyd_steinberg(&self, pixels: &[u8], width: usize) -> Vec<u8> {
+        let mut result = Vec::with_capacity(pixels.len() / RGBA_SIZE_BYTES);
+        // Errors carried over to the current & the next row.
+        let mut errors = vec![[0f32; 3]; width + 2];
+        let mut next_errors = vec![[0f32; 3]; width + 2];
+        for row in pixels.chunks(width * RGBA_SIZE_BYTES) {
+            for (x, pix) in row.chunks(RGBA_SIZE_BYTES).enumerate() {
+                let rgb = [pix[0], pix[1], pix[2]];
+                if is_transparent(pix) || self.reserved.contains(&rgb) {
+                    // Transparent & reserved colors are kept exactly and don't spread any error.
+                    result.push(if is_transparent(pix) { TRANSPARENT_INDEX }
+                                else { self.index_of(rgb) });
+                    continue;
+                }
+                let mut wanted = [0f32; 3];
+                for c in 0..3 {
+                    wanted[c] = (rgb[c] as f32 + errors[x + 1][c]).max(0.0).min(255.0);
+                }
+                let index = self.index_of([wanted[0] as u8, wanted[1] as u8, wanted[2] as u8]);
+                result.push(index);
+
+                let actual = &self.colors[index as usize * RGB_SIZE_BYTES..];
+                for c in 0..3 {
+                    let error = wanted[c] - actual[c] as f32;
+                    errors[x + 2][c] += error * 7.0 / 16.0;
+                    next_errors[x][c] += error * 3.0 / 16.0;
+                    next_errors[x + 1][c] += error * 5.0 / 16.0;
+                    next_errors[x + 2][c] += error * 1.0 / 16.0;
+                }
+            }
+            errors = next_errors;
+            next_errors = vec![[0f32; 3]; width + 2];
+        }
+        result
+    }
+
+    fn quantize_ordered(&self, pixels: &[u8], width: usize) -> Vec<u8> {
+        pixels.chunks(RGBA_SIZE_BYTES).enumerate().map(|(i, pix)| {
+            let rgb = [pix[0], pix[1], pix[2]];
+            if is_transparent(pix) {
+                return TRANSPARENT_INDEX;
+            }
+            if self.reserved.contains(&rgb) {
+                return self.index_of(rgb);
+            }
+            let (x, y) = (i % width, i / width);
+            let threshold = (BAYER_MATRIX[y % 4][x % 4] as f32 + 0.5) / 16.0 - 0.5;
+            let offset = threshold * ORDERED_DITHERING_SPREAD;
+            let mut dithered = [0u8; 3];
+            for c in 0..3 {
+                dithered[c] = (rgb[c] as f32 + offset).max(0.0).min(255.0) as u8;
+            }
+            self.index_of(dithered)
+        }).collect()
+    }
+}
+
+const BAYER_MATRIX: [[u8; 4]; 4] = [
+    [ 0,  8,  2, 10],
+    [12,  4, 14,  6],
+    [ 3, 11,  1,  9],
+    [15,  7, 13,  5],
+];
+/// How far (in color values) can the ordered dithering shift the pixels.
+const ORDERED_DITHERING_SPREAD: f32 = 32.0;
+
+/// Mean squared error of opaque pixels after they've been quantized to given palette.
+fn mean_error(palette: &[u8], pixels: &[u8], indices: &[u8]) -> f64 {
+    let mut total = 0f64;
+    let mut count = 0;
+    for (pix, &index) in pixels.chunks(RGBA_SIZE_BYTES).zip(indices) {
+        if let Some(color) = color_at(palette, index) {
+            total += (0..3).map(|c| (pix[c] as f64 - color[c] as f64).powi(2)).sum::<f64>();
+            count += 1;
+        }
+    }
+    if count > 0 { total / count as f64 } else { 0.0 }
+}
+
+/// Color at given palette index, or None if it's the transparent one.
+fn color_at(palette: &[u8], index: u8) -> Option<[u8; 3]> {
+    if index == TRANSPARENT_INDEX {
+        return None;
+    }
+    let i = index as usize * RGB_SIZE_BYTES;
+    Some([palette[i], palette[i + 1], palette[i + 2]])
+}
+
+/// Find the bounding rectangle (x, y, width, height) of pixels that differ
+/// between two canvases of given width.
+fn changed_rect<T: PartialEq>(previous: &[T], current: &[T],
+                              width: usize) -> Option<(usize, usize, usize, usize)> {
+    let mut rect: Option<(usize, usize, usize, usize)> = None;  // min & max coordinates
+    for (i, (p, c)) in previous.iter().zip(current).enumerate() {
+        if p != c {
+            let (x, y) = (i % width, i / width);
+            rect = Some(match rect {
+                None => (x, y, x, y),
+                Some((x0, y0, x1, y1)) => (x0.min(x), y0.min(y), x1.max(x), y1.max(y)),
+            });
+        }
+    }
+    rect.map(|(x0, y0, x1, y1)| (x0, y0, x1 - x0 + 1, y1 - y0 + 1))
 }
 
 /// Low-level function that performs color quantization of an image.
@@ -336,33 +972,31 @@ pub fn encode_modified<W: Write>(orig_anim: &GifAnimation,
 /// * `palette` is a contiguous buffer of RGB colors in the palette used
 /// * `transparent` is optional palette index of the transparent color
 pub fn quantize_image(image: &DynamicImage, quality: u8) -> (Vec<u8>, Vec<u8>, Option<u8>) {
-    let mut pixels = image.raw_pixels().to_owned();
-
-    //
-    // This is essentially gif::Frame::from_rgba().
-    // The code is lifted here so that we can adjust the crucial COLOR_SAMPLE_FACTION
-    // passed to the color quantizer.
-    // (The original default of 1 makes it way too slow for most practical purposes).
-    //
-
-    let mut transparent = None;
-    for pix in pixels.chunks_mut(4) {
-        if pix[3] != 0 {
-            pix[3] = 0xFF;
-        } else {
-            transparent = Some([pix[0], pix[1], pix[2], pix[3]])
-        }
-    }
-
-    let quantizer = NeuQuant::new(color_faction(quality), 256, &pixels[..]);
+    quantize_image_with(image, quality, Dithering::None, &[])
+}
 
-    let buffer = pixels.chunks(RGBA_SIZE_BYTES)
-        .map(|pix| quantizer.index_of(pix) as u8)
-        .collect();
-    let palette = quantizer.color_map_rgb();
-    let transparent = transparent.map(|t| quantizer.index_of(&t) as u8);
+/// Perform color quantization of an image, using given dithering method
+/// and making sure that the reserved colors are present in the palette exactly.
+///
+/// Returns the same values as `quantize_image`.
+/// Transparent pixels always get a palette entry of their own,
+/// so that no opaque pixel becomes transparent (or vice versa).
+pub fn quantize_image_with(image: &DynamicImage, quality: u8, dithering: Dithering,
+                           reserved_colors: &[[u8; 3]]) -> (Vec<u8>, Vec<u8>, Option<u8>) {
+    let pixels = image.to_rgba().into_raw();
+    let width = image.width() as usize;
 
-    (buffer, palette, transparent)
+    // Unlike gif::Frame::from_rgba(), we compute the palette from a sample
+    // with adjustable COLOR_SAMPLE_FACTION, as the default of 1 makes it
+    // way too slow for most practical purposes.
+    let palette = Palette::new(&sample_pixels(Some(&pixels[..])), quality, reserved_colors);
+    let buffer = palette.quantize(&pixels, width, dithering);
+    let transparent = if buffer.contains(&TRANSPARENT_INDEX) {
+        Some(TRANSPARENT_INDEX)
+    } else {
+        None
+    };
+    (buffer, palette.to_rgb(), transparent)
 }
 
 /// Convert the user-facing quality percentage value (1-100)
@@ -430,8 +1064,15 @@ const BGCOLOR_OFFSET: usize = 11;
 
 #[cfg(test)]
 mod tests {
+    use std::io;
+    use std::path::Path;
+    use gif;
+    use image::{DynamicImage, Rgba, RgbaImage};
     use spectral::prelude::*;
-    use super::{color_faction, MAX_COLOR_SAMPLE_FACTION};
+    use super::{color_at, color_faction, decode, decode_from_file, encode_modified,
+                encode_with_options, optimize_frames, quantize_image, quantize_image_with,
+                DecodeError, Dithering, EncodeOptions, GifAnimation, GifFrame, ReservedColors,
+                MAX_COLOR_SAMPLE_FACTION, MAX_RESERVED_COLORS};
 
     #[test]
     fn color_faction_edges() {
@@ -457,4 +1098,251 @@ mod tests {
         sorted_cfs.sort_by(|a, b| b.cmp(a));
         assert_that!(cfs.iter()).equals_iterator(&sorted_cfs.iter());
     }
+
+    fn animation(frames: Vec<(RgbaImage, u16)>) -> GifAnimation {
+        let (width, height) = frames[0].0.dimensions();
+        let frames = frames.into_iter().map(|(img, delay)| {
+            let mut metadata = gif::Frame::default();
+            metadata.delay = delay;
+            metadata.width = width as u16;
+            metadata.height = height as u16;
+            GifFrame{image: DynamicImage::ImageRgba8(img), metadata}
+        }).collect();
+        GifAnimation{
+            width: width as u16, height: height as u16,
+            palette: vec![], bg_color: None, frames,
+        }
+    }
+
+    #[test]
+    fn identical_frames_are_merged() {
+        let gray = RgbaImage::from_pixel(8, 8, Rgba{data: [128, 128, 128, 255]});
+        let mut dotted = gray.clone();
+        dotted.put_pixel(3, 2, Rgba{data: [255, 0, 0, 255]});
+        let anim = animation(vec![
+            (gray.clone(), 5), (gray.clone(), 5), (gray, 10), (dotted, 7),
+        ]);
+
+        let options = EncodeOptions{quality: 100, optimize: true, ..EncodeOptions::default()};
+        let (palette, frames) = optimize_frames(&anim, &options, &|_| Ok(())).unwrap();
+        assert_eq!(256 * 3, palette.len());
+        assert_eq!(2, frames.len());
+        assert_eq!(20, frames[0].delay);
+        assert_eq!(7, frames[1].delay);
+        // Only the changed pixel is encoded in the second frame.
+        assert_eq!((3, 2, 1, 1),
+            (frames[1].left, frames[1].top, frames[1].width, frames[1].height));
+    }
+
+    #[test]
+    fn optimized_templates_are_smaller() {
+        let templates = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../data/templates");
+        for name in &["badger.gif", "slowclap.gif", "smart.gif"] {
+            let anim = decode_from_file(templates.join(name)).unwrap();
+
+            let mut plain = vec![];
+            let options = EncodeOptions{optimize: false, ..EncodeOptions::default()};
+            encode_with_options(&anim, &options, &mut plain).unwrap();
+            let mut optimized = vec![];
+            let options = EncodeOptions{optimize: true, ..options};
+            encode_with_options(&anim, &options, &mut optimized).unwrap();
+
+            assert!(optimized.len() < plain.len(),
+                "optimized {} is {} bytes, unoptimized is {} bytes",
+                name, optimized.len(), plain.len());
+            let reloaded = decode(&mut &optimized[..]).unwrap();
+            assert!(0 < reloaded.frames_count() && reloaded.frames_count() <= anim.frames_count());
+        }
+    }
+
+    #[test]
+    fn mismatched_frames_are_rejected() {
+        let gray = RgbaImage::from_pixel(8, 8, Rgba{data: [128, 128, 128, 255]});
+        let anim = animation(vec![(gray.clone(), 5), (gray, 5)]);
+        let img = |w, h| DynamicImage::ImageRgba8(RgbaImage::new(w, h));
+
+        // Wrong number of frames.
+        assert!(encode_modified(&anim, vec![img(8, 8)], 60, io::sink()).is_err());
+        // Frames of different sizes.
+        assert!(encode_modified(&anim, vec![img(8, 8), img(4, 4)], 60, io::sink()).is_err());
+        // Resized frames are fine, though.
+        assert!(encode_modified(&anim, vec![img(4, 4), img(4, 4)], 60, io::sink()).is_ok());
+    }
+
+    #[test]
+    fn parallel_encoding_is_identical() {
+        let templates = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../data/templates");
+        let anim = decode_from_file(templates.join("badger.gif")).unwrap();
+        for &optimize in &[false, true] {
+            let mut sequential = vec![];
+            let options = EncodeOptions{optimize, parallelism: 1, ..EncodeOptions::default()};
+            encode_with_options(&anim, &options, &mut sequential).unwrap();
+            let mut parallel = vec![];
+            let options = EncodeOptions{parallelism: 4, ..options};
+            encode_with_options(&anim, &options, &mut parallel).unwrap();
+            assert!(sequential == parallel, "optimize: {}", optimize);
+        }
+    }
+
+    /// Image where the left half is transparent and the right one is opaque,
+    /// using the same color for both.
+    fn half_transparent(width: u32, height: u32) -> RgbaImage {
+        RgbaImage::from_fn(width, height, |x, _| {
+            Rgba{data: [0, 0, 0, if x < width / 2 { 0 } else { 0xff }]}
+        })
+    }
+
+    #[test]
+    fn transparent_color_is_distinct() {
+        let img = DynamicImage::ImageRgba8(half_transparent(4, 4));
+        let (buffer, _, transparent) = quantize_image(&img, 60);
+        let transparent = transparent.expect("transparent color");
+        for (i, &index) in buffer.iter().enumerate() {
+            assert_eq!(i % 4 < 2, index == transparent, "pixel #{}", i);
+        }
+    }
+
+    #[test]
+    fn transparency_survives_encoding() {
+        let opaque = RgbaImage::from_pixel(4, 4, Rgba{data: [0xff, 0, 0, 0xff]});
+        let anim = animation(vec![(opaque, 10), (half_transparent(4, 4), 10)]);
+        for &optimize in &[false, true] {
+            let mut bytes = vec![];
+            let options = EncodeOptions{optimize, ..EncodeOptions::default()};
+            encode_with_options(&anim, &options, &mut bytes).unwrap();
+
+            let decoded = decode(&mut &bytes[..]).unwrap();
+            let frames: Vec<_> = decoded.iter_frames().map(|f| f.image.to_rgba()).collect();
+            assert_eq!(2, frames.len());
+            assert_eq!(0xff, frames[0].get_pixel(0, 0).data[3]);
+            // Pixels which were opaque in the previous frame must not show through.
+            assert_eq!(0, frames[1].get_pixel(0, 0).data[3], "optimize: {}", optimize);
+            assert_eq!(0xff, frames[1].get_pixel(3, 0).data[3], "optimize: {}", optimize);
+        }
+    }
+
+    #[test]
+    fn oversized_screen_is_rejected() {
+        let mut bytes = b"GIF89a".to_vec();
+        bytes.extend_from_slice(&[0xff, 0xff, 0xff, 0xff, 0, 0, 0]);  // 65535x65535, no palette
+        bytes.push(0x3b);  // trailer
+        match decode(&mut &bytes[..]) {
+            Err(DecodeError::TooLarge(_)) => {}
+            r => panic!("unexpected result: {:?}", r.map(|a| a.frames_count())),
+        }
+    }
+
+    #[test]
+    fn transparent_template_survives_encoding() {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../data/fixtures");
+        // Left half of every frame is transparent, right half is opaque.
+        let anim = decode_from_file(fixtures.join("transparent-animated.gif")).unwrap();
+        for &dithering in DITHERINGS {
+            for &optimize in &[false, true] {
+                let mut bytes = vec![];
+                let options = EncodeOptions{optimize, dithering, ..EncodeOptions::default()};
+                encode_with_options(&anim, &options, &mut bytes).unwrap();
+
+                let decoded = decode(&mut &bytes[..]).unwrap();
+                assert_eq!(anim.frames_count(), decoded.frames_count());
+                for frame in decoded.iter_frames() {
+                    let img = frame.image.to_rgba();
+                    let (width, height) = img.dimensions();
+                    for (x, _, pixel) in img.enumerate_pixels() {
+                        assert_eq!(if x < width / 2 { 0 } else { 0xff }, pixel.data[3],
+                            "{:?}, optimize: {}, {}x{}", dithering, optimize, width, height);
+                    }
+                }
+            }
+        }
+    }
+
+    const DITHERINGS: &'static [Dithering] =
+        &[Dithering::None, Dithering::FloydSteinberg, Dithering::Ordered];
+
+    /// Horizontal gradient of grays, from black to white.
+    fn gradient(width: u32, height: u32) -> RgbaImage {
+        RgbaImage::from_fn(width, height, |x, _| {
+            let v = (x * 255 / (width - 1)) as u8;
+            Rgba{data: [v, v, v, 0xff]}
+        })
+    }
+
+    #[test]
+    fn reserved_colors_are_exact() {
+        let reserved = [[0x12, 0x34, 0x56], [0xff, 0xff, 0xff]];
+        let mut img = gradient(64, 8);
+        for x in 0..64 {
+            let color = reserved[x as usize % 2];
+            img.put_pixel(x, 3, Rgba{data: [color[0], color[1], color[2], 0xff]});
+        }
+        let img = DynamicImage::ImageRgba8(img);
+
+        for &dithering in DITHERINGS {
+            let (buffer, palette, _) = quantize_image_with(&img, 1, dithering, &reserved);
+            assert_eq!(Some(reserved[0]), color_at(&palette, 0), "{:?}", dithering);
+            assert_eq!(Some(reserved[1]), color_at(&palette, 1), "{:?}", dithering);
+            for x in 0..64 {
+                let index = buffer[3 * 64 + x];
+                assert_eq!(Some(reserved[x % 2]), color_at(&palette, index),
+                    "{:?}, pixel #{}", dithering, x);
+            }
+        }
+    }
+
+    #[test]
+    fn reserved_color_set() {
+        let colors: Vec<_> = (0..40).map(|i| [i as u8 / 2, 0, 0]).collect();
+        let reserved = ReservedColors::new(&colors);
+        assert_eq!(MAX_RESERVED_COLORS, reserved.len());  // duplicates skipped, rest ignored
+        assert_eq!([0, 0, 0], reserved[0]);
+        assert_eq!([1, 0, 0], reserved[1]);
+        assert!(ReservedColors::default().is_empty());
+    }
+
+    #[test]
+    fn dithering_keeps_transparency() {
+        let img = DynamicImage::ImageRgba8(half_transparent(8, 8));
+        for &dithering in DITHERINGS {
+            let (buffer, _, transparent) = quantize_image_with(&img, 60, dithering, &[]);
+            let transparent = transparent.expect("transparent color");
+            for (i, &index) in buffer.iter().enumerate() {
+                assert_eq!(i % 8 < 4, index == transparent, "{:?}, pixel #{}", dithering, i);
+            }
+        }
+    }
+
+    #[test]
+    fn ordered_dithering_tiles_the_pattern() {
+        // Every pixel has the same color, so the result only depends
+        // on the position within the 4x4 dithering matrix.
+        let img = DynamicImage::ImageRgba8(
+            RgbaImage::from_pixel(16, 16, Rgba{data: [0x70, 0x90, 0x30, 0xff]}));
+        let (buffer, _, _) = quantize_image_with(&img, 60, Dithering::Ordered, &[]);
+        for y in 0..12 {
+            for x in 0..12 {
+                let index = buffer[y * 16 + x];
+                assert_eq!(index, buffer[y * 16 + x + 4], "pixel ({}, {})", x, y);
+                assert_eq!(index, buffer[(y + 4) * 16 + x], "pixel ({}, {})", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn floyd_steinberg_preserves_average() {
+        let img = gradient(256, 16);
+        let pixels = img.clone().into_raw();
+        let (buffer, palette, _) = quantize_image_with(
+            &DynamicImage::ImageRgba8(img), 10, Dithering::FloydSteinberg, &[]);
+
+        // Error diffusion keeps the average brightness of each row.
+        for (y, row) in buffer.chunks(256).enumerate() {
+            let original: f64 = pixels[y * 256 * 4..(y + 1) * 256 * 4].chunks(4)
+                .map(|pix| pix[0] as f64).sum::<f64>() / 256.0;
+            let quantized: f64 = row.iter()
+                .map(|&i| color_at(&palette, i).unwrap()[0] as f64).sum::<f64>() / 256.0;
+            assert!((original - quantized).abs() < 2.0,
+                "row #{}: {} vs. {}", y, original, quantized);
+        }
+    }
 }
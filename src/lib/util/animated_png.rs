@@ -0,0 +1,603 @@
+//! Module handling the decoding & encoding of animated PNGs (APNG).
+//!
+//! The `image` crate only understands the default image of an APNG file,
+//! so we parse the animation chunks ourselves and have it decode
+//! every frame as a separate PNG image. Encoding works in reverse:
+//! frames are encoded as regular PNGs and their image data is then
+//! repackaged into animation chunks.
+//!
+//! See https://wiki.mozilla.org/APNG_Specification for the details of the format.
+
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use image::{self, DynamicImage, GenericImage, ImageFormat, Pixel, Rgba, RgbaImage};
+
+use super::animation;
+
+
+// Data structures
+
+/// Animation loaded from an APNG file.
+/// The frames are kept in their decoded (RGBA) form, each covering the whole canvas.
+#[derive(Clone)]
+pub struct PngAnimation {
+    /// Width of the animation canvas.
+    pub width: u32,
+    /// Height of the animation canvas.
+    pub height: u32,
+    /// How many times the animation should be played (0 means forever).
+    pub num_plays: u32,
+    /// Animation frames.
+    frames: Vec<PngFrame>,
+}
+
+impl PngAnimation {
+    /// Create an animation from given frames.
+    ///
+    /// All frames must be of the same size.
+    pub fn new(frames: Vec<PngFrame>) -> Self {
+        let (width, height) = frames.first()
+            .map(|f| f.image.dimensions()).unwrap_or((0, 0));
+        for f in &frames {
+            assert_eq!((width, height), f.image.dimensions(),
+                "all frames of PNG animation must have the same size");
+        }
+        PngAnimation{width, height, num_plays: 0, frames}
+    }
+
+    /// How many frames there are in the animation.
+    #[inline]
+    pub fn frames_count(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Iterate over the frames in animation.
+    #[inline]
+    pub fn iter_frames<'a>(&'a self) -> Box<Iterator<Item=&'a PngFrame> + 'a> {
+        Box::new(self.frames.iter())
+    }
+
+    /// Create a new animation out of (possibly repeated) frames of this one,
+    /// given by their indices and the durations they should now be displayed for.
+    pub fn rearrange<I>(&self, frames: I) -> PngAnimation
+        where I: IntoIterator<Item=(usize, Duration)>
+    {
+        let frames = frames.into_iter().map(|(index, delay)| PngFrame{
+            delay, ..self.frames[index].clone()
+        }).collect();
+        PngAnimation{width: self.width, height: self.height, num_plays: self.num_plays, frames}
+    }
+}
+
+impl fmt::Debug for PngAnimation {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("PngAnimation")
+            .field("width", &self.width)
+            .field("height", &self.height)
+            .field("num_plays", &self.num_plays)
+            .field("frames", &format_args!("<{} frames>", self.frames.len()))
+            .finish()
+    }
+}
+
+
+/// A single frame of an animated PNG.
+#[derive(Clone)]
+pub struct PngFrame {
+    /// The image of the frame.
+    pub image: DynamicImage,
+    /// How long the frame is displayed for.
+    pub delay: Duration,
+}
+
+impl fmt::Debug for PngFrame {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let (w, h) = self.image.dimensions();
+        fmt.debug_struct("PngFrame")
+            .field("image", &format_args!("{}x{}", w, h))
+            .field("delay", &self.delay)
+            .finish()
+    }
+}
+
+
+// Checking PNG properties
+
+/// Check if the path points to a PNG file.
+pub fn is_png<P: AsRef<Path>>(path: P) -> bool {
+    let path = path.as_ref();
+    trace!("Checking if {} is a PNG", path.display());
+    path.extension().and_then(|s| s.to_str())
+        .map(|ext| ext.to_lowercase() == "png").unwrap_or(false)
+}
+
+/// Check if given PNG image is animated.
+/// Returns None if it cannot be determined (e.g. file doesn't exist).
+pub fn is_png_animated<P: AsRef<Path>>(path: P) -> Option<bool> {
+    let path = path.as_ref();
+    trace!("Checking if {} is an animated PNG", path.display());
+
+    let mut bytes = vec![];
+    try_opt!(File::open(path).and_then(|mut f| f.read_to_end(&mut bytes)).map_err(|e| {
+        warn!("Failed to read file {} to check if it's animated PNG: {}",
+            path.display(), e); e
+    }).ok());
+    is_animated(&bytes)
+}
+
+/// Check if given PNG data is an animation.
+/// Returns None if it cannot be determined (e.g. the data isn't a valid PNG).
+pub fn is_animated(bytes: &[u8]) -> Option<bool> {
+    let chunks = try_opt!(parse_chunks(bytes).ok());
+
+    // The animation control chunk must appear before the image data.
+    for (kind, data) in chunks {
+        match kind {
+            b"acTL" if data.len() >= 8 => {
+                let num_frames = read_u32(data);
+                trace!("Image is an animated PNG with {} frames", num_frames);
+                return Some(num_frames > 1);
+            }
+            b"IDAT" => break,
+            _ => {}
+        }
+    }
+    trace!("Image is a still PNG image");
+    Some(false)
+}
+
+
+// Decoding animated PNGs
+
+/// Error that can occur while decoding animated PNG.
+#[derive(Debug)]
+pub enum DecodeError {
+    /// I/O error encountered when decoding PNG.
+    Io(io::Error),
+    /// Malformed APNG structure.
+    Format(String),
+    /// Error when decoding the image of one of the frames.
+    Image(image::ImageError),
+}
+
+impl From<io::Error> for DecodeError {
+    fn from(inner: io::Error) -> Self {
+        DecodeError::Io(inner)
+    }
+}
+impl From<image::ImageError> for DecodeError {
+    fn from(inner: image::ImageError) -> Self {
+        DecodeError::Image(inner)
+    }
+}
+
+impl Error for DecodeError {
+    fn description(&self) -> &str { "PNG animation decode error" }
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            DecodeError::Io(ref e) => Some(e),
+            DecodeError::Image(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::Io(ref e) => write!(fmt, "I/O error while decoding APNG: {}", e),
+            DecodeError::Format(ref e) => write!(fmt, "malformed APNG file: {}", e),
+            DecodeError::Image(ref e) => write!(fmt, "cannot decode APNG frame: {}", e),
+        }
+    }
+}
+
+
+/// Decode animated PNG from given file.
+pub fn decode_from_file<P: AsRef<Path>>(path: P) -> Result<PngAnimation, DecodeError> {
+    let path = path.as_ref();
+    trace!("Loading animated PNG from {}", path.display());
+    let mut file = File::open(path)?;
+    decode(&mut file)
+}
+
+/// Decode animated PNG from given reader.
+pub fn decode<R: Read>(input: &mut R) -> Result<PngAnimation, DecodeError> {
+    let mut bytes = vec![];
+    input.read_to_end(&mut bytes)?;
+    let chunks = parse_chunks(&bytes)?;
+
+    let mut header: Option<&[u8]> = None;
+    let mut num_plays = 0;
+    let mut shared_chunks = vec![];  // e.g. palette, which all frames need to be decoded
+    let mut frame_controls: Vec<FrameControl> = vec![];
+    let mut frame_data: Vec<Vec<u8>> = vec![];
+    for (kind, data) in chunks {
+        match kind {
+            b"IHDR" => header = Some(data),
+            b"acTL" if data.len() >= 8 => num_plays = read_u32(&data[4..]),
+            b"fcTL" => {
+                frame_controls.push(FrameControl::parse(data)?);
+                frame_data.push(vec![]);
+            }
+            // Image data for the default image is only a part of the animation
+            // if it's been preceded by the frame control chunk.
+            b"IDAT" => if let Some(fd) = frame_data.last_mut() {
+                fd.extend_from_slice(data);
+            },
+            b"fdAT" if data.len() > 4 => match frame_data.last_mut() {
+                Some(fd) => fd.extend_from_slice(&data[4..]),  // skip sequence number
+                None => return Err(DecodeError::Format("fdAT before fcTL".into())),
+            },
+            b"IEND" => break,
+            _ => if frame_controls.is_empty() && is_shared_chunk(kind) {
+                shared_chunks.push((kind, data));
+            },
+        }
+    }
+    let header = header.ok_or_else(|| DecodeError::Format("missing IHDR".into()))?;
+    if header.len() < 13 {
+        return Err(DecodeError::Format("IHDR too short".into()));
+    }
+    let (width, height) = (read_u32(&header[0..]), read_u32(&header[4..]));
+    animation::check_decoding_limits(width, height, frame_controls.len())
+        .map_err(DecodeError::Format)?;
+    for fc in &frame_controls {
+        fc.check_bounds(width, height)?;
+    }
+
+    // Decode the frames and "draw" them on a virtual canvas, applying the
+    // blending & disposal operations, so that each frame covers the whole canvas.
+    let mut canvas = RgbaImage::from_pixel(width, height, TRANSPARENT);
+    let mut frames = Vec::with_capacity(frame_controls.len());
+    for (i, (fc, data)) in frame_controls.iter().zip(frame_data).enumerate() {
+        trace!("Decoding APNG frame #{}: {:?}", i + 1, fc);
+        let mut png = vec![];
+        png.extend_from_slice(PNG_SIGNATURE);
+        let mut frame_header = header.to_vec();
+        write_u32_at(&mut frame_header[0..], fc.width);
+        write_u32_at(&mut frame_header[4..], fc.height);
+        write_chunk(&mut png, b"IHDR", &frame_header);
+        for &(kind, chunk_data) in &shared_chunks {
+            write_chunk(&mut png, kind, chunk_data);
+        }
+        write_chunk(&mut png, b"IDAT", &data);
+        write_chunk(&mut png, b"IEND", &[]);
+        let image = image::load_from_memory_with_format(&png, ImageFormat::PNG)?.to_rgba();
+        if image.dimensions() != (fc.width, fc.height) {
+            return Err(DecodeError::Format(format!(
+                "frame #{} decoded as {}x{} rather than {}x{}",
+                i + 1, image.width(), image.height(), fc.width, fc.height)));
+        }
+
+        let previous = if fc.dispose_op == DISPOSE_OP_PREVIOUS {
+            Some(canvas.clone())
+        } else {
+            None
+        };
+        // The frame is known to lie within the canvas, so this cannot overflow.
+        for (x, y, pixel) in image.enumerate_pixels() {
+            let (cx, cy) = (fc.x_offset + x, fc.y_offset + y);
+            if fc.blend_op == BLEND_OP_OVER {
+                canvas.get_pixel_mut(cx, cy).blend(pixel);
+            } else {
+                canvas.put_pixel(cx, cy, *pixel);
+            }
+        }
+        frames.push(PngFrame{
+            image: DynamicImage::ImageRgba8(canvas.clone()),
+            delay: fc.delay(),
+        });
+
+        match fc.dispose_op {
+            DISPOSE_OP_BACKGROUND => {
+                for y in fc.y_offset..(fc.y_offset + fc.height) {
+                    for x in fc.x_offset..(fc.x_offset + fc.width) {
+                        canvas.put_pixel(x, y, TRANSPARENT);
+                    }
+                }
+            }
+            DISPOSE_OP_PREVIOUS => canvas = previous.unwrap(),
+            _ => {}
+        }
+    }
+    if frames.is_empty() {
+        return Err(DecodeError::Format("no animation frames".into()));
+    }
+
+    debug!("Animated PNG successfully loaded: {}x{} with {} frames",
+        width, height, frames.len());
+    Ok(PngAnimation{width, height, num_plays, frames})
+}
+
+/// Whether the chunk of given type needs to be copied into every frame to decode it.
+fn is_shared_chunk(kind: &[u8]) -> bool {
+    match kind {
+        b"PLTE" | b"tRNS" | b"gAMA" | b"cHRM" | b"sRGB" | b"iCCP" | b"sBIT" => true,
+        _ => false,
+    }
+}
+
+/// Contents of the frame control (fcTL) chunk.
+#[derive(Debug)]
+struct FrameControl {
+    width: u32,
+    height: u32,
+    x_offset: u32,
+    y_offset: u32,
+    delay_num: u16,
+    delay_den: u16,
+    dispose_op: u8,
+    blend_op: u8,
+}
+
+impl FrameControl {
+    fn parse(data: &[u8]) -> Result<Self, DecodeError> {
+        if data.len() < 26 {
+            return Err(DecodeError::Format("fcTL too short".into()));
+        }
+        // The first 4 bytes are the sequence number.
+        Ok(FrameControl{
+            width: read_u32(&data[4..]),
+            height: read_u32(&data[8..]),
+            x_offset: read_u32(&data[12..]),
+            y_offset: read_u32(&data[16..]),
+            delay_num: read_u16(&data[20..]),
+            delay_den: read_u16(&data[22..]),
+            dispose_op: data[24],
+            blend_op: data[25],
+        })
+    }
+
+    /// Check that the frame is not empty, and lies entirely within a canvas of given size.
+    fn check_bounds(&self, width: u32, height: u32) -> Result<(), DecodeError> {
+        let right = self.x_offset.checked_add(self.width);
+        let bottom = self.y_offset.checked_add(self.height);
+        let within = match (right, bottom) {
+            (Some(r), Some(b)) => r <= width && b <= height,
+            _ => false,
+        };
+        if self.width == 0 || self.height == 0 || !within {
+            return Err(DecodeError::Format(format!(
+                "frame {}x{}+{}+{} doesn't fit within the {}x{} canvas",
+                self.width, self.height, self.x_offset, self.y_offset, width, height)));
+        }
+        Ok(())
+    }
+
+    fn delay(&self) -> Duration {
+        // Zero denominator means hundredths of a second.
+        let den = if self.delay_den == 0 { 100 } else { self.delay_den as u64 };
+        Duration::from_millis(self.delay_num as u64 * 1000 / den)
+    }
+}
+
+const DISPOSE_OP_BACKGROUND: u8 = 1;
+const DISPOSE_OP_PREVIOUS: u8 = 2;
+const BLEND_OP_OVER: u8 = 1;
+
+const TRANSPARENT: Rgba<u8> = Rgba{data: [0, 0, 0, 0]};
+
+
+// Encoding animated PNGs
+
+/// Encode an animated PNG and write it to given writer.
+#[inline]
+pub fn encode<W: Write>(anim: &PngAnimation, output: W) -> io::Result<()> {
+    encode_with_hook(anim, output, &|_| Ok(()))
+}
+
+/// Encode an animated PNG and write it to given writer,
+/// calling given function with the index of every frame before it's encoded.
+///
+/// If the function returns an error, the encoding stops and fails with that error
+/// (before anything is written to the output).
+pub fn encode_with_hook<W: Write>(anim: &PngAnimation, mut output: W,
+                                  on_frame: &(Fn(usize) -> io::Result<()> + Sync))
+                                  -> io::Result<()> {
+    let mut result = vec![];
+    result.extend_from_slice(PNG_SIGNATURE);
+
+    let mut sequence = 0;
+    for (i, frame) in anim.iter_frames().enumerate() {
+        on_frame(i)?;
+        trace!("Writing frame #{}", i + 1);
+        let (width, height) = frame.image.dimensions();
+
+        let rgba = frame.image.to_rgba();
+        let mut png = vec![];
+        image::png::PNGEncoder::new(&mut png).encode(&*rgba, width, height, image::RGBA(8))?;
+        let chunks = parse_chunks(&png)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+
+        if i == 0 {
+            let header = chunks.iter().find(|&&(kind, _)| kind == b"IHDR")
+                .map(|&(_, data)| data).expect("IHDR in encoded PNG");
+            write_chunk(&mut result, b"IHDR", header);
+
+            let mut actl = vec![0; 8];
+            write_u32_at(&mut actl[0..], anim.frames_count() as u32);
+            write_u32_at(&mut actl[4..], anim.num_plays);
+            write_chunk(&mut result, b"acTL", &actl);
+        }
+
+        let mut fctl = vec![0; 26];
+        write_u32_at(&mut fctl[0..], sequence);
+        write_u32_at(&mut fctl[4..], width);
+        write_u32_at(&mut fctl[8..], height);
+        // Offsets are zero, as every frame covers the whole canvas.
+        let delay_ms = frame.delay.as_secs() * 1000
+            + frame.delay.subsec_nanos() as u64 / 1_000_000;
+        write_u16_at(&mut fctl[20..], delay_ms.min(0xffff) as u16);
+        write_u16_at(&mut fctl[22..], 1000);
+        // Dispose & blend ops are zero (none & source), for the same reason.
+        write_chunk(&mut result, b"fcTL", &fctl);
+        sequence += 1;
+
+        for &(kind, data) in chunks.iter().filter(|&&(kind, _)| kind == b"IDAT") {
+            if i == 0 {
+                write_chunk(&mut result, kind, data);
+            } else {
+                let mut fdat = Vec::with_capacity(4 + data.len());
+                fdat.extend_from_slice(&[0; 4]);
+                write_u32_at(&mut fdat[0..], sequence);
+                fdat.extend_from_slice(data);
+                write_chunk(&mut result, b"fdAT", &fdat);
+                sequence += 1;
+            }
+        }
+    }
+    write_chunk(&mut result, b"IEND", &[]);
+
+    output.write_all(&result)
+}
+
+
+// Reading & writing PNG chunks
+
+pub(crate) const PNG_SIGNATURE: &'static [u8] = &[0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+
+/// Split the PNG file into chunks, returning their types & data.
+pub(crate) fn parse_chunks(bytes: &[u8]) -> Result<Vec<(&[u8], &[u8])>, DecodeError> {
+    if !bytes.starts_with(PNG_SIGNATURE) {
+        return Err(DecodeError::Format("not a PNG file".into()));
+    }
+    let mut chunks = vec![];
+    let mut rest = &bytes[PNG_SIGNATURE.len()..];
+    while rest.len() >= 12 {
+        let length = read_u32(rest) as usize;
+        if rest.len() - 12 < length {
+            return Err(DecodeError::Format("truncated chunk".into()));
+        }
+        chunks.push((&rest[4..8], &rest[8..8 + length]));
+        rest = &rest[12 + length..];  // skip the CRC, too
+    }
+    Ok(chunks)
+}
+
+pub(crate) fn write_chunk(out: &mut Vec<u8>, kind: &[u8], data: &[u8]) {
+    let mut length = [0; 4];
+    write_u32_at(&mut length, data.len() as u32);
+    out.extend_from_slice(&length);
+
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let mut crc = [0; 4];
+    write_u32_at(&mut crc, crc32(&out[start..]));
+    out.extend_from_slice(&crc);
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    (bytes[0] as u32) << 24 | (bytes[1] as u32) << 16 | (bytes[2] as u32) << 8 | bytes[3] as u32
+}
+
+fn read_u16(bytes: &[u8]) -> u16 {
+    (bytes[0] as u16) << 8 | bytes[1] as u16
+}
+
+fn write_u32_at(bytes: &mut [u8], v: u32) {
+    bytes[0] = (v >> 24) as u8;
+    bytes[1] = (v >> 16) as u8;
+    bytes[2] = (v >> 8) as u8;
+    bytes[3] = v as u8;
+}
+
+fn write_u16_at(bytes: &mut [u8], v: u16) {
+    bytes[0] = (v >> 8) as u8;
+    bytes[1] = v as u8;
+}
+
+/// Compute the CRC-32 checksum used by PNG chunks.
+fn crc32(bytes: &[u8]) -> u32 {
+    lazy_static! {
+        static ref TABLE: Vec<u32> = (0..256).map(|n| {
+            (0..8).fold(n as u32, |c, _| if c & 1 != 0 { 0xedb88320 ^ (c >> 1) } else { c >> 1 })
+        }).collect();
+    }
+    !bytes.iter().fold(!0u32, |crc, &b| TABLE[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use image::{DynamicImage, Rgba, RgbaImage};
+    use super::{crc32, decode, encode, parse_chunks, write_chunk, write_u32_at,
+                PngAnimation, PngFrame, PNG_SIGNATURE};
+
+    #[test]
+    fn crc() {
+        assert_eq!(0xcbf43926, crc32(b"123456789"));
+        assert_eq!(0xae426082, crc32(b"IEND"));
+    }
+
+    #[test]
+    fn roundtrip() {
+        let frames = [0x00, 0x80, 0xff].iter().map(|&v| PngFrame{
+            image: DynamicImage::ImageRgba8(
+                RgbaImage::from_pixel(16, 8, Rgba{data: [v, v, v, 0xff]})),
+            delay: Duration::from_millis(40),
+        }).collect();
+        let mut anim = PngAnimation::new(frames);
+        anim.num_plays = 3;
+
+        let mut bytes = vec![];
+        encode(&anim, &mut bytes).unwrap();
+        let decoded = decode(&mut &bytes[..]).unwrap();
+
+        assert_eq!((16, 8), (decoded.width, decoded.height));
+        assert_eq!(3, decoded.num_plays);
+        assert_eq!(3, decoded.frames_count());
+        for (orig, frame) in anim.iter_frames().zip(decoded.iter_frames()) {
+            assert_eq!(orig.delay, frame.delay);
+            assert_eq!(orig.image.raw_pixels(), frame.image.raw_pixels());
+        }
+    }
+
+    /// Encode a two-frame animation, then tamper with its chunks using given function.
+    fn tampered<F: FnMut(&[u8], &mut Vec<u8>)>(mut f: F) -> Vec<u8> {
+        let frames = (0..2).map(|_| PngFrame{
+            image: DynamicImage::ImageRgba8(RgbaImage::new(16, 8)),
+            delay: Duration::from_millis(40),
+        }).collect();
+        let mut bytes = vec![];
+        encode(&PngAnimation::new(frames), &mut bytes).unwrap();
+
+        let mut result = PNG_SIGNATURE.to_vec();
+        for (kind, data) in parse_chunks(&bytes).unwrap() {
+            let mut data = data.to_vec();
+            f(kind, &mut data);
+            write_chunk(&mut result, kind, &data);
+        }
+        result
+    }
+
+    #[test]
+    fn huge_canvas_is_rejected() {
+        let bytes = tampered(|kind, data| if kind == b"IHDR" {
+            write_u32_at(&mut data[0..], 1 << 20);
+            write_u32_at(&mut data[4..], 1 << 20);
+        });
+        assert!(decode(&mut &bytes[..]).is_err());
+    }
+
+    #[test]
+    fn frames_outside_canvas_are_rejected() {
+        // Offset which would overflow when the frame's width is added to it.
+        let bytes = tampered(|kind, data| if kind == b"fcTL" {
+            write_u32_at(&mut data[12..], u32::max_value() - 4);
+        });
+        assert!(decode(&mut &bytes[..]).is_err());
+        // Frame sticking out of the canvas.
+        let bytes = tampered(|kind, data| if kind == b"fcTL" {
+            write_u32_at(&mut data[16..], 4);
+        });
+        assert!(decode(&mut &bytes[..]).is_err());
+    }
+}
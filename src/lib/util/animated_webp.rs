@@ -0,0 +1,94 @@
+//! Module defining animations loaded from WebP files.
+//!
+//! Unlike the rest of WebP support (see the `webp` module), these types
+//! don't require libwebp, so that the shape of `Template` doesn't depend
+//! on whether the `webp` feature is enabled.
+
+use std::fmt;
+use std::time::Duration;
+
+use image::{DynamicImage, GenericImage};
+
+
+/// Animation loaded from a WebP file.
+///
+/// The frames are kept fully composited, i.e. each of them covers the entire canvas.
+#[derive(Clone)]
+pub struct WebpAnimation {
+    /// Width of the animation canvas.
+    pub width: u32,
+    /// Height of the animation canvas.
+    pub height: u32,
+    /// How many times the animation should be played (0 means forever).
+    pub loop_count: u16,
+    /// Animation frames.
+    frames: Vec<WebpFrame>,
+}
+
+impl WebpAnimation {
+    /// Create an animation from given frames.
+    ///
+    /// All frames must be of the same size.
+    pub fn new(frames: Vec<WebpFrame>) -> Self {
+        let (width, height) = frames.first()
+            .map(|f| f.image.dimensions()).unwrap_or((0, 0));
+        for f in &frames {
+            assert_eq!((width, height), f.image.dimensions(),
+                "all frames of WebP animation must have the same size");
+        }
+        WebpAnimation{width, height, loop_count: 0, frames}
+    }
+
+    /// How many frames there are in the animation.
+    #[inline]
+    pub fn frames_count(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Iterate over the frames in animation.
+    #[inline]
+    pub fn iter_frames<'a>(&'a self) -> Box<Iterator<Item=&'a WebpFrame> + 'a> {
+        Box::new(self.frames.iter())
+    }
+
+    /// Create a new animation out of (possibly repeated) frames of this one,
+    /// given by their indices and the durations they should now be displayed for.
+    pub fn rearrange<I>(&self, frames: I) -> WebpAnimation
+        where I: IntoIterator<Item=(usize, Duration)>
+    {
+        let frames = frames.into_iter().map(|(index, delay)| WebpFrame{
+            delay, ..self.frames[index].clone()
+        }).collect();
+        WebpAnimation{width: self.width, height: self.height, loop_count: self.loop_count, frames}
+    }
+}
+
+impl fmt::Debug for WebpAnimation {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("WebpAnimation")
+            .field("width", &self.width)
+            .field("height", &self.height)
+            .field("loop_count", &self.loop_count)
+            .field("frames", &format_args!("<{} frames>", self.frames.len()))
+            .finish()
+    }
+}
+
+/// A single frame of an animated WebP.
+#[derive(Clone)]
+pub struct WebpFrame {
+    /// The image of the frame.
+    pub image: DynamicImage,
+    /// How long the frame is displayed for.
+    pub delay: Duration,
+}
+
+impl fmt::Debug for WebpFrame {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let (w, h) = self.image.dimensions();
+        fmt.debug_struct("WebpFrame")
+            .field("image", &format_args!("{}x{}", w, h))
+            .field("delay", &self.delay)
+            .finish()
+    }
+}
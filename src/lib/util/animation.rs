@@ -0,0 +1,158 @@
+//! Module for changing the playback of animations.
+
+use std::cmp::max;
+use std::time::Duration;
+
+use model::AnimationOptions;
+
+
+/// Delays shorter than this are not honored by web browsers.
+const MIN_BROWSER_DELAY_MS: u64 = 20;
+/// Delay that web browsers use instead of those that are too short.
+const BROWSER_FALLBACK_DELAY_MS: u64 = 100;
+
+
+/// Maximum width & height of an animation canvas that will be decoded.
+pub const MAX_CANVAS_SIZE: u32 = 8192;
+/// Maximum number of frames in an animation that will be decoded.
+pub const MAX_FRAME_COUNT: usize = 1024;
+/// Maximum number of pixels in all the frames of an animation that will be decoded.
+///
+/// Decoded frames are kept as whole RGBA canvases, so this bounds the memory they take.
+pub const MAX_TOTAL_PIXELS: u64 = 64 * 1024 * 1024;
+
+/// Check the size of an animation, as declared in its file, against the decoding limits.
+///
+/// This should be done before anything is allocated for the frames,
+/// as the declared size cannot be trusted.
+pub fn check_decoding_limits(width: u32, height: u32, frame_count: usize) -> Result<(), String> {
+    if width == 0 || height == 0 || width > MAX_CANVAS_SIZE || height > MAX_CANVAS_SIZE {
+        return Err(format!("invalid canvas size {}x{} (must be between 1x1 and {}x{})",
+            width, height, MAX_CANVAS_SIZE, MAX_CANVAS_SIZE));
+    }
+    if frame_count > MAX_FRAME_COUNT {
+        return Err(format!("too many frames: {} > {}", frame_count, MAX_FRAME_COUNT));
+    }
+    let total_pixels = width as u64 * height as u64 * frame_count as u64;
+    if total_pixels > MAX_TOTAL_PIXELS {
+        return Err(format!("animation too large: {} frame(s) of {}x{}",
+            frame_count, width, height));
+    }
+    Ok(())
+}
+
+
+/// Arrange the frames of an animation (given by their delays) according to given options.
+///
+/// Returns the indices of the original frames to play in order,
+/// together with the new durations they should be displayed for.
+/// The frame range of the options is expected to be valid for the animation.
+pub fn arrange_frames<I>(delays: I, options: &AnimationOptions) -> Vec<(usize, Duration)>
+    where I: IntoIterator<Item=Duration>
+{
+    let mut frames: Vec<_> = delays.into_iter().map(duration_ms).enumerate().collect();
+
+    if options.normalize_delays {
+        for &mut (_, ref mut delay_ms) in &mut frames {
+            *delay_ms = browser_delay_ms(*delay_ms);
+        }
+    }
+
+    let end = options.end_frame.map(|e| e.min(frames.len())).unwrap_or(frames.len());
+    let start = options.start_frame.map(|s| s.min(end)).unwrap_or(0);
+    frames.truncate(end);
+    frames.drain(..start);
+
+    if options.reverse {
+        frames.reverse();
+    }
+    if options.boomerang && frames.len() > 2 {
+        // (The result can have up to twice as many frames as the template,
+        // so callers need to check it against MAX_FRAME_COUNT.)
+        // Play the frames backwards without repeating the last & first one,
+        // as they are adjacent to their own copies when the animation loops.
+        let backwards: Vec<_> = frames[1..frames.len() - 1].iter().rev().cloned().collect();
+        frames.extend(backwards);
+    }
+
+    if let Some(speed) = options.speed {
+        for &mut (_, ref mut delay_ms) in &mut frames {
+            *delay_ms = (*delay_ms as f64 / speed as f64).round() as u64;
+            if options.normalize_delays {
+                *delay_ms = max(*delay_ms, MIN_BROWSER_DELAY_MS);
+            }
+        }
+    }
+
+    frames.into_iter()
+        .map(|(index, delay_ms)| (index, Duration::from_millis(delay_ms)))
+        .collect()
+}
+
+/// Convert a frame delay to whole milliseconds.
+pub fn duration_ms(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + duration.subsec_nanos() as u64 / 1_000_000
+}
+
+/// Normalize a frame delay (in milliseconds) the way web browsers do,
+/// i.e. play frames that are too short for 100 milliseconds instead.
+pub fn browser_delay_ms(delay_ms: u64) -> u64 {
+    if delay_ms < MIN_BROWSER_DELAY_MS { BROWSER_FALLBACK_DELAY_MS } else { delay_ms }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use model::AnimationOptions;
+    use super::{arrange_frames, check_decoding_limits, MAX_CANVAS_SIZE};
+
+    fn delays(ms: &[u64]) -> Vec<Duration> {
+        ms.iter().map(|&ms| Duration::from_millis(ms)).collect()
+    }
+
+    fn indices(frames: &[(usize, Duration)]) -> Vec<usize> {
+        frames.iter().map(|&(i, _)| i).collect()
+    }
+
+    #[test]
+    fn default_options_keep_frames() {
+        let frames = arrange_frames(delays(&[10, 20, 30]), &AnimationOptions::default());
+        assert_eq!(vec![(0, Duration::from_millis(10)),
+                        (1, Duration::from_millis(20)),
+                        (2, Duration::from_millis(30))], frames);
+    }
+
+    #[test]
+    fn trim_reverse_and_boomerang() {
+        let options = AnimationOptions{
+            start_frame: Some(1),
+            end_frame: Some(5),
+            reverse: true,
+            boomerang: true,
+            ..AnimationOptions::default()
+        };
+        let frames = arrange_frames(delays(&[50; 6]), &options);
+        assert_eq!(vec![4, 3, 2, 1, 2, 3], indices(&frames));
+    }
+
+    #[test]
+    fn speed_and_normalized_delays() {
+        let options = AnimationOptions{speed: Some(4.0), ..AnimationOptions::default()};
+        let frames = arrange_frames(delays(&[0, 40, 200]), &options);
+        assert_eq!(delays(&[0, 10, 50]), frames.into_iter().map(|(_, d)| d).collect::<Vec<_>>());
+
+        let options = AnimationOptions{normalize_delays: true, ..options};
+        let frames = arrange_frames(delays(&[0, 40, 200]), &options);
+        assert_eq!(delays(&[25, 20, 50]), frames.into_iter().map(|(_, d)| d).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn decoding_limits() {
+        assert!(check_decoding_limits(640, 480, 100).is_ok());
+        assert!(check_decoding_limits(0, 480, 1).is_err());
+        assert!(check_decoding_limits(MAX_CANVAS_SIZE + 1, 1, 1).is_err());
+        assert!(check_decoding_limits(16, 16, 100000).is_err());
+        assert!(check_decoding_limits(4096, 4096, 100).is_err());
+    }
+}
@@ -0,0 +1,221 @@
+//! Module implementing the image filters.
+
+use image::{self, DynamicImage, FilterType, GenericImage, ImageFormat, Rgba, RgbaImage};
+use rand::{Rng, SeedableRng, XorShiftRng};
+
+use model::Filter;
+
+
+/// Apply given filter to an image.
+///
+/// Returns a new image, which is always RGBA
+/// (so that it can be further captioned and/or encoded as GIF frame).
+pub fn apply(img: DynamicImage, filter: &Filter) -> DynamicImage {
+    let (width, height) = img.dimensions();
+    trace!("Applying {:?} filter to a {}x{} image", filter, width, height);
+
+    let img = match *filter {
+        Filter::Grayscale => map_pixels(img, grayscale),
+        Filter::Sepia => map_pixels(img, sepia),
+        Filter::Invert => map_pixels(img, invert),
+        Filter::Blur(sigma) => img.blur(sigma),
+        Filter::Sharpen(sigma) => img.unsharpen(sigma, SHARPEN_THRESHOLD),
+        Filter::Saturate(factor) => map_pixels(img, |p| saturate(p, factor)),
+        Filter::Contrast(c) => img.adjust_contrast(c),
+        Filter::Pixelate(block) => pixelate(img, block),
+        Filter::JpegCrunch(quality) => jpeg_crunch(img, quality),
+        Filter::Noise(amount) => noise(img, amount),
+        Filter::DeepFry => {
+            let mut img = img;
+            for f in Filter::deep_fry() {
+                img = apply(img, f);
+            }
+            img
+        }
+    };
+
+    match img {
+        img @ DynamicImage::ImageRgba8(_) => img,
+        img => DynamicImage::ImageRgba8(img.to_rgba()),
+    }
+}
+
+/// Difference in brightness that unsharp masking must see in order to sharpen a pixel.
+const SHARPEN_THRESHOLD: i32 = 1;
+
+
+// Per-pixel filters.
+
+/// Convert an image to RGBA and transform all its pixels with given function.
+fn map_pixels<F>(img: DynamicImage, f: F) -> DynamicImage
+    where F: Fn(Rgba<u8>) -> Rgba<u8>
+{
+    let mut img = match img {
+        DynamicImage::ImageRgba8(img) => img,
+        img => img.to_rgba(),
+    };
+    for pixel in img.pixels_mut() {
+        *pixel = f(*pixel);
+    }
+    DynamicImage::ImageRgba8(img)
+}
+
+fn grayscale(pixel: Rgba<u8>) -> Rgba<u8> {
+    let (r, g, b, a) = channels(pixel);
+    let l = clamp(luma(r, g, b));
+    Rgba{data: [l, l, l, a]}
+}
+
+fn invert(pixel: Rgba<u8>) -> Rgba<u8> {
+    let (r, g, b, a) = channels(pixel);
+    Rgba{data: [0xff - r, 0xff - g, 0xff - b, a]}
+}
+
+fn sepia(pixel: Rgba<u8>) -> Rgba<u8> {
+    let (r, g, b, a) = channels(pixel);
+    let (r, g, b) = (r as f32, g as f32, b as f32);
+    Rgba{data: [
+        clamp(0.393 * r + 0.769 * g + 0.189 * b),
+        clamp(0.349 * r + 0.686 * g + 0.168 * b),
+        clamp(0.272 * r + 0.534 * g + 0.131 * b),
+        a,
+    ]}
+}
+
+fn saturate(pixel: Rgba<u8>, factor: f32) -> Rgba<u8> {
+    let (r, g, b, a) = channels(pixel);
+    let l = luma(r, g, b);
+    let adjust = |c: u8| clamp(l + (c as f32 - l) * factor);
+    Rgba{data: [adjust(r), adjust(g), adjust(b), a]}
+}
+
+#[inline]
+fn channels(pixel: Rgba<u8>) -> (u8, u8, u8, u8) {
+    (pixel.data[0], pixel.data[1], pixel.data[2], pixel.data[3])
+}
+
+/// Compute the perceived brightness of an RGB color.
+#[inline]
+fn luma(r: u8, g: u8, b: u8) -> f32 {
+    0.299 * r as f32 + 0.587 * g as f32 + 0.114 * b as f32
+}
+
+#[inline]
+fn clamp(v: f32) -> u8 {
+    v.round().max(0.0).min(255.0) as u8
+}
+
+
+// Other filters.
+
+/// Pixelate the image by averaging it over blocks of given size.
+fn pixelate(img: DynamicImage, block: u32) -> DynamicImage {
+    if block <= 1 {
+        return img;
+    }
+    let (width, height) = img.dimensions();
+    let small_width = (width / block).max(1);
+    let small_height = (height / block).max(1);
+    img.resize_exact(small_width, small_height, FilterType::Triangle)
+        .resize_exact(width, height, FilterType::Nearest)
+}
+
+/// Run the image through a round of JPEG compression with given quality.
+///
+/// Since JPEG doesn't support transparency, the alpha channel is carried over
+/// from the original image.
+fn jpeg_crunch(img: DynamicImage, quality: u8) -> DynamicImage {
+    let (width, height) = img.dimensions();
+    let rgba = img.to_rgba();
+    let rgb = img.to_rgb();
+
+    let mut bytes = vec![];
+    let encoded = image::jpeg::JPEGEncoder::new_with_quality(&mut bytes, quality)
+        .encode(&*rgb, width, height, image::RGB(8));
+    if let Err(e) = encoded {
+        warn!("Failed to encode {}x{} image as JPEG for crunching: {}", width, height, e);
+        return DynamicImage::ImageRgba8(rgba);
+    }
+
+    let crunched = match image::load_from_memory_with_format(&bytes, ImageFormat::JPEG) {
+        Ok(img) => img,
+        Err(e) => {
+            warn!("Failed to decode the crunched JPEG image: {}", e);
+            return DynamicImage::ImageRgba8(rgba);
+        }
+    };
+
+    let mut result = crunched.to_rgba();
+    for (pixel, orig) in result.pixels_mut().zip(rgba.pixels()) {
+        pixel.data[3] = orig.data[3];
+    }
+    DynamicImage::ImageRgba8(result)
+}
+
+/// Add random monochrome noise of given intensity to the image.
+///
+/// The random generator is seeded with a constant, so the result is deterministic
+/// (which also means that all frames of an animation get the same "grain").
+fn noise(img: DynamicImage, amount: f32) -> DynamicImage {
+    let amount = amount.max(0.0).min(1.0) * 255.0;
+    if amount == 0.0 {
+        return img;
+    }
+
+    let mut rng = XorShiftRng::from_seed(NOISE_SEED);
+    let mut img = img.to_rgba();
+    for pixel in img.pixels_mut() {
+        let delta: f32 = rng.gen_range(-amount, amount);
+        for c in pixel.data[..3].iter_mut() {
+            *c = clamp(*c as f32 + delta);
+        }
+    }
+    DynamicImage::ImageRgba8(img)
+}
+
+const NOISE_SEED: [u32; 4] = [0x2a, 0x10f, 0x1337, 0xdeadbeef];
+
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, GenericImage, Rgba, RgbaImage};
+    use model::Filter;
+    use super::apply;
+
+    #[test]
+    fn filters_keep_size_and_alpha() {
+        let pixel = Rgba{data: [0x10, 0x80, 0xf0, 0x7f]};
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(32, 24, pixel));
+        let filters = [
+            Filter::Grayscale, Filter::Sepia, Filter::Invert, Filter::Saturate(2.0),
+            Filter::Pixelate(5), Filter::JpegCrunch(10), Filter::Noise(0.5),
+        ];
+        for f in filters.iter() {
+            let result = apply(img.clone(), f);
+            assert_eq!((32, 24), result.dimensions(), "{:?} changed the image size", f);
+            let alpha = result.as_rgba8().unwrap().get_pixel(7, 7).data[3];
+            assert_eq!(0x7f, alpha, "{:?} changed the alpha channel", f);
+        }
+    }
+
+    #[test]
+    fn grayscale_and_invert() {
+        let img = DynamicImage::ImageRgba8(
+            RgbaImage::from_pixel(2, 2, Rgba{data: [0xff, 0x0, 0x0, 0xff]}));
+        let gray = apply(img.clone(), &Filter::Grayscale);
+        let pixel = gray.as_rgba8().unwrap().get_pixel(0, 0).data;
+        assert!(pixel[0] == pixel[1] && pixel[1] == pixel[2]);
+
+        let inverted = apply(img, &Filter::Invert);
+        assert_eq!([0x0, 0xff, 0xff, 0xff], inverted.as_rgba8().unwrap().get_pixel(1, 1).data);
+    }
+
+    #[test]
+    fn deep_fry_is_deterministic() {
+        let img = DynamicImage::ImageRgba8(
+            RgbaImage::from_pixel(16, 16, Rgba{data: [0x80, 0x60, 0x40, 0xff]}));
+        let first = apply(img.clone(), &Filter::DeepFry);
+        let second = apply(img, &Filter::DeepFry);
+        assert_eq!(first.raw_pixels(), second.raw_pixels());
+    }
+}
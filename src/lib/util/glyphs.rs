@@ -0,0 +1,204 @@
+//! Module implementing the cache of rasterized glyphs.
+//!
+//! Most captions are rendered using the same font at a handful of sizes,
+//! so the glyph bitmaps are kept around and reused rather than rasterized
+//! every time they are drawn.
+
+use rusttype::{point, Point, PositionedGlyph};
+
+use super::cache::ThreadSafeCache;
+
+
+/// Cache of rasterized glyphs.
+pub type GlyphCache = ThreadSafeCache<GlyphKey, GlyphBitmap>;
+
+/// Number of distinct subpixel positions (along each axis)
+/// that a glyph can be rasterized at.
+const SUBPIXEL_STEPS: u8 = 4;
+
+
+/// Key identifying a rasterized glyph in the `GlyphCache`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct GlyphKey {
+    /// Name of the font that the glyph comes from.
+    font: String,
+    /// ID of the glyph within the font.
+    glyph: u32,
+    /// Horizontal & vertical scale of the glyph (as bits of the `f32`s, for hashing).
+    scale: (u32, u32),
+    /// Subpixel offset of the glyph's origin, in 1/`SUBPIXEL_STEPS` of a pixel.
+    offset: (u8, u8),
+}
+
+impl GlyphKey {
+    /// Create the key for given glyph of a font with given name,
+    /// rasterized at given subpixel offset.
+    pub fn new(font: &str, glyph: &PositionedGlyph, offset: (u8, u8)) -> Self {
+        let scale = glyph.unpositioned().scale();
+        GlyphKey{
+            font: font.to_owned(),
+            glyph: glyph.id().0,
+            scale: (scale.x.to_bits(), scale.y.to_bits()),
+            offset,
+        }
+    }
+}
+
+
+/// Coverage bitmap of a rasterized glyph.
+#[derive(Clone, Debug)]
+pub struct GlyphBitmap {
+    /// Position of the bitmap's top-left corner relative to the pixel of the glyph's origin.
+    left: i32,
+    top: i32,
+    width: u32,
+    height: u32,
+    /// Coverage (alpha) of the bitmap's pixels, row by row.
+    coverage: Vec<u8>,
+}
+
+impl GlyphBitmap {
+    /// Rasterize the glyph with its origin at given subpixel offset.
+    pub fn rasterize(glyph: &PositionedGlyph, offset: (u8, u8)) -> Self {
+        let origin = point(offset.0 as f32 / SUBPIXEL_STEPS as f32,
+                           offset.1 as f32 / SUBPIXEL_STEPS as f32);
+        let glyph = glyph.unpositioned().clone().positioned(origin);
+        let bbox = match glyph.pixel_bounding_box() {
+            Some(bbox) => bbox,
+            None => return GlyphBitmap{left: 0, top: 0, width: 0, height: 0, coverage: vec![]},
+        };
+
+        let (width, height) = (bbox.width() as u32, bbox.height() as u32);
+        let mut coverage = vec![0; (width * height) as usize];
+        glyph.draw(|x, y, v| {
+            coverage[(y * width + x) as usize] = (v * 255f32) as u8;
+        });
+        GlyphBitmap{left: bbox.min.x, top: bbox.min.y, width, height, coverage}
+    }
+
+    /// Call given function for every (non-transparent) pixel of the bitmap,
+    /// passing its coordinates relative to the glyph's origin pixel & its coverage.
+    pub fn for_each_pixel<F: FnMut(i32, i32, u8)>(&self, mut f: F) {
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let alpha = self.coverage[(y * self.width + x) as usize];
+                if alpha > 0 {
+                    f(self.left + x as i32, self.top + y as i32, alpha);
+                }
+            }
+        }
+    }
+}
+
+
+/// Split the position of a glyph's origin into the pixel it falls into
+/// and the subpixel offset within it (in 1/`SUBPIXEL_STEPS` of a pixel).
+pub fn split_position(position: Point<f32>) -> ((i32, i32), (u8, u8)) {
+    let (x, offset_x) = split_coordinate(position.x);
+    let (y, offset_y) = split_coordinate(position.y);
+    ((x, y), (offset_x, offset_y))
+}
+
+fn split_coordinate(coord: f32) -> (i32, u8) {
+    let pixel = coord.floor();
+    let offset = ((coord - pixel) * SUBPIXEL_STEPS as f32).round() as u8;
+    if offset >= SUBPIXEL_STEPS {
+        (pixel as i32 + 1, 0)
+    } else {
+        (pixel as i32, offset)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, RgbaImage};
+    use rusttype::{point, Rect, Scale};
+
+    use caption::tests::font;
+    use model::{Color, HAlign, VAlign};
+    use util::text::{render_line, Style};
+    use super::{GlyphBitmap, GlyphCache, SUBPIXEL_STEPS, split_position};
+
+    #[test]
+    fn split_positions() {
+        assert_eq!(((3, 7), (0, 0)), split_position(point(3.0, 7.0)));
+        assert_eq!(((3, 7), (1, 2)), split_position(point(3.25, 7.5)));
+        assert_eq!(((4, -2), (0, 3)), split_position(point(3.9, -1.3)));
+    }
+
+    #[test]
+    fn quantized_glyphs_are_close_to_exact() {
+        let font = font();
+        let (width, height) = (200, 50);
+        let index = |x: i32, y: i32| {
+            if x >= 0 && y >= 0 && x < width && y < height {
+                Some((y * width + x) as usize)
+            } else {
+                None
+            }
+        };
+
+        // Each glyph is compared on its own, so that the errors of neighboring glyphs
+        // which share a pixel don't add up.
+        let mut count = 0;
+        for glyph in font.layout("LOLOLOL", Scale::uniform(32.0), point(7.3, 36.7)) {
+            // Rasterize the glyph at its exact (unquantized) position,
+            // and from the bitmap that the cache would store.
+            let mut exact = vec![0i32; (width * height) as usize];
+            if let Some(bbox) = glyph.pixel_bounding_box() {
+                glyph.draw(|x, y, v| {
+                    if let Some(i) = index(bbox.min.x + x as i32, bbox.min.y + y as i32) {
+                        exact[i] = (v * 255f32) as i32;
+                    }
+                });
+            }
+            let mut quantized = vec![0i32; (width * height) as usize];
+            let ((origin_x, origin_y), offset) = split_position(glyph.position());
+            GlyphBitmap::rasterize(&glyph, offset).for_each_pixel(|x, y, alpha| {
+                if let Some(i) = index(origin_x + x, origin_y + y) {
+                    quantized[i] = alpha as i32;
+                }
+            });
+            assert!(exact.iter().any(|&c| c > 0));
+
+            // The glyph is moved by at most half a subpixel step along each axis,
+            // which can only change the coverage of a pixel by as much (plus rounding).
+            let tolerance = 255 / SUBPIXEL_STEPS as i32 + 2;
+            for (i, (e, q)) in exact.iter().zip(&quantized).enumerate() {
+                assert!((e - q).abs() <= tolerance,
+                    "glyph #{}, pixel ({}, {}): exact coverage {}, quantized {}",
+                    count, i as i32 % width, i as i32 / width, e, q);
+            }
+            count += 1;
+        }
+        assert_eq!(7, count);
+    }
+
+    #[test]
+    fn cache_reuses_glyphs() {
+        let font = font();
+        let cache = GlyphCache::new(64);
+
+        let rect = Rect{min: point(0.0, 0.0), max: point(200.0, 50.0)};
+        let align = (HAlign::Center, VAlign::Middle);
+        let render = || {
+            let img = DynamicImage::ImageRgba8(RgbaImage::new(200, 50));
+            let style = Style::new(&font, 32.0, Color(0xff, 0xff, 0xff))
+                .with_glyph_cache(&cache, "Impact");
+            render_line(img, "LOLOLOL", align, rect, &style)
+        };
+
+        // "LOLOLOL" has only two distinct glyphs, but they may land
+        // at different subpixel offsets, so there are at most 7 misses at first.
+        render();
+        let misses = cache.misses();
+        assert!(misses >= 2 && misses <= 7);
+        assert_eq!(7, cache.hits() + misses);
+
+        // Rendering the same text again doesn't need to rasterize anything.
+        render();
+        assert_eq!(misses, cache.misses());
+        assert_eq!(14, cache.hits() + cache.misses());
+    }
+}
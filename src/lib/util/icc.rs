@@ -0,0 +1,291 @@
+//! Module implementing the conversion of ICC-profiled images to sRGB.
+//!
+//! Only the matrix/TRC profiles of RGB images are supported,
+//! which is what cameras & phones (Display P3, Adobe RGB, etc.) embed in practice.
+
+use image::{DynamicImage, GenericImage, Pixel, Rgba, RgbaImage};
+
+
+/// Convert an image with given ICC profile to the sRGB color space.
+///
+/// Returns `None` if the conversion isn't necessary (the profile is sRGB already)
+/// or not supported for this profile.
+pub fn convert_to_srgb(img: &DynamicImage, profile: &[u8]) -> Option<DynamicImage> {
+    let profile = try_opt!(Profile::parse(profile));
+    if profile.is_srgb() {
+        trace!("ICC profile is sRGB, skipping conversion");
+        return None;
+    }
+
+    // Combine the profile's RGB->XYZ matrix with the XYZ->sRGB one.
+    let mut matrix = [[0f32; 3]; 3];
+    for i in 0..3 {
+        for j in 0..3 {
+            matrix[i][j] = (0..3).map(|k| XYZ_TO_SRGB[i][k] * profile.colorants[j][k]).sum();
+        }
+    }
+    let linear: Vec<Vec<f32>> = profile.curves.iter()
+        .map(|c| (0..256).map(|v| c.eval(v as f32 / 255.0)).collect())
+        .collect();
+    let encode: Vec<u8> = (0..SRGB_LUT_SIZE)
+        .map(|i| (srgb_encode(i as f32 / (SRGB_LUT_SIZE - 1) as f32) * 255.0).round() as u8)
+        .collect();
+
+    let (width, height) = img.dimensions();
+    let mut result = RgbaImage::new(width, height);
+    for (x, y, pixel) in img.pixels() {
+        let channels = pixel.channels();
+        let rgb = [linear[0][channels[0] as usize],
+                   linear[1][channels[1] as usize],
+                   linear[2][channels[2] as usize]];
+        let mut out = [0u8, 0, 0, channels[3]];
+        for i in 0..3 {
+            let v = matrix[i][0] * rgb[0] + matrix[i][1] * rgb[1] + matrix[i][2] * rgb[2];
+            let index = (v.max(0.0).min(1.0) * (SRGB_LUT_SIZE - 1) as f32).round() as usize;
+            out[i] = encode[index];
+        }
+        result.put_pixel(x, y, Rgba{data: out});
+    }
+    Some(DynamicImage::ImageRgba8(result))
+}
+
+const SRGB_LUT_SIZE: usize = 4096;
+
+/// Matrix converting D50-adapted XYZ (the ICC connection space) to linear sRGB.
+const XYZ_TO_SRGB: [[f32; 3]; 3] = [
+    [ 3.1338561, -1.6168667, -0.4906146],
+    [-0.9787684,  1.9161415,  0.0334540],
+    [ 0.0719453, -0.2289914,  1.4052427],
+];
+/// Colorants (XYZ of red, green, blue primaries) of the D50-adapted sRGB.
+const SRGB_COLORANTS: [[f32; 3]; 3] = [
+    [0.4361, 0.2225, 0.0139],
+    [0.3851, 0.7169, 0.0971],
+    [0.1431, 0.0606, 0.7141],
+];
+const SRGB_COLORANTS_TOLERANCE: f32 = 0.005;
+
+/// Apply the sRGB transfer function to a linear value.
+fn srgb_encode(v: f32) -> f32 {
+    if v <= 0.0031308 { 12.92 * v } else { 1.055 * v.powf(1.0 / 2.4) - 0.055 }
+}
+
+
+/// The parts of a matrix/TRC ICC profile that we need for the conversion.
+#[derive(Debug)]
+struct Profile {
+    /// XYZ values of the red, green, and blue primaries.
+    colorants: [[f32; 3]; 3],
+    /// Tone reproduction curves of the red, green, and blue channels.
+    curves: Vec<Curve>,
+}
+
+impl Profile {
+    fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < HEADER_SIZE + 4 || &data[16..20] != b"RGB " {
+            return None;
+        }
+        let mut colorants = [[0f32; 3]; 3];
+        for (i, tag) in [b"rXYZ", b"gXYZ", b"bXYZ"].iter().enumerate() {
+            let xyz = try_opt!(find_tag(data, &tag[..]));
+            if xyz.len() < 20 || &xyz[..4] != b"XYZ " {
+                return None;
+            }
+            for j in 0..3 {
+                colorants[i][j] = read_s15_fixed16(&xyz[8 + j * 4..]);
+            }
+        }
+        let mut curves = vec![];
+        for tag in [b"rTRC", b"gTRC", b"bTRC"].iter() {
+            curves.push(try_opt!(find_tag(data, &tag[..]).and_then(Curve::parse)));
+        }
+        Some(Profile{colorants, curves})
+    }
+
+    fn is_srgb(&self) -> bool {
+        self.colorants.iter().zip(SRGB_COLORANTS.iter())
+            .all(|(c, s)| c.iter().zip(s.iter())
+                .all(|(a, b)| (a - b).abs() < SRGB_COLORANTS_TOLERANCE))
+    }
+}
+
+const HEADER_SIZE: usize = 128;
+
+/// Find the data of a tag with given signature in the ICC profile.
+fn find_tag<'d>(data: &'d [u8], signature: &[u8]) -> Option<&'d [u8]> {
+    let tag_count = read_u32(&data[HEADER_SIZE..]) as usize;
+    (0..tag_count).map(|i| HEADER_SIZE + 4 + i * 12)
+        .take_while(|&entry| entry + 12 <= data.len())
+        .find(|&entry| &data[entry..entry + 4] == signature)
+        .and_then(|entry| {
+            let offset = read_u32(&data[entry + 4..]) as usize;
+            let size = read_u32(&data[entry + 8..]) as usize;
+            data.get(offset..offset.saturating_add(size))
+        })
+}
+
+
+/// Tone reproduction curve of a single channel.
+#[derive(Debug)]
+enum Curve {
+    /// Simple power function.
+    Gamma(f32),
+    /// Sampled values of the curve, spread evenly over the 0..1 range.
+    Table(Vec<f32>),
+    /// Parametric curve Y = (aX+b)^g + e for X >= d, and Y = cX + f otherwise.
+    Parametric{g: f32, a: f32, b: f32, c: f32, d: f32, e: f32, f: f32},
+}
+
+impl Curve {
+    fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < 12 {
+            return None;
+        }
+        match &data[..4] {
+            b"curv" => {
+                let count = read_u32(&data[8..]) as usize;
+                match count {
+                    0 => Some(Curve::Gamma(1.0)),
+                    1 if data.len() >= 14 =>
+                        Some(Curve::Gamma(read_u16(&data[12..]) as f32 / 256.0)),
+                    _ if data.len() >= 12 + count * 2 => Some(Curve::Table((0..count)
+                        .map(|i| read_u16(&data[12 + i * 2..]) as f32 / 65535.0)
+                        .collect())),
+                    _ => None,
+                }
+            }
+            b"para" => {
+                let function = read_u16(&data[8..]);
+                let param_count = match function {
+                    0 => 1, 1 => 3, 2 => 4, 3 => 5, 4 => 7,
+                    _ => return None,
+                };
+                if data.len() < 12 + param_count * 4 {
+                    return None;
+                }
+                let p: Vec<f32> = (0..param_count)
+                    .map(|i| read_s15_fixed16(&data[12 + i * 4..])).collect();
+                let g = p[0];
+                Some(match function {
+                    0 => Curve::Gamma(g),
+                    1 => Curve::Parametric{
+                        g, a: p[1], b: p[2], c: 0.0, d: -p[2] / p[1], e: 0.0, f: 0.0,
+                    },
+                    2 => Curve::Parametric{
+                        g, a: p[1], b: p[2], c: 0.0, d: -p[2] / p[1], e: p[3], f: p[3],
+                    },
+                    3 => Curve::Parametric{g, a: p[1], b: p[2], c: p[3], d: p[4], e: 0.0, f: 0.0},
+                    _ => Curve::Parametric{g, a: p[1], b: p[2], c: p[3], d: p[4], e: p[5], f: p[6]},
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Compute the linear value for given encoded one (both in the 0..1 range).
+    fn eval(&self, x: f32) -> f32 {
+        match *self {
+            Curve::Gamma(g) => x.powf(g),
+            Curve::Table(ref table) => {
+                let pos = x * (table.len() - 1) as f32;
+                let (i, frac) = (pos.floor() as usize, pos.fract());
+                match table.get(i + 1) {
+                    Some(next) => table[i] + (next - table[i]) * frac,
+                    None => table[i],
+                }
+            }
+            Curve::Parametric{g, a, b, c, d, e, f} => {
+                if x >= d { (a * x + b).max(0.0).powf(g) + e } else { c * x + f }
+            }
+        }
+    }
+}
+
+
+fn read_u16(bytes: &[u8]) -> u16 {
+    (bytes[0] as u16) << 8 | bytes[1] as u16
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    (bytes[0] as u32) << 24 | (bytes[1] as u32) << 16 | (bytes[2] as u32) << 8 | bytes[3] as u32
+}
+
+fn read_s15_fixed16(bytes: &[u8]) -> f32 {
+    read_u32(bytes) as i32 as f32 / 65536.0
+}
+
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, Rgba, RgbaImage};
+    use super::{convert_to_srgb, Curve, SRGB_COLORANTS};
+
+    /// Build a minimal matrix/TRC profile with given colorants & a gamma curve.
+    fn profile(colorants: [[f32; 3]; 3], gamma: f32) -> Vec<u8> {
+        fn u32_be(v: u32) -> Vec<u8> {
+            vec![(v >> 24) as u8, (v >> 16) as u8, (v >> 8) as u8, v as u8]
+        }
+
+        let tags: Vec<(&[u8], Vec<u8>)> = vec![
+            (b"rXYZ", colorants[0].to_vec()), (b"gXYZ", colorants[1].to_vec()),
+            (b"bXYZ", colorants[2].to_vec()),
+        ].into_iter().map(|(sig, xyz)| {
+            let mut data = b"XYZ \0\0\0\0".to_vec();
+            for v in xyz { data.extend(u32_be((v * 65536.0).round() as i32 as u32)); }
+            (sig as &[u8], data)
+        }).chain([b"rTRC", b"gTRC", b"bTRC"].iter().map(|sig| {
+            let mut data = b"curv\0\0\0\0".to_vec();
+            data.extend(u32_be(1));
+            let g = (gamma * 256.0) as u16;
+            data.extend(&[(g >> 8) as u8, g as u8]);
+            (&sig[..], data)
+        })).collect();
+
+        let mut result = vec![0; 128];
+        result[16..20].copy_from_slice(b"RGB ");
+        result.extend(u32_be(tags.len() as u32));
+        let mut offset = 128 + 4 + tags.len() * 12;
+        let mut data = vec![];
+        for &(sig, ref tag) in &tags {
+            result.extend_from_slice(sig);
+            result.extend(u32_be(offset as u32));
+            result.extend(u32_be(tag.len() as u32));
+            offset += tag.len();
+            data.extend_from_slice(tag);
+        }
+        result.extend(data);
+        result
+    }
+
+    #[test]
+    fn srgb_profile_is_skipped() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::new(2, 2));
+        assert!(convert_to_srgb(&img, &profile(SRGB_COLORANTS, 2.2)).is_none());
+        assert!(convert_to_srgb(&img, b"not a profile").is_none());
+    }
+
+    #[test]
+    fn wide_gamut_conversion() {
+        // Display P3 primaries, adapted to D50.
+        let p3 = [[0.5151, 0.2412, -0.0011], [0.2920, 0.6922, 0.0419], [0.1571, 0.0666, 0.7841]];
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(2, 1, |x, _| {
+            if x == 0 { Rgba{data: [255, 255, 255, 255]} } else { Rgba{data: [255, 0, 0, 128]} }
+        }));
+        let converted = convert_to_srgb(&img, &profile(p3, 2.2)).unwrap().to_rgba();
+
+        // White stays white, while the P3 red is outside of sRGB gamut.
+        let white = converted.get_pixel(0, 0).data;
+        assert!(white.iter().all(|&c| c >= 253), "white became {:?}", white);
+        let red = converted.get_pixel(1, 0).data;
+        assert_eq!([255, 0, 0, 128], red);
+    }
+
+    #[test]
+    fn parametric_curve() {
+        let srgb = Curve::Parametric{
+            g: 2.4, a: 1.0 / 1.055, b: 0.055 / 1.055, c: 1.0 / 12.92, d: 0.04045, e: 0.0, f: 0.0};
+        assert!((srgb.eval(1.0) - 1.0).abs() < 1e-4);
+        assert!((srgb.eval(0.5) - 0.2140).abs() < 1e-3);
+        assert_eq!(0.0, srgb.eval(0.0));
+    }
+}
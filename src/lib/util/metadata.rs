@@ -0,0 +1,359 @@
+//! Module handling the metadata (EXIF & ICC profiles) of template images.
+
+use image::{DynamicImage, ImageFormat};
+
+use super::animated_png::{self, PNG_SIGNATURE};
+use super::icc;
+
+
+/// Metadata of an image file that affects how the image should be displayed.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Metadata {
+    /// Orientation of the image, as given by its EXIF data.
+    pub orientation: Orientation,
+    /// Embedded ICC color profile, if any.
+    pub icc_profile: Option<Vec<u8>>,
+    /// Raw EXIF data (a TIFF structure), if any.
+    pub exif: Option<Vec<u8>>,
+}
+
+impl Metadata {
+    /// Read the metadata from the contents of a JPEG or PNG file.
+    ///
+    /// Metadata of other formats is currently ignored.
+    pub fn read(bytes: &[u8]) -> Self {
+        let mut metadata = Metadata::default();
+        if bytes.starts_with(JPEG_SOI) {
+            metadata.read_jpeg(bytes);
+        } else if bytes.starts_with(PNG_SIGNATURE) {
+            metadata.read_png(bytes);
+        }
+        if let Some(orientation) = metadata.exif.as_ref().and_then(|e| exif_orientation(e)) {
+            metadata.orientation = orientation;
+        }
+        metadata
+    }
+
+    fn read_jpeg(&mut self, bytes: &[u8]) {
+        let mut icc_chunks = vec![];
+        for (marker, data) in iter_jpeg_segments(bytes) {
+            match marker {
+                APP1 if data.starts_with(EXIF_HEADER) =>
+                    self.exif = Some(data[EXIF_HEADER.len()..].to_vec()),
+                // ICC profile may be split across several segments, each starting with
+                // its sequence number (counting from 1) & the total number of segments.
+                APP2 if data.starts_with(ICC_HEADER) && data.len() > ICC_HEADER.len() + 2 =>
+                    icc_chunks.push((data[ICC_HEADER.len()], &data[ICC_HEADER.len() + 2..])),
+                _ => {}
+            }
+        }
+        if !icc_chunks.is_empty() {
+            icc_chunks.sort_by_key(|&(seq, _)| seq);
+            self.icc_profile = Some(icc_chunks.into_iter()
+                .flat_map(|(_, chunk)| chunk.iter().cloned()).collect());
+        }
+    }
+
+    fn read_png(&mut self, bytes: &[u8]) {
+        for (kind, data) in animated_png::parse_chunks(bytes).unwrap_or_default() {
+            match kind {
+                b"eXIf" => self.exif = Some(data.to_vec()),
+                // The profile is zlib-compressed here, which we cannot inflate yet.
+                b"iCCP" => trace!("Ignoring the ICC profile of PNG image"),
+                b"IDAT" => break,
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Metadata {
+    /// Apply the metadata to given image, so that it's displayed correctly
+    /// without it (i.e. rotated upright and in the sRGB color space).
+    ///
+    /// The metadata is updated to reflect that.
+    pub fn normalize(&mut self, mut img: DynamicImage) -> DynamicImage {
+        if self.orientation != Orientation::Normal {
+            trace!("Applying EXIF orientation: {:?}", self.orientation);
+            img = self.orientation.apply(img);
+            self.orientation = Orientation::Normal;
+            if let Some(ref mut exif) = self.exif {
+                reset_exif_orientation(exif);
+            }
+        }
+        let converted = self.icc_profile.as_ref().and_then(|p| icc::convert_to_srgb(&img, p));
+        if let Some(converted) = converted {
+            trace!("Converted image with ICC profile to sRGB");
+            img = converted;
+            self.icc_profile = None;
+        }
+        img
+    }
+
+    /// Embed the metadata into an encoded image of given format.
+    ///
+    /// Only JPEG and PNG images are supported; others are returned unchanged.
+    /// Note that the ICC profile is only preserved in JPEGs.
+    pub fn embed(&self, bytes: Vec<u8>, format: ImageFormat) -> Vec<u8> {
+        match format {
+            ImageFormat::JPEG if bytes.starts_with(JPEG_SOI) => self.embed_jpeg(bytes),
+            ImageFormat::PNG if bytes.starts_with(PNG_SIGNATURE) => self.embed_png(bytes),
+            _ => bytes,
+        }
+    }
+
+    fn embed_jpeg(&self, bytes: Vec<u8>) -> Vec<u8> {
+        let mut segments = vec![];
+        if let Some(ref exif) = self.exif {
+            let mut data = EXIF_HEADER.to_vec();
+            data.extend_from_slice(exif);
+            if data.len() <= MAX_SEGMENT_SIZE {
+                write_jpeg_segment(&mut segments, APP1, &data);
+            } else {
+                warn!("EXIF data too large to embed in JPEG ({} bytes)", exif.len());
+            }
+        }
+        if let Some(ref profile) = self.icc_profile {
+            let chunk_size = MAX_SEGMENT_SIZE - ICC_HEADER.len() - 2;
+            let count = (profile.len() + chunk_size - 1) / chunk_size;
+            if count <= 255 {
+                for (i, chunk) in profile.chunks(chunk_size).enumerate() {
+                    let mut data = ICC_HEADER.to_vec();
+                    data.extend_from_slice(&[i as u8 + 1, count as u8]);
+                    data.extend_from_slice(chunk);
+                    write_jpeg_segment(&mut segments, APP2, &data);
+                }
+            }
+        }
+
+        // The new segments go after the JFIF header (if any), which must be first.
+        let mut pos = JPEG_SOI.len();
+        if let Some((APP0, data)) = iter_jpeg_segments(&bytes).next() {
+            pos += 4 + data.len();
+        }
+        let mut result = Vec::with_capacity(bytes.len() + segments.len());
+        result.extend_from_slice(&bytes[..pos]);
+        result.extend(segments);
+        result.extend_from_slice(&bytes[pos..]);
+        result
+    }
+
+    fn embed_png(&self, bytes: Vec<u8>) -> Vec<u8> {
+        let exif = match self.exif {
+            Some(ref exif) => exif,
+            None => return bytes,
+        };
+        // The eXIf chunk must precede the image data, so put it right after the header.
+        let ihdr_end = PNG_SIGNATURE.len() + PNG_CHUNK_OVERHEAD + PNG_IHDR_SIZE;
+        if bytes.len() < ihdr_end {
+            return bytes;
+        }
+        let mut result = Vec::with_capacity(bytes.len() + PNG_CHUNK_OVERHEAD + exif.len());
+        result.extend_from_slice(&bytes[..ihdr_end]);
+        animated_png::write_chunk(&mut result, b"eXIf", exif);
+        result.extend_from_slice(&bytes[ihdr_end..]);
+        result
+    }
+}
+
+const PNG_CHUNK_OVERHEAD: usize = 12;  // length, type, and CRC
+const PNG_IHDR_SIZE: usize = 13;
+
+
+/// Orientation of the image, as given by the EXIF Orientation tag.
+///
+/// Variant names describe the transformation needed to display the image upright.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Orientation {
+    /// Image is upright already.
+    Normal,
+    /// Image needs to be flipped horizontally.
+    FlipHorizontal,
+    /// Image needs to be rotated by 180 degrees.
+    Rotate180,
+    /// Image needs to be flipped vertically.
+    FlipVertical,
+    /// Image needs to be mirrored along its top-left to bottom-right diagonal.
+    Transpose,
+    /// Image needs to be rotated by 90 degrees clockwise.
+    Rotate90,
+    /// Image needs to be mirrored along its top-right to bottom-left diagonal.
+    Transverse,
+    /// Image needs to be rotated by 270 degrees clockwise.
+    Rotate270,
+}
+
+impl Default for Orientation {
+    fn default() -> Self {
+        Orientation::Normal
+    }
+}
+
+impl Orientation {
+    /// Create the Orientation from a value of the EXIF tag.
+    pub fn from_exif(value: u16) -> Option<Self> {
+        match value {
+            1 => Some(Orientation::Normal),
+            2 => Some(Orientation::FlipHorizontal),
+            3 => Some(Orientation::Rotate180),
+            4 => Some(Orientation::FlipVertical),
+            5 => Some(Orientation::Transpose),
+            6 => Some(Orientation::Rotate90),
+            7 => Some(Orientation::Transverse),
+            8 => Some(Orientation::Rotate270),
+            _ => None,
+        }
+    }
+
+    /// Transform given image so that it's upright.
+    pub fn apply(&self, img: DynamicImage) -> DynamicImage {
+        match *self {
+            Orientation::Normal => img,
+            Orientation::FlipHorizontal => img.fliph(),
+            Orientation::Rotate180 => img.rotate180(),
+            Orientation::FlipVertical => img.flipv(),
+            Orientation::Transpose => img.rotate90().fliph(),
+            Orientation::Rotate90 => img.rotate90(),
+            Orientation::Transverse => img.rotate270().fliph(),
+            Orientation::Rotate270 => img.rotate270(),
+        }
+    }
+}
+
+
+// Handling EXIF
+
+const EXIF_HEADER: &'static [u8] = b"Exif\0\0";
+const EXIF_ORIENTATION_TAG: u16 = 0x0112;
+
+/// Read the orientation from EXIF data.
+fn exif_orientation(exif: &[u8]) -> Option<Orientation> {
+    let (offset, big_endian) = try_opt!(find_exif_orientation(exif));
+    Orientation::from_exif(read_u16(&exif[offset..], big_endian))
+}
+
+/// Set the orientation in EXIF data to normal, if it's there.
+fn reset_exif_orientation(exif: &mut [u8]) {
+    if let Some((offset, big_endian)) = find_exif_orientation(exif) {
+        let bytes = if big_endian { [0, 1] } else { [1, 0] };
+        exif[offset..offset + 2].copy_from_slice(&bytes);
+    }
+}
+
+/// Find the offset of the orientation value in EXIF data, and its endianness.
+fn find_exif_orientation(exif: &[u8]) -> Option<(usize, bool)> {
+    if exif.len() < 8 {
+        return None;
+    }
+    let big_endian = match &exif[..4] {
+        b"II*\0" => false,
+        b"MM\0*" => true,
+        _ => return None,
+    };
+    // The orientation tag lives in the first IFD (Image File Directory).
+    let ifd = read_u32(&exif[4..], big_endian) as usize;
+    if exif.len() < ifd.saturating_add(2) {
+        return None;
+    }
+    let entry_count = read_u16(&exif[ifd..], big_endian) as usize;
+    (0..entry_count).map(|i| ifd + 2 + i * 12)
+        .take_while(|&entry| entry + 12 <= exif.len())
+        .find(|&entry| read_u16(&exif[entry..], big_endian) == EXIF_ORIENTATION_TAG)
+        .map(|entry| (entry + 8, big_endian))  // the value is inline in the entry
+}
+
+fn read_u16(bytes: &[u8], big_endian: bool) -> u16 {
+    if big_endian {
+        (bytes[0] as u16) << 8 | bytes[1] as u16
+    } else {
+        bytes[0] as u16 | (bytes[1] as u16) << 8
+    }
+}
+
+fn read_u32(bytes: &[u8], big_endian: bool) -> u32 {
+    if big_endian {
+        (read_u16(bytes, true) as u32) << 16 | read_u16(&bytes[2..], true) as u32
+    } else {
+        read_u16(bytes, false) as u32 | (read_u16(&bytes[2..], false) as u32) << 16
+    }
+}
+
+
+// Reading & writing JPEG segments
+
+const JPEG_SOI: &'static [u8] = &[0xff, 0xd8];
+const JPEG_SOS: u8 = 0xda;
+const APP0: u8 = 0xe0;
+const APP1: u8 = 0xe1;
+const APP2: u8 = 0xe2;
+const ICC_HEADER: &'static [u8] = b"ICC_PROFILE\0";
+/// Maximum size of JPEG segment data (its length field includes itself).
+const MAX_SEGMENT_SIZE: usize = 0xffff - 2;
+
+/// Iterate over the JPEG segments (markers & their data) before the image data.
+fn iter_jpeg_segments<'j>(bytes: &'j [u8]) -> Box<Iterator<Item=(u8, &'j [u8])> + 'j> {
+    let mut rest = &bytes[JPEG_SOI.len().min(bytes.len())..];
+    Box::new((0..).map(move |_| {
+        if rest.len() < 4 || rest[0] != 0xff || rest[1] == JPEG_SOS {
+            return None;
+        }
+        let marker = rest[1];
+        let length = read_u16(&rest[2..], true) as usize;
+        if length < 2 || rest.len() < 2 + length {
+            return None;
+        }
+        let data = &rest[4..2 + length];
+        rest = &rest[2 + length..];
+        Some((marker, data))
+    }).take_while(Option::is_some).map(Option::unwrap))
+}
+
+fn write_jpeg_segment(out: &mut Vec<u8>, marker: u8, data: &[u8]) {
+    let length = data.len() + 2;
+    out.extend_from_slice(&[0xff, marker, (length >> 8) as u8, length as u8]);
+    out.extend_from_slice(data);
+}
+
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, GenericImage, ImageFormat, Rgba, RgbaImage};
+    use super::{Metadata, Orientation};
+
+    /// Minimal little-endian EXIF data with just the orientation tag.
+    fn exif(orientation: u16) -> Vec<u8> {
+        let mut exif = b"II*\0\x08\0\0\0\x01\0".to_vec();
+        exif.extend_from_slice(&[0x12, 0x01, 3, 0, 1, 0, 0, 0]);  // tag, SHORT type, count
+        exif.extend_from_slice(&[orientation as u8, (orientation >> 8) as u8, 0, 0]);
+        exif.extend_from_slice(&[0, 0, 0, 0]);  // no next IFD
+        exif
+    }
+
+    #[test]
+    fn orientation() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(3, 2, |x, y| {
+            Rgba{data: [x as u8, y as u8, 0, 255]}
+        }));
+        let mut metadata = Metadata{exif: Some(exif(6)), ..Metadata::default()};
+        metadata.orientation = Orientation::Rotate90;
+
+        let rotated = metadata.normalize(img);
+        assert_eq!((2, 3), rotated.dimensions());
+        // Top-left corner of the original image is now in the top-right.
+        assert_eq!(Rgba{data: [0, 0, 0, 255]}, rotated.get_pixel(1, 0));
+        assert_eq!(Orientation::Normal, metadata.orientation);
+        assert_eq!(exif(1), metadata.exif.unwrap());
+    }
+
+    #[test]
+    fn jpeg_roundtrip() {
+        let jpeg = b"\xff\xd8\xff\xe0\x00\x04JF\xff\xda".to_vec();
+        let metadata = Metadata{
+            orientation: Orientation::Rotate270,
+            icc_profile: Some(vec![42; 100]),
+            exif: Some(exif(8)),
+        };
+        let embedded = metadata.embed(jpeg, ImageFormat::JPEG);
+        assert_eq!(metadata, Metadata::read(&embedded));
+    }
+}
@@ -1,5 +1,16 @@
 //! Utility code.
 
 pub mod animated_gif;
+pub mod animated_png;
+pub mod animated_webp;
+pub mod animation;
 pub mod cache;
+pub mod filters;
+pub mod glyphs;
+pub mod icc;
+pub mod metadata;
+pub mod parallel;
 pub mod text;
+pub mod transform;
+#[cfg(feature = "webp")]
+pub mod webp;
@@ -0,0 +1,53 @@
+//! Module for processing items (like animation frames) in parallel.
+
+use rayon::prelude::*;
+
+
+/// Apply a function to every item, processing at most `parallelism` items at once.
+///
+/// The results are returned in the same order as the items,
+/// so the outcome is the same as if they were processed sequentially.
+pub fn map<T, R, F>(items: Vec<T>, parallelism: usize, f: F) -> Vec<R>
+    where T: Send, R: Send, F: Fn(T) -> R + Sync
+{
+    if parallelism <= 1 || items.len() <= 1 {
+        return items.into_iter().map(f).collect();
+    }
+
+    // Split the items into (at most) `parallelism` chunks of consecutive items,
+    // each processed sequentially on one thread.
+    let chunk_size = (items.len() + parallelism - 1) / parallelism;
+    let mut chunks: Vec<Vec<T>> = Vec::with_capacity(parallelism);
+    let mut items = items.into_iter().peekable();
+    while items.peek().is_some() {
+        chunks.push(items.by_ref().take(chunk_size).collect());
+    }
+    trace!("Processing {} chunk(s) of at most {} item(s) in parallel",
+        chunks.len(), chunk_size);
+
+    let results: Vec<Vec<R>> = chunks.into_par_iter()
+        .map(|chunk| chunk.into_iter().map(&f).collect())
+        .collect();
+    results.into_iter().flat_map(|r| r).collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::map;
+
+    #[test]
+    fn results_keep_order() {
+        let items: Vec<u32> = (0..100).collect();
+        let expected: Vec<u32> = items.iter().map(|x| x * x).collect();
+        for &parallelism in &[1, 2, 3, 7, 100, 1000] {
+            assert_eq!(expected, map(items.clone(), parallelism, |x| x * x));
+        }
+    }
+
+    #[test]
+    fn empty() {
+        let items: Vec<u32> = vec![];
+        assert!(map(items, 4, |x| x + 1).is_empty());
+    }
+}
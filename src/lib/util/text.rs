@@ -3,6 +3,7 @@
 use std::collections::HashSet;
 use std::fmt;
 use std::ops::{Add, Div, Sub};
+use std::sync::Arc;
 
 use float_ord::FloatOrd;
 use image::{DynamicImage, GenericImage};
@@ -10,9 +11,9 @@ use itertools::Itertools;
 use num::One;
 use regex::Regex;
 use rusttype::{GlyphId, Font, point, Point, Rect, Scale};
-use unreachable::unreachable;
 
-use model::{Color, HAlign, VAlign, DEFAULT_TEXT_SIZE};
+use model::{Color, HAlign, VAlign};
+use super::glyphs::{GlyphBitmap, GlyphCache, GlyphKey, split_position};
 
 
 /// Check if given font has all the glyphs for given text.
@@ -94,6 +95,8 @@ pub struct Style<'f> {
     font: &'f Font<'f>,
     size: f32,
     color: Color,
+    /// Cache for the rasterized glyphs, along with the name of the font in it.
+    glyph_cache: Option<(&'f GlyphCache, &'f str)>,
 }
 
 impl<'f> Style<'f> {
@@ -103,7 +106,13 @@ impl<'f> Style<'f> {
         if size <= 0.0 {
             panic!("text::Style got negative size ({})", size);
         }
-        Style{font, size, color}
+        Style{font, size, color, glyph_cache: None}
+    }
+
+    /// Use given cache for the glyphs of the font, which is known under given name.
+    #[inline]
+    pub fn with_glyph_cache(mut self, cache: &'f GlyphCache, font_name: &'f str) -> Self {
+        self.glyph_cache = Some((cache, font_name)); self
     }
 
     /// Get a text `Scale` corresponding to the `Style`.
@@ -135,34 +144,25 @@ pub fn render_text<A: Into<Alignment>>(img: DynamicImage,
                                        s: &str,
                                        align: A, rect: Rect<f32>,
                                        style: Style) -> DynamicImage {
+    let layout = layout_text(s, style.font, style.size, rect.width());
+    render_layout(img, &layout, align, rect, style)
+}
+
+/// Renders text that has already been laid out onto given image.
+///
+/// The size of the text is taken from the layout rather than the `Style`.
+pub fn render_layout<A: Into<Alignment>>(img: DynamicImage,
+                                         layout: &TextLayout,
+                                         align: A, rect: Rect<f32>,
+                                         style: Style) -> DynamicImage {
     let mut img = img;
     let align: Alignment = align.into();
-    trace!("render_text(..., <length: {}>, {:?}, {:?}, {:?})",
-        s.len(), align, rect, style);
+    let style = Style{size: layout.size, ..style};
+    trace!("render_layout(..., <{} line(s)>, {:?}, {:?}, {:?})",
+        layout.lines.len(), align, rect, style);
 
-    let mut lines = break_lines(s, &style, rect.width());
-    trace!("Text broken into {} line(s)", lines.len());
-
-    // TODO: do we need some adjustment for VAlign::Middle, too?
-    if align.vertical == VAlign::Bottom {
-        lines.reverse();
-    }
-
-    let mut rect = rect;
-    let line_height = style.line_height();
-    for line in lines {
-        img = render_line(img, &line, align, rect, &style);
-
-        // After rendering the line, shrink the rectangle by subtracting
-        // line_height from its height in a way that plays well with vertical alignment.
-        match align.vertical {
-            VAlign::Top => rect.min.y += line_height,
-            VAlign::Middle => {
-                rect.min.y += line_height / 2.0;
-                rect.max.y -= line_height / 2.0;
-            }
-            VAlign::Bottom => rect.max.y -= line_height,
-        }
+    for (&(ref line, width), line_rect) in layout.lines.iter().zip(layout.line_rects(align, rect)) {
+        img = draw_line(img, line, width, align, line_rect, &style);
     }
     img
 }
@@ -175,164 +175,249 @@ pub fn render_line<A: Into<Alignment>>(img: DynamicImage,
                                        s: &str,
                                        align: A, rect: Rect<f32>,
                                        style: &Style) -> DynamicImage {
-    let mut img = img;
     let align: Alignment = align.into();
     trace!("render_line(..., {:?}, {:?}, {:?}, {:?})",
         s, align, rect, style);
 
+    // Unless it's a straightforward rendering in the top-left corner,
+    // we need to compute the final bounds of the text first,
+    // so that we can account for it when computing the start position.
+    let width = if align.horizontal != HAlign::Left { text_width(s, style) } else { 0.0 };
+    draw_line(img, s, width, align, rect, style)
+}
+
+/// Draw a line of text of known width onto given image.
+fn draw_line(img: DynamicImage,
+             s: &str, width: f32,
+             align: Alignment, rect: Rect<f32>,
+             style: &Style) -> DynamicImage {
+    let mut img = img;
+
     // Rendering text requires alpha blending.
     if img.as_rgba8().is_none() {
         img = DynamicImage::ImageRgba8(img.to_rgba());
     }
 
-    let scale = style.scale();
-    let v_metrics = style.font.v_metrics(scale);
+    // Figure out where we're drawing, and then draw the text.
+    let position = line_position(style.font, style.size, width, align, rect);
+    for glyph in style.font.layout(s, style.scale(), position) {
+        let ((origin_x, origin_y), offset) = split_position(glyph.position());
+        let bitmap = match style.glyph_cache {
+            Some((cache, font_name)) => {
+                let key = GlyphKey::new(font_name, &glyph, offset);
+                cache.get(&key).unwrap_or_else(|| {
+                    cache.put(key, GlyphBitmap::rasterize(&glyph, offset))
+                })
+            }
+            None => Arc::new(GlyphBitmap::rasterize(&glyph, offset)),
+        };
+        bitmap.for_each_pixel(|x, y, alpha| {
+            let (x, y) = (origin_x + x, origin_y + y);
+            if x >= 0 && y >= 0 && img.in_bounds(x as u32, y as u32) {
+                img.blend_pixel(x as u32, y as u32, style.color.to_rgba(alpha));
+            }
+        });
+    }
+
+    img
+}
+
+
+/// Compute the position of the baseline origin of a line of text of given size & width
+/// which is aligned within given rectangle.
+fn line_position<'f>(font: &'f Font<'f>, size: f32, width: f32,
+                     align: Alignment, rect: Rect<f32>) -> Point<f32> {
+    let v_metrics = font.v_metrics(Scale::uniform(size));
 
-    // Figure out where we're drawing.
-    //
-    // Unless it's a straightforward rendering in the top-left corner,
-    // we need to compute the final bounds of the text first,
-    // so that we can account for it when computing the start position.
-    //
     let mut position = align.origin_within(rect);
-    if align.horizontal != HAlign::Left {
-        let width = text_width(s, &style);
-        match align.horizontal {
-            HAlign::Center => position.x -= width / 2.0,
-            HAlign::Right => position.x -= width,
-            _ => unsafe { unreachable(); },
-        }
+    match align.horizontal {
+        HAlign::Left => {},
+        HAlign::Center => position.x -= width / 2.0,
+        HAlign::Right => position.x -= width,
     }
     match align.vertical {
         VAlign::Top => position.y += v_metrics.ascent,
-        VAlign::Middle => {
-            let height = style.size;
-            position.y += v_metrics.ascent - height / 2.0;
-        },
-        VAlign::Bottom => {
-            position.y -= v_metrics.descent.abs();  // it's usually negative
-        },
-    }
-
-    // Now we can draw the text.
-    for glyph in style.font.layout(s, scale, position) {
-        if let Some(bbox) = glyph.pixel_bounding_box() {
-            glyph.draw(|x, y, v| {
-                let x = (bbox.min.x + x as i32) as u32;
-                let y = (bbox.min.y + y as i32) as u32;
-                let alpha = (v * 255f32) as u8;
-                if img.in_bounds(x, y) {
-                    img.blend_pixel(x, y, style.color.to_rgba(alpha));
-                }
-            });
-        }
+        VAlign::Middle => position.y += v_metrics.ascent - size / 2.0,
+        VAlign::Bottom => position.y -= v_metrics.descent.abs(),  // it's usually negative
     }
-
-    img
+    position
 }
 
 
-/// Return the maximum text size that'd still allow us to fit the text
-/// within given rectangle.
-///
-/// The size returned may be ridiculous if the text is long enough
-/// (or the rectangle is small enough). However, if the size cannot be determined
-/// in reasonable number of iterations, None is returned.
-pub fn fit_text<'s, 'f>(rect: Rect<f32>, s: &'s str, font: &'f Font<'f>) -> Option<f32> {
-    trace!("fit_text({:?}, <{} bytes of text>, ...", rect, s.len());
-    if rect.width() <= 0.0 || rect.height() <= 0.0 {
-        return None;
+/// Text broken into lines at a particular size, ready to be rendered.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TextLayout {
+    /// Size of the text.
+    pub size: f32,
+    /// Height of a single line of the text.
+    pub line_height: f32,
+    /// Lines of the text, along with their pixel widths.
+    pub lines: Vec<(String, f32)>,
+}
+
+impl TextLayout {
+    /// Pixel width of the widest line.
+    pub fn width(&self) -> f32 {
+        self.lines.iter().map(|&(_, w)| FloatOrd(w)).max().map(|w| w.0).unwrap_or(0.0)
     }
 
-    // TODO: pick a larger default size so that short texts will
-    // still completely fill larger rectangles
-    let mut size = DEFAULT_TEXT_SIZE;
-    let unused_color = Color::white();  // not used, but needed for Style
+    /// Pixel height of all the lines.
+    #[inline]
+    pub fn height(&self) -> f32 {
+        self.lines.len() as f32 * self.line_height
+    }
 
-    // Gradually shrink the text, break it into lines,
-    // and try to fit it within the given rectangle.
+    /// Compute the bounding boxes of the lines (in order)
+    /// when they're rendered with given alignment within given rectangle.
     ///
-    // Continue to do so until succeeded,
-    // or a maximum number of iterations has been reached.
-    const SHRINK_FACTOR: f32 = 0.9;
-    const MAX_ITERS: usize = 16;
-    let mut iters = 1;
-    while iters <= MAX_ITERS {
-        let style = Style::new(font, size, unused_color);
-        let lines = break_lines(s, &style, rect.width());
-
-        let width = lines.iter().map(|line| text_width(line, &style))
-            .map(FloatOrd).max().map(|w| w.0)
-            .unwrap_or(0.0);
-        let height = lines.len() as f32 * style.line_height();
-        if width <= rect.width() && height <= rect.height() {
-            break;  // Found a fitting size.
-        }
+    /// The boxes span the lines' width, and the height of the font's
+    /// ascent & descent (so they don't depend on the actual glyphs in the line).
+    pub fn line_bounds<'f, A>(&self, font: &'f Font<'f>, align: A,
+                              rect: Rect<f32>) -> Vec<Rect<f32>>
+        where A: Into<Alignment>
+    {
+        let align: Alignment = align.into();
+        let v_metrics = font.v_metrics(Scale::uniform(self.size));
+        self.lines.iter().zip(self.line_rects(align, rect))
+            .map(|(&(_, width), line_rect)| {
+                let origin = line_position(font, self.size, width, align, line_rect);
+                Rect{
+                    min: point(origin.x, origin.y - v_metrics.ascent),
+                    max: point(origin.x + width, origin.y + v_metrics.descent.abs()),
+                }
+            })
+            .collect()
+    }
 
-        let new_size = size * SHRINK_FACTOR;
-        if new_size >= size {
-            // Seems we got REALLY small and float inaccuracies started to matter.
-            warn!("Text size lost accuracy ({:?}) after {} iterations, starting from size {}",
-                new_size, iters, DEFAULT_TEXT_SIZE);
-            return None;
+    /// Compute the rectangles (in order) that each of the lines is aligned within
+    /// when the whole text is aligned within given rectangle.
+    fn line_rects(&self, align: Alignment, rect: Rect<f32>) -> Vec<Rect<f32>> {
+        let mut rects = Vec::with_capacity(self.lines.len());
+
+        // Bottom-aligned lines are rendered from the last one,
+        // shrinking the rectangle by subtracting line height from its height
+        // after each line in a way that plays well with vertical alignment.
+        // TODO: do we need some adjustment for VAlign::Middle, too?
+        let mut rect = rect;
+        for _ in &self.lines {
+            rects.push(rect);
+            match align.vertical {
+                VAlign::Top => rect.min.y += self.line_height,
+                VAlign::Middle => {
+                    rect.min.y += self.line_height / 2.0;
+                    rect.max.y -= self.line_height / 2.0;
+                }
+                VAlign::Bottom => rect.max.y -= self.line_height,
+            }
         }
-        size = new_size;
-        iters += 1;
+        if align.vertical == VAlign::Bottom {
+            rects.reverse();
+        }
+        rects
+    }
+}
+
+/// Lay out the text at given size, breaking it into lines of given maximum width.
+pub fn layout_text<'f>(s: &str, font: &'f Font<'f>, size: f32, line_width: f32) -> TextLayout {
+    let style = Style::new(font, size, Color::white());  // color is unused
+    TextLayout{
+        size,
+        line_height: style.line_height(),
+        lines: break_lines(s, &style, line_width),
     }
+}
 
-    if iters > MAX_ITERS {
-        warn!(
-            "Couldn't fit text in a {}x{} rect even after {} iterations (last attempt: {})",
-            rect.width(), rect.height(), MAX_ITERS, size);
+
+/// Difference between text sizes below which the search for the best one stops.
+const SIZE_PRECISION: f32 = 0.5;
+
+/// Lay out the text at the largest size from given (min, max) range
+/// that'd still allow it to fit within given rectangle.
+///
+/// If the text doesn't fit even at the minimum size, it is laid out at that size
+/// (and will overflow the rectangle when rendered).
+/// None is returned only if the rectangle is empty.
+pub fn fit_text<'s, 'f>(rect: Rect<f32>, s: &'s str, font: &'f Font<'f>,
+                        size_range: (f32, f32)) -> Option<TextLayout> {
+    trace!("fit_text({:?}, <{} bytes of text>, ..., {:?})", rect, s.len(), size_range);
+    if rect.width() <= 0.0 || rect.height() <= 0.0 {
         return None;
     }
-    Some(size)
+    let layout = search_size(size_range, |size| {
+        let layout = layout_text(s, font, size, rect.width());
+        let fits = layout.width() <= rect.width() && layout.height() <= rect.height();
+        (fits, layout)
+    });
+    Some(layout)
 }
 
-/// Return the maximum text size that'd still allow us to fit a line
-/// within given maximum width.
+/// Lay out the text at the largest size from given (min, max) range
+/// that'd still allow every line of it to fit within given maximum width
+/// without being broken up.
 ///
-/// The size returned may be ridiculous if the text is long enough
-/// (or max_width is low enough). However, if the size cannot be determined
-/// in reasonable number of iterations, None is returned.
+/// If the text doesn't fit even at the minimum size, it is laid out at that size
+/// (and broken into further lines if needed).
+/// None is returned only if max_width isn't positive.
 ///
-/// This should only be called on single-line texts.
-/// Any preexisting line break characters will be ignored.
-pub fn fit_line<'s, 'f>(max_width: f32, s: &'s str, font: &'f Font<'f>) -> Option<f32> {
-    trace!("fit_line({:?}, <{} bytes of text>, ...)", max_width, s.len());
+/// This should generally be called on single-line texts.
+pub fn fit_line<'s, 'f>(max_width: f32, s: &'s str, font: &'f Font<'f>,
+                        size_range: (f32, f32)) -> Option<TextLayout> {
+    trace!("fit_line({:?}, <{} bytes of text>, ..., {:?})", max_width, s.len(), size_range);
     if max_width <= 0.0 {
         return None;
     }
-
-    // TODO: pick a larger default size so that short texts will
-    // still completely fill larger rectangles
-    let mut size = DEFAULT_TEXT_SIZE;
-    let color = Color::white();  // not used, but needed for Style
-
-    // Gradually shrink the size and try to fit it,
-    // but prevent infinite loops if we can't fit it after all.
-    const MAX_ITERS: usize = 16;
-    let mut iters = 1;
-    while iters <= MAX_ITERS && text_width(s, &Style::new(font, size, color)) > max_width {
-        const SHRINK_FACTOR: f32 = 0.9;
-        let new_size = size * SHRINK_FACTOR;
-        if new_size >= size {
-            // Seems we got REALLY small and float inaccuracies started to matter.
-            warn!("Text size lost accuracy ({:?}) after {} iterations, starting from {}",
-                new_size, iters, DEFAULT_TEXT_SIZE);
-            return None;
+    let layout = search_size(size_range, |size| {
+        let style = Style::new(font, size, Color::white());  // color is unused
+        let lines: Vec<_> = s.lines()
+            .map(|line| (line.to_owned(), text_width(line, &style)))
+            .collect();
+        if lines.iter().all(|&(_, w)| w <= max_width) {
+            (true, TextLayout{size, line_height: style.line_height(), lines})
+        } else {
+            (false, layout_text(s, font, size, max_width))
         }
-        size = new_size;
-        iters += 1;
+    });
+    Some(layout)
+}
+
+/// Binary search for the largest size within given (min, max) range that fits,
+/// according to given function which also lays out the text at that size.
+///
+/// Returns the layout for the size found,
+/// or for the minimum size if none of the sizes fit.
+fn search_size<F>(size_range: (f32, f32), mut try_size: F) -> TextLayout
+    where F: FnMut(f32) -> (bool, TextLayout)
+{
+    let (min, max) = size_range;
+    debug_assert!(0.0 < min && min <= max, "invalid text size range: {:?}", size_range);
+
+    // Most short texts will fit at the maximum size right away.
+    let (fits, layout) = try_size(max);
+    if fits {
+        return layout;
+    }
+    let (fits, mut best) = try_size(min);
+    if !fits {
+        warn!("Couldn't fit text even at the minimum size of {}", min);
+        return best;
     }
 
-    if iters > MAX_ITERS {
-        warn!(
-            "Couldn't fit text in a width of {} even after {} iterations (last attempt: {})",
-            max_width, MAX_ITERS, size);
-        return None;
+    // Invariant: text fits at `low` (with `best` being its layout), but not at `high`.
+    let (mut low, mut high) = (min, max);
+    let mut iters = 2;
+    while high - low > SIZE_PRECISION {
+        let size = low + (high - low) / 2.0;
+        let (fits, layout) = try_size(size);
+        if fits {
+            low = size;
+            best = layout;
+        } else {
+            high = size;
+        }
+        iters += 1;
     }
-    Some(size)
+    trace!("Found text size {} in {} iteration(s)", low, iters);
+    best
 }
 
 
@@ -353,6 +438,17 @@ fn text_width(s: &str, style: &Style) -> f32 {
         .next().unwrap_or(0.0)
 }
 
+/// Compute the pixel width of a segment of text,
+/// which is either a single word or whitespace between words.
+fn segment_width(s: &str, style: &Style) -> f32 {
+    // Whitespace doesn't have any glyph bounds that text_width() could measure.
+    if s.chars().all(char::is_whitespace) {
+        s.chars().map(|c| char_width(c, style)).sum()
+    } else {
+        text_width(s, style)
+    }
+}
+
 /// Compute the pixel width of given character.
 fn char_width(c: char, style: &Style) -> f32 {
     // This isn't just text_width() call for a 1-char string,
@@ -365,16 +461,17 @@ fn char_width(c: char, style: &Style) -> f32 {
 
 // Line breaking.
 
-/// Break the text into lines, fitting given width.
-fn break_lines(s: &str, style: &Style, line_width: f32) -> Vec<String> {
+/// Break the text into lines fitting given width,
+/// returning them together with their pixel widths.
+fn break_lines(s: &str, style: &Style, line_width: f32) -> Vec<(String, f32)> {
     s.lines()
         .flat_map(|line| break_single_line(line, style, line_width))
         .collect()
 }
 
-/// Break a single line into multiple lines.
+/// Break a single line into multiple lines (with their widths).
 /// The line should not contain explicit line breaks.
-fn break_single_line(s: &str, style: &Style, line_width: f32) -> Vec<String> {
+fn break_single_line(s: &str, style: &Style, line_width: f32) -> Vec<(String, f32)> {
     lazy_static! {
         static ref WORD_BOUNDARY: Regex = Regex::new(r"\b").unwrap();
     }
@@ -391,7 +488,7 @@ fn break_single_line(s: &str, style: &Style, line_width: f32) -> Vec<String> {
     let mut current_line = String::new();
     let mut current_width = 0.0;
     for segment in segments {
-        let mut segment_width = text_width(segment, style);
+        let mut segment_width = segment_width(segment, style);
 
         // Simplest case is when the segment trivially fits within the line.
         if current_width + segment_width < line_width {
@@ -404,7 +501,7 @@ fn break_single_line(s: &str, style: &Style, line_width: f32) -> Vec<String> {
         // break the current line before it & put the segment in the next one.
         if segment_width < line_width {
             if !current_line.is_empty() {
-                result.push(current_line);
+                result.push(finish_line(current_line, current_width, style));
             }
             // If the overflowing segment is just a single space,
             // then just forget about it completely.
@@ -428,7 +525,13 @@ fn break_single_line(s: &str, style: &Style, line_width: f32) -> Vec<String> {
             // to the next line, too.
             let mut carryover: Vec<char> = vec![];
             let mut carryover_width = 0.0;
+            //
+            // (Every line gets at least one character though, however wide,
+            // or we would never run out of the carryover).
             while current_width + segment_width > line_width {
+                if current_line.is_empty() && segment.chars().nth(1).is_none() {
+                    break;
+                }
                 match segment.pop() {
                     Some(c) => {
                         carryover.push(c);
@@ -453,7 +556,7 @@ fn break_single_line(s: &str, style: &Style, line_width: f32) -> Vec<String> {
             }
 
             // Otherwise, we need to start a new line for the carryover part...
-            result.push(current_line);
+            result.push(finish_line(current_line, current_width, style));
             current_line = String::new();
             current_width = 0.0;
 
@@ -464,8 +567,80 @@ fn break_single_line(s: &str, style: &Style, line_width: f32) -> Vec<String> {
         }
     }
     if !current_line.is_empty() {
-        result.push(current_line);
+        result.push(finish_line(current_line, current_width, style));
     }
 
     result
 }
+
+/// Finish a line of given width by removing the trailing whitespace,
+/// which shouldn't affect the alignment of the line.
+fn finish_line(line: String, width: f32, style: &Style) -> (String, f32) {
+    let mut line = line;
+    let mut width = width;
+    while line.ends_with(char::is_whitespace) {
+        let c = line.pop().unwrap();
+        width -= char_width(c, style);
+    }
+    (line, width.max(0.0))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use rusttype::{point, Rect};
+
+    use caption::tests::font;
+    use super::{fit_line, fit_text, layout_text};
+
+    const TEXT: &'static str = "ONE DOES NOT SIMPLY WALK INTO MORDOR";
+
+    fn rect(width: f32, height: f32) -> Rect<f32> {
+        Rect{min: point(0.0, 0.0), max: point(width, height)}
+    }
+
+    #[test]
+    fn fit_text_finds_largest_fitting_size() {
+        let font = font();
+        let rect = rect(300.0, 150.0);
+        let layout = fit_text(rect, TEXT, &font, (8.0, 128.0)).unwrap();
+        assert!(layout.width() <= rect.width() && layout.height() <= rect.height());
+        assert!(layout.size > 8.0 && layout.size < 128.0);
+
+        // A noticeably larger size wouldn't fit anymore.
+        let larger = layout_text(TEXT, &font, layout.size + 1.0, rect.width());
+        assert!(larger.width() > rect.width() || larger.height() > rect.height());
+    }
+
+    #[test]
+    fn fit_text_respects_size_range() {
+        let font = font();
+        let layout = fit_text(rect(1000.0, 1000.0), "LOL", &font, (8.0, 64.0)).unwrap();
+        assert_eq!(64.0, layout.size);
+
+        let layout = fit_text(rect(50.0, 20.0), TEXT, &font, (8.0, 64.0)).unwrap();
+        assert_eq!(8.0, layout.size);
+
+        assert!(fit_text(rect(0.0, 100.0), TEXT, &font, (8.0, 64.0)).is_none());
+    }
+
+    #[test]
+    fn fit_line_keeps_text_in_one_line() {
+        let font = font();
+        let layout = fit_line(400.0, TEXT, &font, (8.0, 128.0)).unwrap();
+        assert_eq!(1, layout.lines.len());
+        assert_eq!(TEXT, layout.lines[0].0);
+        assert!(layout.width() <= 400.0);
+    }
+
+    #[test]
+    fn layout_lines_have_no_trailing_whitespace() {
+        let font = font();
+        let layout = layout_text(TEXT, &font, 32.0, 200.0);
+        assert!(layout.lines.len() > 1);
+        for &(ref line, width) in &layout.lines {
+            assert!(!line.ends_with(' '));
+            assert!(width > 0.0 && width <= 200.0);
+        }
+    }
+}
@@ -0,0 +1,276 @@
+//! Module implementing geometric transformations of template images.
+
+use image::{ColorType, DynamicImage, FilterType, GenericImage, Rgba, RgbaImage};
+
+use model::Transform;
+
+
+/// Apply given transformation to an image.
+/// Returns a new image.
+pub fn apply(img: DynamicImage, transform: &Transform) -> DynamicImage {
+    trace!("Applying {:?} to a {}x{} image",
+        transform, img.dimensions().0, img.dimensions().1);
+    match *transform {
+        Transform::Crop{left, top, width, height} => crop(img, left, top, width, height),
+        Transform::FlipHorizontal => img.fliph(),
+        Transform::FlipVertical => img.flipv(),
+        Transform::Rotate90 => img.rotate90(),
+        Transform::Rotate180 => img.rotate180(),
+        Transform::Rotate270 => img.rotate270(),
+        Transform::Rotate{degrees, background} => {
+            if transform.is_noop() {
+                return img;
+            }
+            let background = background
+                .map(|c| c.to_rgba(0xff))
+                .unwrap_or(Rgba{data: [0, 0, 0, 0]});
+            DynamicImage::ImageRgba8(rotate(&img, degrees, background))
+        }
+    }
+}
+
+
+/// Compute the size of the image that would result from applying given transformation
+/// to an image of given size, without actually applying it.
+pub fn output_size(size: (u32, u32), transform: &Transform) -> (u32, u32) {
+    let (width, height) = size;
+    match *transform {
+        Transform::Crop{left, top, width: crop_width, height: crop_height} => {
+            if left >= width || top >= height {
+                size  // crop is ignored
+            } else {
+                (crop_width.min(width - left), crop_height.min(height - top))
+            }
+        }
+        Transform::FlipHorizontal | Transform::FlipVertical | Transform::Rotate180 => size,
+        Transform::Rotate90 | Transform::Rotate270 => (height, width),
+        Transform::Rotate{degrees, ..} => {
+            if transform.is_noop() { size } else { rotated_size(size, degrees) }
+        }
+    }
+}
+
+
+/// Cut out given rectangular region of the image.
+///
+/// The rectangle is clipped to image bounds. If nothing remains of it,
+/// the image is returned unchanged.
+fn crop(img: DynamicImage, left: u32, top: u32, width: u32, height: u32) -> DynamicImage {
+    let mut img = img;
+    let (img_width, img_height) = img.dimensions();
+    if left >= img_width || top >= img_height {
+        warn!("Crop rectangle {}x{}+{}+{} lies outside of the {}x{} image, ignoring it",
+            width, height, left, top, img_width, img_height);
+        return img;
+    }
+
+    let width = width.min(img_width - left);
+    let height = height.min(img_height - top);
+    trace!("Cropping to {}x{}+{}+{}", width, height, left, top);
+    img.crop(left, top, width, height)
+}
+
+
+/// Resize the image to fit within given bounds, preserving its aspect ratio.
+///
+/// Unlike `DynamicImage::resize`, this takes care not to bleed the color
+/// of transparent pixels (which is usually black) into the opaque ones.
+pub fn resize(img: &DynamicImage, width: u32, height: u32, filter: FilterType) -> DynamicImage {
+    match img.color() {
+        ColorType::RGBA(_) | ColorType::GrayA(_) => {}
+        _ => return img.resize(width, height, filter),
+    }
+
+    // Resize with the color channels premultiplied by alpha,
+    // so that transparent pixels don't contribute any color.
+    let mut rgba = img.to_rgba();
+    for pixel in rgba.pixels_mut() {
+        let alpha = pixel.data[3] as u32;
+        for c in pixel.data[..3].iter_mut() {
+            *c = (*c as u32 * alpha / 255) as u8;
+        }
+    }
+    let mut resized = DynamicImage::ImageRgba8(rgba).resize(width, height, filter).to_rgba();
+    for pixel in resized.pixels_mut() {
+        let alpha = pixel.data[3] as u32;
+        for c in pixel.data[..3].iter_mut() {
+            *c = if alpha == 0 { 0 } else { (*c as u32 * 255 / alpha).min(255) as u8 };
+        }
+    }
+    DynamicImage::ImageRgba8(resized)
+}
+
+
+/// Rotate the image clockwise by an arbitrary angle.
+///
+/// The resulting image is large enough to contain the whole rotated original.
+/// Area that it doesn't cover is filled with the `background` pixel.
+pub fn rotate(img: &DynamicImage, degrees: f32, background: Rgba<u8>) -> RgbaImage {
+    let src = img.to_rgba();
+    let (width, height) = src.dimensions();
+    let (sin, cos) = degrees.to_radians().sin_cos();
+
+    let (new_width, new_height) = rotated_size((width, height), degrees);
+    trace!("Rotating {}x{} image by {}deg results in {}x{} image",
+        width, height, degrees, new_width, new_height);
+
+    let (cx, cy) = (width as f32 / 2.0, height as f32 / 2.0);
+    let (new_cx, new_cy) = (new_width as f32 / 2.0, new_height as f32 / 2.0);
+
+    let mut result = RgbaImage::from_pixel(new_width, new_height, background);
+    for (x, y, pixel) in result.enumerate_pixels_mut() {
+        // Map the center of the target pixel back onto the source image
+        // by rotating it in the opposite direction.
+        let dx = x as f32 + 0.5 - new_cx;
+        let dy = y as f32 + 0.5 - new_cy;
+        let src_x = dx * cos + dy * sin + cx - 0.5;
+        let src_y = -dx * sin + dy * cos + cy - 0.5;
+        if let Some(p) = sample_bilinear(&src, src_x, src_y) {
+            *pixel = p;
+        }
+    }
+    result
+}
+
+/// Size of the image that fits the whole image of given size rotated by given angle.
+fn rotated_size(size: (u32, u32), degrees: f32) -> (u32, u32) {
+    let (width, height) = (size.0 as f32, size.1 as f32);
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    ((width * cos.abs() + height * sin.abs()).round() as u32,
+     (width * sin.abs() + height * cos.abs()).round() as u32)
+}
+
+/// Sample the image at given (fractional) pixel coordinates
+/// using bilinear interpolation.
+///
+/// Returns `None` if the coordinates fall outside of the image.
+fn sample_bilinear(img: &RgbaImage, x: f32, y: f32) -> Option<Rgba<u8>> {
+    let (width, height) = img.dimensions();
+    if x < -0.5 || y < -0.5 || x > width as f32 - 0.5 || y > height as f32 - 0.5 {
+        return None;
+    }
+
+    let x = x.max(0.0).min((width - 1) as f32);
+    let y = y.max(0.0).min((height - 1) as f32);
+    let (x0, y0) = (x.floor() as u32, y.floor() as u32);
+    let (x1, y1) = ((x0 + 1).min(width - 1), (y0 + 1).min(height - 1));
+    let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+
+    let corners = [
+        (img.get_pixel(x0, y0), (1.0 - fx) * (1.0 - fy)),
+        (img.get_pixel(x1, y0), fx * (1.0 - fy)),
+        (img.get_pixel(x0, y1), (1.0 - fx) * fy),
+        (img.get_pixel(x1, y1), fx * fy),
+    ];
+    // Color channels are weighted by alpha, too,
+    // so that transparent pixels don't contribute any color.
+    let alpha: f32 = corners.iter().map(|&(p, w)| p.data[3] as f32 * w).sum();
+    let mut data = [0u8; 4];
+    data[3] = alpha.round().max(0.0).min(255.0) as u8;
+    if alpha > 0.0 {
+        for (i, channel) in data[..3].iter_mut().enumerate() {
+            let value: f32 = corners.iter()
+                .map(|&(p, w)| p.data[i] as f32 * p.data[3] as f32 * w).sum();
+            *channel = (value / alpha).round().max(0.0).min(255.0) as u8;
+        }
+    }
+    Some(Rgba{data})
+}
+
+
+#[cfg(test)]
+mod tests {
+    use image::{self, DynamicImage, FilterType, GenericImage, Rgba, RgbaImage};
+    use caption::tests::data_dir;
+    use model::Transform;
+    use super::{apply, output_size, resize, rotate};
+
+    #[test]
+    fn crop_is_clipped() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::new(100, 50));
+        let result = apply(img, &Transform::Crop{left: 80, top: 10, width: 50, height: 20});
+        assert_eq!((20, 20), result.dimensions());
+    }
+
+    #[test]
+    fn crop_outside_is_ignored() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::new(100, 50));
+        let result = apply(img, &Transform::Crop{left: 200, top: 10, width: 50, height: 20});
+        assert_eq!((100, 50), result.dimensions());
+    }
+
+    #[test]
+    fn right_angle_rotation_swaps_dimensions() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::new(100, 50));
+        assert_eq!((50, 100), apply(img.clone(), &Transform::Rotate90).dimensions());
+        assert_eq!((100, 50), apply(img.clone(), &Transform::Rotate180).dimensions());
+        assert_eq!((50, 100), apply(img, &Transform::Rotate270).dimensions());
+    }
+
+    #[test]
+    fn arbitrary_rotation_fills_background() {
+        let white = Rgba{data: [0xff, 0xff, 0xff, 0xff]};
+        let red = Rgba{data: [0xff, 0x0, 0x0, 0xff]};
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(100, 100, white));
+
+        let result = rotate(&img, 45.0, red);
+        let (width, height) = result.dimensions();
+        assert_eq!((141, 141), (width, height));
+        assert_eq!(red, *result.get_pixel(0, 0));  // Corners are uncovered...
+        assert_eq!(white, *result.get_pixel(width / 2, height / 2));  // ...but center isn't.
+    }
+
+    #[test]
+    fn output_size_matches_result() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::new(100, 50));
+        for t in &[Transform::Crop{left: 80, top: 10, width: 50, height: 20},
+                   Transform::Crop{left: 200, top: 10, width: 50, height: 20},
+                   Transform::FlipVertical, Transform::Rotate90,
+                   Transform::rotate(30.0, None), Transform::rotate(360.0, None)] {
+            assert_eq!(apply(img.clone(), t).dimensions(), output_size((100, 50), t),
+                       "transform {:?}", t);
+        }
+    }
+
+    #[test]
+    fn resize_doesnt_bleed_transparent_color() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(8, 8, |x, _| {
+            if x < 4 { Rgba{data: [0, 0, 0, 0]} } else { Rgba{data: [0xff, 0xff, 0xff, 0xff]} }
+        }));
+        let result = resize(&img, 4, 4, FilterType::Triangle);
+        assert_eq!((4, 4), result.dimensions());
+        for (_, _, pixel) in result.pixels() {
+            // Pixels are either fully transparent, or (partially) opaque & white.
+            if pixel.data[3] > 0 {
+                assert_eq!([0xff, 0xff, 0xff], pixel.data[..3], "pixel {:?}", pixel);
+            }
+        }
+        assert_eq!(0, result.get_pixel(0, 0).data[3]);
+        assert_eq!(0xff, result.get_pixel(3, 0).data[3]);
+    }
+
+    #[test]
+    fn transparent_template_stays_transparent() {
+        let fixtures = data_dir().join("fixtures");
+        // Left half of the fixture is transparent, right half is opaque & white.
+        let img = image::open(fixtures.join("transparent.png")).unwrap();
+        let (width, height) = img.dimensions();
+
+        let transformed = vec![
+            resize(&img, width * 2, height * 2, FilterType::Lanczos3),
+            resize(&img, width / 2, height / 2, FilterType::Triangle),
+            apply(img.clone(), &Transform::FlipVertical),
+            apply(img.clone(), &Transform::rotate(30.0, None)),
+        ];
+        for result in transformed {
+            let (w, h) = result.dimensions();
+            for (_, _, pixel) in result.pixels() {
+                if pixel.data[3] > 0 {
+                    assert_eq!([0xff, 0xff, 0xff], pixel.data[..3], "pixel {:?}", pixel);
+                }
+            }
+            assert_eq!(0, result.get_pixel(w / 4, h / 2).data[3], "{}x{}", w, h);
+            assert_eq!(0xff, result.get_pixel(3 * w / 4, h / 2).data[3], "{}x{}", w, h);
+        }
+    }
+}
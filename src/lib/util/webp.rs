@@ -0,0 +1,534 @@
+//! Module implementing the encoding & decoding of WebP images & animations.
+//!
+//! Individual images are encoded & decoded by libwebp (via the `webp` crate).
+//! Animations are then assembled from those (or split into them) by handling
+//! the RIFF container with ANIM/ANMF chunks ourselves, as described in:
+//! https://developers.google.com/speed/webp/docs/riff_container
+
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::time::Duration;
+
+use image::{DynamicImage, GenericImage, ImageBuffer, Pixel, Rgba, RgbaImage};
+use webp;
+
+use super::animation;
+pub use super::animated_webp::{WebpAnimation, WebpFrame};
+
+
+/// Compression method for WebP images.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    /// Lossy compression with given quality percentage.
+    Lossy(u8),
+    /// Lossless compression.
+    Lossless,
+}
+
+
+/// Encode a still image as WebP.
+pub fn encode(img: &DynamicImage, compression: Compression) -> io::Result<Vec<u8>> {
+    let (width, height) = img.dimensions();
+    let rgba = img.to_rgba();
+    let encoder = webp::Encoder::from_rgba(&*rgba, width, height);
+    let memory = match compression {
+        Compression::Lossy(quality) => encoder.encode(quality as f32),
+        Compression::Lossless => encoder.encode_lossless(),
+    };
+    if memory.len() < RIFF_HEADER_SIZE {
+        return Err(io::Error::new(io::ErrorKind::Other,
+            format!("failed to encode {}x{} image as WebP", width, height)));
+    }
+    Ok(memory.to_vec())
+}
+
+/// Encode an animation from given frames (along with their display durations).
+///
+/// All frames must be of the same size.
+/// If `loop_count` is `None`, the animation will loop forever;
+/// otherwise it's repeated given number of times after being played once.
+#[inline]
+pub fn encode_animation(frames: &[(DynamicImage, Duration)],
+                        compression: Compression,
+                        loop_count: Option<u16>) -> io::Result<Vec<u8>> {
+    encode_animation_with_hook(frames, compression, loop_count, &|_| Ok(()))
+}
+
+/// Encode an animation from given frames like `encode_animation`,
+/// calling given function with the index of every frame before it's encoded.
+///
+/// If the function returns an error, the encoding stops and fails with that error.
+pub fn encode_animation_with_hook(frames: &[(DynamicImage, Duration)],
+                                  compression: Compression,
+                                  loop_count: Option<u16>,
+                                  on_frame: &(Fn(usize) -> io::Result<()> + Sync))
+                                  -> io::Result<Vec<u8>> {
+    let (width, height) = frames.first().map(|&(ref img, _)| img.dimensions())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no frames to encode"))?;
+
+    let mut has_alpha = false;
+    let mut body = vec![];
+
+    // Animation parameters: transparent background & the loop count (0 means forever).
+    let mut anim = vec![0u8; 4];
+    let loops = loop_count.map(|n| n.saturating_add(1)).unwrap_or(0);
+    anim.extend_from_slice(&[loops as u8, (loops >> 8) as u8]);
+    write_chunk(&mut body, b"ANIM", &anim);
+
+    for (i, &(ref img, delay)) in frames.iter().enumerate() {
+        on_frame(i)?;
+        trace!("Encoding frame #{} of WebP animation", i + 1);
+        assert_eq!((width, height), img.dimensions(),
+            "all frames of WebP animation must have the same size");
+        let encoded = encode(img, compression)?;
+
+        let mut frame = Vec::with_capacity(ANMF_HEADER_SIZE + encoded.len());
+        write_u24(&mut frame, 0);  // X offset
+        write_u24(&mut frame, 0);  // Y offset
+        write_u24(&mut frame, width - 1);
+        write_u24(&mut frame, height - 1);
+        write_u24(&mut frame, duration_ms(delay));
+        // Frames cover the whole canvas, so they shouldn't be blended with the previous ones.
+        frame.push(ANMF_NO_BLEND);
+
+        // Copy the image data chunks, except for the extended header
+        // (which only makes sense at the file level).
+        for (fourcc, chunk) in iter_chunks(&encoded[RIFF_HEADER_SIZE..]) {
+            match fourcc {
+                b"VP8X" => continue,
+                b"ALPH" | b"VP8L" => has_alpha = true,
+                _ => {}
+            }
+            frame.extend_from_slice(chunk);
+        }
+        write_chunk(&mut body, b"ANMF", &frame);
+    }
+
+    let mut vp8x = vec![];
+    vp8x.push(VP8X_ANIMATION | if has_alpha { VP8X_ALPHA } else { 0 });
+    vp8x.extend_from_slice(&[0, 0, 0]);
+    write_u24(&mut vp8x, width - 1);
+    write_u24(&mut vp8x, height - 1);
+
+    let mut chunks = vec![];
+    write_chunk(&mut chunks, b"VP8X", &vp8x);
+    chunks.extend(body);
+
+    let mut result = Vec::with_capacity(RIFF_HEADER_SIZE + chunks.len());
+    result.extend_from_slice(b"RIFF");
+    write_u32(&mut result, (4 + chunks.len()) as u32);
+    result.extend_from_slice(b"WEBP");
+    result.extend(chunks);
+    Ok(result)
+}
+
+
+// Decoding
+
+/// Error that can occur while decoding WebP.
+#[derive(Debug)]
+pub enum DecodeError {
+    /// I/O error encountered when reading the WebP file.
+    Io(io::Error),
+    /// Malformed WebP file, or one that libwebp couldn't decode.
+    Format(String),
+}
+
+impl From<io::Error> for DecodeError {
+    fn from(inner: io::Error) -> Self {
+        DecodeError::Io(inner)
+    }
+}
+
+impl Error for DecodeError {
+    fn description(&self) -> &str { "WebP decode error" }
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            DecodeError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::Io(ref e) => write!(fmt, "I/O error while decoding WebP: {}", e),
+            DecodeError::Format(ref e) => write!(fmt, "malformed WebP file: {}", e),
+        }
+    }
+}
+
+
+/// Check if the path points to a WebP file.
+pub fn is_webp<P: AsRef<Path>>(path: P) -> bool {
+    let path = path.as_ref();
+    trace!("Checking if {} is a WebP", path.display());
+    path.extension().and_then(|s| s.to_str())
+        .map(|ext| ext.to_lowercase() == "webp").unwrap_or(false)
+}
+
+/// Check if given WebP data is an animation.
+pub fn is_animated(bytes: &[u8]) -> bool {
+    if !is_riff_webp(bytes) {
+        return false;
+    }
+    iter_chunks(&bytes[RIFF_HEADER_SIZE..])
+        .find(|&(fourcc, _)| fourcc == b"VP8X")
+        .and_then(|(_, chunk)| chunk.get(CHUNK_HEADER_SIZE).cloned())
+        .map(|flags| flags & VP8X_ANIMATION != 0)
+        .unwrap_or(false)
+}
+
+/// Read the canvas size of WebP image or animation from its header, without decoding it.
+pub fn dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    if !is_riff_webp(bytes) {
+        return None;
+    }
+    let (fourcc, chunk) = try_opt!(iter_chunks(&bytes[RIFF_HEADER_SIZE..]).next());
+    let data = chunk_payload(chunk);
+    match fourcc {
+        b"VP8X" if data.len() >= 10 =>
+            Some((read_u24(&data[4..]) + 1, read_u24(&data[7..]) + 1)),
+        // Lossy bitstream: frame tag & start code, followed by 14-bit width & height.
+        b"VP8 " if data.len() >= 10 =>
+            Some(((read_u16(&data[6..]) & 0x3fff) as u32,
+                  (read_u16(&data[8..]) & 0x3fff) as u32)),
+        // Lossless bitstream: signature byte, followed by 14-bit width & height minus one.
+        b"VP8L" if data.len() >= 5 => {
+            let bits = read_u32(&data[1..]);
+            Some(((bits & 0x3fff) + 1, ((bits >> 14) & 0x3fff) + 1))
+        }
+        _ => None,
+    }
+}
+
+/// Read the contents of a WebP file.
+pub fn read_file<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, DecodeError> {
+    let path = path.as_ref();
+    trace!("Reading WebP file {}", path.display());
+    let mut bytes = vec![];
+    File::open(path)?.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Decode a still WebP image.
+pub fn decode(bytes: &[u8]) -> Result<DynamicImage, DecodeError> {
+    let (width, height) = dimensions(bytes).ok_or_else(||
+        DecodeError::Format("missing WebP image header".into()))?;
+    // Check the declared size before libwebp allocates the image.
+    animation::check_decoding_limits(width, height, 1).map_err(DecodeError::Format)?;
+
+    let decoded = webp::Decoder::new(bytes).decode()
+        .ok_or_else(|| DecodeError::Format("libwebp failed to decode the image".into()))?;
+    let (width, height) = (decoded.width(), decoded.height());
+    let pixels = decoded.to_vec();
+    let image = if decoded.is_alpha() {
+        ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageRgba8)
+    } else {
+        ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageRgb8)
+    };
+    image.ok_or_else(|| DecodeError::Format(
+        format!("unexpected size of decoded {}x{} image", width, height)))
+}
+
+/// Decode an animated WebP.
+pub fn decode_animation(bytes: &[u8]) -> Result<WebpAnimation, DecodeError> {
+    if !is_riff_webp(bytes) {
+        return Err(DecodeError::Format("missing RIFF WEBP header".into()));
+    }
+
+    let frame_count = iter_chunks(&bytes[RIFF_HEADER_SIZE..])
+        .filter(|&(fourcc, _)| fourcc == b"ANMF")
+        .count();
+    let mut canvas_size = None;
+    let mut loop_count = 0;
+    let mut canvas: Option<RgbaImage> = None;
+    let mut dispose_rect: Option<(u32, u32, u32, u32)> = None;
+    let mut frames = vec![];
+    for (fourcc, chunk) in iter_chunks(&bytes[RIFF_HEADER_SIZE..]) {
+        let data = chunk_payload(chunk);
+        match fourcc {
+            b"VP8X" if data.len() >= 10 => {
+                let (width, height) = (read_u24(&data[4..]) + 1, read_u24(&data[7..]) + 1);
+                // Check the declared size before the canvas is allocated for it.
+                animation::check_decoding_limits(width, height, frame_count)
+                    .map_err(DecodeError::Format)?;
+                canvas_size = Some((width, height));
+            }
+            b"ANIM" if data.len() >= 6 => loop_count = data[4] as u16 | (data[5] as u16) << 8,
+            b"ANMF" if data.len() >= ANMF_HEADER_SIZE => {
+                let (width, height) = canvas_size.ok_or_else(||
+                    DecodeError::Format("ANMF before VP8X".into()))?;
+                let canvas = canvas.get_or_insert_with(||
+                    RgbaImage::from_pixel(width, height, TRANSPARENT));
+
+                // Frame area of the previous frame may need to be cleared first.
+                if let Some((x, y, w, h)) = dispose_rect.take() {
+                    for cy in y..(y + h).min(height) {
+                        for cx in x..(x + w).min(width) {
+                            canvas.put_pixel(cx, cy, TRANSPARENT);
+                        }
+                    }
+                }
+
+                // Offsets are stored divided by two.
+                let (x, y) = (read_u24(&data[0..]) * 2, read_u24(&data[3..]) * 2);
+                let (w, h) = (read_u24(&data[6..]) + 1, read_u24(&data[9..]) + 1);
+                let delay = Duration::from_millis(read_u24(&data[12..]) as u64);
+                let flags = data[15];
+                trace!("Decoding WebP frame #{} at ({}, {})", frames.len() + 1, x, y);
+                if x + w > width || y + h > height {
+                    return Err(DecodeError::Format(format!(
+                        "frame {}x{}+{}+{} doesn't fit within the {}x{} canvas",
+                        w, h, x, y, width, height)));
+                }
+
+                let image = decode(&frame_as_webp(&data[ANMF_HEADER_SIZE..]))?.to_rgba();
+                if image.dimensions() != (w, h) {
+                    return Err(DecodeError::Format(format!(
+                        "frame #{} decoded as {}x{} rather than {}x{}",
+                        frames.len() + 1, image.width(), image.height(), w, h)));
+                }
+                for (fx, fy, pixel) in image.enumerate_pixels() {
+                    let (cx, cy) = (x + fx, y + fy);
+                    if cx >= width || cy >= height {
+                        continue;
+                    }
+                    if flags & ANMF_NO_BLEND != 0 {
+                        canvas.put_pixel(cx, cy, *pixel);
+                    } else {
+                        canvas.get_pixel_mut(cx, cy).blend(pixel);
+                    }
+                }
+                frames.push(WebpFrame{
+                    image: DynamicImage::ImageRgba8(canvas.clone()),
+                    delay,
+                });
+                if flags & ANMF_DISPOSE_BACKGROUND != 0 {
+                    dispose_rect = Some((x, y, w, h));
+                }
+            }
+            _ => {}
+        }
+    }
+    let (width, height) = canvas_size.ok_or_else(||
+        DecodeError::Format("missing VP8X".into()))?;
+    if frames.is_empty() {
+        return Err(DecodeError::Format("no animation frames".into()));
+    }
+
+    debug!("Animated WebP successfully loaded: {}x{} with {} frames",
+        width, height, frames.len());
+    let mut anim = WebpAnimation::new(frames);
+    anim.loop_count = loop_count;
+    Ok(anim)
+}
+
+/// Wrap the image data of an animation frame (ALPH and VP8/VP8L chunks)
+/// into a standalone WebP file, so that libwebp can decode it.
+fn frame_as_webp(frame_data: &[u8]) -> Vec<u8> {
+    let mut chunks = vec![];
+    let mut has_alpha = false;
+    let mut size = None;
+    for (fourcc, chunk) in iter_chunks(frame_data) {
+        let data = chunk_payload(chunk);
+        match fourcc {
+            b"ALPH" => has_alpha = true,
+            b"VP8 " if data.len() >= 10 => size = Some(
+                ((read_u16(&data[6..]) & 0x3fff) as u32, (read_u16(&data[8..]) & 0x3fff) as u32)),
+            b"VP8L" if data.len() >= 5 => {
+                let bits = read_u32(&data[1..]);
+                size = Some(((bits & 0x3fff) + 1, ((bits >> 14) & 0x3fff) + 1));
+            }
+            _ => {}
+        }
+        chunks.extend_from_slice(chunk);
+    }
+
+    let mut body = vec![];
+    // Separate alpha channel is only allowed in the extended format.
+    if let (true, Some((width, height))) = (has_alpha, size) {
+        let mut vp8x = vec![VP8X_ALPHA, 0, 0, 0];
+        write_u24(&mut vp8x, width - 1);
+        write_u24(&mut vp8x, height - 1);
+        write_chunk(&mut body, b"VP8X", &vp8x);
+    }
+    body.extend(chunks);
+
+    let mut result = Vec::with_capacity(RIFF_HEADER_SIZE + body.len());
+    result.extend_from_slice(b"RIFF");
+    write_u32(&mut result, (4 + body.len()) as u32);
+    result.extend_from_slice(b"WEBP");
+    result.extend(body);
+    result
+}
+
+/// Check if given data is a WebP image (or animation).
+pub fn is_riff_webp(bytes: &[u8]) -> bool {
+    bytes.len() >= RIFF_HEADER_SIZE && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP"
+}
+
+const TRANSPARENT: Rgba<u8> = Rgba{data: [0, 0, 0, 0]};
+
+
+/// Size of the "RIFF <size> WEBP" header.
+const RIFF_HEADER_SIZE: usize = 12;
+/// Size of the ANMF chunk payload before the frame data.
+const ANMF_HEADER_SIZE: usize = 16;
+
+const VP8X_ALPHA: u8 = 0x10;
+const VP8X_ANIMATION: u8 = 0x02;
+const ANMF_NO_BLEND: u8 = 0x02;
+const ANMF_DISPOSE_BACKGROUND: u8 = 0x01;
+
+/// Convert frame delay to milliseconds, using the same minimum as browsers do for GIFs.
+fn duration_ms(delay: Duration) -> u32 {
+    // Browsers render GIF frames with (almost) zero delay at 100ms,
+    // so this keeps animations converted from GIFs at the speed people are used to.
+    let ms = animation::browser_delay_ms(animation::duration_ms(delay));
+    ms.min(MAX_U24 as u64) as u32
+}
+const MAX_U24: u32 = 0xffffff;
+
+
+// Writing & parsing RIFF chunks.
+
+fn write_chunk(out: &mut Vec<u8>, fourcc: &[u8; 4], payload: &[u8]) {
+    out.extend_from_slice(fourcc);
+    write_u32(out, payload.len() as u32);
+    out.extend_from_slice(payload);
+    if payload.len() % 2 == 1 {
+        out.push(0);  // chunks are padded to even size
+    }
+}
+
+/// Iterate over RIFF chunks in given data,
+/// yielding their FourCC & the entire chunk (including the header & padding).
+fn iter_chunks<'d>(data: &'d [u8]) -> Box<Iterator<Item=(&'d [u8], &'d [u8])> + 'd> {
+    let mut rest = data;
+    Box::new((0..).map(move |_| {
+        if rest.len() < CHUNK_HEADER_SIZE {
+            return None;
+        }
+        let size = read_u32(&rest[4..8]) as usize;
+        let total = (CHUNK_HEADER_SIZE + size + size % 2).min(rest.len());
+        let (chunk, remaining) = rest.split_at(total);
+        rest = remaining;
+        Some((&chunk[..4], chunk))
+    }).take_while(Option::is_some).map(Option::unwrap))
+}
+const CHUNK_HEADER_SIZE: usize = 8;
+
+/// Strip the header & padding off of a chunk returned by `iter_chunks`.
+fn chunk_payload(chunk: &[u8]) -> &[u8] {
+    let size = read_u32(&chunk[4..8]) as usize;
+    &chunk[CHUNK_HEADER_SIZE..(CHUNK_HEADER_SIZE + size).min(chunk.len())]
+}
+
+fn write_u24(out: &mut Vec<u8>, v: u32) {
+    out.extend_from_slice(&[v as u8, (v >> 8) as u8, (v >> 16) as u8]);
+}
+
+fn write_u32(out: &mut Vec<u8>, v: u32) {
+    out.extend_from_slice(&[v as u8, (v >> 8) as u8, (v >> 16) as u8, (v >> 24) as u8]);
+}
+
+fn read_u16(bytes: &[u8]) -> u16 {
+    bytes[0] as u16 | (bytes[1] as u16) << 8
+}
+
+fn read_u24(bytes: &[u8]) -> u32 {
+    bytes[0] as u32 | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    bytes[0] as u32 | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use image::{DynamicImage, Rgba, RgbaImage};
+    use super::{Compression, decode_animation, dimensions, duration_ms, encode, encode_animation,
+                is_animated, iter_chunks, RIFF_HEADER_SIZE};
+
+    #[test]
+    fn gif_like_delays() {
+        assert_eq!(100, duration_ms(Duration::from_millis(0)));
+        assert_eq!(100, duration_ms(Duration::from_millis(10)));
+        assert_eq!(40, duration_ms(Duration::from_millis(40)));
+    }
+
+    #[test]
+    fn animation_structure() {
+        let frames: Vec<_> = [0x00, 0x80, 0xff].iter().map(|&v| {
+            let img = RgbaImage::from_pixel(16, 8, Rgba{data: [v, v, v, 0xff]});
+            (DynamicImage::ImageRgba8(img), Duration::from_millis(50))
+        }).collect();
+        let webp = encode_animation(&frames, Compression::Lossless, None).unwrap();
+
+        assert_eq!(b"RIFF", &webp[..4]);
+        assert_eq!(b"WEBP", &webp[8..12]);
+        let fourccs: Vec<_> = iter_chunks(&webp[RIFF_HEADER_SIZE..])
+            .map(|(f, _)| f.to_vec()).collect();
+        assert_eq!(vec![b"VP8X".to_vec(), b"ANIM".to_vec(),
+                        b"ANMF".to_vec(), b"ANMF".to_vec(), b"ANMF".to_vec()], fourccs);
+    }
+
+    #[test]
+    fn animation_roundtrip() {
+        let frames: Vec<_> = [0x00, 0xff].iter().map(|&v| {
+            let img = RgbaImage::from_pixel(4, 4, Rgba{data: [v, 0, 0, 0xff]});
+            (DynamicImage::ImageRgba8(img), Duration::from_millis(70))
+        }).collect();
+        let webp = encode_animation(&frames, Compression::Lossless, Some(2)).unwrap();
+        assert!(is_animated(&webp));
+
+        let anim = decode_animation(&webp).unwrap();
+        assert_eq!((4, 4), (anim.width, anim.height));
+        assert_eq!(3, anim.loop_count);
+        let decoded: Vec<_> = anim.iter_frames().collect();
+        assert_eq!(2, decoded.len());
+        assert_eq!(Duration::from_millis(70), decoded[1].delay);
+        assert_eq!(Rgba{data: [0xff, 0, 0, 0xff]}, *decoded[1].image.to_rgba().get_pixel(2, 2));
+    }
+
+    #[test]
+    fn dimensions_from_header() {
+        let opaque = DynamicImage::ImageRgba8(
+            RgbaImage::from_pixel(16, 8, Rgba{data: [0x80, 0, 0, 0xff]}));
+        let transparent = DynamicImage::ImageRgba8(RgbaImage::new(16, 8));
+        for img in &[opaque, transparent] {
+            for &compression in &[Compression::Lossless, Compression::Lossy(80)] {
+                let webp = encode(img, compression).unwrap();
+                assert_eq!(Some((16, 8)), dimensions(&webp), "{:?}", compression);
+            }
+        }
+
+        let frames = vec![(DynamicImage::ImageRgba8(RgbaImage::new(16, 8)),
+                           Duration::from_millis(70)); 2];
+        let webp = encode_animation(&frames, Compression::Lossless, None).unwrap();
+        assert_eq!(Some((16, 8)), dimensions(&webp));
+        assert_eq!(None, dimensions(b"RIFF"));
+    }
+
+    #[test]
+    fn huge_canvas_is_rejected() {
+        let frames: Vec<_> = (0..2).map(|_| {
+            (DynamicImage::ImageRgba8(RgbaImage::new(4, 4)), Duration::from_millis(70))
+        }).collect();
+        let mut webp = encode_animation(&frames, Compression::Lossless, None).unwrap();
+
+        // Canvas width & height of VP8X, which is the first chunk.
+        let vp8x = RIFF_HEADER_SIZE + 8;
+        for b in &mut webp[vp8x + 4..vp8x + 10] {
+            *b = 0xff;
+        }
+        assert!(decode_animation(&webp).is_err());
+    }
+}
@@ -53,6 +53,9 @@ pub struct Options {
     /// Number of threads to use for image captioning.
     /// If omitted, the actual count will be based on the number of CPUs.
     pub render_threads: Option<usize>,
+    /// Maximum number of image macros waiting for a free render thread.
+    /// If omitted, the queue is unbounded.
+    pub max_queue_length: Option<usize>,
     /// Quality of GIF animations rendered by the server.
     pub gif_quality: Option<u8>,
     /// Quality of JPEG images produced.
@@ -111,6 +114,11 @@ impl<'a> TryFrom<ArgMatches<'a>> for Options {
             Some(rt) => Some(try!(rt.parse::<usize>().map_err(ArgsError::RenderThreads))),
             None => None,
         };
+        let max_queue_length = match try!(matches.value_of(OPT_MAX_QUEUE_LENGTH).unwrap()
+                .parse::<usize>().map_err(ArgsError::MaxQueueLength)) {
+            0 => None,
+            mql => Some(mql),
+        };
         let gif_quality = match matches.value_of(OPT_GIF_QUALITY) {
             Some(q) => Some(try!(parse_quality(q).map_err(ArgsError::GifQuality))),
             None => None,
@@ -172,7 +180,7 @@ impl<'a> TryFrom<ArgMatches<'a>> for Options {
 
         Ok(Options{
             verbosity, address,
-            render_threads, gif_quality, jpeg_quality,
+            render_threads, max_queue_length, gif_quality, jpeg_quality,
             template_cache_size, font_cache_size, preload,
             request_timeout, shutdown_timeout,
         })
@@ -199,6 +207,9 @@ pub enum ArgsError {
     /// Error while parsing --render-threads flag.
     #[error(no_from)]
     RenderThreads(ParseIntError),
+    /// Error while parsing --max-queue-length flag.
+    #[error(no_from)]
+    MaxQueueLength(ParseIntError),
     /// Error while parsing --gif-quality flag.
     #[error(no_from)]
     GifQuality(QualityError),
@@ -304,6 +315,7 @@ lazy_static! {
 
 const ARG_ADDR: &'static str = "address";
 const OPT_RENDER_THREADS: &'static str = "render-threads";
+const OPT_MAX_QUEUE_LENGTH: &'static str = "max-queue-length";
 const OPT_GIF_QUALITY: &'static str = "gif-quality";
 const OPT_JPEG_QUALITY: &'static str = "jpeg-quality";
 const OPT_TEMPLATE_CACHE_SIZE: &'static str = "template-cache";
@@ -319,6 +331,7 @@ const VALID_PRELOAD: &'static [&'static str] = &["all", "both", "none",
 
 const DEFAULT_HOST: &'static str = "0.0.0.0";
 const DEFAULT_PORT: u16 = 1337;
+const DEFAULT_MAX_QUEUE_LENGTH: usize = 256;
 const DEFAULT_REQUEST_TIMEOUT: u32 = 10;
 const DEFAULT_SHUTDOWN_TIMEOUT: u32 = 30;
 
@@ -361,6 +374,16 @@ fn create_parser<'p>() -> Parser<'p> {
             .long_help(concat!(
                 "Number of threads used for image captioning.\n\n",
                 "If omitted, one thread per each CPU core will be used.")))
+        .arg(Arg::with_name(OPT_MAX_QUEUE_LENGTH)
+            .long("max-queue-length")
+            .value_name("N")
+            .required(false)
+            .default_value(to_static_str(DEFAULT_MAX_QUEUE_LENGTH))
+            .help("Maximum number of images waiting to be rendered")
+            .long_help(concat!(
+                "Maximum number of image macros waiting for a free render thread.\n\n",
+                "Any requests beyond that limit are rejected with 503 Service Unavailable. ",
+                "A value of 0 makes the queue unbounded.")))
         .arg(Arg::with_name(OPT_GIF_QUALITY)
             .long("gif-quality")
             .value_name("PERCENT")
@@ -453,7 +476,7 @@ mod tests {
     use conv::TryFrom;
     use spectral::prelude::*;
     use ::NAME;
-    use super::{parse_from_argv, Resource, VALID_PRELOAD};
+    use super::{parse_from_argv, Resource, DEFAULT_MAX_QUEUE_LENGTH, VALID_PRELOAD};
 
     /// Check that the VALID_PRELOAD options make sense.
     #[test]
@@ -533,6 +556,24 @@ mod tests {
         assert_that!(parse_from_argv(vec![*NAME, "--render-threads", "16"])).is_ok();
     }
 
+    #[test]
+    fn max_queue_length_arg() {
+        // Needs a value.
+        assert_that!(parse_from_argv(vec![*NAME, "--max-queue-length"])).is_err();
+        // Value must be a number.
+        assert_that!(parse_from_argv(vec![*NAME, "--max-queue-length", "foo"])).is_err();
+        // A positive number.
+        assert_that!(parse_from_argv(vec![*NAME, "--max-queue-length", "-42"])).is_err();
+        // This is fine.
+        assert_that!(parse_from_argv(vec![*NAME, "--max-queue-length", "64"])).is_ok();
+
+        // There is a limit by default, and zero disables it.
+        assert_eq!(Some(DEFAULT_MAX_QUEUE_LENGTH),
+            parse_from_argv(vec![*NAME]).unwrap().max_queue_length);
+        assert_eq!(None,
+            parse_from_argv(vec![*NAME, "--max-queue-length", "0"]).unwrap().max_queue_length);
+    }
+
     #[test]
     fn gif_quality_arg() {
         // Needs a value.
@@ -8,11 +8,13 @@ use std::sync::Arc;
 use antidote::Mutex;
 use atomic::Atomic;
 use futures::{BoxFuture, future, Future};
-use futures_cpupool::{self, CpuPool};
+use futures_cpupool;
 use log::LogLevel::*;
 use rand::{self, thread_rng};
-use rofl::{self, CaptionOutput, CaptionError, Font, ImageMacro, Template};
+use rofl::{self, CancelToken, CaptionFuture, CaptionOutput, CaptionError, CaptionPool, Font,
+           ImageMacro, PoolError, PoolStats, ProgressObserver, Template};
 use rofl::cache::ThreadSafeCache;
+use rofl::glyphs::{GlyphBitmap, GlyphKey};
 use thread_id;
 use tokio_timer::{TimeoutError, Timer, TimerError};
 
@@ -28,24 +30,44 @@ lazy_static! {
 
 /// Renders image macros into captioned images.
 pub struct Captioner {
-    pool: Mutex<CpuPool>,
+    pool: Mutex<CaptionPool>,
     engine: rofl::Engine,
     timer: Timer,
     // Configuration params.
+    thread_count: Atomic<usize>,
+    max_queue_length: Atomic<Option<usize>>,
     task_timeout: Atomic<Duration>,
 }
 
 impl Captioner {
     #[inline]
     fn new() -> Self {
-        let pool = Mutex::new(Self::pool_builder().create());
         let engine = Self::engine_builder().build()
             .expect("failed to create rofl::Engine in Captioner::new");
+        let pool = Mutex::new(
+            CaptionPool::with_builder(engine.clone(), &mut Self::pool_builder()));
         let timer = Timer::default();
 
+        let thread_count = Atomic::new(0);
+        let max_queue_length = Atomic::new(None);
         let task_timeout = Atomic::new(Duration::from_secs(0));
 
-        Captioner{pool, engine, timer, task_timeout}
+        Captioner{pool, engine, timer, thread_count, max_queue_length, task_timeout}
+    }
+
+    /// Replace the pool with a new one, configured according to current settings.
+    fn rebuild_pool(&self) {
+        let mut builder = Self::pool_builder();
+        let count = self.thread_count.load(Ordering::Relaxed);
+        if count > 0 {
+            builder.pool_size(count);
+        }
+
+        let mut pool = CaptionPool::with_builder(self.engine.clone(), &mut builder);
+        if let Some(length) = self.max_queue_length.load(Ordering::Relaxed) {
+            pool = pool.with_max_queue_length(length);
+        }
+        *self.pool.lock() = pool;
     }
 
     #[doc(hidden)]
@@ -81,6 +103,16 @@ impl Captioner {
     pub fn font_cache(&self) -> &ThreadSafeCache<String, Font> {
         self.engine.font_cache().unwrap()
     }
+
+    #[inline]
+    pub fn glyph_cache(&self) -> &ThreadSafeCache<GlyphKey, GlyphBitmap> {
+        self.engine.glyph_cache()
+    }
+
+    #[inline]
+    pub fn pool_stats(&self) -> PoolStats {
+        self.pool.lock().stats()
+    }
 }
 
 // Configuration tweaks.
@@ -88,14 +120,19 @@ impl Captioner {
     #[inline]
     pub fn set_thread_count(&self, count: usize) -> &Self {
         trace!("Setting thread count for image captioning to {}", count);
+        self.thread_count.store(count, Ordering::Relaxed);
+        self.rebuild_pool();
+        self
+    }
 
-        let mut builder = Self::pool_builder();
-        if count > 0 {
-            builder.pool_size(count);
+    #[inline]
+    pub fn set_max_queue_length(&self, length: Option<usize>) -> &Self {
+        match length {
+            Some(l) => trace!("Limiting the image captioning queue to {} image macros", l),
+            None => trace!("Removing the limit on image captioning queue"),
         }
-
-        let pool = builder.create();
-        *self.pool.lock() = pool;
+        self.max_queue_length.store(length, Ordering::Relaxed);
+        self.rebuild_pool();
         self
     }
 
@@ -165,47 +202,88 @@ impl Captioner {
 impl Captioner {
     /// Render an image macro as PNG.
     /// The rendering is done in a separate thread.
+    #[inline]
     pub fn render(&self, im: ImageMacro) -> BoxFuture<CaptionOutput, RenderError> {
+        match self.render_with(im, |pool, im, cancel| pool.caption_cancellable(im, cancel)) {
+            Ok(future) => future,
+            Err(e) => future::err(e).boxed(),
+        }
+    }
+
+    /// Render an image macro, reporting the progress of rendering to given observer.
+    ///
+    /// Fails immediately with `RenderError::Unavailable`
+    /// if the rendering cannot be started right now (e.g. because the queue is full).
+    #[inline]
+    pub fn render_with_progress<P>(&self, im: ImageMacro, observer: P)
+                                   -> Result<BoxFuture<CaptionOutput, RenderError>, RenderError>
+        where P: ProgressObserver + 'static
+    {
+        self.render_with(im, move |pool, im, cancel| {
+            pool.caption_with_progress(im, cancel, observer)
+        })
+    }
+
+    /// Render an image macro by submitting it to the CaptionPool with given function.
+    ///
+    /// Fails immediately if the image macro couldn't be submitted.
+    fn render_with<F>(&self, im: ImageMacro, submit: F)
+                      -> Result<BoxFuture<CaptionOutput, RenderError>, RenderError>
+        where F: FnOnce(&CaptionPool, ImageMacro, CancelToken) -> CaptionFuture
+    {
         let pool = match self.pool.try_lock() {
             Ok(p) => p,
             Err(_) => {
                 // Indicates we'd have to wait for the pool lock.
                 // This should be only possible when set_thread_count() happens
                 // to have been called at the exact same moment.
-                warn!("Could not immediately lock CpuPool to render {:?}", im);
+                warn!("Could not immediately lock CaptionPool to render {:?}", im);
                 // TODO: retry a few times, probably with exponential backoff
-                return future::err(RenderError::Unavailable).boxed();
+                return Err(RenderError::Unavailable);
             },
         };
 
-        // Spawn a new task in the thread pool for the rendering process.
-        let task_future = pool.spawn_fn({
-            let im_repr = format!("{:?}", im);
-            let engine = self.engine.clone();
-            move || {
-                match engine.caption(im) {
-                    Ok(out) => {
-                        debug!("Successfully rendered {} as {:?}, final result size: {} bytes",
-                            im_repr, out.format(), out.len());
-                        future::ok(out)
-                    },
-                    Err(e) => {
-                        error!("Failed to render image macro {}: {}", im_repr, e);
-                        future::err(e)
-                    },
-                }
-            }
-        }).map_err(RenderError::Caption);
-
-        // Impose a timeout on the task.
+        // Make the rendering itself stop once the timeout is exceeded.
         let max_duration = self.task_timeout.load(Ordering::Relaxed);
-        if max_duration.as_secs() > 0 {
-            // TODO: this doesn't seem to actually kill the underlying thread,
-            // figure out how to do that
+        let cancel = if max_duration.as_secs() > 0 {
+            CancelToken::new().with_timeout(max_duration)
+        } else {
+            CancelToken::new()
+        };
+
+        // Submit the rendering process as a new task to the thread pool.
+        let im_repr = format!("{:?}", im);
+        let caption_future = submit(&*pool, im, cancel);
+        if caption_future.is_rejected() {
+            warn!("Too many ({}) image macros waiting to be rendered, rejecting {}",
+                pool.max_queue_length().unwrap_or(0), im_repr);
+            return Err(RenderError::Unavailable);
+        }
+        let task_future = caption_future.then(move |result| match result {
+            Ok(out) => {
+                debug!("Successfully rendered {} as {:?}, final result size: {} bytes",
+                    im_repr, out.format(), out.len());
+                Ok(out)
+            },
+            Err(PoolError::Caption(CaptionError::Cancelled)) => {
+                warn!("Rendering of image macro {} timed out", im_repr);
+                Err(RenderError::Timeout)
+            },
+            Err(PoolError::Caption(e)) => {
+                error!("Failed to render image macro {}: {}", im_repr, e);
+                Err(RenderError::Caption(e))
+            },
+            // Rejected image macros have been handled above already.
+            Err(PoolError::QueueFull(..)) => Err(RenderError::Unavailable),
+        });
+
+        // Impose a timeout on the task, in case it's stuck in a long stage of rendering.
+        // (Dropping the future will then cancel the rendering as well).
+        Ok(if max_duration.as_secs() > 0 {
             self.timer.timeout(task_future, max_duration).boxed()
         } else {
             task_future.boxed()
-        }
+        })
     }
 }
 
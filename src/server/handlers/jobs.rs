@@ -0,0 +1,206 @@
+//! Module implementing render jobs, whose progress can be followed
+//! as a stream of server-sent events.
+//! This is used by the /caption/jobs request handlers.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use antidote::Mutex;
+use futures::{Future, Sink, Stream, stream};
+use futures::sync::mpsc::{self, UnboundedSender};
+use futures_cpupool::{self, CpuPool};
+use hyper::{Body, Chunk, StatusCode};
+use rand::{self, Rng};
+use rofl::{CaptionOutput, ImageMacro, Progress};
+use rofl::cache::ThreadSafeCache;
+use serde_json::Value as Json;
+use tokio_timer::Timer;
+
+use super::{CAPTIONER, RenderError, status_code_for};
+
+
+/// Maximum number of render jobs that are kept track of.
+/// When it's exceeded, the least recently used jobs are forgotten.
+const MAX_JOB_COUNT: usize = 256;
+
+/// How long the results of finished render jobs are kept around (in seconds).
+/// Afterwards, the jobs are forgotten.
+///
+/// This has to be within the maximum timeout of the default `Timer` (~409 secs).
+const FINISHED_JOB_TTL_SECS: u64 = 300;
+
+lazy_static! {
+    /// Recently started render jobs, by their IDs.
+    static ref JOBS: ThreadSafeCache<String, Job> = ThreadSafeCache::new(MAX_JOB_COUNT);
+
+    /// Thread pool which drives the rendering futures & event streams of the jobs.
+    static ref EVENT_POOL: CpuPool = futures_cpupool::Builder::new()
+        .pool_size(1)
+        .name_prefix("job-events-")
+        .create();
+
+    /// Timer which expires the finished render jobs.
+    static ref TIMER: Timer = Timer::default();
+}
+
+
+/// Start a job rendering given image macro.
+/// Returns the ID of the job.
+///
+/// If the rendering cannot be started right now (e.g. because the render queue is full),
+/// no job is created and the error is returned instead.
+pub fn start(im: ImageMacro) -> Result<String, RenderError> {
+    let id = format!("{:016x}", rand::thread_rng().gen::<u64>());
+    debug!("Starting render job {} for {:?}", id, im);
+
+    let job = JOBS.put(id.clone(), Job::new());
+    let render = match CAPTIONER.render_with_progress(im, {
+        let job = job.clone();
+        move |progress: Progress| job.emit(Event::progress(progress))
+    }) {
+        Ok(r) => r,
+        Err(e) => {
+            debug!("Render job {} could not be started: {}", id, e);
+            JOBS.remove(&id);
+            return Err(e);
+        }
+    };
+    EVENT_POOL.spawn(render.then(move |result| {
+        job.finish(result.map_err(|e| (status_code_for(&e), e.to_string())));
+        // Keep the result around for a while, so that the client can fetch it.
+        TIMER.sleep(Duration::from_secs(FINISHED_JOB_TTL_SECS))
+    }).then({
+        let id = id.clone();
+        move |result| {
+            match result {
+                Ok(()) => {
+                    trace!("Forgetting finished render job {}", id);
+                    JOBS.remove(&id);
+                }
+                // The job will be eventually evicted from JOBS as the least recently used.
+                Err(e) => warn!("Could not expire render job {}: {}", id, e),
+            }
+            Ok::<(), ()>(())
+        }
+    })).forget();
+
+    Ok(id)
+}
+
+/// Find a render job by its ID.
+#[inline]
+pub fn find(id: &str) -> Option<Arc<Job>> {
+    JOBS.get(id)
+}
+
+
+/// Render job started by a request to /caption/jobs.
+pub struct Job {
+    state: Mutex<JobState>,
+}
+
+#[derive(Default)]
+struct JobState {
+    /// Events that the job has emitted so far.
+    events: Vec<Event>,
+    /// Channels of the event streams which follow the job.
+    subscribers: Vec<UnboundedSender<Event>>,
+    /// Result of the job (or the HTTP status & message of its error), if it has finished.
+    result: Option<Result<CaptionOutput, (StatusCode, String)>>,
+}
+
+impl Job {
+    #[inline]
+    fn new() -> Self {
+        Job{state: Mutex::new(JobState::default())}
+    }
+
+    /// Result of the job, if it has finished.
+    #[inline]
+    pub fn result(&self) -> Option<Result<CaptionOutput, (StatusCode, String)>> {
+        self.state.lock().result.clone()
+    }
+
+    /// Create the body of a server-sent events response
+    /// with all the events of the job, both past and future.
+    ///
+    /// The response ends once the job finishes.
+    pub fn event_body(&self) -> Body {
+        let events = {
+            let mut state = self.state.lock();
+            let past = stream::iter(state.events.clone().into_iter().map(Ok::<_, ()>));
+            if state.result.is_some() {
+                past.boxed()
+            } else {
+                let (tx, rx) = mpsc::unbounded();
+                state.subscribers.push(tx);
+                past.chain(rx).boxed()
+            }
+        };
+
+        let (sender, body) = Body::pair();
+        EVENT_POOL.spawn(events.fold(sender, |sender, event| {
+            // Fails if the client has disconnected, which ends the stream.
+            sender.send(Ok(event.to_chunk())).map_err(|_| ())
+        }).map(|_| ())).forget();
+        body
+    }
+}
+
+impl Job {
+    /// Emit an event to everyone following the job.
+    fn emit(&self, event: Event) {
+        let mut state = self.state.lock();
+        state.subscribers.retain(|s| s.unbounded_send(event.clone()).is_ok());
+        state.events.push(event);
+    }
+
+    /// Finish the job with given result.
+    fn finish(&self, result: Result<CaptionOutput, (StatusCode, String)>) {
+        match result {
+            Ok(ref out) => debug!("Render job finished with {} bytes of {:?}",
+                out.len(), out.format()),
+            Err((_, ref msg)) => debug!("Render job failed: {}", msg),
+        }
+        self.emit(Event::finish(&result));
+
+        let mut state = self.state.lock();
+        state.subscribers.clear();  // ends the event streams
+        state.result = Some(result);
+    }
+}
+
+
+/// Event of a render job, as sent to the clients.
+#[derive(Clone, Debug)]
+struct Event {
+    name: &'static str,
+    data: Json,
+}
+
+impl Event {
+    /// Event reporting the progress of rendering.
+    fn progress(progress: Progress) -> Self {
+        Event{name: "progress", data: json!({
+            "stage": progress.stage.name(),
+            "frame": progress.frame,
+            "frame_count": progress.frame_count,
+        })}
+    }
+
+    /// Final event of the job, with the given result.
+    fn finish(result: &Result<CaptionOutput, (StatusCode, String)>) -> Self {
+        match *result {
+            Ok(ref out) => Event{name: "done", data: json!({
+                "mime_type": out.mime_type().map(|mt| mt.to_string()),
+                "size": out.len(),
+            })},
+            Err((_, ref msg)) => Event{name: "error", data: json!({"error": msg})},
+        }
+    }
+
+    /// Format the event as a chunk of the server-sent events response.
+    fn to_chunk(&self) -> Chunk {
+        format!("event: {}\ndata: {}\n\n", self.name, self.data).into()
+    }
+}
@@ -1,6 +1,7 @@
 //! Module with the server's request handlers.
 
 mod captioner;
+mod jobs;
 pub mod list;
 pub mod util;
 
@@ -11,14 +12,15 @@ use std::path::PathBuf;
 
 use futures::{BoxFuture, future, Future};
 use hyper::{self, Method, StatusCode, Uri};
-use hyper::header::{ContentLength, ContentType};
+use hyper::header::{CacheControl, CacheDirective, ContentLength, ContentType};
 use hyper::server::Response;
-use rofl::{CaptionError, ImageMacro};
+use mime::Mime;
+use rofl::{CaptionError, CaptionOutput, ImageMacro};
 use serde_json;
 use serde_qs;
 
 pub use self::captioner::{CAPTIONER, RenderError};
-use self::util::error_response;
+use self::util::{error_response, json_response};
 
 
 lazy_static! {
@@ -27,11 +29,73 @@ lazy_static! {
 
     static ref FONT_DIR: PathBuf =
         env::current_dir().unwrap().join("data").join("fonts");
+
+    /// MIME type of server-sent events.
+    static ref EVENT_STREAM: Mime = "text/event-stream".parse().unwrap();
 }
 
 
 /// Handle the image captioning HTTP request.
 pub fn caption_macro(method: Method, url: Uri, body: Vec<u8>) -> BoxFuture<Response, hyper::Error> {
+    let im = match decode_image_macro(method, url, body) {
+        Ok(im) => im,
+        Err(response) => return future::ok(response).boxed(),
+    };
+    CAPTIONER.render(im)
+        .map(output_response)
+        .or_else(|e| future::ok(error_response(status_code_for(&e), e)))
+        .boxed()
+}
+
+/// Handle the HTTP request which starts a render job.
+pub fn start_caption_job(method: Method, url: Uri,
+                         body: Vec<u8>) -> BoxFuture<Response, hyper::Error> {
+    let im = match decode_image_macro(method, url, body) {
+        Ok(im) => im,
+        Err(response) => return future::ok(response).boxed(),
+    };
+    let id = match jobs::start(im) {
+        Ok(id) => id,
+        Err(e) => return future::ok(error_response(status_code_for(&e), e)).boxed(),
+    };
+    let response = json_response(json!({
+        "id": id,
+        "result": format!("/caption/jobs/{}", id),
+        "events": format!("/caption/jobs/{}/events", id),
+    })).with_status(StatusCode::Accepted);
+    future::ok(response).boxed()
+}
+
+/// Handle the HTTP request for the result of a render job.
+pub fn caption_job_result(id: &str) -> Response {
+    let job = match jobs::find(id) {
+        Some(job) => job,
+        None => return error_response(StatusCode::NotFound, format!("unknown job `{}`", id)),
+    };
+    match job.result() {
+        Some(Ok(out)) => output_response(out),
+        Some(Err((status_code, message))) => error_response(status_code, message),
+        None => json_response(json!({"id": id, "status": "pending"}))
+            .with_status(StatusCode::Accepted),
+    }
+}
+
+/// Handle the HTTP request for the stream of server-sent events about a render job.
+pub fn caption_job_events(id: &str) -> Response {
+    let job = match jobs::find(id) {
+        Some(job) => job,
+        None => return error_response(StatusCode::NotFound, format!("unknown job `{}`", id)),
+    };
+    Response::new()
+        .with_header(ContentType(EVENT_STREAM.clone()))
+        .with_header(CacheControl(vec![CacheDirective::NoCache]))
+        .with_body(job.event_body())
+}
+
+
+/// Decode the image macro from the captioning HTTP request,
+/// or return the (erroneous) response to send back.
+fn decode_image_macro(method: Method, url: Uri, body: Vec<u8>) -> Result<ImageMacro, Response> {
     let parsed_im: Result<_, Box<Error>> = match method {
         Method::Get => {
             let query = match url.query() {
@@ -52,7 +116,7 @@ pub fn caption_macro(method: Method, url: Uri, body: Vec<u8>) -> BoxFuture<Respo
             let response = Response::new().with_status(StatusCode::MethodNotAllowed)
                 .with_header(ContentType::plaintext())
                 .with_header(ContentLength(0));
-            return future::ok(response).boxed();
+            return Err(response);
         },
     };
 
@@ -60,28 +124,27 @@ pub fn caption_macro(method: Method, url: Uri, body: Vec<u8>) -> BoxFuture<Respo
         Ok(im) => im,
         Err(e) => {
             error!("Failed to decode image macro: {}", e);
-            return future::ok(error_response(
+            return Err(error_response(
                 StatusCode::BadRequest,
-                format!("cannot decode request: {}", e))).boxed();
+                format!("cannot decode request: {}", e)));
         },
     };
     debug!("Decoded {:?}", im);
+    Ok(im)
+}
 
-    CAPTIONER.render(im)
-        .map(|out| {
-            let mime_type = match out.mime_type() {
-                Some(mt) => mt,
-                None => return error_response(
-                    StatusCode::InternalServerError,
-                    format!("invalid format: {:?}", out.format())),
-            };
-            Response::new()
-                .with_header(ContentType(mime_type))
-                .with_header(ContentLength(out.len() as u64))
-                .with_body(out.into_bytes())
-        })
-        .or_else(|e| future::ok(error_response(status_code_for(&e), e)))
-        .boxed()
+/// Create the HTTP response with a rendered image macro.
+fn output_response(out: CaptionOutput) -> Response {
+    let mime_type = match out.mime_type() {
+        Some(mt) => mt,
+        None => return error_response(
+            StatusCode::InternalServerError,
+            format!("invalid format: {:?}", out.format())),
+    };
+    Response::new()
+        .with_header(ContentType(mime_type))
+        .with_header(ContentLength(out.len() as u64))
+        .with_body(out.into_bytes())
 }
 
 
@@ -90,10 +153,18 @@ fn status_code_for(e: &RenderError) -> StatusCode {
     match *e {
         RenderError::Caption(ref e) => match *e {
             CaptionError::Template{..} => StatusCode::NotFound,
+            CaptionError::Image(..) => StatusCode::BadRequest,
             CaptionError::Font{..} => StatusCode::NotFound,
+            CaptionError::UnknownEffect(..) => StatusCode::BadRequest,
+            CaptionError::Effect{..} => StatusCode::InternalServerError,
+            CaptionError::FrameOutOfRange{..} => StatusCode::BadRequest,
+            CaptionError::TooLarge{..} => StatusCode::BadRequest,
+            CaptionError::TooManyFrames(..) => StatusCode::BadRequest,
+            CaptionError::Config(..) => StatusCode::InternalServerError,
             CaptionError::Encode(..) => StatusCode::InternalServerError,
+            CaptionError::Cancelled => StatusCode::ServiceUnavailable,
         },
-        RenderError::Timeout => StatusCode::InternalServerError,
+        RenderError::Timeout => StatusCode::GatewayTimeout,
         RenderError::Unavailable => StatusCode::ServiceUnavailable,
     }
 }
@@ -200,6 +200,11 @@ fn set_config<S, B>(opts: Options, server: &mut Server<S, B>)
         CAPTIONER.set_thread_count(rt_count);
         debug!("Number of threads for image captioning set to {}", rt_count);
     }
+    CAPTIONER.set_max_queue_length(opts.max_queue_length);
+    match opts.max_queue_length {
+        Some(mql) => debug!("Image captioning queue limited to {} image macros", mql),
+        None => debug!("Image captioning queue is unbounded."),
+    }
     if let Some(quality) = opts.gif_quality {
         if CAPTIONER.set_gif_quality(quality) {
             debug!("GIF animation quality set to {}%", quality);
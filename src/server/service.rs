@@ -7,18 +7,23 @@ use futures::{BoxFuture, future, Future};
 use hyper::{self, Get, StatusCode};
 use hyper::header::{Expires, ContentLength, ContentType};
 use hyper::server::{Service, Request, Response};
+use rofl::PoolStats;
 use rofl::cache::ThreadSafeCache;
 use serde_json::Value as Json;
 use time::precise_time_s;
 
 use ext::hyper::BodyExt;
-use handlers::{CAPTIONER, caption_macro};
+use handlers::{CAPTIONER, caption_job_events, caption_job_result, caption_macro,
+               start_caption_job};
 use handlers::list::{list_fonts, list_templates};
 use handlers::util::json_response;
 
 
 pub struct Rofl;
 
+/// Prefix of the paths of requests about a particular render job.
+const JOB_PATH_PREFIX: &'static str = "/caption/jobs/";
+
 impl Service for Rofl {
     type Request = Request;
     type Response = Response;
@@ -52,6 +57,8 @@ impl Rofl {
     fn handle(&self, req: Request) -> <Rofl as Service>::Future {
         match (req.method(), req.path()) {
             (_, "/caption") => self.handle_caption(req),
+            (_, "/caption/jobs") => self.handle_start_caption_job(req),
+            (&Get, path) if path.starts_with(JOB_PATH_PREFIX) => self.handle_caption_job(req),
             (&Get, "/templates") => self.handle_list_templates(req),
             (&Get, "/fonts") => self.handle_list_fonts(req),
             (&Get, "/stats") => self.handle_stats(req),
@@ -78,6 +85,28 @@ impl Rofl {
             .boxed()
     }
 
+    /// Handle the request which starts a render job.
+    fn handle_start_caption_job(&self, request: Request) -> <Self as Service>::Future {
+        let (method, url, _, _, body) = request.deconstruct();
+        body.into_bytes()
+            .and_then(move |body| start_caption_job(method, url, body))
+            .map(|response| response.with_header(Expires(SystemTime::now().into())))
+            .boxed()
+    }
+
+    /// Handle the request for the result or the events of a render job.
+    fn handle_caption_job(&self, request: Request) -> <Self as Service>::Future {
+        let path = request.path()[JOB_PATH_PREFIX.len()..].to_owned();
+        let mut parts = path.splitn(2, '/');
+        let id = parts.next().unwrap();
+        let response = match parts.next() {
+            None => caption_job_result(id),
+            Some("events") => caption_job_events(id),
+            Some(_) => return self.handle_404(request),
+        };
+        future::ok(response.with_header(Expires(SystemTime::now().into()))).boxed()
+    }
+
     /// Handle the template listing request.
     fn handle_list_templates(&self, _: Request) -> <Self as Service>::Future {
         let template_names = list_templates();
@@ -98,7 +127,9 @@ impl Rofl {
             "cache": {
                 "templates": cache_stats(CAPTIONER.template_cache()),
                 "fonts": cache_stats(CAPTIONER.font_cache()),
-            }
+                "glyphs": cache_stats(CAPTIONER.glyph_cache()),
+            },
+            "render": render_stats(CAPTIONER.pool_stats()),
         });
         return future::ok(json_response(stats)).boxed();
 
@@ -111,6 +142,16 @@ impl Rofl {
                 "hits": cache.hits(),
             })
         }
+
+        fn render_stats(stats: PoolStats) -> Json {
+            json!({
+                "queued": stats.queued,
+                "running": stats.running,
+                "completed": stats.completed,
+                "failed": stats.failed,
+                "rejected": stats.rejected,
+            })
+        }
     }
 }
 